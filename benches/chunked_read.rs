@@ -0,0 +1,111 @@
+// Copyright (c) 2016-2020 The http-serve developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Reads a large file through `ChunkedReadFile::get_range` without any HTTP overhead, reporting
+//! throughput and the number of allocations per read.
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, Criterion};
+use futures::{Stream, StreamExt};
+use http_serve::{ChunkedReadFile, Entity};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fs::File;
+use std::io::Write;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Once};
+use std::time::Duration;
+
+type BoxedError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Wraps the system allocator to count allocations.
+struct CountingAlloc;
+
+static ALLOCS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+/// The size of the file read, in mebibytes.
+const TOTAL_MIB: usize = 64;
+
+type Crf = ChunkedReadFile<Bytes, BoxedError>;
+
+/// Reads the whole entity, returning the number of bytes read.
+///
+/// This runs on a worker thread, as `ChunkedReadFile` requires.
+fn read(rt: &mut tokio::runtime::Runtime, crf: &Arc<Crf>) -> usize {
+    let crf = Arc::clone(crf);
+    let h = rt.spawn(async move {
+        let mut stream: Pin<Box<dyn Stream<Item = Result<Bytes, BoxedError>> + Send + Sync>> =
+            crf.get_range(0..crf.len()).into();
+        let mut total = 0;
+        while let Some(chunk) = stream.next().await {
+            total += chunk.unwrap().len();
+        }
+        total
+    });
+    rt.block_on(h).unwrap()
+}
+
+fn read_64mib(b: &mut criterion::Bencher) {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let path = tmpdir.path().join("f");
+    let mut f = File::create(&path).unwrap();
+    for _ in 0..TOTAL_MIB {
+        f.write_all(&[0; 1 << 20]).unwrap();
+    }
+    let crf = Arc::new(Crf::new(File::open(&path).unwrap(), http::HeaderMap::new()).unwrap());
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+
+    // criterion calls this function several times; report allocations only once.
+    static REPORT: Once = Once::new();
+    REPORT.call_once(|| {
+        let before = ALLOCS.load(Ordering::Relaxed);
+        assert_eq!(read(&mut rt, &crf), TOTAL_MIB << 20);
+        println!(
+            "read_64mib: {} allocations per read",
+            ALLOCS.load(Ordering::Relaxed) - before
+        );
+    });
+
+    b.iter(|| assert_eq!(read(&mut rt, &crf), TOTAL_MIB << 20));
+}
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let mut g = c.benchmark_group("chunked_read");
+    g.throughput(criterion::Throughput::Bytes((TOTAL_MIB << 20) as u64))
+        .bench_function("64mib", read_64mib);
+    g.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default()
+        .sample_size(10)
+        .warm_up_time(Duration::from_millis(100))
+        .measurement_time(Duration::from_secs(2));
+    targets = criterion_benchmark
+}
+criterion_main!(benches);
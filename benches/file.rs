@@ -104,16 +104,12 @@ fn serve_last_byte_1mib(b: &mut criterion::Bencher) {
 }
 
 fn criterion_benchmark(c: &mut Criterion) {
-    c.bench(
-        "serve_full_entity",
-        criterion::Benchmark::new("1kib", |b| serve_full_entity(b, &1))
-            .throughput(criterion::Throughput::Bytes(1024)),
-    );
-    c.bench(
-        "serve_full_entity",
-        criterion::Benchmark::new("1mib", |b| serve_full_entity(b, &1024))
-            .throughput(criterion::Throughput::Bytes(1024 * 1024)),
-    );
+    let mut g = c.benchmark_group("serve_full_entity");
+    g.throughput(criterion::Throughput::Bytes(1024))
+        .bench_function("1kib", |b| serve_full_entity(b, &1));
+    g.throughput(criterion::Throughput::Bytes(1024 * 1024))
+        .bench_function("1mib", |b| serve_full_entity(b, &1024));
+    g.finish();
     c.bench_function("serve_last_byte_1mib", serve_last_byte_1mib);
 }
 
@@ -7,31 +7,37 @@
 // except according to those terms.
 
 //! Benchmarks of serving data built in to the binary via `include_bytes!`, using both the
-//! `serve` function on an `Entity` and the `streaming_body` method.
+//! `serve` function on an `Entity` and the `streaming_body` method, including conditional and
+//! range requests.
 
 use bytes::{Bytes, BytesMut};
-use criterion::{
-    criterion_group, criterion_main, Benchmark, Criterion, ParameterizedBenchmark, Throughput,
-};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
 use futures::Stream;
 use futures::{future, stream};
-use http::header::HeaderValue;
+use http::header::{self, HeaderMap, HeaderValue};
 use http::{Request, Response};
-use http_serve::streaming_body;
+use http_serve::{streaming_body, CompressionPreset};
 use hyper::Body;
 use once_cell::sync::Lazy;
 use std::convert::TryInto;
-use std::io::{Read, Write};
+use std::io::{IoSlice, Read, Write};
 use std::net::SocketAddr;
 use std::ops::Range;
 use std::str::FromStr;
+use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 
 static WONDERLAND: &[u8] = include_bytes!("wonderland.txt");
 
+/// The piece size for the `v` and `w` paths, comparable to a small serialized record.
+const SMALL_PIECE: usize = 32;
+
 type BoxedError = Box<dyn std::error::Error + Send + Sync>;
 
-struct BytesEntity(Bytes);
+/// The etag of the `e` path's entity.
+const ETAG: &str = "\"wonderland\"";
+
+struct BytesEntity(Bytes, Option<HeaderValue>);
 
 impl http_serve::Entity for BytesEntity {
     type Data = Bytes;
@@ -45,9 +51,7 @@ impl http_serve::Entity for BytesEntity {
         range: Range<u64>,
     ) -> Box<dyn Stream<Item = Result<Self::Data, Self::Error>> + Send + Sync> {
         Box::new(stream::once(future::ok(
-            self.0
-                .slice(range.start as usize..range.end as usize)
-                .into(),
+            self.0.slice(range.start as usize..range.end as usize),
         )))
     }
     fn add_headers(&self, headers: &mut http::header::HeaderMap) {
@@ -57,25 +61,107 @@ impl http_serve::Entity for BytesEntity {
         );
     }
     fn etag(&self) -> Option<HeaderValue> {
-        None
+        self.1.clone()
     }
     fn last_modified(&self) -> Option<SystemTime> {
         None
     }
 }
 
+/// An entity shared between requests, with several headers which are either inserted on each
+/// request by `add_headers` or precomputed and returned by `static_headers`.
+#[derive(Clone)]
+struct CachedEntity(Arc<CachedEntityInner>);
+
+struct CachedEntityInner {
+    body: Bytes,
+    headers: Option<HeaderMap>,
+    last_modified: Option<SystemTime>,
+}
+
+impl CachedEntity {
+    fn new(precompute: bool, last_modified: Option<SystemTime>) -> Self {
+        let headers = if precompute {
+            let mut h = HeaderMap::new();
+            add_cached_entity_headers(&mut h);
+            Some(h)
+        } else {
+            None
+        };
+        CachedEntity(Arc::new(CachedEntityInner {
+            body: Bytes::from_static(WONDERLAND),
+            headers,
+            last_modified,
+        }))
+    }
+}
+
+/// Adds headers as an entity might from its metadata, validating each value.
+fn add_cached_entity_headers(h: &mut HeaderMap) {
+    let mime = "text/plain; charset=utf-8";
+    let filename = "wonderland.txt";
+    h.insert(header::CONTENT_TYPE, HeaderValue::from_str(mime).unwrap());
+    h.insert(header::CONTENT_LANGUAGE, HeaderValue::from_static("en"));
+    h.insert(
+        header::CONTENT_DISPOSITION,
+        HeaderValue::from_str(&format!("inline; filename=\"{}\"", filename)).unwrap(),
+    );
+    h.insert(
+        header::X_CONTENT_TYPE_OPTIONS,
+        HeaderValue::from_static("nosniff"),
+    );
+    h.insert(
+        header::LINK,
+        HeaderValue::from_static("</style.css>; rel=preload; as=style"),
+    );
+}
+
+impl http_serve::Entity for CachedEntity {
+    type Data = Bytes;
+    type Error = BoxedError;
+
+    fn len(&self) -> u64 {
+        self.0.body.len() as u64
+    }
+    fn get_range(
+        &self,
+        range: Range<u64>,
+    ) -> Box<dyn Stream<Item = Result<Self::Data, Self::Error>> + Send + Sync> {
+        Box::new(stream::once(future::ok(
+            self.0.body.slice(range.start as usize..range.end as usize),
+        )))
+    }
+    fn add_headers(&self, headers: &mut HeaderMap) {
+        add_cached_entity_headers(headers);
+    }
+    fn static_headers(&self) -> Option<&HeaderMap> {
+        self.0.headers.as_ref()
+    }
+    fn etag(&self) -> Option<HeaderValue> {
+        None
+    }
+    fn last_modified(&self) -> Option<SystemTime> {
+        self.0.last_modified
+    }
+}
+
 async fn serve(req: Request<Body>) -> Result<Response<Body>, BoxedError> {
     let path = req.uri().path();
     let resp = match path.as_bytes()[1] {
         b's' => {
             // static entity
-            http_serve::serve(BytesEntity(Bytes::from_static(WONDERLAND)), &req)
+            http_serve::serve(BytesEntity(Bytes::from_static(WONDERLAND), None), &req)
+        }
+        b'e' => {
+            // static entity with an etag, for conditional requests
+            let etag = Some(HeaderValue::from_static(ETAG));
+            http_serve::serve(BytesEntity(Bytes::from_static(WONDERLAND), etag), &req)
         }
         b'c' => {
             // copied entity
             let mut b = BytesMut::with_capacity(WONDERLAND.len());
             b.extend_from_slice(WONDERLAND);
-            http_serve::serve(BytesEntity(b.freeze()), &req)
+            http_serve::serve(BytesEntity(b.freeze(), None), &req)
         }
         b'b' => {
             // chunked, data written before returning the Response.
@@ -108,6 +194,44 @@ async fn serve(req: Request<Body>) -> Result<Response<Body>, BoxedError> {
             });
             resp
         }
+        b'p' => {
+            // chunked with a compression preset, data written in 4 KiB pieces before returning.
+            let preset = match &path[2..] {
+                "latency" => CompressionPreset::Latency,
+                "balanced" => CompressionPreset::Balanced,
+                "size" => CompressionPreset::Size,
+                p => panic!("unknown preset {}", p),
+            };
+            let (resp, w) = streaming_body(&req).preset(preset).build();
+            if let Some(mut w) = w {
+                for piece in WONDERLAND.chunks(4096) {
+                    w.write_all(piece).unwrap();
+                }
+            }
+            resp
+        }
+        b'v' | b'w' => {
+            // chunked, data written in many small pieces before returning the Response: with
+            // write_vectored in batches for 'v', or one write_all per piece for 'w'.
+            let l = u32::from_str(&path[2..]).unwrap();
+            let (resp, w) = streaming_body(&req).with_gzip_level(l).build();
+            if let Some(mut w) = w {
+                if path.as_bytes()[1] == b'v' {
+                    let pieces: Vec<IoSlice> =
+                        WONDERLAND.chunks(SMALL_PIECE).map(IoSlice::new).collect();
+                    for batch in pieces.chunks(32) {
+                        // http_serve's BodyWriter always consumes the whole batch.
+                        let n = w.write_vectored(batch).unwrap();
+                        assert_eq!(n, batch.iter().map(|b| b.len()).sum::<usize>());
+                    }
+                } else {
+                    for piece in WONDERLAND.chunks(SMALL_PIECE) {
+                        w.write_all(piece).unwrap();
+                    }
+                }
+            }
+            resp
+        }
         _ => unreachable!(),
     };
     Ok(resp)
@@ -143,11 +267,19 @@ static SERVER: Lazy<SocketAddr> = Lazy::new(new_server);
 /// to avoid errors due to ephemeral port exhaustion. This requires some parsing with `httparse`
 /// to read the correct amount of data.
 fn get(b: &mut criterion::Bencher, path: &str) {
+    get_with(b, path, "", 200)
+}
+
+/// Benchmarks a `GET` request as in `get`, with additional headers (each ending in CRLF),
+/// expecting the given status.
+fn get_with(b: &mut criterion::Bencher, path: &str, headers: &str, status: u16) {
     let _ = env_logger::try_init();
     let mut v = Vec::new();
     v.extend(b"GET /");
     v.extend(path.as_bytes());
-    v.extend(&b" HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: gzip\r\n\r\n"[..]);
+    v.extend(&b" HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: gzip\r\n"[..]);
+    v.extend(headers.as_bytes());
+    v.extend(b"\r\n");
 
     // Add enough buffer space for the uncompressed representation and some headers.
     let mut buf = vec![0u8; WONDERLAND.len() + 8192];
@@ -165,7 +297,11 @@ fn get(b: &mut criterion::Bencher, path: &str) {
         let mut resp = httparse::Response::new(&mut hdrs_buf);
         let mut end = s.read(&mut buf[..]).unwrap();
         let mut pos = resp.parse(&buf[..end]).unwrap().unwrap();
-        assert_eq!(resp.code, Some(200));
+        assert_eq!(resp.code, Some(status));
+        if status == 304 {
+            assert_eq!(end, pos);
+            return;
+        }
         let mut hdr_len: Option<usize> = None;
         let mut chunked = false;
         for h in resp.headers {
@@ -224,26 +360,89 @@ fn get(b: &mut criterion::Bencher, path: &str) {
 }
 
 fn criterion_benchmark(c: &mut Criterion) {
-    c.bench(
-        "serve",
-        Benchmark::new("static", |b| get(b, "s"))
-            .throughput(Throughput::Bytes(WONDERLAND.len() as u64)),
-    );
-    c.bench(
-        "serve",
-        Benchmark::new("copied", |b| get(b, "c"))
-            .throughput(Throughput::Bytes(WONDERLAND.len() as u64)),
-    );
-    c.bench(
-        "streaming_body_before",
-        ParameterizedBenchmark::new("gzip", |b, p| get(b, &format!("b4096:{}", p)), 0..=9)
-            .throughput(|_| Throughput::Bytes(WONDERLAND.len() as u64)),
-    );
-    c.bench(
-        "streaming_body_after",
-        ParameterizedBenchmark::new("gzip", |b, p| get(b, &format!("a4096:{}", p)), 0..=9)
-            .throughput(|_| Throughput::Bytes(WONDERLAND.len() as u64)),
-    );
+    let mut g = c.benchmark_group("serve");
+    g.throughput(Throughput::Bytes(WONDERLAND.len() as u64));
+    g.bench_function("static", |b| get(b, "s"));
+    g.bench_function("copied", |b| get(b, "c"));
+    g.finish();
+
+    // Builds (but doesn't send) a 200 response, to isolate the cost of the entity headers. The
+    // last_modified case adds formatting the Date and Last-Modified, which serve skips without
+    // a last modified time.
+    let mut g = c.benchmark_group("serve_headers");
+    let req = Request::get("/").body(()).unwrap();
+    let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+    for &(name, precompute, last_modified) in &[
+        ("add_headers", false, None),
+        ("static_headers", true, None),
+        ("last_modified", true, Some(modified)),
+    ] {
+        let e = CachedEntity::new(precompute, last_modified);
+        g.bench_function(name, |b| {
+            b.iter(|| {
+                let resp: Response<Body> = http_serve::serve(e.clone(), &req);
+                resp
+            })
+        });
+    }
+    g.finish();
+
+    // The overhead of evaluating conditional and range requests, compared to "serve/static".
+    let mut g = c.benchmark_group("serve_conditional");
+    let if_none_match = |etag| format!("If-None-Match: {}\r\n", etag);
+    let cases = [
+        ("if_none_match_hit", if_none_match(ETAG), 304),
+        ("if_none_match_miss", if_none_match("\"other\""), 200),
+        ("range", "Range: bytes=1000-1999\r\n".to_owned(), 206),
+        (
+            "multipart_3",
+            "Range: bytes=0-999,50000-50999,100000-100999\r\n".to_owned(),
+            206,
+        ),
+    ];
+    for (name, headers, status) in &cases {
+        g.bench_function(*name, |b| get_with(b, "e", headers, *status));
+    }
+    g.finish();
+
+    let mut g = c.benchmark_group("streaming_body_before");
+    g.throughput(Throughput::Bytes(WONDERLAND.len() as u64));
+    for p in 0..=9 {
+        g.bench_with_input(BenchmarkId::new("gzip", p), &p, |b, p| {
+            get(b, &format!("b4096:{}", p))
+        });
+    }
+    g.finish();
+
+    let mut g = c.benchmark_group("streaming_body_after");
+    g.throughput(Throughput::Bytes(WONDERLAND.len() as u64));
+    for p in 0..=9 {
+        g.bench_with_input(BenchmarkId::new("gzip", p), &p, |b, p| {
+            get(b, &format!("a4096:{}", p))
+        });
+    }
+    g.finish();
+
+    let mut g = c.benchmark_group("streaming_body_preset");
+    g.throughput(Throughput::Bytes(WONDERLAND.len() as u64));
+    for p in &["latency", "balanced", "size"] {
+        g.bench_with_input(BenchmarkId::from_parameter(p), p, |b, p| {
+            get(b, &format!("p{}", p))
+        });
+    }
+    g.finish();
+
+    let mut g = c.benchmark_group("streaming_body_small_writes");
+    g.throughput(Throughput::Bytes(WONDERLAND.len() as u64));
+    for p in &[0, 6] {
+        g.bench_with_input(BenchmarkId::new("vectored", p), p, |b, p| {
+            get(b, &format!("v{}", p))
+        });
+        g.bench_with_input(BenchmarkId::new("write_all", p), p, |b, p| {
+            get(b, &format!("w{}", p))
+        });
+    }
+    g.finish();
 
     // Also benchmark larger chunksizes, but only with gzip level 0 (disabled). The chunk size
     // difference is dwarfed by gzip overhead. When not gzipping, it makes a noticeable difference,
@@ -254,24 +453,23 @@ fn criterion_benchmark(c: &mut Criterion) {
     //   call, chunks must be at least 1/16th of the total file size.
     //
     // * more memory allocations.
-    c.bench(
-        "streaming_body_before",
-        ParameterizedBenchmark::new(
-            "chunksize",
-            |b, p| get(b, &format!("b{}:0", p)),
-            &[4096, 16384, 65536, 1048576],
-        )
-        .throughput(|_| Throughput::Bytes(WONDERLAND.len() as u64)),
-    );
-    c.bench(
-        "streaming_body_after",
-        ParameterizedBenchmark::new(
-            "chunksize",
-            |b, p| get(b, &format!("a{}:0", p)),
-            &[4096, 16384, 65536, 1048576],
-        )
-        .throughput(|_| Throughput::Bytes(WONDERLAND.len() as u64)),
-    );
+    let mut g = c.benchmark_group("streaming_body_before");
+    g.throughput(Throughput::Bytes(WONDERLAND.len() as u64));
+    for p in &[4096, 16384, 65536, 1048576] {
+        g.bench_with_input(BenchmarkId::new("chunksize", p), p, |b, p| {
+            get(b, &format!("b{}:0", p))
+        });
+    }
+    g.finish();
+
+    let mut g = c.benchmark_group("streaming_body_after");
+    g.throughput(Throughput::Bytes(WONDERLAND.len() as u64));
+    for p in &[4096, 16384, 65536, 1048576] {
+        g.bench_with_input(BenchmarkId::new("chunksize", p), p, |b, p| {
+            get(b, &format!("a{}:0", p))
+        });
+    }
+    g.finish();
 }
 
 criterion_group! {
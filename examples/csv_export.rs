@@ -0,0 +1,123 @@
+// Copyright (c) 2016-2020 The http-serve developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Test program which serves a CSV export of (simulated) database rows on
+//! `http://127.0.0.1:1337/`, in the two ways a handler might:
+//!
+//! *   `/export.csv` knows the export's length up front, as from a `COUNT` of fixed-width rows,
+//!     so it's served with `csv_entity`. It has a `Content-Length` and supports resuming an
+//!     interrupted download.
+//! *   `/stream.csv` doesn't, so it writes rows to a `streaming_body` as they arrive, flushing
+//!     periodically and stopping when the client goes away.
+//!
+//! Some commands to try:
+//!
+//! ```
+//! $ curl -v http://127.0.0.1:1337/export.csv | tail
+//! $ curl -v -H 'Range: bytes=100000-' http://127.0.0.1:1337/export.csv | head
+//! $ curl -v --compressed http://127.0.0.1:1337/stream.csv | head
+//! ```
+
+use bytes::Bytes;
+use futures::{stream, Stream, StreamExt};
+use http::header::{self, HeaderValue};
+use http::{Request, Response, StatusCode};
+use http_serve::{csv_entity, csv_record, streaming_body};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::Body;
+use std::io::Write;
+
+type BoxedError = Box<dyn std::error::Error + Send + Sync>;
+
+const ROWS: u64 = 100_000;
+const SCHEMA: &[&str] = &["id", "sku", "qty"];
+
+/// Flush the streaming body after this many rows, so the client sees progress on a slow query
+/// without a chunk per row.
+const FLUSH_EVERY: u64 = 1_000;
+
+/// Stands in for a database query over a snapshot, returning rows in a stable order.
+fn query() -> impl Stream<Item = Result<Vec<String>, BoxedError>> + Send {
+    stream::iter((0..ROWS).map(|i| {
+        Ok(vec![
+            format!("{:06}", i),
+            format!("SKU-{:04}", i % 10_000),
+            format!("{:03}", i % 1_000),
+        ])
+    }))
+}
+
+/// Stands in for `SELECT COUNT(*)`: each row is the same width.
+fn rows_len() -> u64 {
+    let width = csv_record(&["000000", "SKU-0000", "000"]).len() as u64;
+    ROWS * width
+}
+
+async fn serve(req: Request<Body>) -> Result<Response<Body>, BoxedError> {
+    match req.uri().path() {
+        "/export.csv" => {
+            // The etag identifies the snapshot, so a resumed download can't mix two exports.
+            let e = csv_entity::<Bytes, BoxedError, _, _>(rows_len(), SCHEMA, query)
+                .etag(HeaderValue::from_static("\"snapshot-1\""));
+            Ok(http_serve::serve(e, &req))
+        }
+        "/stream.csv" => {
+            let (mut resp, writer) = streaming_body(&req).build();
+            resp.headers_mut().insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("text/csv; charset=utf-8"),
+            );
+            if let Some(mut w) = writer {
+                tokio::spawn(async move {
+                    let mut rows = Box::pin(query());
+                    let mut n = 0;
+                    if w.write_all(&csv_record(SCHEMA)).is_err() {
+                        return;
+                    }
+                    while let Some(row) = rows.next().await {
+                        let row = match row {
+                            Ok(r) => r,
+                            Err(e) => {
+                                // Ends the body with an error, so the client doesn't mistake
+                                // a partial export for a complete one.
+                                w.abort(e);
+                                return;
+                            }
+                        };
+                        // An error means the client is gone; stop the query.
+                        if w.write_all(&csv_record(&row)).is_err() {
+                            return;
+                        }
+                        n += 1;
+                        if n % FLUSH_EVERY == 0 && w.flush().is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+            Ok(resp)
+        }
+        _ => Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())?),
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), BoxedError> {
+    env_logger::init();
+    let addr = ([127, 0, 0, 1], 1337).into();
+    let make_svc = make_service_fn(move |_conn| {
+        futures::future::ok::<_, std::convert::Infallible>(service_fn(serve))
+    });
+    let server = hyper::server::Server::bind(&addr).serve(make_svc);
+    println!("Serving CSV exports on http://{}.", server.local_addr());
+    server.await?;
+
+    Ok(())
+}
@@ -0,0 +1,94 @@
+// Copyright (c) 2016-2020 The http-serve developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Test program which serves a local file on port 1337 of every local address, IPv4 and IPv6,
+//! until Enter is pressed, then shuts down gracefully.
+//!
+//! Downloads in progress at shutdown get up to 30 seconds to finish; requests arriving on open
+//! connections in the meantime get `503 Service Unavailable`. To try it, start a slow download of
+//! a large file, then press Enter:
+//!
+//! ```
+//! $ curl --limit-rate 1M 'http://[::1]:1337/' > /dev/null
+//! ```
+
+use bytes::Bytes;
+use http::{Request, Response};
+use http_serve::drain::Watch;
+use http_serve::{ChunkedReadFile, ServeOptions};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::Body;
+use std::time::Duration;
+
+struct Context {
+    path: std::ffi::OsString,
+    opts: ServeOptions,
+}
+
+type BoxedError = Box<dyn std::error::Error + Send + Sync>;
+
+async fn serve(ctx: &'static Context, req: Request<Body>) -> Result<Response<Body>, BoxedError> {
+    let f = tokio::task::block_in_place::<_, Result<ChunkedReadFile<Bytes, BoxedError>, BoxedError>>(
+        move || {
+            let f = std::fs::File::open(&ctx.path)?;
+            let headers = http::header::HeaderMap::new();
+            Ok(ChunkedReadFile::new(f, headers)?)
+        },
+    )?;
+    Ok(http_serve::serve_with_options(f, &req, &ctx.opts))
+}
+
+#[tokio::main]
+async fn main() -> Result<(), BoxedError> {
+    let mut args = std::env::args_os();
+    if args.len() != 2 {
+        eprintln!("Expected graceful_shutdown [FILENAME]");
+        std::process::exit(1);
+    }
+    let path = args.nth(1).unwrap();
+
+    let watch = Watch::new(Duration::from_secs(30));
+    let opts = ServeOptions::new().drain(watch.clone());
+    let ctx: &'static Context = Box::leak(Box::new(Context { path, opts }));
+
+    env_logger::init();
+
+    // Listen on IPv6 with IPV6_V6ONLY off, so IPv4 clients are accepted too (as IPv4-mapped
+    // addresses) on one socket, whatever the platform's default.
+    use socket2::{Domain, Socket, Type};
+    let addr: std::net::SocketAddr = (std::net::Ipv6Addr::UNSPECIFIED, 1337).into();
+    let listener = Socket::new(Domain::ipv6(), Type::stream(), None)?;
+    listener.set_only_v6(false)?;
+    listener.set_reuse_address(true)?;
+    listener.bind(&addr.into())?;
+    listener.listen(128)?;
+    let make_svc = make_service_fn(move |_conn| {
+        futures::future::ok::<_, std::convert::Infallible>(service_fn(move |req| serve(ctx, req)))
+    });
+    let server = hyper::server::Server::from_tcp(listener.into_tcp_listener())?.serve(make_svc);
+    println!(
+        "Serving {} on http://{}. Press Enter to shut down.",
+        ctx.path.to_string_lossy(),
+        server.local_addr()
+    );
+
+    // hyper stops accepting connections once this completes, then waits for open ones to close.
+    // Starting the drain alongside bounds that wait: bodies still unfinished after the grace
+    // period are cut off, which closes their connections.
+    let shutdown = async move {
+        tokio::task::spawn_blocking(|| std::io::stdin().read_line(&mut String::new()))
+            .await
+            .unwrap()
+            .unwrap();
+        println!("Shutting down.");
+        tokio::spawn(watch.drain());
+    };
+    server.with_graceful_shutdown(shutdown).await?;
+
+    Ok(())
+}
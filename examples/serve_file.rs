@@ -20,7 +20,13 @@
 
 use bytes::Bytes;
 use http::{Request, Response};
-use http_serve::ChunkedReadFile;
+#[cfg(feature = "compat")]
+use http_serve::compat::{
+    http_entity,
+    http_file::{self, ChunkedReadFile},
+};
+#[cfg(not(feature = "compat"))]
+use http_serve::{self as http_entity, ChunkedReadFile};
 use hyper::service::{make_service_fn, service_fn};
 use hyper::Body;
 
@@ -30,15 +36,32 @@ struct Context {
 
 type BoxedError = Box<dyn std::error::Error + Send + Sync>;
 
+const MIME: &str = "application/octet-stream";
+
+/// Opens `f` through the `http-file` crate's `new(file, pool, mime)` signature, to show the shim.
+#[cfg(feature = "compat")]
+fn open(f: std::fs::File) -> Result<ChunkedReadFile<Bytes, BoxedError>, std::io::Error> {
+    http_file::new(f, None::<()>, http::header::HeaderValue::from_static(MIME))
+}
+
+#[cfg(not(feature = "compat"))]
+fn open(f: std::fs::File) -> Result<ChunkedReadFile<Bytes, BoxedError>, std::io::Error> {
+    let mut headers = http::header::HeaderMap::new();
+    headers.insert(
+        http::header::CONTENT_TYPE,
+        http::header::HeaderValue::from_static(MIME),
+    );
+    ChunkedReadFile::new(f, headers)
+}
+
 async fn serve(ctx: &'static Context, req: Request<Body>) -> Result<Response<Body>, BoxedError> {
     let f = tokio::task::block_in_place::<_, Result<ChunkedReadFile<Bytes, BoxedError>, BoxedError>>(
         move || {
             let f = std::fs::File::open(&ctx.path)?;
-            let headers = http::header::HeaderMap::new();
-            Ok(ChunkedReadFile::new(f, headers)?)
+            Ok(open(f)?)
         },
     )?;
-    Ok(http_serve::serve(f, &req))
+    Ok(http_entity::serve(f, &req))
 }
 
 #[tokio::main]
@@ -50,7 +73,7 @@ async fn main() -> Result<(), BoxedError> {
     }
     let path = args.nth(1).unwrap();
 
-    let ctx: &'static Context = Box::leak(Box::new(Context { path: path }));
+    let ctx: &'static Context = Box::leak(Box::new(Context { path }));
 
     env_logger::init();
     let addr = ([127, 0, 0, 1], 1337).into();
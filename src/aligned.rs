@@ -0,0 +1,280 @@
+// Copyright (c) 2016-2020 The http-serve developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::guarded::Boxed;
+use crate::trim::Trim;
+use crate::{BoxedEntity, ContentCoding, Entity};
+use futures::Stream;
+use http::header::{HeaderMap, HeaderValue};
+use std::ops::Range;
+use std::pin::Pin;
+use std::time::SystemTime;
+
+/// An entity which reads its inner entity only at offsets aligned to a fixed size, for storage
+/// which reads whole extents anyway.
+///
+/// Each range is widened to `alignment` boundaries (or the end of the entity) before calling the
+/// inner entity's `get_range`, and the resulting stream is trimmed back to the requested bytes:
+/// leading bytes are dropped from the first chunks, and the last chunk is truncated. The client
+/// sees exactly the same bytes, `Content-Range`, and `Content-Length` as with the inner entity.
+/// Ranges which are already aligned are passed through unchanged. Everything else, including
+/// content-coded variants (which are wrapped likewise), passes through.
+pub struct AlignedFetchEntity<E> {
+    inner: E,
+    alignment: u64,
+}
+
+impl<E: Entity> AlignedFetchEntity<E> {
+    /// Wraps `inner`. Until `alignment` is set, ranges are passed through unchanged.
+    pub fn new(inner: E) -> Self {
+        AlignedFetchEntity {
+            inner,
+            alignment: 1,
+        }
+    }
+
+    /// Sets the alignment in bytes, such as `4 << 20` for 4 MiB extents.
+    ///
+    /// Panics if `alignment` is 0.
+    pub fn alignment(mut self, alignment: u64) -> Self {
+        assert!(alignment > 0, "alignment must be positive");
+        self.alignment = alignment;
+        self
+    }
+
+    /// Returns the aligned range covering `range`.
+    fn widen(&self, range: &Range<u64>) -> Range<u64> {
+        let a = self.alignment;
+        let start = range.start - range.start % a;
+        let end = match range.end % a {
+            0 => range.end,
+            r => range.end.saturating_add(a - r),
+        };
+        start..std::cmp::min(end, self.inner.len())
+    }
+}
+
+impl<E: Entity> Entity for AlignedFetchEntity<E> {
+    type Data = E::Data;
+    type Error = E::Error;
+
+    fn len(&self) -> u64 {
+        self.inner.len()
+    }
+
+    fn get_range(
+        &self,
+        range: Range<u64>,
+    ) -> Box<dyn Stream<Item = Result<Self::Data, Self::Error>> + Send + Sync> {
+        let fetch = self.widen(&range);
+        if range.start == range.end || fetch == range {
+            return self.inner.get_range(range);
+        }
+        // If the inner stream ends early without an error, breaking the get_range contract, so
+        // does this.
+        Box::new(Trim::new(
+            Pin::from(self.inner.get_range(fetch.clone())),
+            range.start - fetch.start,
+            range.end - range.start,
+        ))
+    }
+
+    fn add_headers(&self, h: &mut HeaderMap) {
+        self.inner.add_headers(h)
+    }
+
+    fn static_headers(&self) -> Option<&HeaderMap> {
+        self.inner.static_headers()
+    }
+
+    fn content_type(&self) -> Option<HeaderValue> {
+        self.inner.content_type()
+    }
+
+    fn etag(&self) -> Option<HeaderValue> {
+        self.inner.etag()
+    }
+
+    fn last_modified(&self) -> Option<SystemTime> {
+        self.inner.last_modified()
+    }
+
+    fn digest(&self) -> Option<HeaderValue> {
+        self.inner.digest()
+    }
+
+    fn content_location(&self) -> Option<HeaderValue> {
+        self.inner.content_location()
+    }
+
+    fn contains(&self, needle: &[u8], within: Range<u64>) -> Option<bool> {
+        self.inner.contains(needle, within)
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn variants(&self) -> Vec<(ContentCoding, BoxedEntity<Self::Data, Self::Error>)> {
+        self.inner
+            .variants()
+            .into_iter()
+            .map(|(coding, v)| {
+                let v: BoxedEntity<Self::Data, Self::Error> = Box::new(AlignedFetchEntity {
+                    inner: Boxed(v),
+                    alignment: self.alignment,
+                });
+                (coding, v)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AlignedFetchEntity;
+    use crate::test_support::FakeRequest;
+    use crate::Entity;
+    use bytes::Bytes;
+    use futures::{stream, Stream, TryStreamExt};
+    use http::header::{self, HeaderMap, HeaderValue};
+    use http::{Response, StatusCode};
+    use std::ops::Range;
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::time::SystemTime;
+
+    type BoxedError = Box<dyn std::error::Error + Send + Sync>;
+
+    /// An entity over `body` which yields chunks of `chunk` bytes (aligned to the entity's start)
+    /// and records the ranges requested of it.
+    struct Recording {
+        body: Vec<u8>,
+        chunk: usize,
+        requested: Arc<Mutex<Vec<Range<u64>>>>,
+    }
+
+    impl Entity for Recording {
+        type Data = Bytes;
+        type Error = BoxedError;
+
+        fn len(&self) -> u64 {
+            self.body.len() as u64
+        }
+        fn get_range(
+            &self,
+            range: Range<u64>,
+        ) -> Box<dyn Stream<Item = Result<Self::Data, Self::Error>> + Send + Sync> {
+            self.requested.lock().unwrap().push(range.clone());
+            let mut chunks = Vec::new();
+            let mut pos = range.start as usize;
+            while pos < range.end as usize {
+                let end = std::cmp::min((pos / self.chunk + 1) * self.chunk, range.end as usize);
+                chunks.push(Ok(Bytes::copy_from_slice(&self.body[pos..end])));
+                pos = end;
+            }
+            Box::new(stream::iter(chunks))
+        }
+        fn add_headers(&self, _: &mut HeaderMap) {}
+        fn etag(&self) -> Option<HeaderValue> {
+            None
+        }
+        fn last_modified(&self) -> Option<SystemTime> {
+            None
+        }
+    }
+
+    fn entity(len: usize, chunk: usize) -> Recording {
+        Recording {
+            body: (0..len).map(|i| (i % 251) as u8).collect(),
+            chunk,
+            requested: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    async fn read<E: Entity<Data = Bytes, Error = BoxedError>>(e: &E, r: Range<u64>) -> Vec<u8> {
+        let chunks: Vec<Bytes> = Pin::from(e.get_range(r)).try_collect().await.unwrap();
+        chunks.concat()
+    }
+
+    /// Every range, with chunks smaller than, equal to, larger than, and misaligned with the
+    /// alignment, matches the unaligned path and is fetched aligned.
+    #[tokio::test]
+    async fn all_ranges() {
+        const LEN: usize = 50;
+        for &chunk in &[1, 3, 8, 64] {
+            let inner = entity(LEN, chunk);
+            let requested = Arc::clone(&inner.requested);
+            let e = AlignedFetchEntity::new(inner).alignment(8);
+            for start in 0..=LEN as u64 {
+                for end in start..=LEN as u64 {
+                    assert_eq!(
+                        read(&e, start..end).await,
+                        &e.inner.body[start as usize..end as usize],
+                        "chunk={} range={}..{}",
+                        chunk,
+                        start,
+                        end
+                    );
+                }
+            }
+            for r in requested.lock().unwrap().iter() {
+                if r.start == r.end {
+                    continue;
+                }
+                assert_eq!(r.start % 8, 0, "{:?}", r);
+                assert!(r.end % 8 == 0 || r.end == LEN as u64, "{:?}", r);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn random_ranges() {
+        const LEN: u64 = 1 << 20;
+        let inner = entity(LEN as usize, 65_536 - 7);
+        let requested = Arc::clone(&inner.requested);
+        let e = AlignedFetchEntity::new(inner).alignment(4096);
+
+        // xorshift64, with a fixed seed for reproducibility.
+        let mut x = 0x2545_f491_4f6c_dd1du64;
+        let mut next = move || {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x
+        };
+        for _ in 0..200 {
+            let a = next() % (LEN + 1);
+            let b = next() % (LEN + 1);
+            let r = std::cmp::min(a, b)..std::cmp::max(a, b);
+            let unaligned = read(&e.inner, r.clone()).await;
+            assert_eq!(read(&e, r.clone()).await, unaligned, "{:?}", r);
+        }
+
+        // Ranges within one unit and already-aligned ranges.
+        requested.lock().unwrap().clear();
+        assert_eq!(read(&e, 4100..4110).await, &e.inner.body[4100..4110]);
+        assert_eq!(read(&e, 8192..16384).await, &e.inner.body[8192..16384]);
+        assert_eq!(
+            &requested.lock().unwrap()[..],
+            &[4096..8192, 8192..16384][..]
+        );
+    }
+
+    #[tokio::test]
+    async fn serve() {
+        let inner = entity(100, 16);
+        let requested = Arc::clone(&inner.requested);
+        let e = AlignedFetchEntity::new(inner).alignment(32);
+        let req = FakeRequest::get().range("bytes=10-19").build();
+        let resp: Response<hyper::Body> = crate::serve(e, &req);
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(resp.headers()[header::CONTENT_RANGE], "bytes 10-19/100");
+        assert_eq!(resp.headers()[header::CONTENT_LENGTH], "10");
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(&body[..], &(10..20).collect::<Vec<u8>>()[..]);
+        assert_eq!(*requested.lock().unwrap(), vec![0..32]);
+    }
+}
@@ -0,0 +1,447 @@
+// Copyright (c) 2016-2020 The http-serve developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::Entity;
+use bytes::Buf;
+use futures::{stream, Stream};
+use http::header::{HeaderMap, HeaderValue};
+use std::error::Error as StdError;
+use std::ops::Range;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+
+/// A cipher decrypting the fixed-size blocks of a [`BlockDecryptEntity`](struct.BlockDecryptEntity.html).
+///
+/// This crate doesn't depend on any cryptography library; implement this with the cipher the
+/// data was encrypted with, such as AES-GCM with a per-file key and a nonce derived from the
+/// block index.
+pub trait BlockCipher: Send + Sync {
+    /// Returns the number of plaintext bytes in each block. Every block but the last is full.
+    fn block_size(&self) -> usize;
+
+    /// Returns the number of bytes each block grows by when encrypted, such as the length of an
+    /// authentication tag. Defaults to 0.
+    fn overhead(&self) -> usize {
+        0
+    }
+
+    /// Decrypts block `index` in place, leaving exactly its plaintext in `buf`.
+    ///
+    /// `buf` holds the block's `block_size() + overhead()` encrypted bytes, or fewer for the last
+    /// block. An error, such as failed authentication, ends the response body with that error;
+    /// none of the block's plaintext is sent.
+    fn decrypt_block(
+        &self,
+        index: u64,
+        buf: &mut Vec<u8>,
+    ) -> Result<(), Box<dyn StdError + Send + Sync>>;
+}
+
+/// An entity whose body is the decryption of an inner entity stored as encrypted blocks.
+///
+/// A range is served by fetching only the blocks covering it from the inner entity, decrypting
+/// each, and trimming the first and last to the range. So a range costs at most two blocks more
+/// than its length, regardless of where it starts. Headers, the etag, and the last modified time
+/// pass through from the inner entity, which should describe the plaintext (for example, with
+/// its `Content-Type` rather than `application/octet-stream`).
+pub struct BlockDecryptEntity<E, C> {
+    inner: E,
+    cipher: Arc<C>,
+}
+
+impl<E: Entity, C: BlockCipher + 'static> BlockDecryptEntity<E, C>
+where
+    E::Error: From<Box<dyn StdError + Send + Sync>>,
+{
+    /// Wraps `inner`, whose body is the blocks encrypted with `cipher`, one after another.
+    ///
+    /// Panics if `cipher.block_size()` is 0.
+    pub fn new(inner: E, cipher: C) -> Self {
+        assert!(cipher.block_size() > 0, "block size must be positive");
+        BlockDecryptEntity {
+            inner,
+            cipher: Arc::new(cipher),
+        }
+    }
+
+    /// Returns the encrypted length of each block.
+    fn encrypted_block_size(&self) -> u64 {
+        (self.cipher.block_size() + self.cipher.overhead()) as u64
+    }
+}
+
+impl<E: Entity, C: BlockCipher + 'static> Entity for BlockDecryptEntity<E, C>
+where
+    E::Error: From<Box<dyn StdError + Send + Sync>>,
+{
+    type Data = E::Data;
+    type Error = E::Error;
+
+    /// Returns the plaintext length. A trailing partial block no longer than the overhead (which
+    /// can't be valid) counts as empty; its decryption is left to report the error.
+    fn len(&self) -> u64 {
+        let eb = self.encrypted_block_size();
+        let inner_len = self.inner.len();
+        let last = (inner_len % eb).saturating_sub(self.cipher.overhead() as u64);
+        inner_len / eb * self.cipher.block_size() as u64 + last
+    }
+
+    fn get_range(
+        &self,
+        range: Range<u64>,
+    ) -> Box<dyn Stream<Item = Result<Self::Data, Self::Error>> + Send + Sync> {
+        if range.start == range.end {
+            return Box::new(stream::empty());
+        }
+        let pb = self.cipher.block_size() as u64;
+        let eb = self.encrypted_block_size();
+        let first = range.start / pb;
+        let last = (range.end - 1) / pb;
+        let inner_end = std::cmp::min((last + 1) * eb, self.inner.len());
+        Box::new(Decrypt {
+            inner: Pin::from(self.inner.get_range(first * eb..inner_end)),
+            cipher: self.cipher.clone(),
+            pending: None,
+            buf: Vec::with_capacity(eb as usize),
+            index: first,
+            range,
+            done: false,
+        })
+    }
+
+    fn add_headers(&self, h: &mut HeaderMap) {
+        self.inner.add_headers(h)
+    }
+
+    fn static_headers(&self) -> Option<&HeaderMap> {
+        self.inner.static_headers()
+    }
+
+    fn content_type(&self) -> Option<HeaderValue> {
+        self.inner.content_type()
+    }
+
+    fn etag(&self) -> Option<HeaderValue> {
+        self.inner.etag()
+    }
+
+    fn last_modified(&self) -> Option<SystemTime> {
+        self.inner.last_modified()
+    }
+}
+
+/// Yields the plaintext of `range`, from `inner`'s blocks starting at block `index`.
+struct Decrypt<D, E, C> {
+    inner: Pin<Box<dyn Stream<Item = Result<D, E>> + Send + Sync>>,
+    cipher: Arc<C>,
+
+    /// The part of `inner`'s latest chunk not yet moved to `buf`.
+    pending: Option<D>,
+
+    /// The encrypted bytes of block `index` received so far.
+    buf: Vec<u8>,
+    index: u64,
+    range: Range<u64>,
+    done: bool,
+}
+
+// No field is structurally pinned; `pending` is only ever moved or used through `&mut`.
+impl<D, E, C> Unpin for Decrypt<D, E, C> {}
+
+impl<D, E, C> Decrypt<D, E, C>
+where
+    D: From<Vec<u8>>,
+    E: From<Box<dyn StdError + Send + Sync>>,
+    C: BlockCipher,
+{
+    /// Decrypts the block in `buf` and returns its part of the range, if any.
+    fn finish_block(&mut self) -> Option<Result<D, E>> {
+        let mut block = std::mem::take(&mut self.buf);
+        if let Err(e) = self.cipher.decrypt_block(self.index, &mut block) {
+            self.done = true;
+            return Some(Err(E::from(e)));
+        }
+        let block_start = self.index * self.cipher.block_size() as u64;
+        self.index += 1;
+        if block_start + block.len() as u64 >= self.range.end {
+            self.done = true;
+        }
+        let from = self.range.start.saturating_sub(block_start) as usize;
+        let to = std::cmp::min(self.range.end - block_start, block.len() as u64) as usize;
+        if from >= to {
+            return None;
+        }
+        block.truncate(to);
+        block.drain(..from);
+        Some(Ok(D::from(block)))
+    }
+}
+
+impl<D, E, C> Stream for Decrypt<D, E, C>
+where
+    D: Buf + From<Vec<u8>>,
+    E: From<Box<dyn StdError + Send + Sync>>,
+    C: BlockCipher,
+{
+    type Item = Result<D, E>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let eb = this.cipher.block_size() + this.cipher.overhead();
+        while !this.done {
+            if this.buf.len() == eb {
+                match this.finish_block() {
+                    Some(r) => return Poll::Ready(Some(r)),
+                    None => continue,
+                }
+            }
+            if let Some(ref mut d) = this.pending {
+                if d.has_remaining() {
+                    let b = d.bytes();
+                    let n = std::cmp::min(b.len(), eb - this.buf.len());
+                    this.buf.extend_from_slice(&b[..n]);
+                    d.advance(n);
+                    continue;
+                }
+                this.pending = None;
+            }
+            match this.inner.as_mut().poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Some(Ok(d))) => this.pending = Some(d),
+                Poll::Ready(Some(Err(e))) => {
+                    this.done = true;
+                    return Poll::Ready(Some(Err(e)));
+                }
+                Poll::Ready(None) if !this.buf.is_empty() => {
+                    // The inner entity's last block, which is partial. If it doesn't reach the
+                    // end of the range, the inner entity ended early.
+                    let r = this.finish_block();
+                    if !this.done {
+                        let msg = format!("encrypted body ended within block {}", this.index - 1);
+                        this.done = true;
+                        return Poll::Ready(Some(Err(E::from(msg.into()))));
+                    }
+                    return Poll::Ready(r);
+                }
+                Poll::Ready(None) => {
+                    let msg = format!("encrypted body ended before block {}", this.index);
+                    this.done = true;
+                    return Poll::Ready(Some(Err(E::from(msg.into()))));
+                }
+            }
+        }
+        Poll::Ready(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BlockCipher, BlockDecryptEntity};
+    use crate::test_support::{FakeEntity, FakeRequest};
+    use crate::Entity;
+    use bytes::Bytes;
+    use futures::{stream, Stream, TryStreamExt};
+    use http::header::{self, HeaderMap, HeaderValue};
+    use http::{Response, StatusCode};
+    use std::error::Error as StdError;
+    use std::ops::Range;
+    use std::pin::Pin;
+    use std::time::SystemTime;
+
+    type BoxedError = Box<dyn StdError + Send + Sync>;
+
+    const BLOCK: usize = 4;
+
+    /// XORs each byte with its block index, and appends a one-byte "tag": the plaintext's
+    /// wrapping sum.
+    struct XorCipher;
+
+    impl BlockCipher for XorCipher {
+        fn block_size(&self) -> usize {
+            BLOCK
+        }
+        fn overhead(&self) -> usize {
+            1
+        }
+        fn decrypt_block(&self, index: u64, buf: &mut Vec<u8>) -> Result<(), BoxedError> {
+            let tag = buf.pop().ok_or("empty block")?;
+            for b in buf.iter_mut() {
+                *b ^= index as u8;
+            }
+            if buf.iter().fold(0u8, |a, &b| a.wrapping_add(b)) != tag {
+                return Err(format!("block {} failed authentication", index).into());
+            }
+            Ok(())
+        }
+    }
+
+    fn encrypt(plaintext: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for (i, block) in plaintext.chunks(BLOCK).enumerate() {
+            out.extend(block.iter().map(|b| b ^ i as u8));
+            out.push(block.iter().fold(0u8, |a, &b| a.wrapping_add(b)));
+        }
+        out
+    }
+
+    /// Serves its body in chunks of `chunk` bytes, so chunks straddle block boundaries.
+    ///
+    /// Its `len` includes `missing` bytes past the end of `body`, as if its storage were truncated
+    /// after the length was read; ranges end early rather than error.
+    struct Chunked {
+        body: Bytes,
+        chunk: usize,
+        missing: usize,
+    }
+
+    impl Entity for Chunked {
+        type Data = Bytes;
+        type Error = BoxedError;
+
+        fn len(&self) -> u64 {
+            (self.body.len() + self.missing) as u64
+        }
+        fn get_range(
+            &self,
+            range: Range<u64>,
+        ) -> Box<dyn Stream<Item = Result<Self::Data, Self::Error>> + Send + Sync> {
+            let end = std::cmp::min(range.end as usize, self.body.len());
+            let body = self
+                .body
+                .slice(std::cmp::min(range.start as usize, end)..end);
+            let chunks: Vec<Result<Bytes, BoxedError>> = (0..body.len())
+                .step_by(self.chunk)
+                .map(|i| Ok(body.slice(i..std::cmp::min(i + self.chunk, body.len()))))
+                .collect();
+            Box::new(stream::iter(chunks))
+        }
+        fn add_headers(&self, _: &mut HeaderMap) {}
+        fn etag(&self) -> Option<HeaderValue> {
+            None
+        }
+        fn last_modified(&self) -> Option<SystemTime> {
+            None
+        }
+    }
+
+    /// Every range, starting and ending anywhere within blocks and spanning any number of them,
+    /// decrypts to the corresponding slice of the plaintext.
+    #[tokio::test]
+    async fn all_ranges() {
+        let plaintext: Vec<u8> = (0..23).map(|i| b'a' + i).collect();
+        for &chunk in &[1, 3, 5, 64] {
+            let e = BlockDecryptEntity::new(
+                Chunked {
+                    body: encrypt(&plaintext).into(),
+                    chunk,
+                    missing: 0,
+                },
+                XorCipher,
+            );
+            assert_eq!(e.len(), plaintext.len() as u64);
+            for start in 0..=plaintext.len() {
+                for end in start..=plaintext.len() {
+                    let s = Pin::from(e.get_range(start as u64..end as u64));
+                    let got: Vec<Bytes> = s.try_collect().await.unwrap();
+                    assert_eq!(
+                        got.concat(),
+                        &plaintext[start..end],
+                        "chunk {} range {}..{}",
+                        chunk,
+                        start,
+                        end
+                    );
+                }
+            }
+        }
+    }
+
+    /// A block failing authentication ends the body with an error, without sending any of it.
+    #[tokio::test]
+    async fn tampered() {
+        let plaintext = b"0123456789abcdef";
+        let mut ciphertext = encrypt(plaintext);
+        ciphertext[11] ^= 0x80; // in block 2
+        let e = BlockDecryptEntity::new(
+            FakeEntity::new(ciphertext).content_type("text/plain"),
+            XorCipher,
+        );
+        let mut s = Pin::from(e.get_range(2..14));
+        let mut got = Vec::new();
+        let err = loop {
+            match s.try_next().await {
+                Ok(Some(b)) => got.extend_from_slice(&b),
+                Ok(None) => panic!("no error"),
+                Err(e) => break e,
+            }
+        };
+        assert_eq!(&got[..], b"234567");
+        assert_eq!(err.to_string(), "block 2 failed authentication");
+
+        // Ranges not covering the block are unaffected.
+        let s = Pin::from(e.get_range(0..8));
+        let got: Vec<Bytes> = s.try_collect().await.unwrap();
+        assert_eq!(got.concat(), b"01234567");
+    }
+
+    /// A cipher without overhead, for which any partial block is valid.
+    struct Identity;
+
+    impl BlockCipher for Identity {
+        fn block_size(&self) -> usize {
+            BLOCK
+        }
+        fn decrypt_block(&self, _index: u64, _buf: &mut Vec<u8>) -> Result<(), BoxedError> {
+            Ok(())
+        }
+    }
+
+    /// An inner entity ending early, at or within a block, ends the body with an error.
+    #[tokio::test]
+    async fn short() {
+        let body = Bytes::from_static(b"0123456789abcdef");
+        for &(missing, err) in &[
+            (4, "encrypted body ended before block 3"),
+            (2, "encrypted body ended within block 3"),
+        ] {
+            let e = BlockDecryptEntity::new(
+                Chunked {
+                    body: body.slice(..body.len() - missing),
+                    chunk: 3,
+                    missing,
+                },
+                Identity,
+            );
+            assert_eq!(e.len(), 16);
+            let s = Pin::from(e.get_range(1..16));
+            let r: Result<Vec<Bytes>, BoxedError> = s.try_collect().await;
+            assert_eq!(r.unwrap_err().to_string(), err);
+        }
+    }
+
+    #[tokio::test]
+    async fn serve() {
+        let plaintext = b"hello, encrypted world!";
+        let e = BlockDecryptEntity::new(
+            FakeEntity::new(encrypt(plaintext))
+                .content_type("text/plain")
+                .etag("\"v1\""),
+            XorCipher,
+        );
+        let req = FakeRequest::get().range("bytes=5-13").build();
+        let resp: Response<hyper::Body> = crate::serve(e, &req);
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(resp.headers()[header::CONTENT_RANGE], "bytes 5-13/23");
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "text/plain");
+        assert_eq!(resp.headers()[header::ETAG], "\"v1\"");
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(&body[..], b", encrypt");
+    }
+}
@@ -7,8 +7,8 @@
 // except according to those terms.
 
 use futures::channel::mpsc;
-use futures::Stream;
 use std::io::{self, Write};
+use std::marker::PhantomData;
 use std::mem;
 
 /// A `std::io::Write` implementation that makes a chunked hyper response body stream.
@@ -28,10 +28,13 @@ where
     D: From<Vec<u8>> + Send + 'static,
     E: Send + 'static,
 {
-    sender: mpsc::UnboundedSender<Result<D, E>>,
+    /// Sends chunks as they fill; they're converted to `D` as they're received.
+    sender: mpsc::UnboundedSender<Result<Vec<u8>, E>>,
 
     /// The next buffer to use. Invariant: capacity > len.
     buf: Vec<u8>,
+
+    phantom: PhantomData<fn() -> D>,
 }
 
 impl<D, E> BodyWriter<D, E>
@@ -39,18 +42,20 @@ where
     D: From<Vec<u8>> + Send + 'static,
     E: Send + 'static,
 {
+    /// Creates a writer and the stream of its chunks. The chunks are as written, so the caller
+    /// can inspect them before converting them to `D`.
     pub(crate) fn with_chunk_size(
         cap: usize,
-    ) -> (Self, Box<dyn Stream<Item = Result<D, E>> + Send>) {
+    ) -> (Self, mpsc::UnboundedReceiver<Result<Vec<u8>, E>>) {
         assert!(cap > 0);
         let (snd, rcv) = mpsc::unbounded();
-        let body = Box::new(rcv);
         (
             BodyWriter {
                 sender: snd,
                 buf: Vec::with_capacity(cap),
+                phantom: PhantomData,
             },
-            body,
+            rcv,
         )
     }
 
@@ -83,11 +88,26 @@ where
         Ok(bytes)
     }
 
+    /// Appends all of `bufs`, sending each chunk as it fills. Unlike `write`, this doesn't stop at
+    /// a chunk boundary, so a single call consumes everything.
+    fn write_vectored(&mut self, bufs: &[io::IoSlice]) -> io::Result<usize> {
+        let mut total = 0;
+        for buf in bufs {
+            let mut buf = &buf[..];
+            while !buf.is_empty() {
+                let n = self.write(buf)?;
+                buf = &buf[n..];
+                total += n;
+            }
+        }
+        Ok(total)
+    }
+
     fn flush(&mut self) -> io::Result<()> {
         if !self.buf.is_empty() {
             let cap = self.buf.capacity();
             let full_buf = mem::replace(&mut self.buf, Vec::with_capacity(cap));
-            if let Err(_) = self.sender.unbounded_send(Ok(full_buf.into())) {
+            if self.sender.unbounded_send(Ok(full_buf)).is_err() {
                 // If this error is returned, no further writes will succeed either.
                 // Therefore, it's acceptable to just drop the full_buf (now e.into_inner())
                 // rather than put it back as self.buf; it won't cause us to write a stream with
@@ -115,22 +135,22 @@ where
 #[cfg(test)]
 mod tests {
     use super::BodyWriter;
-    use futures::{stream::StreamExt, stream::TryStreamExt, Stream};
+    use futures::channel::mpsc;
+    use futures::{stream::StreamExt, stream::TryStreamExt};
     use std::io::Write;
-    use std::pin::Pin;
 
     type BoxedError = Box<dyn std::error::Error + 'static + Send + Sync>;
-    type BodyStream = Box<dyn Stream<Item = Result<Vec<u8>, BoxedError>> + Send>;
+    type BodyStream = mpsc::UnboundedReceiver<Result<Vec<u8>, BoxedError>>;
 
     async fn to_vec(s: BodyStream) -> Vec<u8> {
-        Pin::from(s).try_concat().await.unwrap()
+        s.try_concat().await.unwrap()
     }
 
     // A smaller-than-chunk-size write shouldn't be flushed on write, and there's currently no Drop
     // implementation to do it either.
     #[tokio::test]
     async fn small_no_flush() {
-        let (mut w, body): (_, BodyStream) = BodyWriter::with_chunk_size(4);
+        let (mut w, body): (_, BodyStream) = BodyWriter::<Vec<u8>, _>::with_chunk_size(4);
         assert_eq!(w.write(b"1").unwrap(), 1);
         w.truncate();
         drop(w);
@@ -140,7 +160,7 @@ mod tests {
     // With a flush, the content should show up.
     #[tokio::test]
     async fn small_flush() {
-        let (mut w, body): (_, BodyStream) = BodyWriter::with_chunk_size(4);
+        let (mut w, body): (_, BodyStream) = BodyWriter::<Vec<u8>, _>::with_chunk_size(4);
         assert_eq!(w.write(b"1").unwrap(), 1);
         w.flush().unwrap();
         drop(w);
@@ -150,7 +170,7 @@ mod tests {
     // A write of exactly the chunk size should be automatically flushed.
     #[tokio::test]
     async fn chunk_write() {
-        let (mut w, body): (_, BodyStream) = BodyWriter::with_chunk_size(4);
+        let (mut w, body): (_, BodyStream) = BodyWriter::<Vec<u8>, _>::with_chunk_size(4);
         assert_eq!(w.write(b"1234").unwrap(), 4);
         w.flush().unwrap();
         drop(w);
@@ -160,7 +180,7 @@ mod tests {
     // ...and everything should be set up for the next write as well.
     #[tokio::test]
     async fn chunk_double_write() {
-        let (mut w, body): (_, BodyStream) = BodyWriter::with_chunk_size(4);
+        let (mut w, body): (_, BodyStream) = BodyWriter::<Vec<u8>, _>::with_chunk_size(4);
         assert_eq!(w.write(b"1234").unwrap(), 4);
         assert_eq!(w.write(b"5678").unwrap(), 4);
         w.flush().unwrap();
@@ -171,7 +191,7 @@ mod tests {
     // A larger-than-chunk-size write should be turned into a chunk-size write.
     #[tokio::test]
     async fn large_write() {
-        let (mut w, body): (_, BodyStream) = BodyWriter::with_chunk_size(4);
+        let (mut w, body): (_, BodyStream) = BodyWriter::<Vec<u8>, _>::with_chunk_size(4);
         assert_eq!(w.write(b"123456").unwrap(), 4);
         drop(w);
         assert_eq!(b"1234", &to_vec(body).await[..]);
@@ -180,17 +200,34 @@ mod tests {
     // ...similarly, one that uses all the remaining capacity of the chunk.
     #[tokio::test]
     async fn small_large_write() {
-        let (mut w, body): (_, BodyStream) = BodyWriter::with_chunk_size(4);
+        let (mut w, body): (_, BodyStream) = BodyWriter::<Vec<u8>, _>::with_chunk_size(4);
         assert_eq!(w.write(b"1").unwrap(), 1);
         assert_eq!(w.write(b"2345").unwrap(), 3);
         drop(w);
         assert_eq!(b"1234", &to_vec(body).await[..]);
     }
 
+    // A vectored write should consume every slice, splitting chunks across slice boundaries.
+    #[tokio::test]
+    async fn vectored_write() {
+        use std::io::IoSlice;
+        let (mut w, body): (_, BodyStream) = BodyWriter::<Vec<u8>, _>::with_chunk_size(4);
+        let bufs = [
+            IoSlice::new(b"12"),
+            IoSlice::new(b""),
+            IoSlice::new(b"3456789"),
+        ];
+        assert_eq!(w.write_vectored(&bufs).unwrap(), 9);
+        w.flush().unwrap();
+        drop(w);
+        let chunks = body.try_collect::<Vec<Vec<u8>>>().await.unwrap();
+        assert_eq!(chunks, [&b"1234"[..], &b"5678"[..], &b"9"[..]]);
+    }
+
     // Aborting should add an Err element to the stream, ignoring any unflushed bytes.
     #[tokio::test]
     async fn abort() {
-        let (mut w, body): (_, BodyStream) = BodyWriter::with_chunk_size(4);
+        let (mut w, body): (_, BodyStream) = BodyWriter::<Vec<u8>, _>::with_chunk_size(4);
         w.write_all(b"12345").unwrap();
         w.truncate();
         w.abort(Box::new(std::io::Error::new(
@@ -198,9 +235,7 @@ mod tests {
             "asdf",
         )));
         drop(w);
-        let items = Pin::<_>::from(body)
-            .collect::<Vec<Result<Vec<u8>, BoxedError>>>()
-            .await;
+        let items = body.collect::<Vec<Result<Vec<u8>, BoxedError>>>().await;
         assert_eq!(items.len(), 2);
         assert_eq!(b"1234", &items[0].as_ref().unwrap()[..]);
         items[1].as_ref().unwrap_err();
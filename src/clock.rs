@@ -0,0 +1,120 @@
+// Copyright (c) 2016-2020 The http-serve developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A source of the current time, as used by `serve` for the `Date` header and comparisons
+/// against it.
+///
+/// Set via [`ServeOptions::clock`](struct.ServeOptions.html#method.clock), mostly so that tests can
+/// control the time.
+pub trait Clock: Send + Sync {
+    /// Returns the current time.
+    fn now(&self) -> SystemTime;
+}
+
+/// The system's wall clock, `SystemTime::now()`.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock which never goes backwards by a whole second, even if the clock it wraps does.
+///
+/// When the wall clock steps backwards (such as on an NTP correction or VM resume), a `Date`
+/// could otherwise precede one already sent, and `Last-Modified` values (which `serve` clamps to
+/// the `Date`) could change back and forth, so that caches' `If-Modified-Since` requests flap
+/// between `200` and `304`. This instead holds at the latest second it has returned until the
+/// wrapped clock catches up.
+///
+/// `serve` uses one wrapping `SystemClock` by default, shared by all requests.
+#[derive(Debug)]
+pub struct MonotonicClock<C> {
+    inner: C,
+
+    /// The latest second since the epoch returned.
+    max_secs: AtomicU64,
+}
+
+/// The clock `serve` uses when `ServeOptions::clock` isn't set.
+pub(crate) static DEFAULT_CLOCK: MonotonicClock<SystemClock> = MonotonicClock {
+    inner: SystemClock,
+    max_secs: AtomicU64::new(0),
+};
+
+impl<C: Clock> MonotonicClock<C> {
+    /// Wraps `inner`.
+    pub fn new(inner: C) -> Self {
+        MonotonicClock {
+            inner,
+            max_secs: AtomicU64::new(0),
+        }
+    }
+}
+
+impl<C: Clock> Clock for MonotonicClock<C> {
+    fn now(&self) -> SystemTime {
+        let now = self.inner.now();
+        let secs = now
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let mut max = self.max_secs.load(Ordering::Relaxed);
+        loop {
+            if secs < max {
+                return UNIX_EPOCH + Duration::from_secs(max);
+            }
+            if secs == max {
+                return now;
+            }
+            match self.max_secs.compare_exchange_weak(
+                max,
+                secs,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return now,
+                Err(m) => max = m,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Clock, MonotonicClock};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    /// A clock set by hand, in milliseconds since the epoch.
+    struct FakeClock(AtomicU64);
+
+    impl Clock for FakeClock {
+        fn now(&self) -> SystemTime {
+            UNIX_EPOCH + Duration::from_millis(self.0.load(Ordering::Relaxed))
+        }
+    }
+
+    #[test]
+    fn monotonic() {
+        let c = MonotonicClock::new(FakeClock(AtomicU64::new(10_500)));
+        let at = |ms| UNIX_EPOCH + Duration::from_millis(ms);
+        assert_eq!(c.now(), at(10_500));
+        c.inner.0.store(5_000, Ordering::Relaxed); // steps back.
+        assert_eq!(c.now(), at(10_000));
+        c.inner.0.store(10_700, Ordering::Relaxed); // back within the same second.
+        assert_eq!(c.now(), at(10_700));
+        c.inner.0.store(12_000, Ordering::Relaxed);
+        assert_eq!(c.now(), at(12_000));
+    }
+}
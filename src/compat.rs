@@ -0,0 +1,94 @@
+// Copyright (c) 2016-2020 The http-serve developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Module paths matching the `http-entity` and `http-file` crates which were merged to form
+//! `http-serve`, to ease migration. Available with the `compat` feature.
+//!
+//! New code should use the crate root instead.
+
+/// Items formerly in the `http-entity` crate.
+pub mod http_entity {
+    pub use crate::{serve, Entity};
+}
+
+/// Items formerly in the `http-file` crate.
+pub mod http_file {
+    use bytes::{Buf, Bytes};
+    use http::header::{self, HeaderMap, HeaderValue};
+    use std::error::Error as StdError;
+    use std::io;
+
+    pub use crate::ChunkedReadFile;
+
+    /// Creates a `ChunkedReadFile` with the `http-file` crate's `new(file, pool, mime)` argument
+    /// order.
+    ///
+    /// `pool` is ignored and dropped unused; it's accepted only so existing callers compile.
+    /// Reads now happen within `tokio::task::block_in_place` rather than on a separate thread
+    /// pool. `mime` becomes the `Content-Type` header.
+    pub fn new<D, E, P>(
+        file: std::fs::File,
+        pool: Option<P>,
+        mime: HeaderValue,
+    ) -> Result<ChunkedReadFile<D, E>, io::Error>
+    where
+        D: 'static + Send + Sync + Buf + From<Vec<u8>> + From<&'static [u8]> + From<Bytes>,
+        E: 'static
+            + Send
+            + Sync
+            + Into<Box<dyn StdError + Send + Sync>>
+            + From<Box<dyn StdError + Send + Sync>>,
+    {
+        drop(pool);
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, mime);
+        ChunkedReadFile::new(file, headers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::http_entity::{self, Entity};
+    use super::http_file;
+    use bytes::Bytes;
+    use http::header::{self, HeaderMap, HeaderValue};
+    use std::io::Write;
+
+    type BoxedError = Box<dyn std::error::Error + Sync + Send>;
+
+    #[tokio::test(threaded_scheduler)]
+    async fn shims() {
+        tokio::spawn(async move {
+            let tmp = tempfile::tempdir().unwrap();
+            let p = tmp.path().join("f");
+            std::fs::File::create(&p)
+                .unwrap()
+                .write_all(b"asdf")
+                .unwrap();
+
+            let f: http_file::ChunkedReadFile<Bytes, BoxedError> = http_file::new(
+                std::fs::File::open(&p).unwrap(),
+                None::<()>,
+                HeaderValue::from_static("text/plain"),
+            )
+            .unwrap();
+            assert_eq!(f.len(), 4);
+            let mut h = HeaderMap::new();
+            f.add_headers(&mut h);
+            assert_eq!(h.get(header::CONTENT_TYPE).unwrap(), "text/plain");
+
+            let req = http::Request::get("/").body(()).unwrap();
+            let resp: http::Response<hyper::Body> = http_entity::serve(f, &req);
+            assert_eq!(resp.status(), http::StatusCode::OK);
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            assert_eq!(&body[..], b"asdf");
+        })
+        .await
+        .unwrap();
+    }
+}
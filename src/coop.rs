@@ -0,0 +1,54 @@
+// Copyright (c) 2016-2020 The http-serve developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use bytes::Buf;
+use futures::Stream;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// A stream adapter which returns `Poll::Pending` (with an immediate wakeup) after every
+/// `budget` bytes, so that a body stream which is always ready doesn't monopolize its executor
+/// thread.
+pub(crate) struct CooperativeYield<S: ?Sized> {
+    inner: Pin<Box<S>>,
+    budget: u64,
+    since_yield: u64,
+}
+
+impl<S: ?Sized> CooperativeYield<S> {
+    pub(crate) fn new(inner: Pin<Box<S>>, budget: u64) -> Self {
+        CooperativeYield {
+            inner,
+            budget,
+            since_yield: 0,
+        }
+    }
+}
+
+impl<S, D, E> Stream for CooperativeYield<S>
+where
+    S: Stream<Item = Result<D, E>> + ?Sized,
+    D: Buf,
+{
+    type Item = Result<D, E>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        // CooperativeYield is Unpin; the inner stream is pinned on the heap.
+        let this = self.get_mut();
+        if this.since_yield >= this.budget {
+            this.since_yield = 0;
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+        let r = this.inner.as_mut().poll_next(cx);
+        if let Poll::Ready(Some(Ok(ref d))) = r {
+            this.since_yield += d.remaining() as u64;
+        }
+        r
+    }
+}
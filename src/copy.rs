@@ -0,0 +1,179 @@
+// Copyright (c) 2016-2020 The http-serve developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::Entity;
+use bytes::Buf;
+use futures::StreamExt;
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// An error from [`copy_entity`](fn.copy_entity.html).
+#[derive(Debug)]
+pub enum CopyError<E> {
+    /// The entity's stream returned an error.
+    Entity(E),
+
+    /// Writing failed.
+    Write(io::Error),
+
+    /// The entity's stream ended after `actual` bytes, short of its length of `expected`.
+    Short { expected: u64, actual: u64 },
+}
+
+impl<E: fmt::Display> fmt::Display for CopyError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CopyError::Entity(ref e) => write!(f, "entity error: {}", e),
+            CopyError::Write(ref e) => write!(f, "write error: {}", e),
+            CopyError::Short { expected, actual } => write!(
+                f,
+                "entity ended after {} bytes, short of its length of {}",
+                actual, expected
+            ),
+        }
+    }
+}
+
+impl<E: std::error::Error + 'static> std::error::Error for CopyError<E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            CopyError::Entity(ref e) => Some(e),
+            CopyError::Write(ref e) => Some(e),
+            CopyError::Short { .. } => None,
+        }
+    }
+}
+
+/// Copies all of `entity` into `w`, returning the number of bytes written.
+///
+/// This reads the entity just as `serve` does for a full `GET`, without building a response,
+/// as for warming a cache or exporting to a file. `w` is flushed at the end. On error, what was
+/// written so far is left in `w`; a copy which ends short of `Entity::len` is an error, so that
+/// a truncated copy isn't mistaken for a complete one.
+///
+/// ```
+/// # async fn f() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+/// let e = http_serve::CowEntity::<bytes::Bytes, std::io::Error>::new(&b"hello"[..]);
+/// let mut buf = Vec::new();
+/// assert_eq!(http_serve::copy_entity(&e, &mut buf).await?, 5);
+/// assert_eq!(&buf[..], b"hello");
+/// # Ok(())
+/// # }
+/// ```
+pub async fn copy_entity<Ent, W>(entity: &Ent, w: &mut W) -> Result<u64, CopyError<Ent::Error>>
+where
+    Ent: Entity,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let expected = entity.len();
+    let mut stream = Pin::from(entity.get_range(0..expected));
+    let mut written = 0;
+    while let Some(chunk) = stream.next().await {
+        let mut chunk = chunk.map_err(CopyError::Entity)?;
+        while chunk.has_remaining() {
+            let n = {
+                let b = chunk.bytes();
+                w.write_all(b).await.map_err(CopyError::Write)?;
+                b.len()
+            };
+            chunk.advance(n);
+            written += n as u64;
+        }
+    }
+    w.flush().await.map_err(CopyError::Write)?;
+    if written < expected {
+        return Err(CopyError::Short {
+            expected,
+            actual: written,
+        });
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{copy_entity, CopyError};
+    use crate::test_support::FakeEntity;
+    use crate::Entity;
+    use bytes::Bytes;
+    use futures::{stream, Stream};
+    use http::header::{HeaderMap, HeaderValue};
+    use std::ops::Range;
+    use std::time::SystemTime;
+
+    type BoxedError = Box<dyn std::error::Error + Send + Sync>;
+
+    /// An entity of `len` bytes whose stream yields `chunks`, regardless of the range.
+    struct Chunks {
+        len: u64,
+        chunks: Vec<Result<&'static [u8], &'static str>>,
+    }
+
+    impl Entity for Chunks {
+        type Data = Bytes;
+        type Error = BoxedError;
+
+        fn len(&self) -> u64 {
+            self.len
+        }
+        fn get_range(
+            &self,
+            _range: Range<u64>,
+        ) -> Box<dyn Stream<Item = Result<Self::Data, Self::Error>> + Send + Sync> {
+            let chunks: Vec<_> = self
+                .chunks
+                .iter()
+                .map(|c| c.map(Bytes::from_static).map_err(BoxedError::from))
+                .collect();
+            Box::new(stream::iter(chunks))
+        }
+        fn add_headers(&self, _: &mut HeaderMap) {}
+        fn etag(&self) -> Option<HeaderValue> {
+            None
+        }
+        fn last_modified(&self) -> Option<SystemTime> {
+            None
+        }
+    }
+
+    #[tokio::test]
+    async fn copy() {
+        let body: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let e = FakeEntity::new(body.clone());
+        let mut buf = Vec::new();
+        assert_eq!(copy_entity(&e, &mut buf).await.unwrap(), body.len() as u64);
+        assert!(buf == body);
+    }
+
+    #[tokio::test]
+    async fn errors() {
+        let mut buf = Vec::new();
+        let e = Chunks {
+            len: 6,
+            chunks: vec![Ok(b"abc"), Err("oops"), Ok(b"def")],
+        };
+        match copy_entity(&e, &mut buf).await {
+            Err(CopyError::Entity(e)) => assert_eq!(e.to_string(), "oops"),
+            r => panic!("unexpected {:?}", r),
+        }
+        assert_eq!(&buf[..], b"abc");
+
+        let mut buf = Vec::new();
+        let e = Chunks {
+            len: 6,
+            chunks: vec![Ok(b"abc")],
+        };
+        let err = copy_entity(&e, &mut buf).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "entity ended after 3 bytes, short of its length of 6"
+        );
+    }
+}
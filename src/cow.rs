@@ -0,0 +1,168 @@
+// Copyright (c) 2016-2020 The http-serve developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::Entity;
+use bytes::{Buf, Bytes};
+use futures::Stream;
+use http::header::{self, HeaderMap, HeaderValue};
+use std::borrow::Cow;
+use std::ops::Range;
+use std::time::SystemTime;
+
+/// An entity held in memory as a `Cow<'static, [u8]>`, so borrowed static data (such as an
+/// `include_bytes!` asset) and owned generated data are served through one type.
+///
+/// Neither is copied: a borrowed body is referenced in place, an owned one is moved in, and
+/// each range is a slice of it. The content type and validators are set with the builder methods.
+pub struct CowEntity<D, E> {
+    body: Bytes,
+    headers: HeaderMap,
+    etag: Option<HeaderValue>,
+    last_modified: Option<SystemTime>,
+    phantom: std::marker::PhantomData<(D, E)>,
+}
+
+impl<D, E> CowEntity<D, E>
+where
+    D: 'static + Send + Sync + Buf + From<Vec<u8>> + From<&'static [u8]> + From<Bytes>,
+    E: 'static + Send + Sync,
+{
+    /// Creates an entity with the given body, which may be a `&'static [u8]`, a `Vec<u8>`, or
+    /// a `Cow` of either.
+    pub fn new<C: Into<Cow<'static, [u8]>>>(body: C) -> Self {
+        let body = match body.into() {
+            Cow::Borrowed(b) => Bytes::from_static(b),
+            Cow::Owned(v) => Bytes::from(v),
+        };
+        CowEntity {
+            body,
+            headers: HeaderMap::new(),
+            etag: None,
+            last_modified: None,
+            phantom: std::marker::PhantomData,
+        }
+    }
+
+    /// Sets the `Content-Type`.
+    pub fn content_type(mut self, content_type: HeaderValue) -> Self {
+        self.headers.insert(header::CONTENT_TYPE, content_type);
+        self
+    }
+
+    /// Sets the etag, which must include its quotes (and `W/` prefix, if weak).
+    pub fn etag(mut self, etag: HeaderValue) -> Self {
+        self.etag = Some(etag);
+        self
+    }
+
+    /// Sets the last modified time.
+    pub fn last_modified(mut self, last_modified: SystemTime) -> Self {
+        self.last_modified = Some(last_modified);
+        self
+    }
+}
+
+impl<D, E> Entity for CowEntity<D, E>
+where
+    D: 'static + Send + Sync + Buf + From<Vec<u8>> + From<&'static [u8]> + From<Bytes>,
+    E: 'static + Send + Sync,
+{
+    type Data = D;
+    type Error = E;
+
+    fn len(&self) -> u64 {
+        self.body.len() as u64
+    }
+
+    fn get_range(
+        &self,
+        range: Range<u64>,
+    ) -> Box<dyn Stream<Item = Result<Self::Data, Self::Error>> + Send + Sync> {
+        let chunk = self.body.slice(range.start as usize..range.end as usize);
+        Box::new(futures::stream::once(futures::future::ok(D::from(chunk))))
+    }
+
+    fn get_bytes(&self, range: Range<u64>) -> Option<Self::Data> {
+        Some(D::from(
+            self.body.slice(range.start as usize..range.end as usize),
+        ))
+    }
+
+    fn add_headers(&self, h: &mut HeaderMap) {
+        h.extend(self.headers.iter().map(|(k, v)| (k.clone(), v.clone())));
+    }
+
+    fn static_headers(&self) -> Option<&HeaderMap> {
+        Some(&self.headers)
+    }
+
+    fn content_type(&self) -> Option<HeaderValue> {
+        self.headers.get(header::CONTENT_TYPE).cloned()
+    }
+
+    fn etag(&self) -> Option<HeaderValue> {
+        self.etag.clone()
+    }
+
+    fn last_modified(&self) -> Option<SystemTime> {
+        self.last_modified
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CowEntity;
+    use crate::test_support::FakeRequest;
+    use bytes::Bytes;
+    use http::header::{self, HeaderValue};
+    use http::{Response, StatusCode};
+    use std::borrow::Cow;
+
+    type BoxedError = Box<dyn std::error::Error + Send + Sync>;
+
+    static STATIC: &[u8] = b"static data, embedded in the binary";
+
+    fn entity(body: Cow<'static, [u8]>) -> CowEntity<Bytes, BoxedError> {
+        CowEntity::new(body)
+            .content_type(HeaderValue::from_static("text/plain"))
+            .etag(HeaderValue::from_static("\"v1\""))
+    }
+
+    #[tokio::test]
+    async fn borrowed_and_owned() {
+        let owned = format!("generated {}", "data, built per request").into_bytes();
+        for body in vec![Cow::Borrowed(STATIC), Cow::Owned(owned)] {
+            let expected = body.to_vec();
+            let req = FakeRequest::get().range("bytes=0-8").build();
+            let resp: Response<hyper::Body> = crate::serve(entity(body.clone()), &req);
+            assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+            assert_eq!(
+                resp.headers()[header::CONTENT_RANGE],
+                format!("bytes 0-8/{}", expected.len())
+            );
+            assert_eq!(resp.headers()[header::CONTENT_TYPE], "text/plain");
+            assert_eq!(resp.headers()[header::ETAG], "\"v1\"");
+            let got = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            assert_eq!(&got[..], &expected[..9]);
+
+            let req = FakeRequest::get().if_none_match("\"v1\"").build();
+            let resp: Response<hyper::Body> = crate::serve(entity(body), &req);
+            assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        }
+    }
+
+    /// A borrowed body is served in place rather than copied.
+    #[tokio::test]
+    async fn borrowed_not_copied() {
+        let req = FakeRequest::get().range("bytes=7-10").build();
+        let e: CowEntity<Bytes, BoxedError> = CowEntity::new(STATIC);
+        let resp: Response<hyper::Body> = crate::serve(e, &req);
+        let got = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(got.as_ptr(), STATIC[7..].as_ptr());
+    }
+}
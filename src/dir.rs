@@ -0,0 +1,339 @@
+// Copyright (c) 2016-2020 The http-serve developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::fs::File;
+use std::io;
+use std::path::{Component, Path};
+
+/// Which symlinks [`open_beneath`](fn.open_beneath.html) follows within the directory it opens
+/// from.
+///
+/// Each applies to symlinks within that directory, not to the directory's own path. A symlink
+/// which isn't followed is reported as not found.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum SymlinkPolicy {
+    /// Follow any symlink, even out of the directory.
+    Follow,
+
+    /// Follow symlinks only while they resolve within the directory, such as names in a
+    /// content-addressed store.
+    FollowIfSameRoot,
+
+    /// Follow no symlinks, such as in a tree of user uploads.
+    Deny,
+}
+
+impl Default for SymlinkPolicy {
+    fn default() -> Self {
+        SymlinkPolicy::FollowIfSameRoot
+    }
+}
+
+/// Opens the file or directory at `path` within `base`, following symlinks as `policy` allows,
+/// for serving with [`ChunkedReadFile`](struct.ChunkedReadFile.html).
+///
+/// `path` must be relative, with no `..` components; others get an `InvalidInput` error. A
+/// symlink which `policy` doesn't follow, or anything other than a regular file or directory
+/// (such as a FIFO, which would block the reading thread), gets a `NotFound` error.
+///
+/// On Unix, unless following any symlink, each component is opened with `openat` and
+/// `O_NOFOLLOW` from the directory opened before it, so no symlink is followed without being
+/// checked, even one swapped in concurrently. Elsewhere the canonical path or each component's
+/// metadata is checked before opening, which such a swap can race.
+pub fn open_beneath(base: &Path, path: &Path, policy: SymlinkPolicy) -> io::Result<File> {
+    let valid = path.components().all(|c| match c {
+        Component::Normal(_) | Component::CurDir => true,
+        _ => false,
+    });
+    if !valid {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "path isn't relative to the base",
+        ));
+    }
+    let file = open_components(base, path, policy)?;
+    let file_type = file.metadata()?.file_type();
+    if !file_type.is_file() && !file_type.is_dir() {
+        return Err(io::ErrorKind::NotFound.into());
+    }
+    Ok(file)
+}
+
+/// The most symlinks `open_components` resolves for one path, as Linux's `MAXSYMLINKS`.
+#[cfg(unix)]
+const MAX_SYMLINKS: usize = 40;
+
+/// Opens `path`, relative to `base`, a component at a time.
+///
+/// Under `FollowIfSameRoot`, a symlink's target is read with `readlinkat` and resolved in turn:
+/// a relative target from the symlink's directory, never rising above `base`, and an absolute one
+/// only if it lies within `base`'s canonical path.
+#[cfg(unix)]
+fn open_components(base: &Path, path: &Path, policy: SymlinkPolicy) -> io::Result<File> {
+    use std::ffi::OsString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::PathBuf;
+
+    if policy == SymlinkPolicy::Follow {
+        return open_path(&base.join(path));
+    }
+
+    // The directories opened from `base` down, and the components yet to open, last first.
+    let mut dirs = vec![File::open(base)?];
+    let mut pending: Vec<OsString> = path
+        .components()
+        .rev()
+        .map(|c| c.as_os_str().to_owned())
+        .collect();
+    let mut canonical_base = None;
+    let mut symlinks = 0;
+    while let Some(name) = pending.pop() {
+        if name == "." {
+            continue;
+        }
+        if name == ".." {
+            if dirs.len() == 1 {
+                return Err(io::ErrorKind::NotFound.into());
+            }
+            dirs.pop();
+            continue;
+        }
+        let dir = &dirs[dirs.len() - 1];
+        let name = std::ffi::CString::new(name.as_bytes())?;
+
+        // O_NONBLOCK keeps a FIFO from blocking the open; it's rejected once opened.
+        let flags = if pending.is_empty() {
+            libc::O_RDONLY | libc::O_NONBLOCK
+        } else {
+            libc::O_RDONLY | libc::O_DIRECTORY
+        };
+        let e = match openat(dir, &name, flags) {
+            Ok(f) => {
+                dirs.push(f);
+                continue;
+            }
+            Err(e) => e,
+        };
+
+        // O_NOFOLLOW reports a symlink as ELOOP on most systems, but EMLINK on FreeBSD; rather
+        // than match either, see if readlinkat succeeds.
+        let target = match readlinkat(dir, &name) {
+            Ok(t) => PathBuf::from(t),
+            Err(_) => return Err(e),
+        };
+        if policy == SymlinkPolicy::Deny {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        symlinks += 1;
+        if symlinks > MAX_SYMLINKS {
+            return Err(e);
+        }
+        if target.is_absolute() {
+            if canonical_base.is_none() {
+                canonical_base = Some(base.canonicalize()?);
+            }
+            let within = match target.strip_prefix(canonical_base.as_ref().unwrap()) {
+                Ok(w) => w,
+                Err(_) => return Err(io::ErrorKind::NotFound.into()),
+            };
+            dirs.truncate(1);
+            pending.extend(within.components().rev().map(|c| c.as_os_str().to_owned()));
+        } else {
+            pending.extend(target.components().rev().map(|c| c.as_os_str().to_owned()));
+        }
+    }
+    Ok(dirs.pop().unwrap())
+}
+
+/// Opens `name` in `dir` without following a symlink.
+#[cfg(unix)]
+fn openat(dir: &File, name: &std::ffi::CStr, flags: std::os::raw::c_int) -> io::Result<File> {
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    let flags = flags | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+    let fd = unsafe { libc::openat(dir.as_raw_fd(), name.as_ptr(), flags) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Reads the target of the symlink `name` in `dir`.
+#[cfg(unix)]
+fn readlinkat(dir: &File, name: &std::ffi::CStr) -> io::Result<std::ffi::OsString> {
+    use std::ffi::OsString;
+    use std::os::unix::ffi::OsStringExt;
+    use std::os::unix::io::AsRawFd;
+
+    let mut buf = vec![0u8; 256];
+    loop {
+        let n = unsafe {
+            libc::readlinkat(
+                dir.as_raw_fd(),
+                name.as_ptr(),
+                buf.as_mut_ptr() as *mut _,
+                buf.len(),
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let n = n as usize;
+
+        // A target which fills the buffer may have been truncated.
+        if n < buf.len() {
+            buf.truncate(n);
+            return Ok(OsString::from_vec(buf));
+        }
+        buf.resize(buf.len() * 2, 0);
+    }
+}
+
+/// Opens `path`, relative to `base`, if `policy` allows the symlinks along it.
+#[cfg(not(unix))]
+fn open_components(base: &Path, path: &Path, policy: SymlinkPolicy) -> io::Result<File> {
+    let full = base.join(path);
+    match policy {
+        SymlinkPolicy::Follow => {}
+        SymlinkPolicy::FollowIfSameRoot => {
+            if !full.canonicalize()?.starts_with(base.canonicalize()?) {
+                return Err(io::ErrorKind::NotFound.into());
+            }
+        }
+        SymlinkPolicy::Deny => {
+            let mut p = base.to_path_buf();
+            for c in path.components() {
+                p.push(c);
+                if std::fs::symlink_metadata(&p)?.file_type().is_symlink() {
+                    return Err(io::ErrorKind::NotFound.into());
+                }
+            }
+        }
+    }
+    open_path(&full)
+}
+
+/// Opens `path` for reading, whether a file or a directory.
+fn open_path(path: &Path) -> io::Result<File> {
+    let mut options = std::fs::OpenOptions::new();
+    options.read(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.custom_flags(libc::O_NONBLOCK);
+    }
+
+    // Directories can only be opened with FILE_FLAG_BACKUP_SEMANTICS.
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::OpenOptionsExt;
+        options.custom_flags(winapi::um::winbase::FILE_FLAG_BACKUP_SEMANTICS);
+    }
+    options.open(path)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::{open_beneath, SymlinkPolicy};
+    use std::io::{self, Read};
+    use std::os::unix::fs::symlink;
+    use std::path::Path;
+
+    /// Creates `root/` with `real.txt`, `dir/f.txt`, and symlinks `in.txt` to `real.txt`,
+    /// `linkdir` to `dir`, `out.txt` to `../outside/secret.txt`, and `outdir` to `../outside`.
+    fn tree() -> tempfile::TempDir {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("root");
+        let outside = tmp.path().join("outside");
+        std::fs::create_dir_all(root.join("dir")).unwrap();
+        std::fs::create_dir(&outside).unwrap();
+        std::fs::write(root.join("real.txt"), b"real").unwrap();
+        std::fs::write(root.join("dir/f.txt"), b"f").unwrap();
+        std::fs::write(outside.join("secret.txt"), b"secret").unwrap();
+        symlink("real.txt", root.join("in.txt")).unwrap();
+        symlink("dir", root.join("linkdir")).unwrap();
+        symlink("../outside/secret.txt", root.join("out.txt")).unwrap();
+        symlink(outside.join("secret.txt"), root.join("absout.txt")).unwrap();
+        symlink("../outside", root.join("outdir")).unwrap();
+        tmp
+    }
+
+    /// Opens `path` within `root/` and returns its contents.
+    fn read(tmp: &tempfile::TempDir, path: &str, policy: SymlinkPolicy) -> io::Result<String> {
+        let root = tmp.path().join("root");
+        let mut s = String::new();
+        open_beneath(&root, Path::new(path), policy)?.read_to_string(&mut s)?;
+        Ok(s)
+    }
+
+    fn not_found(r: io::Result<String>) -> bool {
+        match r {
+            Err(e) => e.kind() == io::ErrorKind::NotFound,
+            Ok(_) => false,
+        }
+    }
+
+    #[test]
+    fn follow() {
+        let tmp = tree();
+        let p = SymlinkPolicy::Follow;
+        assert_eq!(read(&tmp, "in.txt", p).unwrap(), "real");
+        assert_eq!(read(&tmp, "linkdir/f.txt", p).unwrap(), "f");
+        assert_eq!(read(&tmp, "out.txt", p).unwrap(), "secret");
+        assert_eq!(read(&tmp, "outdir/secret.txt", p).unwrap(), "secret");
+    }
+
+    #[test]
+    fn follow_if_same_root() {
+        let tmp = tree();
+        let p = SymlinkPolicy::FollowIfSameRoot;
+        assert_eq!(read(&tmp, "in.txt", p).unwrap(), "real");
+        assert_eq!(read(&tmp, "linkdir/f.txt", p).unwrap(), "f");
+        assert!(not_found(read(&tmp, "out.txt", p)));
+        assert!(not_found(read(&tmp, "absout.txt", p)));
+        assert!(not_found(read(&tmp, "outdir/secret.txt", p)));
+    }
+
+    #[test]
+    fn deny() {
+        let tmp = tree();
+        let p = SymlinkPolicy::Deny;
+        assert_eq!(read(&tmp, "real.txt", p).unwrap(), "real");
+        assert!(not_found(read(&tmp, "in.txt", p)));
+        assert!(not_found(read(&tmp, "linkdir/f.txt", p)));
+        assert!(not_found(read(&tmp, "out.txt", p)));
+    }
+
+    #[test]
+    fn invalid_path() {
+        let tmp = tree();
+        for path in &[
+            "../outside/secret.txt",
+            "dir/../../outside/secret.txt",
+            "/etc",
+        ] {
+            let e = read(&tmp, path, SymlinkPolicy::Follow).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput, "{}", path);
+        }
+    }
+
+    /// A FIFO is refused without blocking on the open.
+    #[test]
+    fn fifo() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let tmp = tree();
+        let fifo = std::ffi::CString::new(tmp.path().join("root/fifo").as_os_str().as_bytes());
+        assert_eq!(unsafe { libc::mkfifo(fifo.unwrap().as_ptr(), 0o600) }, 0);
+        for &p in &[SymlinkPolicy::Follow, SymlinkPolicy::FollowIfSameRoot] {
+            assert!(not_found(read(&tmp, "fifo", p)));
+        }
+    }
+}
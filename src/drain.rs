@@ -0,0 +1,398 @@
+// Copyright (c) 2016-2020 The http-serve developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Coordination between a server's graceful shutdown and in-flight response bodies.
+//!
+//! hyper's `with_graceful_shutdown` stops accepting connections and waits for open ones to
+//! finish, but it has no say over how long a large download takes to finish. A [`Watch`]
+//! attached to responses via [`ServeOptions::drain`](../struct.ServeOptions.html#method.drain)
+//! tracks their bodies so that shutdown can wait for them, within a grace period:
+//!
+//! ```
+//! # use std::time::Duration;
+//! # async fn f(shutdown: impl std::future::Future<Output = ()>) {
+//! let watch = http_serve::drain::Watch::new(Duration::from_secs(30));
+//! let opts = http_serve::ServeOptions::new().drain(watch.clone());
+//! // ...serve requests with `serve_with_options(entity, &req, &opts)`...
+//! shutdown.await;
+//! watch.drain().await; // returns once all bodies have finished or been cut off.
+//! # }
+//! ```
+//!
+//! See `examples/graceful_shutdown.rs` for use with hyper.
+
+use futures::Stream;
+use std::any::Any;
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+use tokio::time::{Delay, Instant};
+
+/// Tracks response bodies in flight, so that shutdown can wait for them to finish.
+///
+/// Once [`drain`](#method.drain) is called, `serve` refuses further requests with `503 Service
+/// Unavailable`, and bodies already underway have the grace period to finish. Those still
+/// unfinished then end with an `Interrupted` error, which hyper reports to the client as a failed
+/// transfer rather than a complete one. This requires the entity's error type to be
+/// `Box<dyn Error + Send + Sync>` or `io::Error`; bodies with other error types simply end, so
+/// clients see the failure only if the body falls short of its `Content-Length`.
+///
+/// Clones share the same state.
+#[derive(Clone)]
+pub struct Watch {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    grace: Duration,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// When the grace period ends, once draining has started.
+    deadline: Option<Instant>,
+
+    next_id: u64,
+
+    /// The unfinished bodies, by id, with the wakers of their latest polls.
+    bodies: HashMap<u64, Option<Waker>>,
+
+    /// The waker of a pending `Drain`, to wake when the last body finishes.
+    drain: Option<Waker>,
+}
+
+impl Watch {
+    /// Creates a watch which gives bodies `grace` to finish once draining starts.
+    pub fn new(grace: Duration) -> Self {
+        Watch {
+            inner: Arc::new(Inner {
+                grace,
+                state: Mutex::new(State::default()),
+            }),
+        }
+    }
+
+    /// Returns true if draining has started.
+    pub fn is_draining(&self) -> bool {
+        self.inner.state.lock().unwrap().deadline.is_some()
+    }
+
+    /// Starts draining, if it hasn't already started, and returns a future which completes once
+    /// every body has finished or the grace period has elapsed.
+    ///
+    /// Draining starts on this call, whether or not the future is polled. When the grace period
+    /// elapses, the future wakes any unfinished bodies so that they end promptly.
+    pub fn drain(&self) -> Drain {
+        let mut s = self.inner.state.lock().unwrap();
+        let deadline = *s
+            .deadline
+            .get_or_insert_with(|| Instant::now() + self.inner.grace);
+        Drain {
+            inner: Arc::clone(&self.inner),
+            deadline,
+            delay: None,
+        }
+    }
+
+    /// Wraps a body stream, tracking it until it ends or is dropped.
+    pub(crate) fn register<D, E>(
+        &self,
+        stream: Box<dyn Stream<Item = Result<D, E>> + Send>,
+    ) -> Box<dyn Stream<Item = Result<D, E>> + Send>
+    where
+        D: 'static,
+        E: 'static,
+    {
+        let mut s = self.inner.state.lock().unwrap();
+        let id = s.next_id;
+        s.next_id += 1;
+        s.bodies.insert(id, None);
+        Box::new(Drained {
+            stream: stream.into(),
+            inner: Arc::clone(&self.inner),
+            id,
+            done: false,
+        })
+    }
+}
+
+impl std::fmt::Debug for Watch {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let s = self.inner.state.lock().unwrap();
+        f.debug_struct("Watch")
+            .field("grace", &self.inner.grace)
+            .field("draining", &s.deadline.is_some())
+            .field("bodies", &s.bodies.len())
+            .finish()
+    }
+}
+
+/// A future which completes when draining has finished, as returned by
+/// [`Watch::drain`](struct.Watch.html#method.drain).
+pub struct Drain {
+    inner: Arc<Inner>,
+    deadline: Instant,
+
+    /// The grace period timer, created on first poll so `drain` needn't be called on a runtime.
+    delay: Option<Delay>,
+}
+
+impl Future for Drain {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let this = self.get_mut();
+        let mut s = this.inner.state.lock().unwrap();
+        if s.bodies.is_empty() {
+            return Poll::Ready(());
+        }
+        let deadline = this.deadline;
+        let delay = this
+            .delay
+            .get_or_insert_with(|| tokio::time::delay_until(deadline));
+        if Pin::new(delay).poll(cx).is_ready() {
+            // Each body ends on its next poll, now that the deadline has passed.
+            for w in s.bodies.values_mut() {
+                if let Some(w) = w.take() {
+                    w.wake();
+                }
+            }
+            return Poll::Ready(());
+        }
+        s.drain = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+/// Returns the error ending a body cut off at the end of the grace period, if `E` is one of the
+/// error types `Watch` can build: `Box<dyn Error + Send + Sync>` or `io::Error`.
+fn shutdown_error<E: 'static>() -> Option<E> {
+    let e: Box<dyn Any> = Box::new(io::Error::new(
+        io::ErrorKind::Interrupted,
+        "server shutting down",
+    ));
+    let e = match e.downcast::<E>() {
+        Ok(e) => return Some(*e),
+        Err(e) => e.downcast::<io::Error>().expect("io::Error"),
+    };
+    let e: Box<dyn Any> = Box::new(Box::<dyn StdError + Send + Sync>::from(*e));
+    e.downcast::<E>().ok().map(|e| *e)
+}
+
+/// A body stream tracked by a `Watch`.
+struct Drained<D, E> {
+    stream: Pin<Box<dyn Stream<Item = Result<D, E>> + Send>>,
+    inner: Arc<Inner>,
+    id: u64,
+    done: bool,
+}
+
+impl<D, E> Drained<D, E> {
+    /// Stops tracking this body, waking a pending `Drain` if it was the last.
+    fn finish(&mut self) {
+        if self.done {
+            return;
+        }
+        self.done = true;
+        let mut s = self.inner.state.lock().unwrap();
+        s.bodies.remove(&self.id);
+        if s.bodies.is_empty() {
+            if let Some(w) = s.drain.take() {
+                w.wake();
+            }
+        }
+    }
+}
+
+impl<D, E: 'static> Stream for Drained<D, E> {
+    type Item = Result<D, E>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Result<D, E>>> {
+        let this = self.get_mut();
+        if this.done {
+            return Poll::Ready(None);
+        }
+        let expired = {
+            let mut s = this.inner.state.lock().unwrap();
+            match s.deadline {
+                Some(d) if Instant::now() >= d => true,
+                _ => {
+                    let w = s.bodies.get_mut(&this.id).expect("registered body");
+                    match w {
+                        Some(w) if w.will_wake(cx.waker()) => {}
+                        _ => *w = Some(cx.waker().clone()),
+                    }
+                    false
+                }
+            }
+        };
+        if expired {
+            this.finish();
+            return Poll::Ready(shutdown_error().map(Err));
+        }
+        let p = this.stream.as_mut().poll_next(cx);
+        if let Poll::Ready(None) = p {
+            this.finish();
+        }
+        p
+    }
+}
+
+impl<D, E> Drop for Drained<D, E> {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Watch;
+    use crate::test_support::{FakeEntity, FakeRequest};
+    use crate::{Entity, ServeDisposition, ServeOptions};
+    use bytes::Bytes;
+    use futures::{stream, FutureExt, Stream, StreamExt};
+    use http::header::{HeaderMap, HeaderValue};
+    use http::{Request, Response, StatusCode};
+    use std::ops::Range;
+    use std::time::{Duration, SystemTime};
+
+    type BoxedError = Box<dyn std::error::Error + Send + Sync>;
+
+    static BODY: &[u8] = b"0123456789abcdefghij";
+
+    /// A download started before draining finishes; requests after it are refused.
+    #[tokio::test]
+    async fn drain_waits_for_body() {
+        let watch = Watch::new(Duration::from_secs(60));
+        let opts = ServeOptions::new().drain(watch.clone());
+        let e = FakeEntity::new(BODY);
+        let get = FakeRequest::get().build();
+        let before: Response<hyper::Body> = crate::serve_with_options(e.clone(), &get, &opts);
+        assert_eq!(before.status(), StatusCode::OK);
+
+        // A response dropped without being polled isn't waited for.
+        let dropped: Response<hyper::Body> = crate::serve_with_options(e.clone(), &get, &opts);
+        drop(dropped);
+
+        assert!(!watch.is_draining());
+        let mut drain = watch.drain();
+        assert!(watch.is_draining());
+        assert!((&mut drain).now_or_never().is_none());
+
+        let after: Response<hyper::Body> = crate::serve_with_options(e, &get, &opts);
+        assert_eq!(after.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            ServeDisposition::of(&after),
+            Some(ServeDisposition::Unavailable)
+        );
+        assert_eq!(
+            after.headers().get(http::header::CONNECTION).unwrap(),
+            "close"
+        );
+
+        let body = hyper::body::to_bytes(before.into_body()).await.unwrap();
+        assert_eq!(&body[..], BODY);
+        tokio::time::timeout(Duration::from_secs(10), drain)
+            .await
+            .unwrap();
+    }
+
+    /// An entity which sends its first byte and then stalls.
+    struct Stalled;
+
+    impl Entity for Stalled {
+        type Data = Bytes;
+        type Error = BoxedError;
+
+        fn len(&self) -> u64 {
+            BODY.len() as u64
+        }
+        fn get_range(
+            &self,
+            range: Range<u64>,
+        ) -> Box<dyn Stream<Item = Result<Self::Data, Self::Error>> + Send + Sync> {
+            let first = Bytes::from_static(&BODY[range.start as usize..range.start as usize + 1]);
+            Box::new(stream::once(futures::future::ok(first)).chain(stream::pending()))
+        }
+        fn add_headers(&self, _: &mut HeaderMap) {}
+        fn etag(&self) -> Option<HeaderValue> {
+            None
+        }
+        fn last_modified(&self) -> Option<SystemTime> {
+            None
+        }
+    }
+
+    /// A body cut off at the end of the grace period ends with an error rather than simply
+    /// ending, so clients see a failure even without a `Content-Length` to fall short of.
+    #[tokio::test]
+    async fn cut_off_with_error() {
+        async fn cut_off<E: 'static + Send>() -> E {
+            let watch = Watch::new(Duration::from_secs(0));
+            let s = stream::once(futures::future::ok(Bytes::from_static(b"0")))
+                .chain(stream::pending::<Result<Bytes, E>>());
+            let mut body = Box::into_pin(watch.register(Box::new(s)));
+            assert_eq!(&body.next().await.unwrap().ok().unwrap()[..], b"0");
+            drop(watch.drain());
+            let e = match body.next().await {
+                Some(Err(e)) => e,
+                _ => panic!("expected an error"),
+            };
+            assert!(body.next().await.is_none());
+            e
+        }
+        let e: BoxedError = cut_off().await;
+        let e = e.downcast::<std::io::Error>().unwrap();
+        assert_eq!(e.kind(), std::io::ErrorKind::Interrupted);
+        let e: std::io::Error = cut_off().await;
+        assert_eq!(e.kind(), std::io::ErrorKind::Interrupted);
+    }
+
+    /// A download unfinished at the end of the grace period is cut off, and the client sees an
+    /// error rather than a short body.
+    #[tokio::test]
+    async fn grace_period_expires() {
+        let watch = Watch::new(Duration::from_millis(50));
+        let opts = ServeOptions::new().drain(watch.clone());
+        let make_svc = hyper::service::make_service_fn(move |_conn| {
+            let opts = opts.clone();
+            futures::future::ok::<_, hyper::Error>(hyper::service::service_fn(
+                move |req: Request<hyper::Body>| {
+                    futures::future::ok::<Response<hyper::Body>, BoxedError>(
+                        crate::serve_with_options(Stalled, &req, &opts),
+                    )
+                },
+            ))
+        });
+        let (tx, rx) = futures::channel::oneshot::channel::<()>();
+        let srv = hyper::server::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let url = format!("http://{}/", srv.local_addr());
+        let srv = tokio::spawn(srv.with_graceful_shutdown(rx.map(|_| ())));
+
+        let mut resp = reqwest::get(&url).await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        assert_eq!(&resp.chunk().await.unwrap().unwrap()[..], b"0");
+
+        tx.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(10), watch.drain())
+            .await
+            .unwrap();
+        resp.chunk().await.unwrap_err();
+        tokio::time::timeout(Duration::from_secs(10), srv)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+}
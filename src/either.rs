@@ -0,0 +1,177 @@
+// Copyright (c) 2016-2020 The http-serve developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::{BoxedEntity, ContentCoding, Entity};
+use futures::future::Either;
+use futures::Stream;
+use http::header::{HeaderMap, HeaderValue};
+use std::ops::Range;
+use std::time::SystemTime;
+
+/// Calls the same expression on whichever side of an `Either` is present.
+macro_rules! delegate {
+    ($either:expr, $e:ident => $body:expr) => {
+        match $either {
+            Either::Left($e) => $body,
+            Either::Right($e) => $body,
+        }
+    };
+}
+
+/// Serves one of two entities, such as a full and a redacted representation of the same
+/// resource chosen per request, without boxing.
+///
+/// All methods delegate to the chosen side, so conditional and range requests are evaluated
+/// against its etag, last modified time, and length.
+impl<L, R> Entity for Either<L, R>
+where
+    L: Entity,
+    R: Entity<Data = L::Data, Error = L::Error>,
+{
+    type Data = L::Data;
+    type Error = L::Error;
+
+    fn len(&self) -> u64 {
+        delegate!(self, e => e.len())
+    }
+
+    fn get_range(
+        &self,
+        range: Range<u64>,
+    ) -> Box<dyn Stream<Item = Result<Self::Data, Self::Error>> + Send + Sync> {
+        delegate!(self, e => e.get_range(range))
+    }
+
+    fn add_headers(&self, h: &mut HeaderMap) {
+        delegate!(self, e => e.add_headers(h))
+    }
+
+    fn static_headers(&self) -> Option<&HeaderMap> {
+        delegate!(self, e => e.static_headers())
+    }
+
+    fn content_type(&self) -> Option<HeaderValue> {
+        delegate!(self, e => e.content_type())
+    }
+
+    fn etag(&self) -> Option<HeaderValue> {
+        delegate!(self, e => e.etag())
+    }
+
+    fn last_modified(&self) -> Option<SystemTime> {
+        delegate!(self, e => e.last_modified())
+    }
+
+    fn digest(&self) -> Option<HeaderValue> {
+        delegate!(self, e => e.digest())
+    }
+
+    fn content_location(&self) -> Option<HeaderValue> {
+        delegate!(self, e => e.content_location())
+    }
+
+    fn get_bytes(&self, range: Range<u64>) -> Option<Self::Data> {
+        delegate!(self, e => e.get_bytes(range))
+    }
+
+    fn contains(&self, needle: &[u8], within: Range<u64>) -> Option<bool> {
+        delegate!(self, e => e.contains(needle, within))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn get_custom_range(
+        &self,
+        unit: &str,
+        first: u64,
+        last: u64,
+    ) -> Option<Box<dyn Stream<Item = Result<Self::Data, Self::Error>> + Send + Sync>> {
+        delegate!(self, e => e.get_custom_range(unit, first, last))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn variants(&self) -> Vec<(ContentCoding, BoxedEntity<Self::Data, Self::Error>)> {
+        delegate!(self, e => e.variants())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::{FakeEntity, FakeRequest};
+    use futures::future::Either;
+    use http::{header, Request, Response, StatusCode};
+
+    /// Set by "middleware" on requests which may only see the redacted document.
+    struct Redacted;
+
+    fn handle(req: &Request<()>) -> Response<hyper::Body> {
+        let e = if req.extensions().get::<Redacted>().is_some() {
+            Either::Right(FakeEntity::new(&b"public part [redacted]"[..]).etag("\"redacted\""))
+        } else {
+            Either::Left(FakeEntity::new(&b"public part secret part"[..]).etag("\"full\""))
+        };
+        crate::serve(e, req)
+    }
+
+    fn redacted(r: FakeRequest) -> Request<()> {
+        let mut r = r.build();
+        r.extensions_mut().insert(Redacted);
+        r
+    }
+
+    #[tokio::test]
+    async fn both_arms() {
+        let resp = handle(&FakeRequest::get().build());
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::ETAG], "\"full\"");
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"public part secret part");
+
+        let resp = handle(&redacted(FakeRequest::get()));
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::ETAG], "\"redacted\"");
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"public part [redacted]");
+    }
+
+    #[tokio::test]
+    async fn revalidation() {
+        let resp = handle(&FakeRequest::get().if_none_match("\"full\"").build());
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        let resp = handle(&redacted(FakeRequest::get().if_none_match("\"redacted\"")));
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+
+        // Each arm's etag doesn't validate the other's representation.
+        let resp = handle(&redacted(FakeRequest::get().if_none_match("\"full\"")));
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::ETAG], "\"redacted\"");
+        let resp = handle(&FakeRequest::get().if_none_match("\"redacted\"").build());
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    /// A probe is answered from the chosen side's `get_bytes`.
+    #[tokio::test]
+    async fn probe() {
+        let full = FakeEntity::new(&b"public part secret part"[..]);
+        let e: Either<_, FakeEntity> = Either::Left(full.clone());
+        let resp: Response<hyper::Body> =
+            crate::serve(e, &FakeRequest::get().range("bytes=0-1").build());
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"pu");
+        assert_eq!(full.get_range_calls(), 0);
+    }
+
+    #[tokio::test]
+    async fn range_on_redacted() {
+        let resp = handle(&redacted(FakeRequest::get().range("bytes=12-21")));
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(resp.headers()[header::CONTENT_RANGE], "bytes 12-21/22");
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"[redacted]");
+    }
+}
@@ -7,21 +7,152 @@
 // except according to those terms.
 
 use http::header::{self, HeaderMap, HeaderValue};
+use std::fmt;
+
+/// Compares two byte strings, optionally in time independent of their contents.
+///
+/// The constant-time path still returns early on a length mismatch; it's only intended to avoid
+/// revealing how long a prefix of a secret-bearing etag a client has guessed correctly.
+fn bytes_eq(a: &[u8], b: &[u8], constant_time: bool) -> bool {
+    if !constant_time {
+        return a == b;
+    }
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// How `serve` compares etags in `If-None-Match`, as set by
+/// [`ServeOptions::if_none_match_comparison`](struct.ServeOptions.html#method.if_none_match_comparison).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum EtagComparison {
+    /// The weak comparison function, under which `W/"x"` and `"x"` match: the default, as
+    /// [RFC 7232 section 3.2](https://tools.ietf.org/html/rfc7232#section-3.2) requires.
+    WeakAllowed,
+
+    /// The strong comparison function, under which weak etags never match.
+    StrongOnly,
+}
+
+impl Default for EtagComparison {
+    fn default() -> Self {
+        EtagComparison::WeakAllowed
+    }
+}
 
 /// Performs weak validation of two etags (such as B"W/\"foo\"" or B"\"bar\"").
-pub fn weak_eq(mut a: &[u8], mut b: &[u8]) -> bool {
+pub fn weak_eq(mut a: &[u8], mut b: &[u8], constant_time: bool) -> bool {
     if a.starts_with(b"W/") {
         a = &a[2..];
     }
     if b.starts_with(b"W/") {
         b = &b[2..];
     }
-    a == b
+    bytes_eq(a, b, constant_time)
 }
 
 /// Performs strong validation of two etags (such as B"W/\"foo\"" or B"\"bar\"").
-pub fn strong_eq(a: &[u8], b: &[u8]) -> bool {
-    a == b && !a.starts_with(b"W/")
+pub fn strong_eq(a: &[u8], b: &[u8], constant_time: bool) -> bool {
+    !a.starts_with(b"W/") && bytes_eq(a, b, constant_time)
+}
+
+/// Returns true if `b` is an `etagc`, a byte allowed within an `opaque-tag`.
+///
+/// ```text
+/// etagc = %x21 / %x23-7E / obs-text
+/// ```
+fn is_etagc(b: u8) -> bool {
+    b == 0x21 || (0x23..=0x7e).contains(&b) || b >= 0x80
+}
+
+/// Returns true if `etag` is a valid `entity-tag` as defined in [RFC 7232 section
+/// 2.3](https://tools.ietf.org/html/rfc7232#section-2.3).
+///
+/// ```text
+/// entity-tag = [ weak ] opaque-tag
+/// weak       = %x57.2F ; "W/", case-sensitive
+/// opaque-tag = DQUOTE *etagc DQUOTE
+/// ```
+#[cfg(any(test, feature = "test-support"))]
+pub fn is_valid(mut etag: &[u8]) -> bool {
+    if etag.starts_with(b"W/") {
+        etag = &etag[2..];
+    }
+    etag.len() >= 2
+        && etag[0] == b'"'
+        && etag[etag.len() - 1] == b'"'
+        && etag[1..etag.len() - 1].iter().all(|&b| is_etagc(b))
+}
+
+/// A tag rejected by [`make_strong_etag`](fn.make_strong_etag.html) or
+/// [`make_weak_etag`](fn.make_weak_etag.html) for containing a byte not allowed in an etag.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct InvalidEtag {
+    offset: usize,
+    byte: u8,
+}
+
+impl InvalidEtag {
+    /// Returns the offset of the first invalid byte within the tag.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Returns the first invalid byte.
+    pub fn byte(&self) -> u8 {
+        self.byte
+    }
+}
+
+impl fmt::Display for InvalidEtag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "invalid byte {:#04x} at offset {} of etag",
+            self.byte, self.offset
+        )
+    }
+}
+
+impl std::error::Error for InvalidEtag {}
+
+/// Returns a strong `ETag` value for the opaque tag `tag`, which is quoted.
+///
+/// `tag` should be the bare tag, such as `v1`, for which this returns `"v1"`. It's an error for
+/// `tag` to contain double quotes, spaces, or control characters, which [RFC 7232 section
+/// 2.3](https://tools.ietf.org/html/rfc7232#section-2.3) disallows.
+///
+/// ```
+/// assert_eq!(http_serve::make_strong_etag("v1").unwrap(), "\"v1\"");
+/// assert!(http_serve::make_strong_etag("\"v1\"").is_err());
+/// ```
+pub fn make_strong_etag(tag: &str) -> Result<HeaderValue, InvalidEtag> {
+    make_etag(b"\"", tag)
+}
+
+/// Returns a weak `ETag` value for the opaque tag `tag`, which is quoted and prefixed with `W/`.
+///
+/// As with [`make_strong_etag`](fn.make_strong_etag.html), `tag` should be the bare tag: `v1`
+/// becomes `W/"v1"`.
+pub fn make_weak_etag(tag: &str) -> Result<HeaderValue, InvalidEtag> {
+    make_etag(b"W/\"", tag)
+}
+
+fn make_etag(prefix: &[u8], tag: &str) -> Result<HeaderValue, InvalidEtag> {
+    let bytes = tag.as_bytes();
+    if let Some(offset) = bytes.iter().position(|&b| !is_etagc(b)) {
+        return Err(InvalidEtag {
+            offset,
+            byte: bytes[offset],
+        });
+    }
+    let mut v = Vec::with_capacity(prefix.len() + bytes.len() + 1);
+    v.extend_from_slice(prefix);
+    v.extend_from_slice(bytes);
+    v.push(b'"');
+    Ok(HeaderValue::from_bytes(&v).expect("etagc bytes are valid in a header value"))
 }
 
 /// Matches a `1#entity-tag`, where `#` is as specified in RFC 7230 section 7.
@@ -38,7 +169,7 @@ struct List<'a> {
 }
 
 impl<'a> List<'a> {
-    fn from(l: &[u8]) -> List {
+    fn from(l: &[u8]) -> List<'_> {
         List {
             remaining: l,
             corrupt: false,
@@ -84,17 +215,26 @@ impl<'a> Iterator for List<'a> {
             }
         }
         self.remaining = rem;
-        return Some(etag);
+        Some(etag)
     }
 }
 
-/// Returns true if `req` doesn't have an `If-None-Match` header matching `req`.
-pub fn none_match(etag: &Option<HeaderValue>, req_hdrs: &HeaderMap) -> Result<bool, &'static str> {
+/// Returns true if `req` doesn't have an `If-None-Match` header matching `req`, comparing etags as
+/// specified by `comparison`.
+pub fn none_match(
+    etag: &Option<HeaderValue>,
+    req_hdrs: &HeaderMap,
+    comparison: EtagComparison,
+    constant_time: bool,
+) -> Result<bool, &'static str> {
     let m = match req_hdrs.get(header::IF_NONE_MATCH) {
         None => return Ok(true),
         Some(m) => m.as_bytes(),
     };
     if m == b"*" {
+        // RFC 7232 section 3.2: "If the field-value is "*", the condition is false if the origin
+        // server has a current representation for the target resource." We always have one,
+        // whether or not it has an etag.
         return Ok(false);
     }
     let mut none_match = true;
@@ -102,8 +242,12 @@ pub fn none_match(etag: &Option<HeaderValue>, req_hdrs: &HeaderMap) -> Result<bo
         let mut items = List::from(m);
         for item in &mut items {
             // RFC 7232 section 3.2: A recipient MUST use the weak comparison function when
-            // comparing entity-tags for If-None-Match
-            if none_match && weak_eq(item, some_etag.as_bytes()) {
+            // comparing entity-tags for If-None-Match. StrongOnly departs from this by request.
+            let eq = match comparison {
+                EtagComparison::WeakAllowed => weak_eq,
+                EtagComparison::StrongOnly => strong_eq,
+            };
+            if none_match && eq(item, some_etag.as_bytes(), constant_time) {
                 none_match = false;
             }
         }
@@ -115,7 +259,11 @@ pub fn none_match(etag: &Option<HeaderValue>, req_hdrs: &HeaderMap) -> Result<bo
 }
 
 /// Returns true if `req` has no `If-Match` header or one which matches `etag`.
-pub fn any_match(etag: &Option<HeaderValue>, req_hdrs: &HeaderMap) -> Result<bool, &'static str> {
+pub fn any_match(
+    etag: &Option<HeaderValue>,
+    req_hdrs: &HeaderMap,
+    constant_time: bool,
+) -> Result<bool, &'static str> {
     let m = match req_hdrs.get(header::IF_MATCH) {
         None => return Ok(true),
         Some(m) => m.as_bytes(),
@@ -129,7 +277,7 @@ pub fn any_match(etag: &Option<HeaderValue>, req_hdrs: &HeaderMap) -> Result<boo
     if let Some(ref some_etag) = *etag {
         let mut items = List::from(m);
         for item in &mut items {
-            if !any_match && strong_eq(item, some_etag.as_bytes()) {
+            if !any_match && strong_eq(item, some_etag.as_bytes(), constant_time) {
                 any_match = true;
             }
         }
@@ -146,22 +294,80 @@ mod tests {
 
     #[test]
     fn weak_eq() {
-        assert!(super::weak_eq(b"\"foo\"", b"\"foo\""));
-        assert!(!super::weak_eq(b"\"foo\"", b"\"bar\""));
-        assert!(super::weak_eq(b"W/\"foo\"", b"\"foo\""));
-        assert!(super::weak_eq(b"\"foo\"", b"W/\"foo\""));
-        assert!(super::weak_eq(b"W/\"foo\"", b"W/\"foo\""));
-        assert!(!super::weak_eq(b"W/\"foo\"", b"W/\"bar\""));
+        for &ct in &[false, true] {
+            assert!(super::weak_eq(b"\"foo\"", b"\"foo\"", ct));
+            assert!(!super::weak_eq(b"\"foo\"", b"\"bar\"", ct));
+            assert!(super::weak_eq(b"W/\"foo\"", b"\"foo\"", ct));
+            assert!(super::weak_eq(b"\"foo\"", b"W/\"foo\"", ct));
+            assert!(super::weak_eq(b"W/\"foo\"", b"W/\"foo\"", ct));
+            assert!(!super::weak_eq(b"W/\"foo\"", b"W/\"bar\"", ct));
+            assert!(!super::weak_eq(b"\"foo\"", b"\"fooo\"", ct));
+            assert!(!super::weak_eq(b"W/\"foo\"", b"\"fo\"", ct));
+        }
     }
 
     #[test]
     fn strong_eq() {
-        assert!(super::strong_eq(b"\"foo\"", b"\"foo\""));
-        assert!(!super::strong_eq(b"\"foo\"", b"\"bar\""));
-        assert!(!super::strong_eq(b"W/\"foo\"", b"\"foo\""));
-        assert!(!super::strong_eq(b"\"foo\"", b"W/\"foo\""));
-        assert!(!super::strong_eq(b"W/\"foo\"", b"W/\"foo\""));
-        assert!(!super::strong_eq(b"W/\"foo\"", b"W/\"bar\""));
+        for &ct in &[false, true] {
+            assert!(super::strong_eq(b"\"foo\"", b"\"foo\"", ct));
+            assert!(!super::strong_eq(b"\"foo\"", b"\"bar\"", ct));
+            assert!(!super::strong_eq(b"W/\"foo\"", b"\"foo\"", ct));
+            assert!(!super::strong_eq(b"\"foo\"", b"W/\"foo\"", ct));
+            assert!(!super::strong_eq(b"W/\"foo\"", b"W/\"foo\"", ct));
+            assert!(!super::strong_eq(b"W/\"foo\"", b"W/\"bar\"", ct));
+            assert!(!super::strong_eq(b"\"foo\"", b"\"fooo\"", ct));
+            assert!(!super::strong_eq(b"\"fooo\"", b"\"foo\"", ct));
+        }
+    }
+
+    #[test]
+    fn bytes_eq() {
+        assert!(super::bytes_eq(b"", b"", true));
+        assert!(super::bytes_eq(b"abc", b"abc", true));
+        assert!(!super::bytes_eq(b"abc", b"abd", true));
+        assert!(!super::bytes_eq(b"abc", b"xbc", true));
+        assert!(!super::bytes_eq(b"abc", b"ab", true));
+        assert!(!super::bytes_eq(b"", b"a", true));
+    }
+
+    #[test]
+    fn is_valid() {
+        assert!(super::is_valid(b"\"foo\""));
+        assert!(super::is_valid(b"W/\"foo\""));
+        assert!(super::is_valid(b"\"\""));
+        assert!(!super::is_valid(b"foo"));
+        assert!(!super::is_valid(b"\""));
+        assert!(!super::is_valid(b"w/\"foo\""));
+        assert!(!super::is_valid(b"\"fo\"o\""));
+        assert!(!super::is_valid(b"\"foo bar\""));
+    }
+
+    #[test]
+    fn make_etag() {
+        assert_eq!(super::make_strong_etag("v1").unwrap(), "\"v1\"");
+        assert_eq!(super::make_weak_etag("v1").unwrap(), "W/\"v1\"");
+        assert_eq!(super::make_strong_etag("").unwrap(), "\"\"");
+        assert_eq!(
+            super::make_strong_etag("ab-12!#~\u{e9}")
+                .unwrap()
+                .as_bytes(),
+            "\"ab-12!#~\u{e9}\"".as_bytes()
+        );
+        for e in &[
+            super::make_strong_etag("v1").unwrap(),
+            super::make_weak_etag("\u{e9}").unwrap(),
+        ] {
+            assert!(super::is_valid(e.as_bytes()), "{:?}", e);
+        }
+
+        let e = super::make_strong_etag("\"v1\"").unwrap_err();
+        assert_eq!((e.offset(), e.byte()), (0, b'"'));
+        assert_eq!(e.to_string(), "invalid byte 0x22 at offset 0 of etag");
+        let e = super::make_weak_etag("a b").unwrap_err();
+        assert_eq!((e.offset(), e.byte()), (1, b' '));
+        for bad in &["a\tb", "a\x7fb", "a\\\"b", "a\nb", "v1\r\nSet-Cookie: a=b"] {
+            assert!(super::make_strong_etag(bad).is_err(), "{:?}", bad);
+        }
     }
 
     #[test]
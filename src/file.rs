@@ -7,9 +7,10 @@
 // except according to those terms.
 
 use crate::platform::{self, FileExt};
-use bytes::Buf;
+use crate::read_limit::ReadLimiter;
+use bytes::{Buf, Bytes, BytesMut};
 use futures::Stream;
-use http::header::{HeaderMap, HeaderValue};
+use http::header::{self, HeaderMap, HeaderValue};
 use std::error::Error as StdError;
 use std::io;
 use std::ops::Range;
@@ -22,16 +23,26 @@ use crate::Entity;
 // a tradeoff between memory usage and thread handoffs.
 static CHUNK_SIZE: u64 = 65_536;
 
+// Chunks are split off a shared backing buffer of BUFFER_SIZE bytes, so a long read allocates
+// once per BUFFER_SIZE / CHUNK_SIZE chunks rather than once per chunk. Once every chunk split off
+// a buffer has been dropped, BytesMut::reserve reclaims it rather than allocating anew.
+static BUFFER_SIZE: usize = 4 * 65_536;
+
+// Holes in sparse files are served as slices of this, rather than read from disk.
+#[cfg(unix)]
+static ZEROS: [u8; CHUNK_SIZE as usize] = [0; CHUNK_SIZE as usize];
+
 /// A HTTP entity created from a `std::fs::File` which reads the file chunk-by-chunk within
 /// a `tokio::task::block_in_place` closure.
 ///
 /// Expects to be used from a tokio threadpool.
-#[derive(Clone)]
 pub struct ChunkedReadFile<
-    D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]>,
+    D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]> + From<Bytes>,
     E: 'static + Send + Into<Box<dyn StdError + Send + Sync>> + From<Box<dyn StdError + Send + Sync>>,
 > {
     inner: Arc<ChunkedReadFileInner>,
+    sparse_aware: bool,
+    limiter: Option<ReadLimiter>,
     phantom: std::marker::PhantomData<(D, E)>,
 }
 
@@ -41,11 +52,34 @@ struct ChunkedReadFileInner {
     mtime: SystemTime,
     f: std::fs::File,
     headers: HeaderMap,
+
+    /// The total bytes returned by `read_at`, so tests can tell which ranges were read.
+    #[cfg(test)]
+    bytes_read: std::sync::atomic::AtomicU64,
+}
+
+// Implemented by hand so that `D` and `E` needn't be `Clone`.
+impl<D, E> Clone for ChunkedReadFile<D, E>
+where
+    D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]> + From<Bytes>,
+    E: 'static
+        + Send
+        + Into<Box<dyn StdError + Send + Sync>>
+        + From<Box<dyn StdError + Send + Sync>>,
+{
+    fn clone(&self) -> Self {
+        ChunkedReadFile {
+            inner: Arc::clone(&self.inner),
+            sparse_aware: self.sparse_aware,
+            limiter: self.limiter.clone(),
+            phantom: std::marker::PhantomData,
+        }
+    }
 }
 
 impl<D, E> ChunkedReadFile<D, E>
 where
-    D: 'static + Send + Sync + Buf + From<Vec<u8>> + From<&'static [u8]>,
+    D: 'static + Send + Sync + Buf + From<Vec<u8>> + From<&'static [u8]> + From<Bytes>,
     E: 'static
         + Send
         + Sync
@@ -68,15 +102,44 @@ where
                 mtime: info.mtime,
                 headers,
                 f: file,
+                #[cfg(test)]
+                bytes_read: std::sync::atomic::AtomicU64::new(0),
             }),
+            sparse_aware: false,
+            limiter: None,
             phantom: std::marker::PhantomData,
         })
     }
+
+    /// Sets whether to look for holes in a sparse file, such as a VM image, and serve them as
+    /// zeros without reading them from disk. The output is the same either way.
+    ///
+    /// Holes are found with `lseek(2)`'s `SEEK_DATA` and `SEEK_HOLE`, which cost two extra system
+    /// calls per chunk. Where the OS or filesystem doesn't support these, the file is read as
+    /// usual.
+    #[cfg(unix)]
+    pub fn sparse_aware(self, sparse_aware: bool) -> Self {
+        ChunkedReadFile {
+            sparse_aware,
+            ..self
+        }
+    }
+
+    /// Sets a limiter for this file's reads, usually shared by every file a server opens.
+    ///
+    /// Each chunk waits for the limiter before it's read. If the limiter sheds the read, the
+    /// body ends with an error holding an [`Overloaded`](struct.Overloaded.html).
+    pub fn read_limiter(self, limiter: ReadLimiter) -> Self {
+        ChunkedReadFile {
+            limiter: Some(limiter),
+            ..self
+        }
+    }
 }
 
 impl<D, E> Entity for ChunkedReadFile<D, E>
 where
-    D: 'static + Send + Sync + Buf + From<Vec<u8>> + From<&'static [u8]>,
+    D: 'static + Send + Sync + Buf + From<Vec<u8>> + From<&'static [u8]> + From<Bytes>,
     E: 'static
         + Send
         + Sync
@@ -94,37 +157,102 @@ where
         &self,
         range: Range<u64>,
     ) -> Box<dyn Stream<Item = Result<Self::Data, Self::Error>> + Send + Sync> {
+        let sparse_aware = self.sparse_aware;
+        let limiter = self.limiter.clone();
         let stream = futures::stream::unfold(
-            (range, Arc::clone(&self.inner)),
-            move |(left, inner)| async {
-                if left.start == left.end {
-                    return None;
-                }
-                let chunk_size = std::cmp::min(CHUNK_SIZE, left.end - left.start) as usize;
-                Some(tokio::task::block_in_place(move || {
-                    // Read directly into an uninitialized buffer. By a strict reading of the
-                    // Vec::set_len docs, this is unsound (the buffer must be initialized first),
-                    // but tokio::io::BufReader does something similar (at least these two calls in
-                    // a row), so I'll assume for now it doesn't cause problems in practice.
-                    // It looks like there's work to avoid this problem here:
-                    // https://github.com/rust-lang/rust/issues/42788
-                    let mut chunk = Vec::with_capacity(chunk_size);
-                    unsafe { chunk.set_len(chunk_size) };
-                    let bytes_read = match inner.f.read_at(&mut chunk, left.start) {
-                        Err(e) => {
-                            return (
-                                Err(Box::<dyn StdError + Send + Sync + 'static>::from(e).into()),
-                                (left, inner),
-                            )
-                        }
-                        Ok(b) => b,
+            (range, Arc::clone(&self.inner), BytesMut::new()),
+            move |(left, inner, mut buf)| {
+                let limiter = limiter.clone();
+                async move {
+                    if left.start == left.end {
+                        return None;
+                    }
+                    let _permit = match limiter {
+                        None => None,
+                        Some(l) => match l.acquire().await {
+                            Ok(p) => Some(p),
+                            Err(e) => {
+                                // Stop after the error.
+                                return Some((
+                                    Err(Box::<dyn StdError + Send + Sync + 'static>::from(e).into()),
+                                    (left.end..left.end, inner, buf),
+                                ));
+                            }
+                        },
                     };
-                    chunk.truncate(bytes_read);
-                    (
-                        Ok(chunk.into()),
-                        (left.start + bytes_read as u64..left.end, inner),
-                    )
-                }))
+                    #[cfg_attr(not(unix), allow(unused_mut))]
+                    let mut chunk_size = std::cmp::min(CHUNK_SIZE, left.end - left.start) as usize;
+                    Some(tokio::task::block_in_place(move || {
+                        #[cfg(unix)]
+                        {
+                            if sparse_aware {
+                                let (hole, end) =
+                                    match platform::extent_at(&inner.f, left.start, inner.len) {
+                                        Err(e) => {
+                                            return (
+                                            Err(Box::<dyn StdError + Send + Sync + 'static>::from(
+                                                e,
+                                            )
+                                            .into()),
+                                            (left, inner, buf),
+                                        );
+                                        }
+                                        Ok(x) => x,
+                                    };
+                                chunk_size =
+                                    std::cmp::min(chunk_size as u64, end - left.start) as usize;
+                                if hole {
+                                    return (
+                                        Ok(D::from(&ZEROS[..chunk_size])),
+                                        (left.start + chunk_size as u64..left.end, inner, buf),
+                                    );
+                                }
+                            }
+                        }
+                        #[cfg(not(unix))]
+                        let _ = sparse_aware;
+
+                        if buf.capacity() < chunk_size {
+                            buf.reserve(std::cmp::max(BUFFER_SIZE, chunk_size));
+                        }
+
+                        // Zero the buffer before reading into it. Reading into uninitialized memory
+                        // via Vec::set_len is unsound; see
+                        // https://github.com/rust-lang/rust/issues/42788
+                        buf.resize(chunk_size, 0);
+                        let bytes_read = match inner.f.read_at(&mut buf, left.start) {
+                            Err(e) => {
+                                buf.clear();
+                                return (
+                                    Err(Box::<dyn StdError + Send + Sync + 'static>::from(e).into()),
+                                    (left, inner, buf),
+                                );
+                            }
+                            Ok(0) => {
+                                // The file is shorter than when it was opened. Stop after the error.
+                                let e = platform::truncated(left.start, inner.len);
+                                return (
+                                    Err(Box::<dyn StdError + Send + Sync + 'static>::from(e).into()),
+                                    (left.end..left.end, inner, buf),
+                                );
+                            }
+                            Ok(b) => b,
+                        };
+                        #[cfg(test)]
+                        inner
+                            .bytes_read
+                            .fetch_add(bytes_read as u64, std::sync::atomic::Ordering::Relaxed);
+                        buf.truncate(bytes_read);
+
+                        // Split off the chunk; later reads write only to the remainder of the
+                        // buffer, never to bytes already yielded.
+                        let chunk = buf.split().freeze();
+                        (
+                            Ok(chunk.into()),
+                            (left.start + bytes_read as u64..left.end, inner, buf),
+                        )
+                    }))
+                }
             },
         );
         let _: &dyn Stream<Item = Result<Self::Data, Self::Error>> = &stream;
@@ -140,6 +268,14 @@ where
         );
     }
 
+    fn static_headers(&self) -> Option<&HeaderMap> {
+        Some(&self.inner.headers)
+    }
+
+    fn content_type(&self) -> Option<HeaderValue> {
+        self.inner.headers.get(header::CONTENT_TYPE).cloned()
+    }
+
     fn etag(&self) -> Option<HeaderValue> {
         // This etag format is similar to Apache's. The etag should change if the file is modified
         // or replaced. The length is probably redundant but doesn't harm anything.
@@ -180,7 +316,7 @@ mod tests {
     use std::io::Write;
 
     type BoxedError = Box<dyn std::error::Error + Sync + Send>;
-    type CRF = ChunkedReadFile<Bytes, BoxedError>;
+    type Crf = ChunkedReadFile<Bytes, BoxedError>;
 
     async fn to_bytes(s: Box<dyn Stream<Item = Result<Bytes, BoxedError>> + Send>) -> Bytes {
         hyper::body::to_bytes(hyper::Body::from(s)).await.unwrap()
@@ -194,7 +330,7 @@ mod tests {
             let mut f = File::create(&p).unwrap();
             f.write_all(b"asdf").unwrap();
 
-            let crf = CRF::new(File::open(&p).unwrap(), HeaderMap::new()).unwrap();
+            let crf = Crf::new(File::open(&p).unwrap(), HeaderMap::new()).unwrap();
             assert_eq!(4, crf.len());
             let etag1 = crf.etag();
 
@@ -204,7 +340,7 @@ mod tests {
 
             // A ChunkedReadFile constructed from a modified file should have a different etag.
             f.write_all(b"jkl;").unwrap();
-            let crf = CRF::new(File::open(&p).unwrap(), HeaderMap::new()).unwrap();
+            let crf = Crf::new(File::open(&p).unwrap(), HeaderMap::new()).unwrap();
             assert_eq!(8, crf.len());
             let etag2 = crf.etag();
             assert_ne!(etag1, etag2);
@@ -212,4 +348,254 @@ mod tests {
         .await
         .unwrap();
     }
+
+    /// Chunks share backing buffers; reading later chunks must not alter earlier ones.
+    #[tokio::test(threaded_scheduler)]
+    async fn no_chunk_aliasing() {
+        tokio::spawn(async move {
+            let tmp = tempfile::tempdir().unwrap();
+            let p = tmp.path().join("f");
+            let mut f = File::create(&p).unwrap();
+            let mut expected = Vec::new();
+            for i in 0..20u8 {
+                let block = [i; 40_000];
+                f.write_all(&block).unwrap();
+                expected.extend_from_slice(&block);
+            }
+
+            let crf = Crf::new(File::open(&p).unwrap(), HeaderMap::new()).unwrap();
+            let stream = crf.get_range(0..crf.len());
+            let chunks: Vec<Bytes> =
+                futures::stream::StreamExt::collect::<Vec<_>>(std::pin::Pin::from(stream))
+                    .await
+                    .into_iter()
+                    .map(Result::unwrap)
+                    .collect();
+            assert!(chunks.len() > 1);
+            let mut actual = Vec::new();
+            for c in &chunks {
+                actual.extend_from_slice(c);
+            }
+            assert!(actual == expected);
+
+            // Read again while holding the first pass's chunks, then recheck them.
+            let again = to_bytes(crf.get_range(0..crf.len())).await;
+            assert!(again[..] == expected[..]);
+            let mut actual = Vec::new();
+            for c in &chunks {
+                actual.extend_from_slice(c);
+            }
+            assert!(actual == expected);
+        })
+        .await
+        .unwrap();
+    }
+
+    /// A client which learns the validators from a `HEAD` and then sends a conditional `GET`
+    /// after the file has been atomically replaced (renamed over) gets a `412` carrying the new
+    /// file's `ETag`, from which it can retry.
+    #[tokio::test(threaded_scheduler)]
+    async fn replaced_between_head_and_get() {
+        use http::{header, Method, Request, Response, StatusCode};
+        use hyper::Body;
+        tokio::spawn(async move {
+            let tmp = tempfile::tempdir().unwrap();
+            let p = tmp.path().join("f");
+            std::fs::write(&p, b"old contents").unwrap();
+            let old = Crf::new(File::open(&p).unwrap(), HeaderMap::new()).unwrap();
+            let req = Request::head("/").body(()).unwrap();
+            let resp: Response<Body> = crate::serve(old.clone(), &req);
+            let old_etag = resp.headers().get(header::ETAG).unwrap().clone();
+
+            let staged = tmp.path().join("f.new");
+            std::fs::write(&staged, b"new contents!").unwrap();
+            std::fs::rename(&staged, &p).unwrap();
+            let new = Crf::new(File::open(&p).unwrap(), HeaderMap::new()).unwrap();
+            let new_etag = new.etag().unwrap();
+            assert_ne!(old_etag, new_etag);
+
+            let get = |etag: &http::HeaderValue, range: Option<&str>| {
+                let mut req = Request::builder()
+                    .method(Method::GET)
+                    .uri("/")
+                    .header(header::IF_MATCH, etag.clone());
+                if let Some(r) = range {
+                    req = req.header(header::RANGE, r);
+                }
+                req.body(()).unwrap()
+            };
+
+            // An entity opened before the rename still refers to the old file.
+            let resp: Response<Body> = crate::serve(old, &get(&old_etag, None));
+            assert_eq!(resp.status(), StatusCode::OK);
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            assert_eq!(&body[..], b"old contents");
+
+            // One opened after it fails the precondition, with or without a range, but the
+            // response reports the current validators.
+            for range in &[None, Some("bytes=0-2")] {
+                let resp: Response<Body> = crate::serve(new.clone(), &get(&old_etag, *range));
+                assert_eq!(resp.status(), StatusCode::PRECONDITION_FAILED);
+                assert_eq!(resp.headers().get(header::ETAG), Some(&new_etag));
+                assert!(resp.headers().contains_key(header::LAST_MODIFIED));
+                let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+                assert_eq!(&body[..], b"Precondition failed");
+            }
+
+            // Retrying with the reported ETag succeeds.
+            let resp: Response<Body> = crate::serve(new, &get(&new_etag, Some("bytes=0-2")));
+            assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            assert_eq!(&body[..], b"new");
+        })
+        .await
+        .unwrap();
+    }
+
+    /// A sparse-aware read should match a normal one byte for byte, without reading the holes.
+    #[cfg(unix)]
+    #[tokio::test(threaded_scheduler)]
+    async fn sparse() {
+        use std::io::{Seek, SeekFrom};
+        use std::sync::atomic::Ordering;
+        tokio::spawn(async move {
+            const MIB: u64 = 1 << 20;
+            let tmp = tempfile::tempdir().unwrap();
+            let p = tmp.path().join("f");
+            let mut f = File::create(&p).unwrap();
+            f.set_len(4 * MIB).unwrap();
+            f.write_all(b"head").unwrap();
+            f.seek(SeekFrom::Start(2 * MIB - 2)).unwrap();
+            f.write_all(b"spans").unwrap();
+            f.seek(SeekFrom::Start(4 * MIB - 4)).unwrap();
+            f.write_all(b"tail").unwrap();
+            drop(f);
+
+            let plain = Crf::new(File::open(&p).unwrap(), HeaderMap::new()).unwrap();
+            let sparse = Crf::new(File::open(&p).unwrap(), HeaderMap::new())
+                .unwrap()
+                .sparse_aware(true);
+            let len = plain.len();
+            let ranges = [
+                0..len,
+                1..3,
+                2 * MIB - 3..2 * MIB + 5,
+                MIB..3 * MIB,
+                len - 4..len,
+            ];
+            for r in &ranges {
+                let expected = to_bytes(plain.get_range(r.clone())).await;
+                let actual = to_bytes(sparse.get_range(r.clone())).await;
+                assert!(expected == actual, "{:?}", r);
+            }
+            let total: u64 = ranges.iter().map(|r| r.end - r.start).sum();
+            assert_eq!(plain.inner.bytes_read.load(Ordering::SeqCst), total);
+
+            // Filesystems may not report holes, or may round extents up to their block size, so
+            // only check that the sparse reads skipped most of the file.
+            let (hole, _) = crate::platform::extent_at(&plain.inner.f, MIB, len).unwrap();
+            if hole {
+                assert!(sparse.inner.bytes_read.load(Ordering::SeqCst) < MIB);
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    /// A file truncated after the entity was constructed ends the response with an error, rather
+    /// than a short body which looks complete (or, with `sparse_aware`, a body padded with
+    /// zeros).
+    #[tokio::test(threaded_scheduler)]
+    async fn truncated() {
+        tokio::spawn(async move {
+            for &sparse_aware in &[false, true] {
+                let tmp = tempfile::tempdir().unwrap();
+                let p = tmp.path().join("f");
+                let mut f = File::create(&p).unwrap();
+                f.write_all(&[b'x'; 100_000]).unwrap();
+                let crf = Crf::new(File::open(&p).unwrap(), HeaderMap::new()).unwrap();
+                #[cfg(unix)]
+                let crf = crf.sparse_aware(sparse_aware);
+                f.set_len(70_000).unwrap();
+
+                for range in &["bytes=0-", "bytes=60000-79999", "bytes=80000-89999"] {
+                    let req = crate::test_support::FakeRequest::get().range(range).build();
+                    let resp: http::Response<hyper::Body> = crate::serve(crf.clone(), &req);
+                    assert_eq!(resp.status(), http::StatusCode::PARTIAL_CONTENT);
+                    let e = hyper::body::to_bytes(resp.into_body()).await.unwrap_err();
+                    assert!(
+                        e.to_string().contains("short of its length of 100000"),
+                        "sparse_aware={} {}: {}",
+                        sparse_aware,
+                        range,
+                        e
+                    );
+                }
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    /// With the limiter saturated, a pre-flight check refuses new responses once the queue is
+    /// full, reads beyond the queue fail at once, and queued reads complete in order as the
+    /// limiter frees up.
+    #[tokio::test(threaded_scheduler)]
+    async fn read_limiter() {
+        use crate::ReadLimiter;
+        use futures::poll;
+        use hyper::body::HttpBody;
+        tokio::spawn(async move {
+            let tmp = tempfile::tempdir().unwrap();
+            let p = tmp.path().join("f");
+            std::fs::write(&p, b"contents").unwrap();
+            let limiter = ReadLimiter::new(1).max_queued(2);
+            let crf = Crf::new(File::open(&p).unwrap(), HeaderMap::new())
+                .unwrap()
+                .read_limiter(limiter.clone());
+            let req = crate::test_support::FakeRequest::get().build();
+
+            // Hold the only permit, as a long read would.
+            let held = limiter.acquire().await.unwrap();
+
+            // Each admitted request's body is polled once, so that it's queued before the next
+            // request's check.
+            let mut bodies = Vec::new();
+            let mut refused = 0;
+            for _ in 0..5 {
+                let reservation = match limiter.try_reserve() {
+                    Ok(r) => r,
+                    Err(_) => {
+                        refused += 1;
+                        continue;
+                    }
+                };
+                let resp: http::Response<hyper::Body> = crate::serve(crf.clone(), &req);
+                assert_eq!(resp.status(), http::StatusCode::OK);
+                drop(reservation);
+                let mut body = resp.into_body();
+                assert!(poll!(body.data()).is_pending());
+                bodies.push(body);
+            }
+            assert_eq!(bodies.len(), 2);
+            assert_eq!(refused, 3);
+
+            // A request which skips the check is shed once its body starts.
+            let resp: http::Response<hyper::Body> = crate::serve(crf.clone(), &req);
+            let e = hyper::body::to_bytes(resp.into_body()).await.unwrap_err();
+            assert!(e.to_string().contains("file read queue is full"), "{}", e);
+            let g = limiter.gauges();
+            assert_eq!((g.in_flight, g.queued, g.rejected), (1, 2, 4));
+
+            drop(held);
+            for b in bodies {
+                let body = hyper::body::to_bytes(b).await.unwrap();
+                assert_eq!(&body[..], b"contents");
+            }
+            let g = limiter.gauges();
+            assert_eq!((g.in_flight, g.queued, g.rejected), (0, 0, 4));
+        })
+        .await
+        .unwrap();
+    }
 }
@@ -0,0 +1,317 @@
+// Copyright (c) 2016-2020 The http-serve developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::range::{self, ResolvedRanges};
+use crate::{BoxedEntity, ContentCoding, Entity};
+use futures::Stream;
+use http::header::{self, HeaderMap, HeaderValue};
+use std::fmt;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// An authorization failure from a `GuardedEntity`'s guard.
+#[derive(Clone, Debug)]
+pub struct Denied {
+    reason: String,
+}
+
+impl Denied {
+    /// Creates a denial with the given reason, which is included in its `Display` output.
+    pub fn new<S: Into<String>>(reason: S) -> Self {
+        Denied {
+            reason: reason.into(),
+        }
+    }
+
+    /// Returns the reason supplied to `new`.
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+}
+
+impl fmt::Display for Denied {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "access denied: {}", self.reason)
+    }
+}
+
+impl std::error::Error for Denied {}
+
+/// An entity which consults a guard before reading each range of an inner entity.
+///
+/// This suits entities whose authorization can expire mid-download, such as those behind signed
+/// URLs with short lifetimes. The guard is called from `get_range`, so it covers each part of a
+/// `multipart/byteranges` response as well as each separate request. A denial becomes an error
+/// in the body stream; in a multipart response, the body stops at the boundary of the first
+/// denied part. Metadata methods pass through to the inner entity, and its variants are guarded
+/// likewise.
+///
+/// By the time `get_range` is called, `serve` has already committed to a status and headers. To
+/// respond with a proper error status instead, call `check_request` before `serve`.
+pub struct GuardedEntity<E, F> {
+    inner: E,
+    guard: Arc<F>,
+}
+
+impl<E, F> GuardedEntity<E, F>
+where
+    E: Entity,
+    E::Error: From<Denied>,
+    F: 'static + Fn(&Range<u64>) -> Result<(), Denied> + Send + Sync,
+{
+    /// Wraps `inner`, consulting `guard` before reading each range.
+    pub fn new(inner: E, guard: F) -> Self {
+        GuardedEntity {
+            inner,
+            guard: Arc::new(guard),
+        }
+    }
+
+    /// Checks the ranges `serve` would read for a request with the given headers.
+    ///
+    /// This checks the whole entity if there's no `Range` header, and nothing if no requested
+    /// range is satisfiable. It doesn't evaluate `If-Range`; if that causes `serve` to read the
+    /// whole entity instead, `get_range` still applies the guard.
+    pub fn check_request(&self, req_hdrs: &HeaderMap) -> Result<(), Denied> {
+        let len = self.inner.len();
+        match range::parse(req_hdrs.get(header::RANGE), len) {
+            ResolvedRanges::None => (self.guard)(&(0..len)),
+            ResolvedRanges::NotSatisfiable => Ok(()),
+            ResolvedRanges::Satisfiable(ranges) => {
+                ranges.ranges().iter().try_for_each(|r| (self.guard)(r))
+            }
+        }
+    }
+}
+
+impl<E, F> Entity for GuardedEntity<E, F>
+where
+    E: Entity,
+    E::Error: From<Denied>,
+    F: 'static + Fn(&Range<u64>) -> Result<(), Denied> + Send + Sync,
+{
+    type Data = E::Data;
+    type Error = E::Error;
+
+    fn len(&self) -> u64 {
+        self.inner.len()
+    }
+
+    fn get_range(
+        &self,
+        range: Range<u64>,
+    ) -> Box<dyn Stream<Item = Result<Self::Data, Self::Error>> + Send + Sync> {
+        if let Err(d) = (self.guard)(&range) {
+            return Box::new(futures::stream::once(futures::future::err(d.into())));
+        }
+        self.inner.get_range(range)
+    }
+
+    fn add_headers(&self, h: &mut HeaderMap) {
+        self.inner.add_headers(h)
+    }
+
+    fn static_headers(&self) -> Option<&HeaderMap> {
+        self.inner.static_headers()
+    }
+
+    fn content_type(&self) -> Option<HeaderValue> {
+        self.inner.content_type()
+    }
+
+    fn etag(&self) -> Option<HeaderValue> {
+        self.inner.etag()
+    }
+
+    fn last_modified(&self) -> Option<SystemTime> {
+        self.inner.last_modified()
+    }
+
+    fn digest(&self) -> Option<HeaderValue> {
+        self.inner.digest()
+    }
+
+    fn content_location(&self) -> Option<HeaderValue> {
+        self.inner.content_location()
+    }
+
+    fn contains(&self, needle: &[u8], within: Range<u64>) -> Option<bool> {
+        self.inner.contains(needle, within)
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn variants(&self) -> Vec<(ContentCoding, BoxedEntity<Self::Data, Self::Error>)> {
+        self.inner
+            .variants()
+            .into_iter()
+            .map(|(coding, v)| {
+                let v: BoxedEntity<Self::Data, Self::Error> = Box::new(GuardedEntity {
+                    inner: Boxed(v),
+                    guard: Arc::clone(&self.guard),
+                });
+                (coding, v)
+            })
+            .collect()
+    }
+}
+
+/// Adapts a `BoxedEntity` to `Entity`, so that variants can be wrapped in a `GuardedEntity` (or
+/// another adapter).
+pub(crate) struct Boxed<D, E>(pub(crate) BoxedEntity<D, E>);
+
+impl<D, E> Entity for Boxed<D, E>
+where
+    D: 'static + Send + Sync + bytes::Buf + From<Vec<u8>> + From<&'static [u8]>,
+    E: 'static + Send + Sync,
+{
+    type Data = D;
+    type Error = E;
+
+    fn len(&self) -> u64 {
+        self.0.len()
+    }
+
+    fn get_range(
+        &self,
+        range: Range<u64>,
+    ) -> Box<dyn Stream<Item = Result<Self::Data, Self::Error>> + Send + Sync> {
+        self.0.get_range(range)
+    }
+
+    fn add_headers(&self, h: &mut HeaderMap) {
+        self.0.add_headers(h)
+    }
+
+    fn static_headers(&self) -> Option<&HeaderMap> {
+        self.0.static_headers()
+    }
+
+    fn content_type(&self) -> Option<HeaderValue> {
+        self.0.content_type()
+    }
+
+    fn etag(&self) -> Option<HeaderValue> {
+        self.0.etag()
+    }
+
+    fn last_modified(&self) -> Option<SystemTime> {
+        self.0.last_modified()
+    }
+
+    fn digest(&self) -> Option<HeaderValue> {
+        self.0.digest()
+    }
+
+    fn content_location(&self) -> Option<HeaderValue> {
+        self.0.content_location()
+    }
+
+    fn get_bytes(&self, range: Range<u64>) -> Option<Self::Data> {
+        self.0.get_bytes(range)
+    }
+
+    fn contains(&self, needle: &[u8], within: Range<u64>) -> Option<bool> {
+        self.0.contains(needle, within)
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn get_custom_range(
+        &self,
+        unit: &str,
+        first: u64,
+        last: u64,
+    ) -> Option<Box<dyn Stream<Item = Result<Self::Data, Self::Error>> + Send + Sync>> {
+        self.0.get_custom_range(unit, first, last)
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn variants(&self) -> Vec<(ContentCoding, BoxedEntity<Self::Data, Self::Error>)> {
+        self.0.variants()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Denied, GuardedEntity};
+    use crate::test_support::{FakeEntity, FakeRequest};
+    use http::{Response, StatusCode};
+    use std::ops::Range;
+
+    static BODY: &[u8] =
+        b"01234567890123456789012345678901234567890123456789012345678901234567890123456789\
+          01234567890123456789012345678901234567890123456789012345678901234567890123456789\
+          01234567890123456789012345678901234567890123456789012345678901234567890123456789";
+
+    /// Denies ranges extending past byte 100.
+    fn guard(r: &Range<u64>) -> Result<(), Denied> {
+        if r.end > 100 {
+            return Err(Denied::new("past threshold"));
+        }
+        Ok(())
+    }
+
+    type Guard = fn(&Range<u64>) -> Result<(), Denied>;
+
+    fn entity() -> GuardedEntity<FakeEntity, Guard> {
+        GuardedEntity::new(FakeEntity::new(BODY), guard)
+    }
+
+    #[test]
+    fn check_request() {
+        let e = entity();
+        let check = |r: FakeRequest| e.check_request(r.build().headers());
+        assert!(check(FakeRequest::get().range("bytes=0-99")).is_ok());
+        assert!(check(FakeRequest::get().range("bytes=100-109")).is_err());
+        assert!(check(FakeRequest::get().range("bytes=0-9, 150-159")).is_err());
+        assert!(check(FakeRequest::get().range("bytes=1000-")).is_ok());
+        assert!(check(FakeRequest::get()).is_err());
+    }
+
+    #[tokio::test]
+    async fn single_range() {
+        let req = FakeRequest::get().range("bytes=0-9").build();
+        let resp: Response<hyper::Body> = crate::serve(entity(), &req);
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"0123456789");
+
+        let req = FakeRequest::get().range("bytes=150-159").build();
+        let resp: Response<hyper::Body> = crate::serve(entity(), &req);
+        let err = hyper::body::to_bytes(resp.into_body()).await.unwrap_err();
+        assert!(err.to_string().contains("past threshold"), "{}", err);
+    }
+
+    #[cfg(feature = "multipart")]
+    #[tokio::test]
+    async fn multipart_stops_at_boundary() {
+        use futures::StreamExt;
+        let req = FakeRequest::get().range("bytes=0-9, 150-159").build();
+        let resp: Response<hyper::Body> = crate::serve(entity(), &req);
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        let mut body = resp.into_body();
+        let mut received = Vec::new();
+        let err = loop {
+            match body.next().await.unwrap() {
+                Ok(b) => received.extend_from_slice(&b),
+                Err(e) => break e,
+            }
+        };
+        assert!(err.to_string().contains("past threshold"), "{}", err);
+
+        // The first part was sent in full, followed by the second part's headers but none of its
+        // body.
+        let received = String::from_utf8(received).unwrap();
+        assert!(received.contains("\r\n\r\n0123456789\r\n"), "{}", received);
+        assert!(
+            received.ends_with("Content-Range: bytes 150-159/240\r\n\r\n"),
+            "{}",
+            received
+        );
+    }
+}
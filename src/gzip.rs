@@ -10,6 +10,12 @@ use crate::chunker;
 use std::io::{self, Write};
 use std::mem;
 
+#[cfg(test)]
+thread_local! {
+    /// The number of gzip encoders constructed on this thread, so tests can tell when none were.
+    pub(crate) static ENCODERS: std::cell::Cell<usize> = std::cell::Cell::new(0);
+}
+
 /// A `std::io::Write` implementation that makes a chunked hyper response body stream.
 /// Automatically applies `gzip` content encoding if requested by the client.
 ///
@@ -26,7 +32,11 @@ use std::mem;
 /// On drop, the stream will be "finished" (for gzip, this writes a special footer). There's no way
 /// to know the complete stream was written successfully. It's inherent in the combination of
 /// HTTP / TCP / Unix sockets / hyper anyway that only the client knows this.
-pub struct BodyWriter<D, E>(Inner<D, E>)
+pub struct BodyWriter<D, E>(
+    Inner<D, E>,
+    /// If true, flush after every successful `write`.
+    bool,
+)
 where
     D: From<Vec<u8>> + Send + 'static,
     E: Send + 'static;
@@ -50,11 +60,79 @@ where
     E: Send + 'static,
 {
     pub(crate) fn raw(raw: chunker::BodyWriter<D, E>) -> Self {
-        BodyWriter(Inner::Raw(raw))
+        BodyWriter(Inner::Raw(raw), false)
     }
 
     pub(crate) fn gzipped(raw: chunker::BodyWriter<D, E>, level: flate2::Compression) -> Self {
-        BodyWriter(Inner::Gzipped(flate2::GzBuilder::new().write(raw, level)))
+        #[cfg(test)]
+        ENCODERS.with(|n| n.set(n.get() + 1));
+        BodyWriter(
+            Inner::Gzipped(flate2::GzBuilder::new().write(raw, level)),
+            false,
+        )
+    }
+
+    /// Sets whether to flush after every successful `write`, trading compression ratio and
+    /// chunk overhead for latency.
+    pub(crate) fn with_flush_writes(self, flush_writes: bool) -> Self {
+        BodyWriter(self.0, flush_writes)
+    }
+
+    /// Writes and flushes a single [server-sent
+    /// events](https://html.spec.whatwg.org/multipage/server-sent-events.html) event.
+    ///
+    /// `data` may span multiple lines; each becomes its own `data:` field. `event` and `id` must
+    /// be single lines. The response should be built with
+    /// `StreamingBodyBuilder::with_event_stream`.
+    pub fn write_sse_event(
+        &mut self,
+        event: Option<&str>,
+        id: Option<&str>,
+        data: &str,
+    ) -> io::Result<()> {
+        let is_line_break = |c| c == '\r' || c == '\n';
+        if event.map(|e| e.contains(is_line_break)).unwrap_or(false)
+            || id
+                .map(|i| i.contains(is_line_break) || i.contains('\0'))
+                .unwrap_or(false)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "SSE event and id fields must be single lines",
+            ));
+        }
+        let mut buf = Vec::with_capacity(data.len() + 64);
+        if let Some(e) = event {
+            buf.extend_from_slice(b"event: ");
+            buf.extend_from_slice(e.as_bytes());
+            buf.push(b'\n');
+        }
+        if let Some(i) = id {
+            buf.extend_from_slice(b"id: ");
+            buf.extend_from_slice(i.as_bytes());
+            buf.push(b'\n');
+        }
+
+        // Lines may be terminated by CRLF, LF, or CR.
+        let mut rest = data;
+        loop {
+            let end = rest.find(is_line_break).unwrap_or(rest.len());
+            buf.extend_from_slice(b"data: ");
+            buf.extend_from_slice(&rest.as_bytes()[..end]);
+            buf.push(b'\n');
+            if end == rest.len() {
+                break;
+            }
+            let skip = if rest[end..].starts_with("\r\n") {
+                2
+            } else {
+                1
+            };
+            rest = &rest[end + skip..];
+        }
+        buf.push(b'\n');
+        self.write_all(&buf)?;
+        self.flush()
     }
 
     /// Causes the HTTP connection to be dropped abruptly.
@@ -65,6 +143,17 @@ where
             Inner::Gzipped(ref mut g) => g.get_mut().abort(error),
         };
     }
+
+    /// Marks the body as dead after a failed write, or flushes after a successful one if
+    /// requested.
+    fn after_write(&mut self, r: io::Result<usize>) -> io::Result<usize> {
+        if r.is_err() {
+            self.0 = Inner::Dead;
+        } else if self.1 {
+            self.flush()?;
+        }
+        r
+    }
 }
 
 impl<D, E> Write for BodyWriter<D, E>
@@ -78,10 +167,21 @@ where
             Inner::Raw(ref mut w) => w.write(buf),
             Inner::Gzipped(ref mut w) => w.write(buf),
         };
-        if r.is_err() {
-            self.0 = Inner::Dead;
-        }
-        r
+        self.after_write(r)
+    }
+
+    /// Writes all of `bufs`; the returned length is always their total length.
+    ///
+    /// Uncompressed, the slices are copied straight into the current chunk. Compressed, runs of
+    /// small slices are first gathered into one buffer, as each call into the encoder has a
+    /// significant fixed cost; large slices are fed to it in place.
+    fn write_vectored(&mut self, bufs: &[io::IoSlice]) -> io::Result<usize> {
+        let r = match self.0 {
+            Inner::Dead => Err(io::Error::new(io::ErrorKind::BrokenPipe, "body is dead"))?,
+            Inner::Raw(ref mut w) => w.write_vectored(bufs),
+            Inner::Gzipped(ref mut w) => write_coalesced(w, bufs),
+        };
+        self.after_write(r)
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -96,3 +196,32 @@ where
         r
     }
 }
+
+/// Slices smaller than this are gathered before being passed to the gzip encoder.
+const COALESCE_THRESHOLD: usize = 1024;
+
+/// Writes all of `bufs` to `w`, gathering runs of small slices into a single `write_all`.
+fn write_coalesced<W: Write>(w: &mut W, bufs: &[io::IoSlice]) -> io::Result<usize> {
+    let mut total = 0;
+    let mut pending = Vec::new();
+    for b in bufs {
+        total += b.len();
+        if b.len() >= COALESCE_THRESHOLD {
+            if !pending.is_empty() {
+                w.write_all(&pending)?;
+                pending.clear();
+            }
+            w.write_all(b)?;
+        } else {
+            pending.extend_from_slice(b);
+            if pending.len() >= COALESCE_THRESHOLD {
+                w.write_all(&pending)?;
+                pending.clear();
+            }
+        }
+    }
+    if !pending.is_empty() {
+        w.write_all(&pending)?;
+    }
+    Ok(total)
+}
@@ -0,0 +1,89 @@
+// Copyright (c) 2016-2020 The http-serve developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::{BoxedEntity, ContentCoding, Entity};
+use futures::Stream;
+use http::header::{HeaderMap, HeaderValue};
+use std::ops::Range;
+use std::time::SystemTime;
+
+/// An entity served by `serve_immutable`: the inner entity, with the known `ETag` in place of its
+/// own and no `Last-Modified`.
+///
+/// Content-coded variants keep their own etags.
+pub(crate) struct ImmutableEntity<E> {
+    pub(crate) inner: E,
+    pub(crate) etag: HeaderValue,
+}
+
+impl<E: Entity> Entity for ImmutableEntity<E> {
+    type Data = E::Data;
+    type Error = E::Error;
+
+    fn len(&self) -> u64 {
+        self.inner.len()
+    }
+
+    fn get_range(
+        &self,
+        range: Range<u64>,
+    ) -> Box<dyn Stream<Item = Result<Self::Data, Self::Error>> + Send + Sync> {
+        self.inner.get_range(range)
+    }
+
+    fn add_headers(&self, h: &mut HeaderMap) {
+        self.inner.add_headers(h)
+    }
+
+    fn static_headers(&self) -> Option<&HeaderMap> {
+        self.inner.static_headers()
+    }
+
+    fn content_type(&self) -> Option<HeaderValue> {
+        self.inner.content_type()
+    }
+
+    fn etag(&self) -> Option<HeaderValue> {
+        Some(self.etag.clone())
+    }
+
+    fn last_modified(&self) -> Option<SystemTime> {
+        None
+    }
+
+    fn digest(&self) -> Option<HeaderValue> {
+        self.inner.digest()
+    }
+
+    fn content_location(&self) -> Option<HeaderValue> {
+        self.inner.content_location()
+    }
+
+    fn get_bytes(&self, range: Range<u64>) -> Option<Self::Data> {
+        self.inner.get_bytes(range)
+    }
+
+    fn contains(&self, needle: &[u8], within: Range<u64>) -> Option<bool> {
+        self.inner.contains(needle, within)
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn get_custom_range(
+        &self,
+        unit: &str,
+        first: u64,
+        last: u64,
+    ) -> Option<Box<dyn Stream<Item = Result<Self::Data, Self::Error>> + Send + Sync>> {
+        self.inner.get_custom_range(unit, first, last)
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn variants(&self) -> Vec<(ContentCoding, BoxedEntity<Self::Data, Self::Error>)> {
+        self.inner.variants()
+    }
+}
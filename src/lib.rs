@@ -64,16 +64,19 @@
 //! `http_body::Body` trait which uses a different `Data` type than `hyper::Chunk`.
 
 use bytes::Buf;
-use futures::Stream;
-use http::header::{self, HeaderMap, HeaderValue};
+use futures::{Stream, StreamExt};
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
 use std::ops::Range;
 use std::str::FromStr;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 /// Returns a HeaderValue for the given formatted data.
 /// Caller must make two guarantees:
 ///    * The data fits within `max_len` (or the write will panic).
 ///    * The data are ASCII (or HeaderValue's safety will be violated).
+///
+/// As nothing checks for CR or LF, use this only to format numbers into constant text. Anything
+/// else, even text chosen among constants, goes through a checked constructor.
 macro_rules! unsafe_fmt_ascii_val {
     ($max_len:expr, $fmt:expr, $($arg:tt)+) => {{
         let mut buf = bytes::BytesMut::with_capacity($max_len);
@@ -85,17 +88,85 @@ macro_rules! unsafe_fmt_ascii_val {
     }}
 }
 
+mod aligned;
+mod block_decrypt;
 mod chunker;
+mod clock;
+#[cfg(feature = "compat")]
+pub mod compat;
+mod coop;
+mod copy;
+mod cow;
+mod csv;
+mod dir;
+pub mod drain;
+mod either;
 mod etag;
 mod file;
+mod guarded;
 mod gzip;
+mod immutable;
+mod materialize;
+#[cfg(feature = "multipart")]
+mod multipart;
 mod platform;
+mod prefixed;
 mod range;
+mod read_limit;
+#[cfg(feature = "multipart")]
+mod rng;
 mod serving;
+mod skip_prefix;
+pub mod sync;
+mod tally;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
+#[cfg(feature = "tower-http-compat")]
+pub mod tower_http_compat;
+mod trailers;
+mod trim;
+#[cfg(feature = "upstream")]
+mod upstream;
+mod zip;
 
+pub use crate::aligned::AlignedFetchEntity;
+pub use crate::block_decrypt::{BlockCipher, BlockDecryptEntity};
+pub use crate::clock::{Clock, MonotonicClock, SystemClock};
+pub use crate::copy::{copy_entity, CopyError};
+pub use crate::cow::CowEntity;
+pub use crate::csv::{csv_entity, csv_record};
+pub use crate::dir::{open_beneath, SymlinkPolicy};
+pub use crate::etag::{make_strong_etag, make_weak_etag, EtagComparison, InvalidEtag};
 pub use crate::file::ChunkedReadFile;
+pub use crate::guarded::{Denied, GuardedEntity};
 pub use crate::gzip::BodyWriter;
-pub use crate::serving::serve;
+pub use crate::materialize::{MaterializedEntity, Materializer};
+pub use crate::prefixed::{PrefixedEntity, UTF8_BOM};
+pub use crate::range::{
+    diagnose_range, parse_range_str, MalformedRange, RangeDiagnostics, ResolvedRanges,
+    SatisfiableRanges,
+};
+pub use crate::read_limit::{Overloaded, ReadGauges, ReadLimiter, Reservation};
+pub use crate::serving::{
+    replace_if, serve, serve_immutable, serve_stream, serve_with_options, serve_with_trailers,
+    would_not_modify, would_not_modify_with_options, would_precondition_fail,
+    would_precondition_fail_with_options, CachePolicy, DroppedHeaders, HeaderOverflow,
+    MultipartFallback, MultirangeMode, ServeDisposition, ServeOptions, ServedRanges,
+};
+pub use crate::skip_prefix::SkipPrefixEntity;
+pub use crate::tally::BytesSent;
+#[cfg(any(test, feature = "test-support"))]
+pub use crate::test_support::{validate_entity, Violation};
+pub use crate::trailers::TrailerBody;
+#[cfg(feature = "upstream")]
+pub use crate::upstream::HttpUpstreamEntity;
+pub use crate::zip::serve_zip;
+
+/// Two entities in one type, for choosing per request; re-exported from `futures`.
+///
+/// `Either<L, R>` implements `Entity` when `L` and `R` do with the same `Data` and `Error`,
+/// delegating to whichever is present.
+pub use futures::future::Either;
 
 /// A reusable, read-only, byte-rangeable HTTP entity for GET and HEAD serving.
 /// Must return exactly the same data on every call.
@@ -104,7 +175,17 @@ pub trait Entity: 'static + Send + Sync {
 
     /// The type of a data chunk.
     ///
-    /// Commonly `bytes::Bytes` but may be something more exotic.
+    /// Commonly `bytes::Bytes` but may be something more exotic. Besides the `Send`, `Sync`, and
+    /// `'static` bounds needed to hand chunks to hyper, `serve` relies on:
+    ///
+    /// *   `Buf`, to measure chunks and read them out.
+    /// *   `From<&'static [u8]>`, for fixed text such as `multipart/byteranges` framing.
+    /// *   `From<Vec<u8>>`, for generated text such as per-part headers and error bodies.
+    ///
+    /// A chunk type lacking any of these is rejected where the `Entity` impl names it, rather
+    /// than at a later call to `serve`. A newtype around `Bytes` can satisfy them by delegating
+    /// each to `Bytes`'s own impls. Entities in this crate which produce data from other sources
+    /// (such as `ChunkedReadFile`) additionally require `From<Bytes>`.
     type Data: 'static + Send + Sync + Buf + From<Vec<u8>> + From<&'static [u8]>;
 
     /// Returns the length of the entity's body in bytes.
@@ -116,6 +197,14 @@ pub trait Entity: 'static + Send + Sync {
     }
 
     /// Gets the body bytes indicated by `range`.
+    ///
+    /// `serve` resolves ranges against `len` and sends a `Content-Length` based on them before
+    /// calling this, so the stream must yield exactly `range.end - range.start` bytes or end with
+    /// an error. If the underlying data no longer supports the range, such as a file truncated
+    /// after `len` was read, yield an error rather than end early. The entities in this crate do
+    /// so. `serve` drops any excess bytes; a stream which ends early without an error makes
+    /// `serve` stop the body there, so the response falls short of its `Content-Length` and is
+    /// aborted rather than appearing complete.
     fn get_range(
         &self,
         range: Range<u64>,
@@ -127,9 +216,35 @@ pub trait Entity: 'static + Send + Sync {
     /// `Content-Range`, `Date`, `Cache-Control`, `ETag`, `Expires`, `Content-Location`, and `Vary`.
     ///
     /// This function will be called only when that section says that headers such as
-    /// `Content-Type` should be included in the response.
+    /// `Content-Type` should be included in the response. Values are sent verbatim, so a
+    /// `Content-Type` should carry any parameters the body needs, such as
+    /// `text/html; charset=utf-8`; this includes each part of a `multipart/byteranges` response.
     fn add_headers(&self, _: &mut HeaderMap);
 
+    /// Returns the headers `add_headers` would add, if precomputed.
+    ///
+    /// Entities served many times can build their headers once, at construction, and return
+    /// them here; `serve` then copies them into the response (cheaply, as `HeaderValue`s share
+    /// their bytes) rather than calling `add_headers`. The default implementation returns `None`.
+    fn static_headers(&self) -> Option<&HeaderMap> {
+        None
+    }
+
+    /// Returns the `Content-Type` that `add_headers` would add, if any.
+    ///
+    /// `serve` uses this where it needs only the type, such as for each part of a
+    /// `multipart/byteranges` response. The default implementation takes it from
+    /// `static_headers` if available and otherwise calls `add_headers` on a scratch `HeaderMap`;
+    /// entities which know their type directly can override it to avoid that allocation.
+    fn content_type(&self) -> Option<HeaderValue> {
+        if let Some(h) = self.static_headers() {
+            return h.get(header::CONTENT_TYPE).cloned();
+        }
+        let mut h = HeaderMap::new();
+        self.add_headers(&mut h);
+        h.remove(header::CONTENT_TYPE)
+    }
+
     /// Returns an etag for this entity, if available.
     /// Implementations are encouraged to provide a strong etag. [RFC 7232 section
     /// 2.1](https://tools.ietf.org/html/rfc7232#section-2.1) notes that only strong etags
@@ -141,6 +256,117 @@ pub trait Entity: 'static + Send + Sync {
     /// this time is in the future, as required by [RFC 7232 section
     /// 2.2.1](https://tools.ietf.org/html/rfc7232#section-2.2.1).
     fn last_modified(&self) -> Option<SystemTime>;
+
+    /// Returns alternate content-coded representations of this entity, such as a gzipped copy
+    /// stored at rest.
+    ///
+    /// The entity itself is always the `identity` variant; the default implementation returns no
+    /// others. `serve` chooses among the entity and these variants according to the request's
+    /// `Accept-Encoding` header, sets `Content-Encoding` on the response as appropriate, and adds
+    /// `Vary: accept-encoding` whenever variants are available. Each variant should supply its own
+    /// etag, distinct from the other representations'.
+    #[allow(clippy::type_complexity)]
+    fn variants(&self) -> Vec<(ContentCoding, BoxedEntity<Self::Data, Self::Error>)> {
+        Vec::new()
+    }
+
+    /// Returns a digest of the entity's body as an [RFC 9530](https://www.rfc-editor.org/rfc/rfc9530)
+    /// dictionary such as `sha-256=:<base64>:`, if available.
+    ///
+    /// `serve` sends it as both `Repr-Digest` and `Content-Digest` on `200 OK` responses, where
+    /// the content is the whole representation. It's omitted from `206 Partial Content`
+    /// responses, where a `Content-Digest` would have to cover only the partial content. Like
+    /// etags, digests cover the encoded bytes, so each variant should supply its own. The default
+    /// implementation returns `None`.
+    fn digest(&self) -> Option<HeaderValue> {
+        None
+    }
+
+    /// Returns the `Content-Location` of this representation, if it has its own URI.
+    ///
+    /// `serve` sends it on `200 OK`, `206 Partial Content`, and `304 Not Modified` responses, as
+    /// RFC 7232 section 4.1 requires a `304` to carry the `Content-Location` a `200` would. For a
+    /// content-coded variant (see `variants`), naming the variant tells caches which stored
+    /// response a `304` revalidates. As with the other headers `serve` sets itself, `add_headers`
+    /// should exclude it. The default implementation returns `None`.
+    fn content_location(&self) -> Option<HeaderValue> {
+        None
+    }
+
+    /// Returns the given range of the body at once, if the entity holds it in memory.
+    ///
+    /// `serve` uses this for probes (see `ServeOptions::probe_size`), embedding the bytes in the
+    /// response body rather than calling `get_range`. As it's called while building the response,
+    /// it must have no side effects, such as file I/O. Thus file entities such as
+    /// `ChunkedReadFile` don't implement it, and their probes are read with `get_range` like any
+    /// other range. The result must be exactly the range's bytes; anything else is ignored in
+    /// favor of `get_range`. The default implementation returns `None`.
+    fn get_bytes(&self, _range: Range<u64>) -> Option<Self::Data> {
+        None
+    }
+
+    /// Returns whether `needle` occurs within the given range of the body, if cheaply known.
+    ///
+    /// This is a hint used when `ServeOptions::verify_boundary` is set. Entities which hold their
+    /// body in memory can search it; the default implementation returns `None`, meaning unknown,
+    /// in which case verification is skipped.
+    fn contains(&self, _needle: &[u8], _within: Range<u64>) -> Option<bool> {
+        None
+    }
+
+    /// Gets the body for the range `first` through `last` (inclusive) in `unit`, a range unit
+    /// other than `bytes` such as `ms` for time-based media, or returns `None` if the entity
+    /// doesn't serve ranges in `unit`.
+    ///
+    /// `serve` calls this for a `Range: <unit>=<first>-<last>` request header while deciding how
+    /// to respond, so the stream should put off any I/O until it's polled. Given a stream, `serve`
+    /// responds with `206 Partial Content` and `Content-Range: <unit> <first>-<last>`, and without
+    /// a `Content-Length`, as the body's length in bytes isn't known in advance. Given `None`, it
+    /// ignores the header and serves the full entity. For the same reason, the response is exempt
+    /// from `ServeOptions::max_content_length` and carries no `ServedRanges`. The default
+    /// implementation returns `None`, supporting no units; an entity which transforms another's
+    /// bytes shouldn't forward this.
+    #[allow(clippy::type_complexity)]
+    fn get_custom_range(
+        &self,
+        _unit: &str,
+        _first: u64,
+        _last: u64,
+    ) -> Option<Box<dyn Stream<Item = Result<Self::Data, Self::Error>> + Send + Sync>> {
+        None
+    }
+}
+
+/// A boxed entity trait object with the given data and error types.
+pub type BoxedEntity<D, E> = Box<dyn Entity<Data = D, Error = E>>;
+
+/// A content coding, as described in [RFC 7231 section
+/// 3.1.2.1](https://tools.ietf.org/html/rfc7231#section-3.1.2.1).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum ContentCoding {
+    /// No encoding.
+    Identity,
+
+    /// The `gzip` coding, as produced by the `gzip` program.
+    Gzip,
+
+    /// The `br` coding, as described in [RFC 7932](https://tools.ietf.org/html/rfc7932).
+    ///
+    /// Only served from [variants](trait.Entity.html#method.variants) stored at rest; this crate
+    /// doesn't compress with it.
+    Brotli,
+}
+
+impl ContentCoding {
+    /// Returns the token used for this coding in `Accept-Encoding` and `Content-Encoding` headers.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ContentCoding::Identity => "identity",
+            ContentCoding::Gzip => "gzip",
+            ContentCoding::Brotli => "br",
+        }
+    }
 }
 
 /// Parses an RFC 7231 section 5.3.1 `qvalue` into an integer in [0, 1000].
@@ -175,11 +401,17 @@ fn parse_qvalue(s: &str) -> Result<u16, ()> {
 /// Follows the rules of [RFC 7231 section
 /// 5.3.4](https://tools.ietf.org/html/rfc7231#section-5.3.4).
 pub fn should_gzip(headers: &HeaderMap) -> bool {
+    should_encode(headers, ContentCoding::Gzip)
+}
+
+/// Returns iff it's preferable to use `Content-Encoding: <coding>` when responding to the given
+/// request, rather than no content coding, as `should_gzip` does for gzip.
+fn should_encode(headers: &HeaderMap, coding: ContentCoding) -> bool {
     let v = match headers.get(header::ACCEPT_ENCODING) {
         None => return false,
         Some(v) => v,
     };
-    let (mut gzip_q, mut identity_q, mut star_q) = (None, None, None);
+    let (mut coding_q, mut identity_q, mut star_q) = (None, None, None);
     let parts = match v.to_str() {
         Ok(s) => s.split(','),
         Err(_) => return false,
@@ -191,21 +423,21 @@ pub fn should_gzip(headers: &HeaderMap) -> bool {
         let last_part = parts
             .next()
             .expect("rsplitn should return at least one part");
-        let coding;
+        let c;
         let quality;
         match parts.next() {
             None => {
-                coding = last_part;
+                c = last_part;
                 quality = 1000;
             }
-            Some(c) => {
+            Some(p) => {
                 if !last_part.starts_with("q=") {
                     return false; // unparseable.
                 }
                 let q = &last_part[2..];
                 match parse_qvalue(q) {
                     Ok(q) => {
-                        coding = c;
+                        c = p;
                         quality = q;
                     }
                     Err(_) => return false, // unparseable.
@@ -213,16 +445,16 @@ pub fn should_gzip(headers: &HeaderMap) -> bool {
             }
         }
 
-        if coding == "gzip" {
-            gzip_q = Some(quality);
-        } else if coding == "identity" {
+        if c == coding.as_str() {
+            coding_q = Some(quality);
+        } else if c == "identity" {
             identity_q = Some(quality);
-        } else if coding == "*" {
+        } else if c == "*" {
             star_q = Some(quality);
         }
     }
 
-    let gzip_q = gzip_q.or(star_q).unwrap_or(0);
+    let coding_q = coding_q.or(star_q).unwrap_or(0);
 
     // "If the representation has no content-coding, then it is
     // acceptable by default unless specifically excluded by the
@@ -230,14 +462,41 @@ pub fn should_gzip(headers: &HeaderMap) -> bool {
     // without a more specific entry for "identity"."
     let identity_q = identity_q.or(star_q).unwrap_or(1);
 
-    gzip_q > 0 && gzip_q >= identity_q
+    coding_q > 0 && coding_q >= identity_q
+}
+
+/// A named bundle of compression settings for `StreamingBodyBuilder::preset`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum CompressionPreset {
+    /// Favors getting each write to the client promptly: gzip level 1, 1 KiB chunks, and a flush
+    /// after every write.
+    Latency,
+
+    /// The defaults: gzip level 6, 4 KiB chunks, and no flushes beyond those requested.
+    Balanced,
+
+    /// Favors a small response: gzip level 9, 64 KiB chunks, and no flushes beyond those
+    /// requested.
+    Size,
 }
 
 pub struct StreamingBodyBuilder {
     chunk_size: usize,
     gzip_level: u32,
-    should_gzip: bool,
+    flush_writes: bool,
+    coding: ContentCoding,
+
+    /// True if `coding` was negotiated from the request's `Accept-Encoding`.
+    negotiated: bool,
     body_needed: bool,
+    last_event_id: Option<String>,
+    event_stream: bool,
+
+    /// True unless the request is known to use HTTP/2 or later, which forbids `Keep-Alive`.
+    http1: bool,
+    keep_alive: Option<(Duration, Option<u32>)>,
+    report_bytes_sent: bool,
 }
 
 /// Adds a streaming body to the given request if a body is needed.
@@ -245,12 +504,49 @@ pub struct StreamingBodyBuilder {
 /// Currently the body is added for non-HEAD requests. In the future, this may also follow
 /// conditional GET rules, omitting the body and stripping out entity headers from the response as
 /// desired.
+///
+/// A `HEAD` response carries the same `Content-Encoding` and `Vary` headers as the `GET` response
+/// would, but no `BodyWriter` and thus no compressor is created for it. As the encoded length is
+/// unknown without compressing, neither response has a `Content-Length`.
 pub fn streaming_body<T>(req: &http::Request<T>) -> StreamingBodyBuilder {
+    let coding = if should_gzip(req.headers()) {
+        ContentCoding::Gzip
+    } else {
+        ContentCoding::Identity
+    };
+    StreamingBodyBuilder {
+        negotiated: true,
+        body_needed: *req.method() != http::method::Method::HEAD,
+        last_event_id: req
+            .headers()
+            .get("last-event-id")
+            .and_then(|v| v.to_str().ok())
+            .map(String::from),
+        http1: req.version() < http::Version::HTTP_2,
+        ..streaming_body_raw(coding)
+    }
+}
+
+/// Adds a streaming body with the given content coding, for responses built without a request in
+/// hand, such as when pre-rendering into a cache.
+///
+/// The body is always needed, and as no negotiation took place, the response has no `Vary:
+/// accept-encoding`. `ContentCoding::Gzip` is ignored if the gzip level is set to 0 or the
+/// response is an event stream, as with `streaming_body`. `ContentCoding::Brotli` is always
+/// ignored.
+pub fn streaming_body_raw(coding: ContentCoding) -> StreamingBodyBuilder {
     StreamingBodyBuilder {
         chunk_size: 4096,
         gzip_level: 6,
-        should_gzip: should_gzip(req.headers()),
-        body_needed: *req.method() != http::method::Method::HEAD,
+        flush_writes: false,
+        coding,
+        negotiated: false,
+        body_needed: true,
+        last_event_id: None,
+        event_stream: false,
+        http1: true,
+        keep_alive: None,
+        report_bytes_sent: false,
     }
 }
 
@@ -273,32 +569,138 @@ impl StreamingBodyBuilder {
         StreamingBodyBuilder { gzip_level, ..self }
     }
 
+    /// Sets whether the `BodyWriter` flushes after every write.
+    ///
+    /// Flushing sends data to the client sooner at the cost of compression ratio and chunk
+    /// overhead. Defaults to false.
+    pub fn with_flush_writes(self, flush_writes: bool) -> Self {
+        StreamingBodyBuilder {
+            flush_writes,
+            ..self
+        }
+    }
+
+    /// Applies a compression preset, setting the gzip level, chunk size, and flush policy.
+    ///
+    /// Later calls to `with_gzip_level`, `with_chunk_size`, or `with_flush_writes` override the
+    /// preset's choices.
+    pub fn preset(self, preset: CompressionPreset) -> Self {
+        let (gzip_level, chunk_size, flush_writes) = match preset {
+            CompressionPreset::Latency => (1, 1024, true),
+            CompressionPreset::Balanced => (6, 4096, false),
+            CompressionPreset::Size => (9, 65_536, false),
+        };
+        StreamingBodyBuilder {
+            gzip_level,
+            chunk_size,
+            flush_writes,
+            ..self
+        }
+    }
+
+    /// Configures the response as a [server-sent
+    /// events](https://html.spec.whatwg.org/multipage/server-sent-events.html) stream.
+    ///
+    /// This sets `Content-Type: text/event-stream` and disables compression, which would
+    /// otherwise delay events until the compressor's buffer fills. Use
+    /// `BodyWriter::write_sse_event` to send events.
+    pub fn with_event_stream(self) -> Self {
+        StreamingBodyBuilder {
+            event_stream: true,
+            ..self
+        }
+    }
+
+    /// Sets a `Keep-Alive` header hinting how long the connection may stay idle, and optionally
+    /// how many more requests it may carry, as for long-poll responses.
+    ///
+    /// This only informs the client; it doesn't change hyper's own connection handling. The header
+    /// is connection-specific, so it's omitted when the request used HTTP/2 or later. `timeout`
+    /// is sent in whole seconds.
+    pub fn with_keep_alive(self, timeout: Duration, max: Option<u32>) -> Self {
+        StreamingBodyBuilder {
+            keep_alive: Some((timeout, max)),
+            ..self
+        }
+    }
+
+    /// Sets whether to attach a [`BytesSent`](struct.BytesSent.html) extension to the response,
+    /// reporting how many body bytes were sent once the body ends. Counts are of the body as
+    /// sent, after any compression. Defaults to false; it's never attached when no body is
+    /// needed.
+    pub fn with_report_bytes_sent(self, report_bytes_sent: bool) -> Self {
+        StreamingBodyBuilder {
+            report_bytes_sent,
+            ..self
+        }
+    }
+
+    /// Returns the request's `Last-Event-ID` header, if present and valid UTF-8.
+    ///
+    /// A client reconnecting to a [server-sent
+    /// events](https://html.spec.whatwg.org/multipage/server-sent-events.html) stream sends the
+    /// id of the last event it received, so the handler can resume after it.
+    pub fn last_event_id(&self) -> Option<&str> {
+        self.last_event_id.as_deref()
+    }
+
     pub fn build<P, D, E>(self) -> (http::Response<P>, Option<BodyWriter<D, E>>)
     where
         D: From<Vec<u8>> + Send + Sync,
         E: Send + Sync,
         P: From<Box<dyn Stream<Item = Result<D, E>> + Send>>,
     {
-        let (w, stream) = chunker::BodyWriter::with_chunk_size(self.chunk_size);
+        let (w, rcv) = chunker::BodyWriter::with_chunk_size(self.chunk_size);
+        let mut sent = None;
+        let stream: Box<dyn Stream<Item = Result<D, E>> + Send> =
+            if self.report_bytes_sent && self.body_needed {
+                let (tallied, s) = tally::Tallied::new(rcv, Vec::len);
+                sent = Some(s);
+                Box::new(tallied.map(|r| r.map(D::from)))
+            } else {
+                Box::new(rcv.map(|r| r.map(D::from)))
+            };
         let mut resp = http::Response::new(stream.into());
-        resp.headers_mut()
-            .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+        if let Some(s) = sent {
+            resp.extensions_mut().insert(s);
+        }
+        let gzip = self.coding == ContentCoding::Gzip && self.gzip_level > 0 && !self.event_stream;
+        if self.event_stream {
+            resp.headers_mut().insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("text/event-stream"),
+            );
+        } else if self.negotiated {
+            resp.headers_mut()
+                .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+        }
 
-        if self.should_gzip && self.gzip_level > 0 {
+        if gzip {
             resp.headers_mut()
                 .append(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
         }
 
+        if let (Some((timeout, max)), true) = (self.keep_alive, self.http1) {
+            let v = match max {
+                Some(m) => format!("timeout={}, max={}", timeout.as_secs(), m),
+                None => format!("timeout={}", timeout.as_secs()),
+            };
+            resp.headers_mut().insert(
+                HeaderName::from_static("keep-alive"),
+                HeaderValue::from_str(&v).expect("keep-alive is a valid header value"),
+            );
+        }
+
         if !self.body_needed {
             return (resp, None);
         }
 
-        let w = match self.should_gzip && self.gzip_level > 0 {
+        let w = match gzip {
             true => BodyWriter::gzipped(w, flate2::Compression::new(self.gzip_level)),
             false => BodyWriter::raw(w),
         };
 
-        (resp, Some(w))
+        (resp, Some(w.with_flush_writes(self.flush_writes)))
     }
 }
 
@@ -313,6 +715,186 @@ mod tests {
         h
     }
 
+    #[test]
+    fn presets() {
+        use super::CompressionPreset;
+        let req = http::Request::get("/").body(()).unwrap();
+        let resolve = |b: super::StreamingBodyBuilder| (b.gzip_level, b.chunk_size, b.flush_writes);
+        let b = || super::streaming_body(&req);
+        assert_eq!(
+            resolve(b()),
+            resolve(b().preset(CompressionPreset::Balanced))
+        );
+        assert_eq!(
+            resolve(b().preset(CompressionPreset::Latency)),
+            (1, 1024, true)
+        );
+        assert_eq!(
+            resolve(b().preset(CompressionPreset::Balanced)),
+            (6, 4096, false)
+        );
+        assert_eq!(
+            resolve(b().preset(CompressionPreset::Size)),
+            (9, 65_536, false)
+        );
+
+        // Explicit settings after the preset win; those before it don't.
+        assert_eq!(
+            resolve(
+                b().preset(CompressionPreset::Size)
+                    .with_gzip_level(3)
+                    .with_flush_writes(true)
+            ),
+            (3, 65_536, true)
+        );
+        assert_eq!(
+            resolve(b().with_gzip_level(3).preset(CompressionPreset::Size)),
+            (9, 65_536, false)
+        );
+    }
+
+    #[tokio::test]
+    async fn sse_event() {
+        type BoxedError = Box<dyn std::error::Error + Send + Sync>;
+        let req = http::Request::get("/")
+            .header("Accept-Encoding", "gzip")
+            .body(())
+            .unwrap();
+        let (resp, w) = super::streaming_body(&req).with_event_stream().build();
+        let resp: http::Response<hyper::Body> = resp;
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/event-stream"
+        );
+        assert_eq!(resp.headers().get(header::CONTENT_ENCODING), None);
+        let mut w: super::BodyWriter<bytes::Bytes, BoxedError> = w.unwrap();
+        w.write_sse_event(Some("update"), Some("7"), "line 1\nline 2\r\n\rline 4")
+            .unwrap();
+        w.write_sse_event(None, None, "").unwrap();
+        assert!(w.write_sse_event(Some("bad\nevent"), None, "data").is_err());
+        drop(w);
+        let buf = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(
+            std::str::from_utf8(&buf).unwrap(),
+            "event: update\nid: 7\ndata: line 1\ndata: line 2\ndata: \ndata: line 4\n\n\
+             data: \n\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn vectored_writes() {
+        use std::io::{IoSlice, Read, Write};
+        type BoxedError = Box<dyn std::error::Error + Send + Sync>;
+        let mut expected = Vec::new();
+        for i in 0..200 {
+            expected.extend_from_slice(format!("header {}\npayload {}\n", i, i).as_bytes());
+        }
+        for &gzip in &[false, true] {
+            let mut req = http::Request::get("/");
+            if gzip {
+                req = req.header("Accept-Encoding", "gzip");
+            }
+            let req = req.body(()).unwrap();
+            let (resp, w) = super::streaming_body(&req).with_chunk_size(64).build();
+            let resp: http::Response<hyper::Body> = resp;
+            let mut w: super::BodyWriter<bytes::Bytes, BoxedError> = w.unwrap();
+            for i in 0..200 {
+                let h = format!("header {}\n", i);
+                let p = format!("payload {}\n", i);
+                if i % 3 == 0 {
+                    w.write_all(h.as_bytes()).unwrap();
+                    w.write_all(p.as_bytes()).unwrap();
+                } else {
+                    let bufs = [IoSlice::new(h.as_bytes()), IoSlice::new(p.as_bytes())];
+                    assert_eq!(w.write_vectored(&bufs).unwrap(), h.len() + p.len());
+                }
+            }
+            drop(w);
+            assert_eq!(resp.headers().contains_key(header::CONTENT_ENCODING), gzip);
+            let buf = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            let actual = if gzip {
+                let mut d = Vec::new();
+                flate2::read::GzDecoder::new(&buf[..])
+                    .read_to_end(&mut d)
+                    .unwrap();
+                d
+            } else {
+                buf.to_vec()
+            };
+            assert_eq!(actual, expected, "gzip={}", gzip);
+        }
+    }
+
+    #[tokio::test]
+    async fn streaming_body_raw() {
+        use std::io::{Read, Write};
+        type BoxedError = Box<dyn std::error::Error + Send + Sync>;
+        for &coding in &[super::ContentCoding::Identity, super::ContentCoding::Gzip] {
+            let (resp, w) = super::streaming_body_raw(coding).build();
+            let resp: http::Response<hyper::Body> = resp;
+            let mut w: super::BodyWriter<bytes::Bytes, BoxedError> = w.unwrap();
+            for _ in 0..100 {
+                w.write_all(b"pre-rendered ").unwrap();
+            }
+            drop(w);
+            assert_eq!(resp.headers().get(header::VARY), None);
+            let gzipped = resp.headers().get(header::CONTENT_ENCODING).is_some();
+            assert_eq!(gzipped, coding == super::ContentCoding::Gzip);
+            let buf = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            let body = match coding {
+                super::ContentCoding::Gzip => {
+                    let mut d = Vec::new();
+                    flate2::read::GzDecoder::new(&buf[..])
+                        .read_to_end(&mut d)
+                        .unwrap();
+                    d
+                }
+                _ => buf.to_vec(),
+            };
+            assert_eq!(body, b"pre-rendered ".repeat(100));
+        }
+    }
+
+    #[test]
+    fn keep_alive() {
+        use std::time::Duration;
+        type BoxedError = Box<dyn std::error::Error + Send + Sync>;
+        let get = |version, keep_alive: Option<(u64, Option<u32>)>| {
+            let req = http::Request::get("/").version(version).body(()).unwrap();
+            let mut b = super::streaming_body(&req);
+            if let Some((t, m)) = keep_alive {
+                b = b.with_keep_alive(Duration::from_secs(t), m);
+            }
+            let (resp, _w): (
+                http::Response<hyper::Body>,
+                Option<super::BodyWriter<bytes::Bytes, BoxedError>>,
+            ) = b.build();
+            resp.headers()
+                .get("keep-alive")
+                .map(|v| v.to_str().unwrap().to_owned())
+        };
+        let v11 = http::Version::HTTP_11;
+        assert_eq!(get(v11, None), None);
+        assert_eq!(get(v11, Some((30, None))).as_deref(), Some("timeout=30"));
+        assert_eq!(
+            get(v11, Some((30, Some(100)))).as_deref(),
+            Some("timeout=30, max=100")
+        );
+        assert_eq!(get(http::Version::HTTP_2, Some((30, None))), None);
+    }
+
+    #[test]
+    fn last_event_id() {
+        let req = http::Request::get("/")
+            .header("Last-Event-ID", "42")
+            .body(())
+            .unwrap();
+        assert_eq!(super::streaming_body(&req).last_event_id(), Some("42"));
+
+        let req = http::Request::get("/").body(()).unwrap();
+        assert_eq!(super::streaming_body(&req).last_event_id(), None);
+    }
+
     #[test]
     fn parse_qvalue() {
         use super::parse_qvalue;
@@ -357,6 +939,15 @@ mod tests {
         // empty implies that the user agent does not want any content-coding in
         // response."
         assert!(!super::should_gzip(&ae_hdrs("")));
+        assert!(!super::should_gzip(&ae_hdrs("br")));
+        assert!(super::should_encode(
+            &ae_hdrs("br;q=0.5, gzip"),
+            super::ContentCoding::Brotli
+        ));
+        assert!(!super::should_encode(
+            &ae_hdrs("gzip"),
+            super::ContentCoding::Brotli
+        ));
 
         // "The asterisk "*" symbol in an Accept-Encoding field
         // matches any available content-coding not explicitly listed in the
@@ -376,4 +967,83 @@ mod tests {
         // send a response without any content-coding."
         assert!(!super::should_gzip(&ae_hdrs("*;q=0")));
     }
+
+    #[tokio::test]
+    async fn report_bytes_sent() {
+        use http_body::Body as _;
+        use std::io::Write;
+        type BoxedError = Box<dyn std::error::Error + Send + Sync>;
+
+        // A full transfer counts the body as sent, compressed or not.
+        for &coding in &[super::ContentCoding::Identity, super::ContentCoding::Gzip] {
+            let (mut resp, w) = super::streaming_body_raw(coding)
+                .with_report_bytes_sent(true)
+                .build();
+            let sent = resp.extensions_mut().remove::<super::BytesSent>().unwrap();
+            let resp: http::Response<hyper::Body> = resp;
+            let mut w: super::BodyWriter<bytes::Bytes, BoxedError> = w.unwrap();
+            for _ in 0..1000 {
+                w.write_all(b"metered ").unwrap();
+            }
+            drop(w);
+            let buf = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            assert_eq!(sent.await, buf.len() as u64, "{:?}", coding);
+        }
+
+        // A truncated one counts what was taken before the body was dropped.
+        let (mut resp, w) = super::streaming_body_raw(super::ContentCoding::Identity)
+            .with_chunk_size(16)
+            .with_report_bytes_sent(true)
+            .build();
+        let sent = resp.extensions_mut().remove::<super::BytesSent>().unwrap();
+        let resp: http::Response<hyper::Body> = resp;
+        let mut w: super::BodyWriter<bytes::Bytes, BoxedError> = w.unwrap();
+        w.write_all(&[b'x'; 64]).unwrap();
+        let mut body = resp.into_body();
+        assert_eq!(body.data().await.unwrap().unwrap().len(), 16);
+        drop(body);
+        assert_eq!(sent.await, 16);
+        drop(w);
+
+        // HEAD responses have no body to count.
+        let req = http::Request::head("/").body(()).unwrap();
+        let (resp, w) = super::streaming_body(&req)
+            .with_report_bytes_sent(true)
+            .build::<hyper::Body, bytes::Bytes, BoxedError>();
+        assert!(w.is_none());
+        assert!(resp.extensions().get::<super::BytesSent>().is_none());
+    }
+
+    #[test]
+    fn head_skips_compressor() {
+        type BoxedError = Box<dyn std::error::Error + Send + Sync>;
+        let encoders = || crate::gzip::ENCODERS.with(|n| n.get());
+        let build = |method: http::Method| {
+            let req = http::Request::builder()
+                .method(method)
+                .header(header::ACCEPT_ENCODING, "gzip")
+                .body(())
+                .unwrap();
+            super::streaming_body(&req).build::<hyper::Body, bytes::Bytes, BoxedError>()
+        };
+
+        let before = encoders();
+        let (resp, w) = build(http::Method::HEAD);
+        assert!(w.is_none());
+        assert_eq!(encoders(), before);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_ENCODING).unwrap(),
+            "gzip"
+        );
+        assert_eq!(resp.headers().get(header::VARY).unwrap(), "accept-encoding");
+        assert!(resp.headers().get(header::CONTENT_LENGTH).is_none());
+
+        let (resp, w) = build(http::Method::GET);
+        assert!(w.is_some());
+        assert_eq!(encoders(), before + 1);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_ENCODING).unwrap(),
+            "gzip"
+        );
+    }
 }
@@ -0,0 +1,665 @@
+// Copyright (c) 2016-2020 The http-serve developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::{ChunkedReadFile, Entity};
+use bytes::{Buf, Bytes};
+use futures::channel::oneshot;
+use futures::{Stream, StreamExt};
+use http::header::{self, HeaderMap, HeaderValue};
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// The default `Materializer::memory_limit`.
+const DEFAULT_MEMORY_LIMIT: u64 = 1 << 20;
+
+/// Distinguishes spool files created by this process.
+static NEXT_SPOOL_FILE: AtomicU64 = AtomicU64::new(0);
+
+/// A cache of generated bodies, such as images transcoded on the fly, which makes them servable
+/// with byte ranges.
+///
+/// An `Entity` must know its length up front and return the same bytes on every call, which a
+/// generator run per request can't promise. [`get`](#method.get) instead runs the generator once
+/// per validator, spools its output, and returns a [`MaterializedEntity`] which serves the spooled
+/// bytes, ranges included. Output up to `memory_limit` bytes is held in memory; larger output
+/// goes to a file in `temp_dir`, which is read as `ChunkedReadFile` does and so expects to be used
+/// from a tokio threadpool.
+///
+/// Spooled bodies are evicted in least-recently-used order once their total size exceeds the
+/// capacity. Entities already returned keep their bodies alive until dropped. Clones share the
+/// same cache.
+///
+/// ```
+/// # use bytes::Bytes;
+/// # use http::header::{HeaderMap, HeaderValue};
+/// # type BoxedError = Box<dyn std::error::Error + Send + Sync>;
+/// # async fn f(req: http::Request<hyper::Body>) -> Result<http::Response<hyper::Body>, BoxedError> {
+/// # let resize = |_| futures::stream::once(futures::future::ok::<_, BoxedError>(Bytes::new()));
+/// let cache = http_serve::Materializer::<Bytes, BoxedError>::new(64 << 20);
+/// let etag = HeaderValue::from_static("\"cat.jpg@256px-v3\"");
+/// let e = cache.get(etag, HeaderMap::new(), || resize(256)).await?;
+/// Ok(http_serve::serve(e, &req))
+/// # }
+/// ```
+pub struct Materializer<D, E>
+where
+    D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]> + From<Bytes>,
+    E: 'static
+        + Send
+        + Into<Box<dyn StdError + Send + Sync>>
+        + From<Box<dyn StdError + Send + Sync>>,
+{
+    inner: Arc<Inner<D, E>>,
+}
+
+struct Inner<D, E>
+where
+    D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]> + From<Bytes>,
+    E: 'static
+        + Send
+        + Into<Box<dyn StdError + Send + Sync>>
+        + From<Box<dyn StdError + Send + Sync>>,
+{
+    capacity: u64,
+    memory_limit: u64,
+    temp_dir: PathBuf,
+    state: Mutex<State<D, E>>,
+}
+
+struct State<D, E>
+where
+    D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]> + From<Bytes>,
+    E: 'static
+        + Send
+        + Into<Box<dyn StdError + Send + Sync>>
+        + From<Box<dyn StdError + Send + Sync>>,
+{
+    slots: HashMap<HeaderValue, Slot<D, E>>,
+
+    /// The total length of the ready spools.
+    total: u64,
+
+    /// Incremented on each use, to order spools for eviction.
+    tick: u64,
+
+    /// The number of generator runs, so tests can tell when generation was shared.
+    #[cfg(test)]
+    generated: usize,
+}
+
+enum Slot<D, E>
+where
+    D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]> + From<Bytes>,
+    E: 'static
+        + Send
+        + Into<Box<dyn StdError + Send + Sync>>
+        + From<Box<dyn StdError + Send + Sync>>,
+{
+    /// Generation is underway; these callers await its spool. If it fails, the senders are
+    /// dropped, and the callers try again.
+    Pending(Vec<oneshot::Sender<Arc<Spool<D, E>>>>),
+
+    Ready {
+        spool: Arc<Spool<D, E>>,
+        last_used: u64,
+    },
+}
+
+/// A generated body.
+enum Spool<D, E>
+where
+    D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]> + From<Bytes>,
+    E: 'static
+        + Send
+        + Into<Box<dyn StdError + Send + Sync>>
+        + From<Box<dyn StdError + Send + Sync>>,
+{
+    Memory(Bytes),
+
+    // `file` is declared first so it's closed before `_path` is removed.
+    File {
+        file: ChunkedReadFile<D, E>,
+        _path: SpoolPath,
+    },
+}
+
+impl<D, E> Spool<D, E>
+where
+    D: 'static + Send + Sync + Buf + From<Vec<u8>> + From<&'static [u8]> + From<Bytes>,
+    E: 'static
+        + Send
+        + Sync
+        + Into<Box<dyn StdError + Send + Sync>>
+        + From<Box<dyn StdError + Send + Sync>>,
+{
+    fn len(&self) -> u64 {
+        match self {
+            Spool::Memory(b) => b.len() as u64,
+            Spool::File { file, .. } => file.len(),
+        }
+    }
+}
+
+/// Removes a spool file when dropped.
+struct SpoolPath(PathBuf);
+
+impl Drop for SpoolPath {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+// Implemented by hand so that `D` and `E` needn't be `Clone`.
+impl<D, E> Clone for Materializer<D, E>
+where
+    D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]> + From<Bytes>,
+    E: 'static
+        + Send
+        + Into<Box<dyn StdError + Send + Sync>>
+        + From<Box<dyn StdError + Send + Sync>>,
+{
+    fn clone(&self) -> Self {
+        Materializer {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<D, E> std::fmt::Debug for Materializer<D, E>
+where
+    D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]> + From<Bytes>,
+    E: 'static
+        + Send
+        + Into<Box<dyn StdError + Send + Sync>>
+        + From<Box<dyn StdError + Send + Sync>>,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let s = self.inner.state.lock().unwrap();
+        f.debug_struct("Materializer")
+            .field("capacity", &self.inner.capacity)
+            .field("memory_limit", &self.inner.memory_limit)
+            .field("temp_dir", &self.inner.temp_dir)
+            .field("entries", &s.slots.len())
+            .field("total", &s.total)
+            .finish()
+    }
+}
+
+impl<D, E> Materializer<D, E>
+where
+    D: 'static + Send + Sync + Buf + From<Vec<u8>> + From<&'static [u8]> + From<Bytes>,
+    E: 'static
+        + Send
+        + Sync
+        + Into<Box<dyn StdError + Send + Sync>>
+        + From<Box<dyn StdError + Send + Sync>>,
+{
+    /// Creates a cache which holds up to `capacity` bytes of generated bodies.
+    pub fn new(capacity: u64) -> Self {
+        Materializer {
+            inner: Arc::new(Inner {
+                capacity,
+                memory_limit: DEFAULT_MEMORY_LIMIT,
+                temp_dir: std::env::temp_dir(),
+                state: Mutex::new(State {
+                    slots: HashMap::new(),
+                    total: 0,
+                    tick: 0,
+                    #[cfg(test)]
+                    generated: 0,
+                }),
+            }),
+        }
+    }
+
+    /// Sets the largest body to hold in memory; larger ones are spooled to a file. Defaults to
+    /// 1 MiB.
+    ///
+    /// Panics if the cache has been cloned.
+    pub fn memory_limit(self, memory_limit: u64) -> Self {
+        self.configure(|i| i.memory_limit = memory_limit)
+    }
+
+    /// Sets the directory for spool files. Defaults to `std::env::temp_dir()`.
+    ///
+    /// Files are removed once evicted and no longer served. Panics if the cache has been
+    /// cloned.
+    pub fn temp_dir(self, temp_dir: PathBuf) -> Self {
+        self.configure(|i| i.temp_dir = temp_dir)
+    }
+
+    fn configure(mut self, f: impl FnOnce(&mut Inner<D, E>)) -> Self {
+        f(Arc::get_mut(&mut self.inner).expect("Materializer configured after clone"));
+        self
+    }
+
+    /// Returns an entity serving the body identified by `etag`, generating it if it's not
+    /// already cached.
+    ///
+    /// `etag` is a validator for the generated body (such as one derived from the source's etag
+    /// and the transcoding parameters), which the entity also sends as its `ETag`. `generate` is
+    /// called only if no body for `etag` is cached or being generated; concurrent calls for the
+    /// same `etag` share one generation. If that fails or is cancelled, the error goes to its
+    /// caller, and the others retry. `headers` are sent with the entity, as by `add_headers`.
+    pub async fn get<F, S, T>(
+        &self,
+        etag: HeaderValue,
+        headers: HeaderMap,
+        generate: F,
+    ) -> Result<MaterializedEntity<D, E>, E>
+    where
+        F: FnOnce() -> S,
+        S: Stream<Item = Result<T, E>>,
+        T: Buf,
+    {
+        let mut generate = Some(generate);
+        loop {
+            let rx = {
+                let mut s = self.inner.state.lock().unwrap();
+                s.tick += 1;
+                let tick = s.tick;
+                match s.slots.get_mut(&etag) {
+                    Some(Slot::Ready { spool, last_used }) => {
+                        *last_used = tick;
+                        return Ok(MaterializedEntity {
+                            spool: Arc::clone(spool),
+                            etag,
+                            headers,
+                        });
+                    }
+                    Some(Slot::Pending(waiters)) => {
+                        let (tx, rx) = oneshot::channel();
+                        waiters.push(tx);
+                        Some(rx)
+                    }
+                    None => {
+                        s.slots.insert(etag.clone(), Slot::Pending(Vec::new()));
+                        None
+                    }
+                }
+            };
+            if let Some(rx) = rx {
+                if let Ok(spool) = rx.await {
+                    return Ok(MaterializedEntity {
+                        spool,
+                        etag,
+                        headers,
+                    });
+                }
+                continue;
+            }
+
+            // This caller generates. The guard clears the pending slot if generation fails or
+            // this future is dropped, so that waiters retry.
+            let mut guard = PendingGuard {
+                inner: &*self.inner,
+                etag: Some(etag.clone()),
+            };
+            let generate = generate.take().expect("generates at most once");
+            #[cfg(test)]
+            {
+                self.inner.state.lock().unwrap().generated += 1;
+            }
+            let spool = Arc::new(self.spool(generate()).await?);
+            guard.etag = None;
+            self.insert(&etag, &spool);
+            return Ok(MaterializedEntity {
+                spool,
+                etag,
+                headers,
+            });
+        }
+    }
+
+    /// Reads `body` into a spool.
+    async fn spool<S, T>(&self, body: S) -> Result<Spool<D, E>, E>
+    where
+        S: Stream<Item = Result<T, E>>,
+        T: Buf,
+    {
+        let mut body = Box::pin(body);
+        let mut buf = Vec::new();
+        let mut file: Option<(std::fs::File, SpoolPath)> = None;
+        while let Some(chunk) = body.next().await {
+            let mut chunk = chunk?;
+            while chunk.has_remaining() {
+                let b = chunk.bytes();
+                let n = b.len();
+                match file {
+                    Some((ref mut f, _)) => write_blocking(f, b)?,
+                    None if (buf.len() + n) as u64 > self.inner.memory_limit => {
+                        let (mut f, path) = self.create_file()?;
+                        write_blocking(&mut f, &buf)?;
+                        write_blocking(&mut f, b)?;
+                        buf = Vec::new();
+                        file = Some((f, path));
+                    }
+                    None => buf.extend_from_slice(b),
+                }
+                chunk.advance(n);
+            }
+        }
+        Ok(match file {
+            None => Spool::Memory(buf.into()),
+            Some((f, path)) => Spool::File {
+                file: ChunkedReadFile::new(f, HeaderMap::new()).map_err(io_err)?,
+                _path: path,
+            },
+        })
+    }
+
+    fn create_file(&self) -> Result<(std::fs::File, SpoolPath), E> {
+        let name = format!(
+            "http-serve-spool-{}-{}",
+            std::process::id(),
+            NEXT_SPOOL_FILE.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = self.inner.temp_dir.join(name);
+        let f = tokio::task::block_in_place(|| {
+            std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&path)
+        })
+        .map_err(io_err)?;
+        Ok((f, SpoolPath(path)))
+    }
+
+    /// Caches a freshly generated spool, hands it to waiters, and evicts as needed.
+    fn insert(&self, etag: &HeaderValue, spool: &Arc<Spool<D, E>>) {
+        let mut s = self.inner.state.lock().unwrap();
+        let waiters = match s.slots.remove(etag) {
+            Some(Slot::Pending(w)) => w,
+            _ => unreachable!("generating slot is pending"),
+        };
+        for w in waiters {
+            let _ = w.send(Arc::clone(spool));
+        }
+        s.tick += 1;
+        let last_used = s.tick;
+        s.total += spool.len();
+        s.slots.insert(
+            etag.clone(),
+            Slot::Ready {
+                spool: Arc::clone(spool),
+                last_used,
+            },
+        );
+
+        // Evict by linear scan; caches hold few enough entries that this is cheap next to
+        // generating a body.
+        while s.total > self.inner.capacity {
+            let lru = s
+                .slots
+                .iter()
+                .filter_map(|(k, v)| match v {
+                    Slot::Ready { last_used, .. } => Some((*last_used, k)),
+                    Slot::Pending(_) => None,
+                })
+                .min()
+                .map(|(_, k)| k.clone());
+            let lru = match lru {
+                None => break,
+                Some(k) => k,
+            };
+            if let Some(Slot::Ready { spool, .. }) = s.slots.remove(&lru) {
+                s.total -= spool.len();
+            }
+        }
+    }
+}
+
+/// Clears a pending slot unless disarmed by taking `etag`.
+struct PendingGuard<'a, D, E>
+where
+    D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]> + From<Bytes>,
+    E: 'static
+        + Send
+        + Into<Box<dyn StdError + Send + Sync>>
+        + From<Box<dyn StdError + Send + Sync>>,
+{
+    inner: &'a Inner<D, E>,
+    etag: Option<HeaderValue>,
+}
+
+impl<'a, D, E> Drop for PendingGuard<'a, D, E>
+where
+    D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]> + From<Bytes>,
+    E: 'static
+        + Send
+        + Into<Box<dyn StdError + Send + Sync>>
+        + From<Box<dyn StdError + Send + Sync>>,
+{
+    fn drop(&mut self) {
+        if let Some(etag) = self.etag.take() {
+            // Dropping the slot drops the waiters' senders.
+            self.inner.state.lock().unwrap().slots.remove(&etag);
+        }
+    }
+}
+
+fn write_blocking<E>(f: &mut std::fs::File, data: &[u8]) -> Result<(), E>
+where
+    E: From<Box<dyn StdError + Send + Sync>>,
+{
+    tokio::task::block_in_place(|| f.write_all(data)).map_err(io_err)
+}
+
+fn io_err<E: From<Box<dyn StdError + Send + Sync>>>(e: io::Error) -> E {
+    let e: Box<dyn StdError + Send + Sync> = Box::new(e);
+    E::from(e)
+}
+
+/// A generated body served from a [`Materializer`]'s spool.
+pub struct MaterializedEntity<D, E>
+where
+    D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]> + From<Bytes>,
+    E: 'static
+        + Send
+        + Into<Box<dyn StdError + Send + Sync>>
+        + From<Box<dyn StdError + Send + Sync>>,
+{
+    spool: Arc<Spool<D, E>>,
+    etag: HeaderValue,
+    headers: HeaderMap,
+}
+
+impl<D, E> Entity for MaterializedEntity<D, E>
+where
+    D: 'static + Send + Sync + Buf + From<Vec<u8>> + From<&'static [u8]> + From<Bytes>,
+    E: 'static
+        + Send
+        + Sync
+        + Into<Box<dyn StdError + Send + Sync>>
+        + From<Box<dyn StdError + Send + Sync>>,
+{
+    type Data = D;
+    type Error = E;
+
+    fn len(&self) -> u64 {
+        self.spool.len()
+    }
+
+    fn get_range(
+        &self,
+        range: Range<u64>,
+    ) -> Box<dyn Stream<Item = Result<Self::Data, Self::Error>> + Send + Sync> {
+        match *self.spool {
+            Spool::Memory(ref b) => Box::new(futures::stream::once(futures::future::ok(D::from(
+                b.slice(range.start as usize..range.end as usize),
+            )))),
+            Spool::File { ref file, .. } => file.get_range(range),
+        }
+    }
+
+    fn add_headers(&self, h: &mut HeaderMap) {
+        h.extend(self.headers.iter().map(|(k, v)| (k.clone(), v.clone())));
+    }
+
+    fn static_headers(&self) -> Option<&HeaderMap> {
+        Some(&self.headers)
+    }
+
+    fn content_type(&self) -> Option<HeaderValue> {
+        self.headers.get(header::CONTENT_TYPE).cloned()
+    }
+
+    fn etag(&self) -> Option<HeaderValue> {
+        Some(self.etag.clone())
+    }
+
+    fn last_modified(&self) -> Option<SystemTime> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Materializer;
+    use crate::test_support::FakeRequest;
+    use crate::Entity;
+    use bytes::Bytes;
+    use futures::channel::oneshot;
+    use futures::{FutureExt, StreamExt};
+    use http::header::{HeaderMap, HeaderValue};
+    use http::{Response, StatusCode};
+
+    type BoxedError = Box<dyn std::error::Error + Send + Sync>;
+    type Cache = Materializer<Bytes, BoxedError>;
+
+    /// Generates `len` bytes in 7-byte chunks, varying with `seed`.
+    fn generate(
+        seed: u8,
+        len: usize,
+    ) -> impl futures::Stream<Item = Result<Bytes, BoxedError>> + Send {
+        let body: Vec<u8> = (0..len).map(|i| seed.wrapping_add(i as u8)).collect();
+        let chunks: Vec<_> = body
+            .chunks(7)
+            .map(|c| Ok(Bytes::from(c.to_vec())))
+            .collect();
+        futures::stream::iter(chunks)
+    }
+
+    fn generated(c: &Cache) -> usize {
+        c.inner.state.lock().unwrap().generated
+    }
+
+    async fn fetch(c: &Cache, seed: u8, range: Option<&str>) -> (StatusCode, Bytes) {
+        let etag = HeaderValue::from_str(&format!("\"{}\"", seed)).unwrap();
+        let e = c
+            .get(etag, HeaderMap::new(), || generate(seed, 1000))
+            .await
+            .unwrap();
+        let mut req = FakeRequest::get();
+        if let Some(r) = range {
+            req = req.range(r);
+        }
+        let resp: Response<hyper::Body> = crate::serve(e, &req.build());
+        let status = resp.status();
+        (
+            status,
+            hyper::body::to_bytes(resp.into_body()).await.unwrap(),
+        )
+    }
+
+    /// Concurrent first fetches share one generation, whose completion they await.
+    #[tokio::test(threaded_scheduler)]
+    async fn concurrent_first_fetch() {
+        let c = Cache::new(1 << 20);
+        let (tx, rx) = oneshot::channel::<()>();
+        let etag = HeaderValue::from_static("\"x\"");
+        let first = c.get(etag.clone(), HeaderMap::new(), || {
+            rx.into_stream()
+                .map(|_| Ok(Bytes::from_static(b"slow")))
+                .chain(generate(0, 10))
+        });
+        let second = c.get(etag, HeaderMap::new(), || generate(1, 10));
+        let mut both = Box::pin(futures::future::join(first, second));
+        assert!((&mut both).now_or_never().is_none());
+        assert_eq!(generated(&c), 1);
+        tx.send(()).unwrap();
+        let (first, second) = both.await;
+        let (first, second) = (first.unwrap(), second.unwrap());
+        assert_eq!(generated(&c), 1);
+        assert_eq!(first.len(), 14);
+        assert_eq!(second.len(), 14);
+    }
+
+    /// If the generating caller is dropped, a waiter generates instead.
+    #[tokio::test(threaded_scheduler)]
+    async fn cancelled_generation() {
+        let c = Cache::new(1 << 20);
+        let etag = HeaderValue::from_static("\"x\"");
+        let mut first = Box::pin(c.get(etag.clone(), HeaderMap::new(), || {
+            futures::stream::pending::<Result<Bytes, BoxedError>>()
+        }));
+        assert!((&mut first).now_or_never().is_none());
+        let mut second = Box::pin(c.get(etag, HeaderMap::new(), || generate(1, 10)));
+        assert!((&mut second).now_or_never().is_none());
+        drop(first);
+        let e = second.await.unwrap();
+        assert_eq!(e.len(), 10);
+        assert_eq!(generated(&c), 2);
+    }
+
+    /// Ranges are served from the spool, whether in memory or in a file.
+    #[tokio::test(threaded_scheduler)]
+    async fn suffix_range() {
+        let tmp = tempfile::tempdir().unwrap();
+        for &memory_limit in &[1 << 20, 100] {
+            let c = Cache::new(1 << 20)
+                .memory_limit(memory_limit)
+                .temp_dir(tmp.path().to_owned());
+            let (status, full) = fetch(&c, 3, None).await;
+            assert_eq!(status, StatusCode::OK);
+            let expected: Vec<u8> = (0..1000).map(|i| 3u8.wrapping_add(i as u8)).collect();
+            assert_eq!(&full[..], &expected[..]);
+            let (status, suffix) = fetch(&c, 3, Some("bytes=-10")).await;
+            assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+            assert_eq!(&suffix[..], &expected[990..]);
+            assert_eq!(generated(&c), 1);
+            let files = std::fs::read_dir(tmp.path()).unwrap().count();
+            assert_eq!(files, if memory_limit == 100 { 1 } else { 0 });
+            drop(c);
+            assert_eq!(std::fs::read_dir(tmp.path()).unwrap().count(), 0);
+        }
+    }
+
+    /// The least recently used body is evicted first, and an evicted body is regenerated.
+    #[tokio::test(threaded_scheduler)]
+    async fn eviction() {
+        let c = Cache::new(2500);
+        fetch(&c, 1, None).await;
+        fetch(&c, 2, None).await;
+        fetch(&c, 1, None).await; // now 2 is least recently used.
+        fetch(&c, 3, None).await; // evicts 2.
+        assert_eq!(generated(&c), 3);
+        fetch(&c, 1, None).await;
+        fetch(&c, 3, None).await;
+        assert_eq!(generated(&c), 3);
+        fetch(&c, 2, None).await; // regenerated, evicting 1.
+        assert_eq!(generated(&c), 4);
+        fetch(&c, 1, None).await;
+        assert_eq!(generated(&c), 5);
+        assert_eq!(c.inner.state.lock().unwrap().total, 2000);
+
+        // A body larger than the capacity is served but not cached.
+        let big = Cache::new(500);
+        fetch(&big, 1, None).await;
+        fetch(&big, 1, None).await;
+        assert_eq!(generated(&big), 2);
+        assert_eq!(big.inner.state.lock().unwrap().total, 0);
+    }
+}
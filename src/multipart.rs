@@ -0,0 +1,227 @@
+// Copyright (c) 2016-2020 The http-serve developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! `multipart/byteranges` responses, compiled only with the `multipart` feature.
+
+use crate::rng::Rng;
+use crate::serving::{header_block_len, ServeOptions, MAX_DECIMAL_U64_BYTES};
+use crate::trim::Trim;
+use crate::Entity;
+use bytes::Buf;
+use futures::Stream;
+use http::header::{self, HeaderMap, HeaderValue};
+use http::StatusCode;
+use std::future::Future;
+use std::io::Write;
+use std::ops::Range;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// A body for use in the "stream of streams" (see `prepare_multipart` and its call site).
+/// This avoids an extra allocation for the part headers and overall trailer.
+pub(crate) enum InnerBody<D, E> {
+    Once(Option<D>),
+    B(Pin<Box<dyn Stream<Item = Result<D, E>> + Sync + Send>>),
+}
+
+impl<D, E> Stream for InnerBody<D, E> {
+    type Item = Result<D, E>;
+    fn poll_next(
+        self: Pin<&mut Self>,
+        ctx: &mut futures::task::Context,
+    ) -> std::task::Poll<Option<Result<D, E>>> {
+        // This is safe because the fields are not structurally pinned.
+        // https://doc.rust-lang.org/std/pin/#pinning-is-not-structural-for-field
+        // (In the B variant, the field _holds_ a pin, but it isn't itself pinned.)
+        match unsafe { self.get_unchecked_mut() } {
+            InnerBody::Once(ref mut o) => std::task::Poll::Ready(o.take().map(|d| Ok(d))),
+            InnerBody::B(b) => b.as_mut().poll_next(ctx),
+        }
+    }
+}
+
+/// The number of boundaries `choose_boundary` tries before giving up on verification.
+const MAX_BOUNDARY_ATTEMPTS: usize = 8;
+
+/// Chooses the `multipart/byteranges` boundary.
+///
+/// This is random, from `opts.rng()`. If `opts.verify_boundary` is set and `ent` reports that the
+/// delimiter occurs in one of `ranges`, it's a random alternative. Returns `None` if every one of
+/// `MAX_BOUNDARY_ATTEMPTS` candidates collides.
+pub(crate) fn choose_boundary<D, E>(
+    ent: &dyn Entity<Data = D, Error = E>,
+    ranges: &[Range<u64>],
+    opts: &ServeOptions,
+) -> Option<String>
+where
+    D: 'static + Send + Sync + Buf + From<Vec<u8>> + From<&'static [u8]>,
+    E: 'static + Send + Sync,
+{
+    let rng = opts.rng();
+    for _ in 0..MAX_BOUNDARY_ATTEMPTS {
+        let boundary = next_boundary_candidate(&rng);
+        if !opts.verify_boundary {
+            return Some(boundary);
+        }
+        let needle = format!("--{}", boundary);
+        let collides = ranges
+            .iter()
+            .any(|r| ent.contains(needle.as_bytes(), r.clone()).unwrap_or(false));
+        if !collides {
+            return Some(boundary);
+        }
+    }
+    #[cfg(feature = "tracing")]
+    tracing::warn!(
+        attempts = MAX_BOUNDARY_ATTEMPTS,
+        "every multipart boundary candidate occurs in the served ranges"
+    );
+    None
+}
+
+/// Generates a random boundary of 16 hex digits.
+pub(crate) fn next_boundary_candidate(rng: &dyn Rng) -> String {
+    let mut b = [0u8; 8];
+    rng.fill_bytes(&mut b);
+    format!("{:016x}", u64::from_le_bytes(b))
+}
+
+/// Prepares to send a `multipart/byteranges` response.
+/// Returns each part's headers followed by the overall trailer, and the total body length.
+///
+/// The body follows the structure of the example in [RFC 7233 appendix
+/// A](https://tools.ietf.org/html/rfc7233#appendix-A), except that each delimiter, including the
+/// first, carries the CRLF which RFC 2046 section 5.1.1 attaches to it:
+///
+/// ```text
+/// CRLF "--" boundary CRLF
+/// "Content-Range: bytes " first "-" last "/" len CRLF
+/// entity headers, each "name: value" CRLF (if included)
+/// CRLF
+/// range data
+/// ...repeated for each range...
+/// CRLF "--" boundary "--" CRLF
+/// ```
+///
+/// So the body starts with an empty preamble and ends with an empty epilogue.
+///
+/// If the entity headers included in each part would take more than `max_part_headers` bytes,
+/// only `Content-Type` and `Content-Encoding` are included. If even those are too long, returns
+/// `None`.
+pub(crate) fn prepare_multipart(
+    entity_headers: Option<HeaderMap>,
+    ranges: &[Range<u64>],
+    len: u64,
+    max_part_headers: Option<usize>,
+    boundary: &str,
+) -> Option<(Vec<Vec<u8>>, u64)> {
+    let mut each_part_headers = Vec::new();
+    if let Some(mut h) = entity_headers {
+        let mut block_len = header_block_len(&h);
+        if let Some(max) = max_part_headers {
+            if block_len > max {
+                // Keep only what's needed to interpret each part's bytes.
+                let mut kept = HeaderMap::new();
+                for name in [header::CONTENT_TYPE, header::CONTENT_ENCODING].iter() {
+                    for v in h.get_all(name) {
+                        kept.append(name.clone(), v.clone());
+                    }
+                }
+                h = kept;
+                block_len = header_block_len(&h);
+                if block_len > max {
+                    return None;
+                }
+            }
+        }
+        each_part_headers.reserve(block_len + 2);
+        for (k, v) in &h {
+            each_part_headers.extend_from_slice(k.as_str().as_bytes());
+            each_part_headers.extend_from_slice(b": ");
+            each_part_headers.extend_from_slice(v.as_bytes());
+            each_part_headers.extend_from_slice(b"\r\n");
+        }
+    }
+    each_part_headers.extend_from_slice(b"\r\n");
+
+    let mut body_len = 0;
+    let mut part_headers: Vec<Vec<u8>> = Vec::with_capacity(ranges.len() + 1);
+    for r in ranges {
+        let mut buf = Vec::with_capacity(64 + boundary.len() + each_part_headers.len());
+        write!(
+            &mut buf,
+            "\r\n--{}\r\nContent-Range: bytes {}-{}/{}\r\n",
+            boundary,
+            r.start,
+            r.end - 1,
+            len
+        )
+        .unwrap();
+        buf.extend_from_slice(&each_part_headers);
+        body_len += buf.len() as u64 + r.end - r.start;
+        part_headers.push(buf);
+    }
+    let trailer = format!("\r\n--{}--\r\n", boundary).into_bytes();
+    body_len += trailer.len() as u64;
+    part_headers.push(trailer);
+    Some((part_headers, body_len))
+}
+
+/// Adds the overall headers of a `multipart/byteranges` response prepared by `prepare_multipart`.
+pub(crate) fn multipart_response(
+    res: http::response::Builder,
+    boundary: &str,
+    body_len: u64,
+) -> http::response::Builder {
+    res.status(StatusCode::PARTIAL_CONTENT)
+        .header(
+            header::CONTENT_LENGTH,
+            unsafe_fmt_ascii_val!(MAX_DECIMAL_U64_BYTES, "{}", body_len),
+        )
+        .header(
+            header::CONTENT_TYPE,
+            HeaderValue::from_str(&format!("multipart/byteranges; boundary={}", boundary))
+                .expect("boundary is a valid header value"),
+        )
+}
+
+/// Produces a single chunk of the body and the following state, for use in an `unfold` call.
+///
+/// Alternates between portions of `part_headers` and their corresponding bodies, then the overall
+/// trailer (the last element of `part_headers`), then end the stream. Ends early once `cut` is
+/// set by a body which fell short (see `Trim::exact`).
+pub(crate) fn next_multipart_body_chunk<D, E>(
+    state: usize,
+    ent: &dyn Entity<Data = D, Error = E>,
+    ranges: &[Range<u64>],
+    part_headers: &mut [Vec<u8>],
+    cut: &Arc<AtomicBool>,
+) -> impl Future<Output = Option<(InnerBody<D, E>, usize)>>
+where
+    D: 'static + Send + Sync + Buf + From<Vec<u8>> + From<&'static [u8]>,
+    E: 'static + Send + Sync,
+{
+    let i = state >> 1;
+    let odd = (state & 1) == 1;
+    let body = if (i == ranges.len() && odd) || cut.load(Ordering::Relaxed) {
+        return futures::future::ready(None);
+    } else if odd {
+        let r = &ranges[i];
+        let part = Pin::from(ent.get_range(r.clone()));
+        InnerBody::B(Box::pin(Trim::exact(
+            part,
+            r.end - r.start,
+            Arc::clone(cut),
+        )))
+    } else {
+        let v = std::mem::take(&mut part_headers[i]);
+        InnerBody::Once(Some(v.into()))
+    };
+    futures::future::ready(Some((body, state + 1)))
+}
@@ -33,6 +33,78 @@ impl FileExt for std::fs::File {
     }
 }
 
+/// Returns the error for a file which ends at `offset`, short of the `len` it had when opened.
+pub fn truncated(offset: u64, len: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        format!(
+            "file ended at byte {}, short of its length of {} when opened",
+            offset, len
+        ),
+    )
+}
+
+/// Finds the extent of a possibly-sparse file at `offset`, which must be less than `len`.
+///
+/// Returns whether the extent is a hole and the offset at which it ends, at most `len`. Where the
+/// OS or filesystem can't report holes, the whole file is data. If the file has been truncated
+/// to `offset` or less, returns an error rather than reporting a hole.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "macos"
+))]
+pub fn extent_at(file: &File, offset: u64, len: u64) -> io::Result<(bool, u64)> {
+    use std::os::unix::io::AsRawFd;
+
+    // Returns None if there's no such offset before the end of the file.
+    let seek = |whence| {
+        let r = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence) };
+        if r >= 0 {
+            return Ok(Some(r as u64));
+        }
+        let e = io::Error::last_os_error();
+        match e.raw_os_error() {
+            Some(libc::ENXIO) => Ok(None),
+            _ => Err(e),
+        }
+    };
+    let data = match seek(libc::SEEK_DATA) {
+        Ok(d) => d,
+        Err(ref e) if e.raw_os_error() == Some(libc::EINVAL) => return Ok((false, len)),
+        Err(e) => return Err(e),
+    };
+    match data {
+        None => {
+            // No data follows: either a trailing hole, or the end of a truncated file.
+            let size = file.metadata()?.len();
+            if offset >= size {
+                return Err(truncated(offset, len));
+            }
+            Ok((true, std::cmp::min(size, len)))
+        }
+        Some(d) if d > offset => Ok((true, std::cmp::min(d, len))),
+        Some(_) => match seek(libc::SEEK_HOLE)? {
+            Some(h) if h > offset => Ok((false, std::cmp::min(h, len))),
+            _ => Ok((false, len)),
+        },
+    }
+}
+
+#[cfg(all(
+    unix,
+    not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "macos"
+    ))
+))]
+pub fn extent_at(_file: &File, _offset: u64, len: u64) -> io::Result<(bool, u64)> {
+    Ok((false, len))
+}
+
 pub struct FileInfo {
     pub inode: u64,
     pub len: u64,
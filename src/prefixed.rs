@@ -0,0 +1,154 @@
+// Copyright (c) 2016-2020 The http-serve developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::Entity;
+use futures::{stream, Stream, StreamExt};
+use http::header::{HeaderMap, HeaderValue};
+use std::io::Write;
+use std::ops::Range;
+use std::pin::Pin;
+use std::time::SystemTime;
+
+/// The UTF-8 encoding of U+FEFF, which some programs (notably Excel, for CSV) need at the start
+/// of a file to recognize it as UTF-8.
+pub const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// An entity which is a fixed byte prefix, such as `UTF8_BOM`, followed by an inner entity.
+///
+/// Ranges are remapped so that offsets count the prefix. Headers and the last modified time pass
+/// through. The etag, if any, is the inner entity's with a suffix identifying the prefix, so it
+/// doesn't match the unprefixed representation. Content-coded variants, whose bodies can't simply
+/// be prefixed, and digests are dropped.
+pub struct PrefixedEntity<E> {
+    inner: E,
+    prefix: &'static [u8],
+}
+
+impl<E: Entity> PrefixedEntity<E> {
+    /// Wraps `inner`, prepending `prefix`.
+    pub fn new(inner: E, prefix: &'static [u8]) -> Self {
+        PrefixedEntity { inner, prefix }
+    }
+}
+
+impl<E: Entity> Entity for PrefixedEntity<E> {
+    type Data = E::Data;
+    type Error = E::Error;
+
+    fn len(&self) -> u64 {
+        self.prefix.len() as u64 + self.inner.len()
+    }
+
+    fn get_range(
+        &self,
+        range: Range<u64>,
+    ) -> Box<dyn Stream<Item = Result<Self::Data, Self::Error>> + Send + Sync> {
+        let plen = self.prefix.len() as u64;
+        let head = if range.start < plen {
+            let end = std::cmp::min(range.end, plen);
+            let head = &self.prefix[range.start as usize..end as usize];
+            Some(Ok(Self::Data::from(head)))
+        } else {
+            None
+        };
+        if range.end <= plen {
+            return Box::new(stream::iter(head));
+        }
+        let start = std::cmp::max(range.start, plen) - plen;
+        let tail = self.inner.get_range(start..range.end - plen);
+        if head.is_none() {
+            return tail;
+        }
+        Box::new(stream::iter(head).chain(Pin::from(tail)))
+    }
+
+    fn add_headers(&self, h: &mut HeaderMap) {
+        self.inner.add_headers(h)
+    }
+
+    fn static_headers(&self) -> Option<&HeaderMap> {
+        self.inner.static_headers()
+    }
+
+    fn content_type(&self) -> Option<HeaderValue> {
+        self.inner.content_type()
+    }
+
+    fn etag(&self) -> Option<HeaderValue> {
+        // Insert "+p" and the prefix in hex before the closing quote.
+        let etag = self.inner.etag()?;
+        let (quote, opaque) = etag.as_bytes().split_last()?;
+        let mut tagged = Vec::with_capacity(etag.len() + 2 + 2 * self.prefix.len());
+        tagged.extend_from_slice(opaque);
+        tagged.extend_from_slice(b"+p");
+        for b in self.prefix {
+            write!(&mut tagged, "{:02x}", b).unwrap();
+        }
+        tagged.push(*quote);
+        HeaderValue::from_maybe_shared(tagged).ok()
+    }
+
+    fn last_modified(&self) -> Option<SystemTime> {
+        self.inner.last_modified()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PrefixedEntity, UTF8_BOM};
+    use crate::test_support::{FakeEntity, FakeRequest};
+    use crate::Entity;
+    use bytes::Bytes;
+    use futures::TryStreamExt;
+    use http::{Response, StatusCode};
+    use std::pin::Pin;
+
+    static CSV: &[u8] = b"name,qty\nwidget,3\n";
+
+    fn entity() -> PrefixedEntity<FakeEntity> {
+        PrefixedEntity::new(FakeEntity::new(CSV).etag("\"v1\""), UTF8_BOM)
+    }
+
+    #[tokio::test]
+    async fn bom_range() {
+        let req = FakeRequest::get().range("bytes=0-5").build();
+        let resp: Response<hyper::Body> = crate::serve(entity(), &req);
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            resp.headers().get(http::header::CONTENT_RANGE).unwrap(),
+            "bytes 0-5/21"
+        );
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"\xEF\xBB\xBFnam");
+    }
+
+    /// Every range, whether within the prefix, within the inner entity, or spanning both, should
+    /// match the corresponding slice of the concatenation.
+    #[tokio::test]
+    async fn all_ranges() {
+        let e = entity();
+        let mut whole = UTF8_BOM.to_vec();
+        whole.extend_from_slice(CSV);
+        assert_eq!(e.len(), whole.len() as u64);
+        for start in 0..=whole.len() {
+            for end in start..=whole.len() {
+                let s = Pin::from(e.get_range(start as u64..end as u64));
+                let chunks: Vec<Bytes> = s.try_collect().await.unwrap();
+                assert_eq!(chunks.concat(), &whole[start..end], "{}..{}", start, end);
+            }
+        }
+    }
+
+    #[test]
+    fn etag() {
+        assert_eq!(entity().etag().unwrap(), "\"v1+pefbbbf\"");
+        let weak = PrefixedEntity::new(FakeEntity::new(CSV).etag("W/\"v1\""), b"x");
+        assert_eq!(weak.etag().unwrap(), "W/\"v1+p78\"");
+        assert_eq!(PrefixedEntity::new(FakeEntity::new(CSV), b"x").etag(), None);
+    }
+}
@@ -14,7 +14,7 @@ use std::str::FromStr;
 
 /// Represents a `Range:` header which has been parsed and resolved to a particular entity length.
 #[derive(Debug, Eq, PartialEq)]
-pub(crate) enum ResolvedRanges {
+pub enum ResolvedRanges {
     /// No `Range:` header was supplied.
     None,
 
@@ -23,81 +23,248 @@ pub(crate) enum ResolvedRanges {
     NotSatisfiable,
 
     /// A `Range:` header was supplied with at least one satisfiable range, included here.
-    /// Non-satisfiable ranges have been dropped. Ranges are converted from the HTTP closed
-    /// interval style to the the std::ops::Range half-open interval style (start inclusive, end
-    /// exclusive).
-    Satisfiable(SmallVec<[Range<u64>; 1]>),
+    /// Non-satisfiable ranges have been dropped.
+    Satisfiable(SatisfiableRanges),
+}
+
+/// The satisfiable ranges of a `Range:` header, in the order they were given.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SatisfiableRanges {
+    pub(crate) ranges: SmallVec<[Range<u64>; 1]>,
+}
+
+impl SatisfiableRanges {
+    /// Returns the ranges, converted from the HTTP closed interval style to the
+    /// `std::ops::Range` half-open interval style (start inclusive, end exclusive).
+    pub fn ranges(&self) -> &[Range<u64>] {
+        &self.ranges[..]
+    }
+}
+
+/// Diagnostics about the parsing of a `Range:` header, for logging and monitoring.
+///
+/// `serve` attaches these to the response's extensions when a `Range:` header was considered, and
+/// records them in its `tracing` event; `diagnose_range` obtains them directly.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RangeDiagnostics {
+    specs: usize,
+    unsatisfiable: usize,
+    clamped: bool,
+    malformed: Option<MalformedRange>,
+}
+
+impl RangeDiagnostics {
+    /// Returns the number of range specs seen, including unsatisfiable ones.
+    ///
+    /// If the header is malformed, this counts specs up to and including the malformed one.
+    pub fn specs(&self) -> usize {
+        self.specs
+    }
+
+    /// Returns the number of specs dropped as unsatisfiable with the entity's length.
+    pub fn unsatisfiable(&self) -> usize {
+        self.unsatisfiable
+    }
+
+    /// Returns true if any spec's last byte position was past the end of the entity and so was
+    /// clamped to its length.
+    pub fn clamped(&self) -> bool {
+        self.clamped
+    }
+
+    /// Returns details of the parse failure, if the header was malformed.
+    ///
+    /// A malformed header is ignored, as if it weren't supplied at all.
+    pub fn malformed(&self) -> Option<&MalformedRange> {
+        self.malformed.as_ref()
+    }
+}
+
+/// Describes where a `Range:` header failed to parse.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MalformedRange {
+    offset: usize,
+    fragment: String,
+}
+
+impl MalformedRange {
+    /// Returns the byte offset within the header value of the fragment which failed to parse.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Returns the fragment which failed to parse. Non-UTF-8 bytes are replaced with U+FFFD.
+    pub fn fragment(&self) -> &str {
+        &self.fragment
+    }
+}
+
+/// Parses a `Range:` header as `serve` would for an entity of length `len`, returning
+/// diagnostics about the result.
+pub fn diagnose_range(range: Option<&HeaderValue>, len: u64) -> RangeDiagnostics {
+    parse_with_diagnostics(range, len).1
 }
 
 /// Parses the byte-range-set in the range header as described in [RFC 7233 section
 /// 2.1](https://tools.ietf.org/html/rfc7233#section-2.1).
 pub(crate) fn parse(range: Option<&HeaderValue>, len: u64) -> ResolvedRanges {
-    let range = match range.and_then(|v| v.to_str().ok()) {
-        None => return ResolvedRanges::None,
+    parse_with_diagnostics(range, len).0
+}
+
+/// Parses as `parse` does, also returning diagnostics.
+pub(crate) fn parse_with_diagnostics(
+    range: Option<&HeaderValue>,
+    len: u64,
+) -> (ResolvedRanges, RangeDiagnostics) {
+    let mut diag = RangeDiagnostics::default();
+    let range = match range {
+        None => return (ResolvedRanges::None, diag),
         Some(r) => r,
     };
+    let range = match range.to_str() {
+        Err(_) => {
+            diag.malformed = Some(MalformedRange {
+                offset: 0,
+                fragment: String::from_utf8_lossy(range.as_bytes()).into_owned(),
+            });
+            return (ResolvedRanges::None, diag);
+        }
+        Ok(r) => r,
+    };
+    parse_str_with_diagnostics(range, len)
+}
+
+/// Parses a `Range:` header value given as a string, such as `bytes=0-10,20-30`, for an entity of
+/// length `len`.
+///
+/// This is the parser `serve` uses, for callers which have the header value outside of an
+/// `http::HeaderValue`. As there, a malformed value or one with a unit other than `bytes` is
+/// treated as if no `Range:` header were supplied.
+pub fn parse_range_str(range: &str, len: u64) -> ResolvedRanges {
+    parse_str_with_diagnostics(range, len).0
+}
+
+fn parse_str_with_diagnostics(range: &str, len: u64) -> (ResolvedRanges, RangeDiagnostics) {
+    let mut diag = RangeDiagnostics::default();
 
     // byte-ranges-specifier = bytes-unit "=" byte-range-set
+    // Other range units are ignored rather than treated as malformed.
     if !range.starts_with("bytes=") {
-        return ResolvedRanges::None;
+        return (ResolvedRanges::None, diag);
     }
 
     // byte-range-set  = 1#( byte-range-spec / suffix-byte-range-spec )
     let mut ranges: SmallVec<[Range<u64>; 1]> = SmallVec::new();
-    for r in range[6..].split(',') {
+    let mut offset = "bytes=".len();
+    for r in range[offset..].split(',') {
+        let spec_offset = offset;
+        offset += r.len() + 1;
+        diag.specs += 1;
+
         // Trim OWS = *( SP / HTAB )
-        let r = r.trim_start_matches(|c| c == ' ' || c == '\t');
-
-        // Parse one of the following.
-        // byte-range-spec = first-byte-pos "-" [ last-byte-pos ]
-        // suffix-byte-range-spec = "-" suffix-length
-        let hyphen = match r.find('-') {
-            None => return ResolvedRanges::None, // unparseable.
-            Some(h) => h,
-        };
-        if hyphen == 0 {
-            // It's a suffix-byte-range-spec.
-            let last = match u64::from_str(&r[1..]) {
-                Err(_) => return ResolvedRanges::None, // unparseable
-                Ok(l) => l,
-            };
-            if last >= len {
-                continue; // this range is not satisfiable; skip.
+        let trimmed = r.trim_start_matches(|c| c == ' ' || c == '\t');
+        let spec_offset = spec_offset + (r.len() - trimmed.len());
+        match parse_spec(trimmed, len) {
+            Err(()) => {
+                diag.malformed = Some(MalformedRange {
+                    offset: spec_offset,
+                    fragment: trimmed.to_owned(),
+                });
+                return (ResolvedRanges::None, diag);
             }
-            ranges.push((len - last)..len);
-        } else {
-            let first = match u64::from_str(&r[0..hyphen]) {
-                Err(_) => return ResolvedRanges::None, // unparseable
-                Ok(f) => f,
-            };
-            let end = if r.len() > hyphen + 1 {
-                cmp::min(
-                    match u64::from_str(&r[hyphen + 1..]) {
-                        Err(_) => return ResolvedRanges::None, // unparseable
-                        Ok(l) => l,
-                    } + 1,
-                    len,
-                )
-            } else {
-                len // no end specified; use EOF.
-            };
-            if first >= end {
-                continue; // this range is not satisfiable; skip.
+            Ok(Spec::Unsatisfiable) => diag.unsatisfiable += 1,
+            Ok(Spec::Satisfiable { range, clamped }) => {
+                diag.clamped |= clamped;
+                ranges.push(range);
             }
-            ranges.push(first..end);
         }
     }
     if !ranges.is_empty() {
-        return ResolvedRanges::Satisfiable(ranges);
+        return (
+            ResolvedRanges::Satisfiable(SatisfiableRanges { ranges }),
+            diag,
+        );
     }
-    return ResolvedRanges::NotSatisfiable;
+    (ResolvedRanges::NotSatisfiable, diag)
+}
+
+/// Parses a `Range:` header in a unit other than `bytes`, returning the unit and the first and
+/// last positions.
+///
+/// Only the form `<unit>=<first>-<last>` is recognized, where `unit` is a token as described in
+/// [RFC 7230 section 3.2.6](https://tools.ietf.org/html/rfc7230#section-3.2.6) and
+/// `first <= last`. Other forms are ignored.
+pub(crate) fn parse_custom(range: Option<&HeaderValue>) -> Option<(&str, u64, u64)> {
+    let range = range?.to_str().ok()?;
+    let eq = range.find('=')?;
+    let (unit, spec) = (&range[..eq], &range[eq + 1..]);
+    let is_tchar = |b: u8| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b);
+    if unit.is_empty() || unit == "bytes" || !unit.bytes().all(is_tchar) {
+        return None;
+    }
+    let hyphen = spec.find('-')?;
+    let first = u64::from_str(&spec[..hyphen]).ok()?;
+    let last = u64::from_str(&spec[hyphen + 1..]).ok()?;
+    if first > last {
+        return None;
+    }
+    Some((unit, first, last))
+}
+
+/// The result of parsing a single (non-malformed) range spec.
+enum Spec {
+    Unsatisfiable,
+    Satisfiable { range: Range<u64>, clamped: bool },
+}
+
+/// Parses one of the following.
+/// byte-range-spec = first-byte-pos "-" [ last-byte-pos ]
+/// suffix-byte-range-spec = "-" suffix-length
+fn parse_spec(r: &str, len: u64) -> Result<Spec, ()> {
+    let hyphen = r.find('-').ok_or(())?;
+    if hyphen == 0 {
+        // It's a suffix-byte-range-spec.
+        let last = u64::from_str(&r[1..]).map_err(|_| ())?;
+        if last >= len {
+            return Ok(Spec::Unsatisfiable);
+        }
+        return Ok(Spec::Satisfiable {
+            range: (len - last)..len,
+            clamped: false,
+        });
+    }
+    let first = u64::from_str(&r[0..hyphen]).map_err(|_| ())?;
+    let (end, clamped) = if r.len() > hyphen + 1 {
+        let last = u64::from_str(&r[hyphen + 1..]).map_err(|_| ())?;
+        let end = last.saturating_add(1);
+        (cmp::min(end, len), end > len)
+    } else {
+        (len, false) // no end specified; use EOF.
+    };
+    if first >= end {
+        return Ok(Spec::Unsatisfiable);
+    }
+    Ok(Spec::Satisfiable {
+        range: first..end,
+        clamped,
+    })
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{parse, ResolvedRanges};
+    use super::{
+        parse, parse_custom, parse_range_str, parse_with_diagnostics, MalformedRange,
+        RangeDiagnostics, ResolvedRanges, SatisfiableRanges,
+    };
     use http::header::HeaderValue;
     use smallvec::SmallVec;
+    use std::ops::Range;
+
+    fn satisfiable(ranges: &SmallVec<[Range<u64>; 1]>) -> ResolvedRanges {
+        ResolvedRanges::Satisfiable(SatisfiableRanges {
+            ranges: ranges.clone(),
+        })
+    }
 
     /// Tests the specific examples enumerated in [RFC 2616 section
     /// 14.35.1](https://tools.ietf.org/html/rfc2616#section-14.35.1).
@@ -107,28 +274,28 @@ mod tests {
 
         v.push(0..500);
         assert_eq!(
-            ResolvedRanges::Satisfiable(v.clone()),
+            satisfiable(&v),
             parse(Some(&HeaderValue::from_static("bytes=0-499")), 10000)
         );
 
         v.clear();
         v.push(500..1000);
         assert_eq!(
-            ResolvedRanges::Satisfiable(v.clone()),
+            satisfiable(&v),
             parse(Some(&HeaderValue::from_static("bytes=500-999")), 10000)
         );
 
         v.clear();
         v.push(9500..10000);
         assert_eq!(
-            ResolvedRanges::Satisfiable(v.clone()),
+            satisfiable(&v),
             parse(Some(&HeaderValue::from_static("bytes=-500")), 10000)
         );
 
         v.clear();
         v.push(9500..10000);
         assert_eq!(
-            ResolvedRanges::Satisfiable(v.clone()),
+            satisfiable(&v),
             parse(Some(&HeaderValue::from_static("bytes=9500-")), 10000)
         );
 
@@ -136,7 +303,7 @@ mod tests {
         v.push(0..1);
         v.push(9999..10000);
         assert_eq!(
-            ResolvedRanges::Satisfiable(v.clone()),
+            satisfiable(&v),
             parse(Some(&HeaderValue::from_static("bytes=0-0,-1")), 10000)
         );
 
@@ -147,7 +314,7 @@ mod tests {
         v.push(500..601);
         v.push(601..1000);
         assert_eq!(
-            ResolvedRanges::Satisfiable(v.clone()),
+            satisfiable(&v),
             parse(
                 Some(&HeaderValue::from_static("bytes=500-600, 601-999")),
                 10000
@@ -158,7 +325,7 @@ mod tests {
         v.push(500..701);
         v.push(601..1000);
         assert_eq!(
-            ResolvedRanges::Satisfiable(v.clone()),
+            satisfiable(&v),
             parse(
                 Some(&HeaderValue::from_static("bytes=500-700, 601-999")),
                 10000
@@ -176,7 +343,7 @@ mod tests {
         let mut v = SmallVec::new();
         v.push(0..500);
         assert_eq!(
-            ResolvedRanges::Satisfiable(v.clone()),
+            satisfiable(&v),
             parse(Some(&HeaderValue::from_static("bytes=0-499,10000-")), 10000)
         );
 
@@ -196,14 +363,14 @@ mod tests {
         v.clear();
         v.push(0..1);
         assert_eq!(
-            ResolvedRanges::Satisfiable(v.clone()),
+            satisfiable(&v),
             parse(Some(&HeaderValue::from_static("bytes=0-0")), 1)
         );
 
         v.clear();
         v.push(0..500);
         assert_eq!(
-            ResolvedRanges::Satisfiable(v.clone()),
+            satisfiable(&v),
             parse(Some(&HeaderValue::from_static("bytes=0-10000")), 500)
         );
     }
@@ -211,6 +378,10 @@ mod tests {
     #[test]
     fn test_resolve_ranges_absent_or_invalid() {
         assert_eq!(ResolvedRanges::None, parse(None, 10000));
+        for &v in &["", " ", "\t", "bytes=", "bytes= "] {
+            let v = HeaderValue::from_static(v);
+            assert_eq!(ResolvedRanges::None, parse(Some(&v), 10000), "{:?}", v);
+        }
     }
 
     #[test]
@@ -220,4 +391,114 @@ mod tests {
             parse(Some(&HeaderValue::from_bytes(b"\xff").unwrap()), 10000)
         );
     }
+
+    fn diag(range: &'static str, len: u64) -> RangeDiagnostics {
+        parse_with_diagnostics(Some(&HeaderValue::from_static(range)), len).1
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        assert_eq!(
+            diag("bytes=0-499", 10000),
+            RangeDiagnostics {
+                specs: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            diag("bytes=0-10000", 500),
+            RangeDiagnostics {
+                specs: 1,
+                clamped: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            diag("bytes=0-499, 10000-, -20000", 10000),
+            RangeDiagnostics {
+                specs: 3,
+                unsatisfiable: 2,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            diag("bytes=0-499, 5x-", 10000),
+            RangeDiagnostics {
+                specs: 2,
+                malformed: Some(MalformedRange {
+                    offset: 13,
+                    fragment: "5x-".to_owned(),
+                }),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            parse_with_diagnostics(Some(&HeaderValue::from_bytes(b"\xff").unwrap()), 10000),
+            (
+                ResolvedRanges::None,
+                RangeDiagnostics {
+                    malformed: Some(MalformedRange {
+                        offset: 0,
+                        fragment: "\u{fffd}".to_owned(),
+                    }),
+                    ..Default::default()
+                }
+            )
+        );
+        assert_eq!(diag("items=0-1", 10000), RangeDiagnostics::default());
+    }
+
+    #[test]
+    fn test_parse_range_str() {
+        let sat = |v: &[(u64, u64)]| satisfiable(&v.iter().map(|&(s, e)| s..e).collect());
+        assert_eq!(
+            parse_range_str("bytes=0-10,20-30", 100),
+            sat(&[(0, 11), (20, 31)])
+        );
+        assert_eq!(parse_range_str("bytes=-5", 100), sat(&[(95, 100)]));
+        assert_eq!(parse_range_str("bytes=90-", 100), sat(&[(90, 100)]));
+        assert_eq!(parse_range_str("bytes=90-200", 100), sat(&[(90, 100)]));
+        assert_eq!(
+            parse_range_str("bytes=0-0, \t5-5", 100),
+            sat(&[(0, 1), (5, 6)])
+        );
+        assert_eq!(
+            parse_range_str("bytes=100-", 100),
+            ResolvedRanges::NotSatisfiable
+        );
+
+        // Malformed values and other units are ignored.
+        for s in &[
+            "",
+            "bytes",
+            "bytes=",
+            "bytes=a-b",
+            "bytes=0-10,",
+            "bytes=10",
+            "bytes=--1",
+            "bytes=0-10 ",
+            "bytes=18446744073709551616-",
+            "items=0-10",
+            "Bytes=0-10",
+        ] {
+            assert_eq!(parse_range_str(s, 100), ResolvedRanges::None, "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn custom() {
+        let p = |s| {
+            parse_custom(Some(&HeaderValue::from_static(s))).map(|(u, f, l)| (u.to_owned(), f, l))
+        };
+        assert_eq!(parse_custom(None), None);
+        assert_eq!(p("ms=1000-2000"), Some(("ms".to_owned(), 1000, 2000)));
+        assert_eq!(p("x-frames=5-5"), Some(("x-frames".to_owned(), 5, 5)));
+        assert_eq!(p("bytes=0-10"), None);
+        assert_eq!(p("ms=2000-1000"), None);
+        assert_eq!(p("ms=1000-"), None);
+        assert_eq!(p("ms=-1000"), None);
+        assert_eq!(p("ms=0-1,2-3"), None);
+        assert_eq!(p("m s=0-1"), None);
+        assert_eq!(p("=0-1"), None);
+    }
 }
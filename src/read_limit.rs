@@ -0,0 +1,403 @@
+// Copyright (c) 2016-2020 The http-serve developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+use tokio::time::{Delay, Instant};
+
+/// Limits the blocking reads of file entities in flight at once, queueing and shedding the rest.
+///
+/// Every chunk a [`ChunkedReadFile`](struct.ChunkedReadFile.html) reads ties up a thread within
+/// `tokio::task::block_in_place`. Under a burst of requests, those reads pile up without bound
+/// and every response slows down. With a limiter set via
+/// [`ChunkedReadFile::read_limiter`](struct.ChunkedReadFile.html#method.read_limiter), each read
+/// first waits its turn, first come first served. A read which finds the queue full, or which
+/// waits longer than `max_wait`, fails with [`Overloaded`](struct.Overloaded.html) instead,
+/// ending its body with an error. Before starting a response, a server can check
+/// [`try_reserve`](#method.try_reserve) to refuse it outright, typically with `503 Service
+/// Unavailable`.
+///
+/// Clones share the same queue.
+#[derive(Clone)]
+pub struct ReadLimiter {
+    max_in_flight: usize,
+    max_queued: usize,
+    max_wait: Option<Duration>,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    in_flight: usize,
+
+    /// Outstanding `Reservation`s, which count as queued reads.
+    reserved: usize,
+
+    next_id: u64,
+
+    /// Reads waiting for a permit, in arrival order, by id, with the wakers of their latest polls.
+    waiters: VecDeque<(u64, Waker)>,
+
+    rejected: u64,
+    timed_out: u64,
+}
+
+impl State {
+    fn queued(&self) -> usize {
+        self.waiters.len() + self.reserved
+    }
+
+    /// Wakes the first waiter, if a permit is free for it.
+    fn wake_next(&self, max_in_flight: usize) {
+        if self.in_flight < max_in_flight {
+            if let Some((_, w)) = self.waiters.front() {
+                w.wake_by_ref();
+            }
+        }
+    }
+}
+
+/// A snapshot of a [`ReadLimiter`](struct.ReadLimiter.html)'s queue, as returned by
+/// [`ReadLimiter::gauges`](struct.ReadLimiter.html#method.gauges).
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ReadGauges {
+    /// Reads currently running.
+    pub in_flight: usize,
+
+    /// Reads waiting to run, plus outstanding reservations.
+    pub queued: usize,
+
+    /// Reads refused because the queue was full, since the limiter was created.
+    pub rejected: u64,
+
+    /// Reads which gave up after waiting `max_wait`, since the limiter was created.
+    pub timed_out: u64,
+}
+
+/// The error for a read shed by a [`ReadLimiter`](struct.ReadLimiter.html), or a refused
+/// [`try_reserve`](struct.ReadLimiter.html#method.try_reserve).
+#[derive(Clone, Debug)]
+pub struct Overloaded {
+    /// How long the read waited before giving up, or `None` if it was refused outright.
+    waited: Option<Duration>,
+}
+
+impl Overloaded {
+    /// Returns true if the read gave up after waiting `max_wait`, rather than finding the queue
+    /// full.
+    pub fn timed_out(&self) -> bool {
+        self.waited.is_some()
+    }
+}
+
+impl fmt::Display for Overloaded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.waited {
+            Some(w) => write!(f, "file read not started within {:?}", w),
+            None => f.write_str("file read queue is full"),
+        }
+    }
+}
+
+impl std::error::Error for Overloaded {}
+
+impl ReadLimiter {
+    /// Creates a limiter which runs up to `max_in_flight` reads at once, with an unbounded queue
+    /// and no limit on waiting.
+    ///
+    /// Panics if `max_in_flight` is 0.
+    pub fn new(max_in_flight: usize) -> Self {
+        assert!(max_in_flight > 0, "max_in_flight must be positive");
+        ReadLimiter {
+            max_in_flight,
+            max_queued: usize::max_value(),
+            max_wait: None,
+            state: Arc::new(Mutex::new(State::default())),
+        }
+    }
+
+    /// Sets the most reads which may wait at once. Reads beyond that fail immediately.
+    pub fn max_queued(self, max_queued: usize) -> Self {
+        ReadLimiter { max_queued, ..self }
+    }
+
+    /// Sets how long a read may wait before failing.
+    pub fn max_wait(self, max_wait: Duration) -> Self {
+        ReadLimiter {
+            max_wait: Some(max_wait),
+            ..self
+        }
+    }
+
+    /// Returns the current queue depth and counts of shed reads, for export as metrics.
+    pub fn gauges(&self) -> ReadGauges {
+        let s = self.state.lock().unwrap();
+        ReadGauges {
+            in_flight: s.in_flight,
+            queued: s.queued(),
+            rejected: s.rejected,
+            timed_out: s.timed_out,
+        }
+    }
+
+    /// Checks, before starting a response, that a read started now wouldn't be refused.
+    ///
+    /// On success, the returned reservation holds a place in the queue until dropped, so that a
+    /// burst of requests can't all pass the check at once. Hold it until the response has been
+    /// created, then drop it before the body is read:
+    ///
+    /// ```
+    /// # use http::{Request, Response, StatusCode};
+    /// # fn f(limiter: &http_serve::ReadLimiter, file: std::fs::File, req: Request<hyper::Body>)
+    /// #     -> Result<Response<hyper::Body>, std::io::Error> {
+    /// let reservation = match limiter.try_reserve() {
+    ///     Ok(r) => r,
+    ///     Err(_) => {
+    ///         let mut resp = Response::new(hyper::Body::from("Service Unavailable"));
+    ///         *resp.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    ///         return Ok(resp);
+    ///     }
+    /// };
+    /// # type BoxedError = Box<dyn std::error::Error + Send + Sync>;
+    /// let file = http_serve::ChunkedReadFile::<bytes::Bytes, BoxedError>::new(
+    ///     file,
+    ///     http::HeaderMap::new(),
+    /// )?
+    /// .read_limiter(limiter.clone());
+    /// let resp = http_serve::serve(file, &req);
+    /// drop(reservation);
+    /// Ok(resp)
+    /// # }
+    /// ```
+    pub fn try_reserve(&self) -> Result<Reservation, Overloaded> {
+        let mut s = self.state.lock().unwrap();
+        if s.in_flight + s.queued() >= self.max_in_flight.saturating_add(self.max_queued) {
+            s.rejected += 1;
+            return Err(Overloaded { waited: None });
+        }
+        s.reserved += 1;
+        Ok(Reservation {
+            limiter: self.clone(),
+        })
+    }
+
+    /// Returns a future which waits for a permit to read.
+    pub(crate) fn acquire(&self) -> Acquire {
+        Acquire {
+            limiter: self.clone(),
+            queued: None,
+        }
+    }
+}
+
+impl fmt::Debug for ReadLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ReadLimiter")
+            .field("max_in_flight", &self.max_in_flight)
+            .field("max_queued", &self.max_queued)
+            .field("max_wait", &self.max_wait)
+            .field("gauges", &self.gauges())
+            .finish()
+    }
+}
+
+/// A place in a [`ReadLimiter`](struct.ReadLimiter.html)'s queue, as returned by
+/// [`ReadLimiter::try_reserve`](struct.ReadLimiter.html#method.try_reserve).
+#[derive(Debug)]
+pub struct Reservation {
+    limiter: ReadLimiter,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.limiter.state.lock().unwrap().reserved -= 1;
+    }
+}
+
+/// Permission to run one read, released when dropped.
+pub(crate) struct ReadPermit {
+    limiter: ReadLimiter,
+}
+
+impl Drop for ReadPermit {
+    fn drop(&mut self) {
+        let mut s = self.limiter.state.lock().unwrap();
+        s.in_flight -= 1;
+        s.wake_next(self.limiter.max_in_flight);
+    }
+}
+
+/// A future which resolves to a `ReadPermit`, or an error if the read is shed.
+pub(crate) struct Acquire {
+    limiter: ReadLimiter,
+
+    /// Set once queued: the id, when the wait started, and the `max_wait` timer.
+    queued: Option<(u64, Instant, Option<Delay>)>,
+}
+
+impl Future for Acquire {
+    type Output = Result<ReadPermit, Overloaded>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let l = &this.limiter;
+        let mut s = l.state.lock().unwrap();
+        let (id, since, delay) = match this.queued {
+            None => {
+                if s.in_flight < l.max_in_flight && s.waiters.is_empty() {
+                    s.in_flight += 1;
+                    drop(s);
+                    return Poll::Ready(Ok(ReadPermit { limiter: l.clone() }));
+                }
+                if s.queued() >= l.max_queued {
+                    s.rejected += 1;
+                    #[cfg(feature = "tracing")]
+                    tracing::warn!(
+                        queued = s.queued(),
+                        "file read queue is full; shedding read"
+                    );
+                    return Poll::Ready(Err(Overloaded { waited: None }));
+                }
+                let id = s.next_id;
+                s.next_id += 1;
+                s.waiters.push_back((id, cx.waker().clone()));
+                let now = Instant::now();
+                let delay = l.max_wait.map(|w| tokio::time::delay_until(now + w));
+                this.queued.get_or_insert((id, now, delay))
+            }
+            Some(ref mut q) => q,
+        };
+        let first = s.waiters.front().map(|&(i, _)| i) == Some(*id);
+        if first && s.in_flight < l.max_in_flight {
+            s.waiters.pop_front();
+            s.in_flight += 1;
+            s.wake_next(l.max_in_flight);
+            drop(s);
+            this.queued = None;
+            return Poll::Ready(Ok(ReadPermit { limiter: l.clone() }));
+        }
+        if let Some(ref mut delay) = *delay {
+            if Pin::new(delay).poll(cx).is_ready() {
+                let waited = since.elapsed();
+                s.waiters.retain(|&(i, _)| i != *id);
+                s.timed_out += 1;
+                s.wake_next(l.max_in_flight);
+                #[cfg(feature = "tracing")]
+                tracing::warn!(?waited, "file read waited too long; shedding read");
+                drop(s);
+                this.queued = None;
+                return Poll::Ready(Err(Overloaded {
+                    waited: Some(waited),
+                }));
+            }
+        }
+        if let Some(&mut (_, ref mut w)) = s.waiters.iter_mut().find(|&&mut (i, _)| i == *id) {
+            if !w.will_wake(cx.waker()) {
+                *w = cx.waker().clone();
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Acquire {
+    fn drop(&mut self) {
+        if let Some((id, _, _)) = self.queued {
+            let mut s = self.limiter.state.lock().unwrap();
+            s.waiters.retain(|&(i, _)| i != id);
+            s.wake_next(self.limiter.max_in_flight);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ReadGauges, ReadLimiter};
+    use futures::{poll, FutureExt};
+    use std::task::Poll;
+    use std::time::Duration;
+
+    fn gauges(in_flight: usize, queued: usize, rejected: u64, timed_out: u64) -> ReadGauges {
+        ReadGauges {
+            in_flight,
+            queued,
+            rejected,
+            timed_out,
+        }
+    }
+
+    /// Reads queue in order up to `max_queued`, and those beyond are refused at once.
+    #[tokio::test]
+    async fn bounded_queue() {
+        let l = ReadLimiter::new(1).max_queued(2);
+        let held = l.acquire().now_or_never().unwrap().unwrap();
+        let mut a = l.acquire();
+        let mut b = l.acquire();
+        assert!(poll!(&mut a).is_pending());
+        assert!(poll!(&mut b).is_pending());
+        let e = l.acquire().await.err().unwrap();
+        assert!(!e.timed_out());
+        assert!(l.try_reserve().is_err());
+        assert_eq!(l.gauges(), gauges(1, 2, 2, 0));
+
+        drop(held);
+        assert!(poll!(&mut b).is_pending());
+        let a = match poll!(&mut a) {
+            Poll::Ready(r) => r.unwrap(),
+            Poll::Pending => panic!("first waiter should be admitted"),
+        };
+        assert_eq!(l.gauges(), gauges(1, 1, 2, 0));
+
+        // One place is free again; a reservation takes it.
+        let r = l.try_reserve().unwrap();
+        assert!(l.acquire().await.is_err());
+        drop(r);
+        drop(a);
+        let b = b.await.unwrap();
+        drop(b);
+        assert_eq!(l.gauges(), gauges(0, 0, 3, 0));
+    }
+
+    #[tokio::test]
+    async fn max_wait() {
+        let l = ReadLimiter::new(1).max_wait(Duration::from_millis(20));
+        let held = l.acquire().await.unwrap();
+        let mut late = l.acquire();
+        assert!(poll!(&mut late).is_pending());
+        let e = l.acquire().await.err().unwrap();
+        assert!(e.timed_out());
+        assert!(e.to_string().starts_with("file read not started within"));
+
+        // The timed-out waiter leaves the queue; the earlier one is still first in line.
+        assert_eq!(l.gauges(), gauges(1, 1, 0, 1));
+        drop(held);
+        let _p = (&mut late).await.unwrap();
+        assert_eq!(l.gauges(), gauges(1, 0, 0, 1));
+    }
+
+    /// A waiter dropped while queued gives up its place.
+    #[tokio::test]
+    async fn cancelled() {
+        let l = ReadLimiter::new(1);
+        let held = l.acquire().await.unwrap();
+        let mut a = l.acquire();
+        let mut b = l.acquire();
+        assert!(poll!(&mut a).is_pending());
+        assert!(poll!(&mut b).is_pending());
+        drop(a);
+        drop(held);
+        let _b = b.await.unwrap();
+        assert_eq!(l.gauges(), gauges(1, 0, 0, 0));
+    }
+}
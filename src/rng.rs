@@ -0,0 +1,102 @@
+// Copyright (c) 2016-2020 The http-serve developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Randomness for `serve`, such as `multipart/byteranges` boundaries.
+//!
+//! None of this needs to be cryptographically strong; it only needs to be unlikely to collide
+//! with entity content. Everything random goes through `Rng` so that tests can pin it with
+//! `ServeOptions::rng_seed`.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Once;
+
+const GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// A source of random bytes, shared by concurrent requests.
+pub(crate) trait Rng: Send + Sync {
+    /// Fills `dest` with random bytes.
+    fn fill_bytes(&self, dest: &mut [u8]);
+}
+
+/// splitmix64 over an atomic counter.
+///
+/// Each output is a bijective mix of a distinct counter value, so a generator never repeats a
+/// 64-bit output until its counter wraps, even when shared between threads.
+#[derive(Debug)]
+pub(crate) struct SplitMix {
+    state: AtomicU64,
+}
+
+impl SplitMix {
+    /// Creates a generator whose outputs are determined by `seed`.
+    pub(crate) const fn new(seed: u64) -> Self {
+        SplitMix {
+            state: AtomicU64::new(seed),
+        }
+    }
+
+    pub(crate) fn next_u64(&self) -> u64 {
+        let mut z = self
+            .state
+            .fetch_add(GAMMA, Ordering::Relaxed)
+            .wrapping_add(GAMMA);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+impl Rng for SplitMix {
+    fn fill_bytes(&self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let v = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&v[..chunk.len()]);
+        }
+    }
+}
+
+static DEFAULT_RNG: SplitMix = SplitMix::new(0);
+static SEED_DEFAULT_RNG: Once = Once::new();
+
+/// Returns the process-wide generator which seeds each response's generator when
+/// `ServeOptions::rng_seed` isn't set.
+///
+/// It's seeded once per process from the OS-seeded keys of `RandomState`.
+pub(crate) fn default_rng() -> &'static SplitMix {
+    SEED_DEFAULT_RNG.call_once(|| {
+        use std::hash::{BuildHasher, Hasher};
+        let seed = std::collections::hash_map::RandomState::new()
+            .build_hasher()
+            .finish();
+        DEFAULT_RNG.state.store(seed, Ordering::Relaxed);
+    });
+    &DEFAULT_RNG
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Rng, SplitMix};
+
+    #[test]
+    fn fill_bytes() {
+        // Outputs are consumed a whole word at a time, so a partial word's tail is discarded.
+        let a = SplitMix::new(7);
+        let mut x = [0u8; 11];
+        a.fill_bytes(&mut x);
+        let b = SplitMix::new(7);
+        let mut y = [0u8; 16];
+        b.fill_bytes(&mut y);
+        assert_eq!(x[..], y[..11]);
+
+        let mut next = [0u8; 8];
+        a.fill_bytes(&mut next);
+        let mut third = [0u8; 8];
+        b.fill_bytes(&mut third);
+        assert_eq!(next, third);
+    }
+}
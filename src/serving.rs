@@ -6,47 +6,64 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use super::Entity;
-use crate::etag;
+use super::{BoxedEntity, ContentCoding, Entity};
+use crate::clock::{Clock, DEFAULT_CLOCK};
+use crate::coop::CooperativeYield;
+use crate::drain::Watch;
+use crate::etag::{self, EtagComparison};
+use crate::immutable::ImmutableEntity;
+#[cfg(feature = "multipart")]
+use crate::multipart::{
+    choose_boundary, multipart_response, next_multipart_body_chunk, prepare_multipart,
+};
 use crate::range;
+#[cfg(feature = "multipart")]
+use crate::rng::{default_rng, SplitMix};
+use crate::tally::Tallied;
+use crate::trailers::TrailerBody;
+use crate::trim::Trim;
 use bytes::Buf;
 use futures::stream::{self, StreamExt};
 use futures::{self, Stream};
-use http::header::{self, HeaderMap, HeaderValue};
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
 use http::{self, Method, Request, Response, StatusCode};
 use http_body::Body;
 use httpdate::{fmt_http_date, parse_http_date};
 use smallvec::SmallVec;
-use std::future::Future;
-use std::io::Write;
 use std::ops::Range;
 use std::pin::Pin;
-use std::time::SystemTime;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
-const MAX_DECIMAL_U64_BYTES: usize = 20; // u64::max_value().to_string().len()
+pub(crate) const MAX_DECIMAL_U64_BYTES: usize = 20; // u64::max_value().to_string().len()
 
 fn parse_modified_hdrs(
     etag: &Option<HeaderValue>,
     req_hdrs: &HeaderMap,
     last_modified: Option<SystemTime>,
+    opts: &ServeOptions,
 ) -> Result<(bool, bool), &'static str> {
-    let precondition_failed = if !etag::any_match(etag, req_hdrs)? {
-        true
-    } else if let (Some(ref m), Some(ref since)) =
+    // See RFC 7232 section 6 <https://tools.ietf.org/html/rfc7232#section-6>: each date
+    // precondition is evaluated only in the absence of its etag counterpart.
+    let precondition_failed = if req_hdrs.contains_key(header::IF_MATCH) {
+        !etag::any_match(etag, req_hdrs, opts.constant_time_etags)?
+    } else if let (Some(ref m), Some(since)) =
         (last_modified, req_hdrs.get(header::IF_UNMODIFIED_SINCE))
     {
-        const ERR: &'static str = "Unparseable If-Unmodified-Since";
+        const ERR: &str = "Unparseable If-Unmodified-Since";
         *m > parse_http_date(since.to_str().map_err(|_| ERR)?).map_err(|_| ERR)?
     } else {
         false
     };
 
-    let not_modified = if !etag::none_match(&etag, req_hdrs).unwrap_or(true) {
-        true
-    } else if let (Some(ref m), Some(ref since)) =
+    let not_modified = if req_hdrs.contains_key(header::IF_NONE_MATCH) {
+        let comparison = opts.if_none_match_comparison;
+        !etag::none_match(etag, req_hdrs, comparison, opts.constant_time_etags).unwrap_or(true)
+    } else if let (Some(ref m), Some(since)) =
         (last_modified, req_hdrs.get(header::IF_MODIFIED_SINCE))
     {
-        const ERR: &'static str = "Unparseable If-Modified-Since";
+        const ERR: &str = "Unparseable If-Modified-Since";
         *m <= parse_http_date(since.to_str().map_err(|_| ERR)?).map_err(|_| ERR)?
     } else {
         false
@@ -55,6 +72,107 @@ fn parse_modified_hdrs(
     Ok((precondition_failed, not_modified))
 }
 
+/// Returns true if `serve` would respond `304 Not Modified` to a `GET` or `HEAD` request with
+/// headers `req_hdrs` for an entity with the given validators.
+///
+/// This allows answering revalidation requests from stored validators, without fetching the
+/// entity itself.
+pub fn would_not_modify(
+    req_hdrs: &HeaderMap,
+    etag: Option<&HeaderValue>,
+    last_modified: Option<SystemTime>,
+) -> bool {
+    would_not_modify_with_options(req_hdrs, etag, last_modified, &ServeOptions::default())
+}
+
+/// Returns true if `serve_with_options` would respond `304 Not Modified` given `opts`; see
+/// [`would_not_modify`](fn.would_not_modify.html).
+pub fn would_not_modify_with_options(
+    req_hdrs: &HeaderMap,
+    etag: Option<&HeaderValue>,
+    last_modified: Option<SystemTime>,
+    opts: &ServeOptions,
+) -> bool {
+    match predicates(req_hdrs, etag, last_modified, opts) {
+        Ok((precondition_failed, not_modified)) => !precondition_failed && not_modified,
+        Err(_) => false,
+    }
+}
+
+/// Returns true if `serve` would respond `412 Precondition Failed` to a `GET` or `HEAD` request
+/// with headers `req_hdrs` for an entity with the given validators.
+pub fn would_precondition_fail(
+    req_hdrs: &HeaderMap,
+    etag: Option<&HeaderValue>,
+    last_modified: Option<SystemTime>,
+) -> bool {
+    would_precondition_fail_with_options(req_hdrs, etag, last_modified, &ServeOptions::default())
+}
+
+/// Returns true if `serve_with_options` would respond `412 Precondition Failed` given `opts`;
+/// see [`would_precondition_fail`](fn.would_precondition_fail.html).
+pub fn would_precondition_fail_with_options(
+    req_hdrs: &HeaderMap,
+    etag: Option<&HeaderValue>,
+    last_modified: Option<SystemTime>,
+    opts: &ServeOptions,
+) -> bool {
+    match predicates(req_hdrs, etag, last_modified, opts) {
+        Ok((precondition_failed, _)) => precondition_failed,
+        Err(_) => false,
+    }
+}
+
+/// Evaluates preconditions on the validators as `serve_inner` would see them given `opts`.
+fn predicates(
+    req_hdrs: &HeaderMap,
+    etag: Option<&HeaderValue>,
+    last_modified: Option<SystemTime>,
+    opts: &ServeOptions,
+) -> Result<(bool, bool), &'static str> {
+    let etag = normalized_etag(etag.cloned(), opts);
+    let last_modified = if opts.omit_last_modified {
+        None
+    } else {
+        last_modified
+    };
+    parse_modified_hdrs(&etag, req_hdrs, last_modified, opts)
+}
+
+/// Returns true if the `If-Range` date `if_range` matches `last_modified`, as described in
+/// `ServeOptions::permissive_if_range_dates`.
+fn if_range_date_matches(
+    if_range: &[u8],
+    etag: Option<&HeaderValue>,
+    last_modified: Option<SystemTime>,
+    now: Option<SystemTime>,
+) -> bool {
+    let strong_etag = match etag {
+        Some(e) => !e.as_bytes().starts_with(b"W/"),
+        None => false,
+    };
+    let (m, now) = match (last_modified, now) {
+        (Some(m), Some(now)) if strong_etag => (m, now),
+        _ => return false,
+    };
+    let since_epoch = match m.duration_since(std::time::UNIX_EPOCH) {
+        Ok(d) if d.subsec_nanos() == 0 => d,
+        _ => return false,
+    };
+    let date = match std::str::from_utf8(if_range)
+        .ok()
+        .and_then(|d| parse_http_date(d).ok())
+    {
+        Some(d) => d,
+        None => return false,
+    };
+    match now.duration_since(m) {
+        Ok(age) if age >= std::time::Duration::from_secs(1) => {}
+        _ => return false,
+    }
+    date.duration_since(std::time::UNIX_EPOCH).ok() == Some(since_epoch)
+}
+
 fn static_body<D, E>(s: &'static str) -> Box<dyn Stream<Item = Result<D, E>> + Send>
 where
     D: 'static + Send + Buf + From<Vec<u8>> + From<&'static [u8]>,
@@ -71,325 +189,5375 @@ where
     Box::new(stream::empty())
 }
 
-/// Serves GET and HEAD requests for a given byte-ranged entity.
-/// Handles conditional & subrange requests.
-/// The caller is expected to have already determined the correct entity and appended
-/// `Expires`, `Cache-Control`, and `Vary` headers if desired.
-pub fn serve<
-    Ent: Entity,
-    B: Body + From<Box<dyn Stream<Item = Result<Ent::Data, Ent::Error>> + Send>>,
-    BI,
->(
-    entity: Ent,
-    req: &Request<BI>,
-) -> Response<B> {
-    // serve takes entity itself for ownership, as needed for the multipart case. But to avoid
-    // monomorphization code bloat when there are many implementations of Entity<Data, Error>,
-    // delegate as much as possible to functions which take a reference to a trait object.
-    match serve_inner(&entity, req) {
-        ServeInner::Simple(res) => res,
-        ServeInner::Multipart {
-            res,
-            mut part_headers,
-            ranges,
-        } => {
-            let bodies = futures::stream::unfold(0, move |state| {
-                next_multipart_body_chunk(state, &entity, &ranges[..], &mut part_headers[..])
-            });
-            let body = bodies.flatten();
-            let body: Box<dyn Stream<Item = Result<Ent::Data, Ent::Error>> + Send> = Box::new(body);
-            res.body(body.into()).unwrap()
-        }
-    }
+/// Options for [`serve_with_options`](fn.serve_with_options.html).
+///
+/// The defaults match the behavior of [`serve`](fn.serve.html).
+#[derive(Clone, Default)]
+pub struct ServeOptions {
+    constant_time_etags: bool,
+    if_none_match_comparison: EtagComparison,
+    normalize_etags: bool,
+    permissive_if_range_dates: bool,
+    range_query_param: Option<String>,
+
+    /// Set by `weak_encoded_etags`, inverted so the default is false.
+    strong_encoded_etags: bool,
+    omit_last_modified: bool,
+
+    /// Set by `emit_etag` and `emit_last_modified`, inverted so the defaults are false.
+    suppress_etag: bool,
+    suppress_last_modified: bool,
+    accept_ranges_always: bool,
+    pub(crate) verify_boundary: bool,
+    handle_options: bool,
+    success_status: Option<StatusCode>,
+    yield_every: Option<u64>,
+    error_trailer: Option<HeaderName>,
+    #[cfg(feature = "content-md5")]
+    content_md5_trailer: bool,
+    #[cfg(feature = "repr-digest-trailer")]
+    repr_digest_trailer: bool,
+    max_multipart_bytes: Option<u64>,
+    always_multipart: bool,
+    max_part_headers_bytes: Option<usize>,
+    max_entity_header_bytes: Option<usize>,
+    header_overflow: HeaderOverflow,
+    multipart_fallback: MultipartFallback,
+    drain: Option<Watch>,
+    report_bytes_sent: bool,
+    reject_ambiguous_framing: bool,
+
+    /// Overrides `rng::default_rng`, for tests.
+    #[cfg(feature = "multipart")]
+    rng_seed: Option<u64>,
+    max_content_length: Option<Arc<MaxContentLengthFn>>,
+    multirange_compat: Option<Arc<MultirangeCompatFn>>,
+    clock: Option<Arc<dyn Clock>>,
+    cache_policy: Option<CachePolicy>,
+    vary: Vec<HeaderName>,
+    error_content_length: bool,
+
+    /// Set by `probe_size`; `None` means `DEFAULT_PROBE_SIZE`.
+    probe_size: Option<u64>,
 }
 
-/// An instruction from `serve_inner` to `serve` on how to respond.
-enum ServeInner<B> {
-    Simple(Response<B>),
-    Multipart {
-        res: http::response::Builder,
-        part_headers: Vec<Vec<u8>>,
-        ranges: SmallVec<[Range<u64>; 1]>,
-    },
+/// The default for `ServeOptions::probe_size`, as for a player's `Range: bytes=0-1`.
+const DEFAULT_PROBE_SIZE: u64 = 2;
+
+type MaxContentLengthFn = dyn Fn(&HeaderMap) -> Option<u64> + Send + Sync;
+type MultirangeCompatFn = dyn Fn(&HeaderMap) -> MultirangeMode + Send + Sync;
+
+impl std::fmt::Debug for ServeOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mut d = f.debug_struct("ServeOptions");
+        d.field("constant_time_etags", &self.constant_time_etags)
+            .field("if_none_match_comparison", &self.if_none_match_comparison)
+            .field("normalize_etags", &self.normalize_etags)
+            .field("permissive_if_range_dates", &self.permissive_if_range_dates)
+            .field("range_query_param", &self.range_query_param)
+            .field("weak_encoded_etags", &!self.strong_encoded_etags)
+            .field("omit_last_modified", &self.omit_last_modified)
+            .field("emit_etag", &!self.suppress_etag)
+            .field("emit_last_modified", &!self.suppress_last_modified)
+            .field("accept_ranges_always", &self.accept_ranges_always)
+            .field("verify_boundary", &self.verify_boundary)
+            .field("handle_options", &self.handle_options)
+            .field("success_status", &self.success_status)
+            .field("yield_every", &self.yield_every)
+            .field("error_trailer", &self.error_trailer);
+        #[cfg(feature = "content-md5")]
+        d.field("content_md5_trailer", &self.content_md5_trailer);
+        #[cfg(feature = "repr-digest-trailer")]
+        d.field("repr_digest_trailer", &self.repr_digest_trailer);
+        d.field("max_multipart_bytes", &self.max_multipart_bytes)
+            .field("always_multipart", &self.always_multipart)
+            .field("max_part_headers_bytes", &self.max_part_headers_bytes)
+            .field("max_entity_header_bytes", &self.max_entity_header_bytes)
+            .field("header_overflow", &self.header_overflow)
+            .field("multipart_fallback", &self.multipart_fallback)
+            .field("drain", &self.drain)
+            .field("report_bytes_sent", &self.report_bytes_sent)
+            .field("reject_ambiguous_framing", &self.reject_ambiguous_framing)
+            .field("max_content_length", &self.max_content_length.is_some())
+            .field("multirange_compat", &self.multirange_compat.is_some())
+            .field("clock", &self.clock.is_some())
+            .field("cache_policy", &self.cache_policy)
+            .field("vary", &self.vary)
+            .field("error_content_length", &self.error_content_length)
+            .field("probe_size", &self.max_probe_len())
+            .finish()
+    }
 }
 
-/// Runs trait object-based inner logic for `serve`.
-fn serve_inner<
-    D: 'static + Send + Sync + Buf + From<Vec<u8>> + From<&'static [u8]>,
-    E: 'static + Send + Sync,
-    B: Body + From<Box<dyn Stream<Item = Result<D, E>> + Send>>,
-    BI,
->(
-    ent: &dyn Entity<Error = E, Data = D>,
-    req: &Request<BI>,
-) -> ServeInner<B> {
-    if *req.method() != Method::GET && *req.method() != Method::HEAD {
-        return ServeInner::Simple(
-            Response::builder()
-                .status(StatusCode::METHOD_NOT_ALLOWED)
-                .header(header::ALLOW, HeaderValue::from_static("get, head"))
-                .body(static_body::<D, E>("This resource only supports GET and HEAD.").into())
-                .unwrap(),
-        );
+impl ServeOptions {
+    /// Returns the default options.
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    let last_modified = ent.last_modified();
-    let etag = ent.etag();
+    /// Sets whether to compare etags in constant time.
+    ///
+    /// When an etag encodes something secret-ish (such as a signed content version), a client
+    /// probing with `If-Match` or `If-None-Match` could otherwise learn how much of a guessed tag
+    /// was correct from response timing. This is rarely a concern for ordinary etags derived
+    /// from file metadata or content hashes, so it's off by default. Lengths are not hidden.
+    pub fn constant_time_etags(mut self, constant_time_etags: bool) -> Self {
+        self.constant_time_etags = constant_time_etags;
+        self
+    }
 
-    let (precondition_failed, not_modified) =
-        match parse_modified_hdrs(&etag, req.headers(), last_modified) {
-            Err(s) => {
-                return ServeInner::Simple(
-                    Response::builder()
-                        .status(StatusCode::BAD_REQUEST)
-                        .body(static_body::<D, E>(s).into())
-                        .unwrap(),
-                )
-            }
-            Ok(p) => p,
-        };
+    /// Sets how etags in `If-None-Match` are compared.
+    ///
+    /// By default, `serve` uses the weak comparison function, as [RFC 7232 section
+    /// 3.2](https://tools.ietf.org/html/rfc7232#section-3.2) requires, so a weak etag in the
+    /// request matches the entity's weak or strong etag of the same opaque tag.
+    /// `EtagComparison::StrongOnly` answers `304 Not Modified` only when both etags are strong
+    /// and identical, so entities with weak etags (including those weakened by
+    /// `weak_encoded_etags`) are always sent in full. `If-Match` and `If-Range` always use the
+    /// strong comparison function.
+    pub fn if_none_match_comparison(mut self, comparison: EtagComparison) -> Self {
+        self.if_none_match_comparison = comparison;
+        self
+    }
 
-    // See RFC 7233 section 4.1 <https://tools.ietf.org/html/rfc7233#section-4.1>: a Partial
-    // Content response should include other representation header fields (aka entity-headers in
-    // RFC 2616) iff the client didn't specify If-Range.
-    let mut range_hdr = req.headers().get(header::RANGE);
-    let include_entity_headers_on_range = match req.headers().get(header::IF_RANGE) {
-        Some(ref if_range) => {
-            let if_range = if_range.as_bytes();
-            if if_range.starts_with(b"W/\"") || if_range.starts_with(b"\"") {
-                // etag case.
-                if let Some(ref some_etag) = etag {
-                    if etag::strong_eq(if_range, some_etag.as_bytes()) {
-                        false
-                    } else {
-                        range_hdr = None;
-                        true
-                    }
-                } else {
-                    range_hdr = None;
-                    true
-                }
-            } else {
-                // Date case.
-                // Use the strong validation rules for an origin server:
-                // <https://tools.ietf.org/html/rfc7232#section-2.2.2>.
-                // The resource could have changed twice in the supplied second, so never match.
-                range_hdr = None;
-                true
-            }
-        }
-        None => true,
-    };
+    /// Sets whether to quote entity etags which lack quotes, such as raw hex digests.
+    ///
+    /// An `ETag` must be a quoted string, optionally prefixed with `W/`, and request headers list
+    /// etags in that form. An unquoted tag never matches them, so clients and caches which
+    /// quote it revalidate with `If-None-Match` in vain, and their `If-Match` requests fail. With
+    /// this set, a tag starting with neither `"` nor `W/"` is wrapped in quotes before it's sent
+    /// and before it's compared. Without it, debug builds with the `tracing` feature warn of
+    /// such tags. Defaults to false.
+    pub fn normalize_etags(mut self, normalize_etags: bool) -> Self {
+        self.normalize_etags = normalize_etags;
+        self
+    }
 
-    let mut res =
-        Response::builder().header(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
-    if let Some(m) = last_modified {
-        // See RFC 7232 section 2.2.1 <https://tools.ietf.org/html/rfc7232#section-2.2.1>: the
-        // Last-Modified must not exceed the Date. To guarantee this, set the Date now rather than
-        // let hyper set it.
-        let d = SystemTime::now();
-        res = res.header(header::DATE, &*fmt_http_date(d));
-        let clamped_m = std::cmp::min(m, d);
-        res = res.header(header::LAST_MODIFIED, &*fmt_http_date(clamped_m));
+    /// Sets whether an `If-Range` date may match the entity's last modified time.
+    ///
+    /// By default, `serve` never honors `Range` when `If-Range` holds a date, as the entity could
+    /// have changed twice within the date's second. When permissive, the date matches if it
+    /// exactly equals the entity's last modified time, that time has no sub-second component and
+    /// is at least a second in the past, and the entity has a strong etag.
+    pub fn permissive_if_range_dates(mut self, permissive_if_range_dates: bool) -> Self {
+        self.permissive_if_range_dates = permissive_if_range_dates;
+        self
     }
-    if let Some(e) = etag {
-        res = res.header(http::header::ETAG, e);
+
+    /// Sets a query parameter to read the range from when the request has no `Range` header,
+    /// for clients which can't set headers.
+    ///
+    /// With `range_query_param("range")`, a request for `/video?range=0-99` is served as if it
+    /// had `Range: bytes=0-99`. The value may also be a whole `Range` header value, such as
+    /// `bytes=0-99,200-299`; it's used verbatim, without percent-decoding, and otherwise handled
+    /// exactly like the header, including `If-Range`. By default, `serve` looks only at the
+    /// header.
+    pub fn range_query_param(mut self, name: &str) -> Self {
+        self.range_query_param = Some(name.to_owned());
+        self
     }
 
-    if precondition_failed {
-        res = res.status(StatusCode::PRECONDITION_FAILED);
-        return ServeInner::Simple(
-            res.body(static_body::<D, E>("Precondition failed").into())
-                .unwrap(),
-        );
+    /// Sets whether to weaken the `ETag` of content-coded responses.
+    ///
+    /// When `serve` sends a variant with a non-identity coding such as gzip, it emits its etag
+    /// with a `W/` prefix, signaling that the bytes may differ from other responses with the same
+    /// tag, as with encoders whose output isn't deterministic. Weak etags still validate with
+    /// `If-None-Match`, but never match `If-Match` or `If-Range`, so clients can't resume a
+    /// content-coded download. Entities whose variants are byte-for-byte stable can pass false to
+    /// send their etags as is. Defaults to true.
+    pub fn weak_encoded_etags(mut self, weak_encoded_etags: bool) -> Self {
+        self.strong_encoded_etags = !weak_encoded_etags;
+        self
     }
 
-    if not_modified {
-        res = res.status(StatusCode::NOT_MODIFIED);
-        return ServeInner::Simple(res.body(empty_body::<D, E>().into()).unwrap());
+    /// Sets whether to leave out `Last-Modified`, so as not to reveal the entity's modification
+    /// time.
+    ///
+    /// `serve` then treats the entity as if it had no last modified time: `If-Modified-Since` and
+    /// `If-Unmodified-Since` are ignored, and an `If-Range` date never matches, so these can't
+    /// be used to probe it either. Validation via `ETag` is unaffected. Defaults to false.
+    pub fn omit_last_modified(mut self, omit_last_modified: bool) -> Self {
+        self.omit_last_modified = omit_last_modified;
+        self
     }
 
-    let len = ent.len();
-    let (range, include_entity_headers) = match range::parse(range_hdr, len) {
-        range::ResolvedRanges::None => (0..len, true),
-        range::ResolvedRanges::Satisfiable(ranges) => {
-            if ranges.len() == 1 {
-                res = res.header(
-                    header::CONTENT_RANGE,
-                    unsafe_fmt_ascii_val!(
-                        MAX_DECIMAL_U64_BYTES * 3 + "bytes -/".len(),
-                        "bytes {}-{}/{}",
-                        ranges[0].start,
-                        ranges[0].end - 1,
-                        len
-                    ),
-                );
-                res = res.status(StatusCode::PARTIAL_CONTENT);
-                (ranges[0].clone(), include_entity_headers_on_range)
-            } else {
-                // Before serving multiple ranges via multipart/byteranges, estimate the total
-                // length. ("80" is the RFC's estimate of the size of each part's header.) If it's
-                // more than simply serving the whole entity, do that instead.
-                let est_len: u64 = ranges.iter().map(|r| 80 + r.end - r.start).sum();
-                if est_len < len {
-                    let (res, part_headers) = prepare_multipart(
-                        ent,
-                        res,
-                        &ranges[..],
-                        len,
-                        include_entity_headers_on_range,
-                    );
-                    if *req.method() == Method::HEAD {
-                        return ServeInner::Simple(res.body(empty_body::<D, E>().into()).unwrap());
-                    }
-                    return ServeInner::Multipart {
-                        res,
-                        part_headers,
-                        ranges,
-                    };
-                }
+    /// Sets whether to send the entity's `ETag` on responses.
+    ///
+    /// Unlike leaving the entity's etag out, this affects only the response header: `If-Match`,
+    /// `If-None-Match`, and `If-Range` are still evaluated against the entity's etag. This suits
+    /// a CDN which assigns its own etags, while clients which cached responses before it was
+    /// introduced still revalidate. Defaults to true.
+    pub fn emit_etag(mut self, emit_etag: bool) -> Self {
+        self.suppress_etag = !emit_etag;
+        self
+    }
 
-                (0..len, true)
-            }
+    /// Sets whether to send the entity's `Last-Modified` on responses.
+    ///
+    /// As with `emit_etag`, conditional requests are still evaluated against the entity's last
+    /// modified time; see `omit_last_modified` to ignore it entirely. Defaults to true.
+    pub fn emit_last_modified(mut self, emit_last_modified: bool) -> Self {
+        self.suppress_last_modified = !emit_last_modified;
+        self
+    }
+
+    /// Sets whether to send `Accept-Ranges: bytes` on every response.
+    ///
+    /// By default, it's sent on responses concerning the entity (`200`, `206`, `304`, `412`, and
+    /// `416`) but not on those rejecting the request itself (`400`, `405`, and `413`). Clients
+    /// probing for range support with arbitrary requests may prefer it everywhere.
+    pub fn accept_ranges_always(mut self, accept_ranges_always: bool) -> Self {
+        self.accept_ranges_always = accept_ranges_always;
+        self
+    }
+
+    /// Sets whether to verify that the `multipart/byteranges` boundary doesn't occur in the
+    /// served ranges.
+    ///
+    /// Each `multipart/byteranges` response gets a random boundary. When this is set, `serve`
+    /// also asks the entity (via `Entity::contains`) whether the boundary's delimiter occurs in
+    /// any range, generating a fresh random boundary on collision, up to a bounded number of
+    /// attempts. If every attempt collides, the request is answered as configured by
+    /// [`multipart_fallback`](#method.multipart_fallback). This is best-effort: it's skipped for
+    /// entities which can't answer cheaply, such as those streamed from elsewhere. Defaults to
+    /// false. Has no effect without the `multipart` feature.
+    pub fn verify_boundary(mut self, verify_boundary: bool) -> Self {
+        self.verify_boundary = verify_boundary;
+        self
+    }
+
+    /// Sets whether to answer `OPTIONS` requests.
+    ///
+    /// When set, `OPTIONS` gets `204 No Content`, and both it and the `405 Method Not Allowed`
+    /// sent for other unsupported methods list `GET, HEAD, OPTIONS` in `Allow`. By default,
+    /// `OPTIONS` is rejected like any other method other than `GET` and `HEAD`.
+    pub fn handle_options(mut self, handle_options: bool) -> Self {
+        self.handle_options = handle_options;
+        self
+    }
+
+    /// Sets the status of responses which would otherwise be `200 OK`, such as `203
+    /// Non-Authoritative Information`.
+    ///
+    /// Other statuses, such as `206 Partial Content` or `304 Not Modified`, are unaffected.
+    ///
+    /// Panics if `status` isn't a success (`2xx`) status.
+    pub fn success_status(mut self, status: StatusCode) -> Self {
+        assert!(status.is_success(), "{} is not a success status", status);
+        self.success_status = Some(status);
+        self
+    }
+
+    /// Makes randomized choices, such as the `multipart/byteranges` boundary, deterministic.
+    ///
+    /// Each response starts a generator afresh from `seed`, so responses served with options of
+    /// the same seed (including the same options, or clones of them) are identical, and
+    /// golden-output tests can compare bodies exactly. By default, each response's generator is
+    /// seeded from a process-wide generator seeded from the OS. Only available with the
+    /// `test-support` and `multipart` features.
+    #[cfg(all(feature = "multipart", any(test, feature = "test-support")))]
+    pub fn rng_seed(mut self, seed: u64) -> Self {
+        self.rng_seed = Some(seed);
+        self
+    }
+
+    /// Sets the body stream to yield to the executor after every `bytes` bytes.
+    ///
+    /// An entity whose body stream is always ready, such as one held in memory, can otherwise
+    /// occupy the executor thread for the whole of a large response, starving other connections
+    /// on single-threaded runtimes. By default, or with `bytes` of 0, `serve` never yields.
+    pub fn yield_every(mut self, bytes: u64) -> Self {
+        self.yield_every = if bytes == 0 { None } else { Some(bytes) };
+        self
+    }
+
+    /// Sets a trailer to end the body with, in place of a stream error, on HTTP/2.
+    ///
+    /// Ordinarily an error from the entity's stream resets the HTTP/2 stream, leaving the client
+    /// to guess why. With this set, [`serve_with_trailers`](fn.serve_with_trailers.html) instead
+    /// ends the body early with this trailer holding a short class of the error: `io` for
+    /// `std::io::Error`s, or `internal`. As the body may then fall short of its length, these
+    /// responses omit `Content-Length`; clients must check for the trailer to detect truncation.
+    ///
+    /// hyper doesn't send trailers on HTTP/1.1, so there errors drop the connection as usual.
+    /// Other entry points, whose body types can't carry trailers, ignore this option.
+    pub fn error_trailer(mut self, name: HeaderName) -> Self {
+        self.error_trailer = Some(name);
+        self
+    }
+
+    /// Sets whether to end entity bodies with a legacy `Content-MD5` trailer, for old clients
+    /// which check transfer integrity with it.
+    ///
+    /// This applies only to [`serve_with_trailers`](fn.serve_with_trailers.html), and as with
+    /// `error_trailer`, only on HTTP/2. `200` and `206` responses to `GET` then carry a `Trailer:
+    /// content-md5` header (though hyper 0.13 drops it from HTTP/2 responses), and the digest is
+    /// computed as the body streams; see `TrailerBody::with_content_md5`. For a
+    /// `multipart/byteranges` response, it covers the whole multipart body. Defaults to false.
+    ///
+    /// Available with the `content-md5` feature.
+    #[cfg(feature = "content-md5")]
+    pub fn content_md5_trailer(mut self, content_md5_trailer: bool) -> Self {
+        self.content_md5_trailer = content_md5_trailer;
+        self
+    }
+
+    /// Sets whether to end full entity bodies with a `Repr-Digest` trailer, for entities too large
+    /// to digest up front.
+    ///
+    /// This applies only to [`serve_with_trailers`](fn.serve_with_trailers.html), and as with
+    /// `error_trailer`, only on HTTP/2. Responses to `GET` which send the whole entity then carry
+    /// a `Trailer: repr-digest` header, and its SHA-256 digest is computed as the body streams;
+    /// see `TrailerBody::with_repr_digest`. A partial response's content isn't the whole
+    /// representation, so gets no such trailer, and neither does an entity which supplies its
+    /// own [`digest`](trait.Entity.html#method.digest). Defaults to false.
+    ///
+    /// Available with the `repr-digest-trailer` feature.
+    #[cfg(feature = "repr-digest-trailer")]
+    pub fn repr_digest_trailer(mut self, repr_digest_trailer: bool) -> Self {
+        self.repr_digest_trailer = repr_digest_trailer;
+        self
+    }
+
+    /// Sets a function returning the most bytes the client will accept, given the request headers.
+    ///
+    /// This supports clients which declare such a limit, typically in a non-standard header. If
+    /// the function returns `Some(max)` and the response's `Content-Length` would exceed `max`,
+    /// `serve` responds `413 Payload Too Large` instead. By default, there's no limit.
+    ///
+    /// Responses from [`Entity::get_custom_range`](trait.Entity.html#method.get_custom_range) are
+    /// exempt, as their length in bytes isn't known in advance.
+    pub fn max_content_length<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&HeaderMap) -> Option<u64> + Send + Sync + 'static,
+    {
+        self.max_content_length = Some(Arc::new(f));
+        self
+    }
+
+    /// Sets a function choosing how to answer a request for multiple ranges, given the request
+    /// headers.
+    ///
+    /// Some clients (notably embedded ones) request multiple ranges but can't parse
+    /// `multipart/byteranges`, expecting the ranges' bytes concatenated. This lets the caller
+    /// recognize them, such as by `User-Agent`, and send them something they can handle. The
+    /// function is called only for requests with more than one satisfiable range, before deciding
+    /// whether to send multipart. By default, every such request gets
+    /// `MultirangeMode::Multipart`.
+    pub fn multirange_compat<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&HeaderMap) -> MultirangeMode + Send + Sync + 'static,
+    {
+        self.multirange_compat = Some(Arc::new(f));
+        self
+    }
+
+    /// Sets the clock for the `Date` header and comparisons against the current time.
+    ///
+    /// `serve` sets the `Date` only on responses concerning an entity with a last modified time,
+    /// so that the `Last-Modified` can be bounded by it, and otherwise leaves it to hyper. By
+    /// default, it uses the system clock, wrapped in a process-wide `MonotonicClock` so that the
+    /// `Date` never goes backwards. A clock supplied here is used as is; wrap it in a
+    /// `MonotonicClock` for the same guarantee.
+    pub fn clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Some(Arc::new(clock));
+        self
+    }
+
+    /// Sets a `Cache-Control` header to send on `200 OK`, `206 Partial Content`, and `304 Not
+    /// Modified` responses. By default, none is sent.
+    pub fn cache_policy(mut self, policy: CachePolicy) -> Self {
+        self.cache_policy = Some(policy);
+        self
+    }
+
+    /// Sets request header names to list in a `Vary` header, whether or not the response was
+    /// negotiated on them, such as `Accept-Language` for an entity chosen by language before
+    /// `serve` was called. By default, `Vary` lists only `Accept-Encoding`, and only when the
+    /// entity has content-coded variants.
+    ///
+    /// The names are merged with `Accept-Encoding` when that applies, and sent on the responses
+    /// which concern the entity (`200`, `206`, `304`, `412`, and `416`) rather than on those
+    /// rejecting the request outright.
+    pub fn vary(mut self, names: &[HeaderName]) -> Self {
+        self.vary = names.to_vec();
+        self
+    }
+
+    /// Sets whether error responses (`400`, `405`, `412`, `413`, `416`, and `503`) carry an
+    /// explicit `Content-Length` for their short text bodies, including `Content-Length: 0` for
+    /// empty ones, for intermediaries which insist on one. `204` and `304` responses never do.
+    /// Defaults to false, leaving the framing to hyper.
+    pub fn error_content_length(mut self, error_content_length: bool) -> Self {
+        self.error_content_length = error_content_length;
+        self
+    }
+
+    /// Sets whether to reject `GET` and `HEAD` requests with ambiguous message framing.
+    ///
+    /// A request carrying both `Content-Length` and `Transfer-Encoding`, or more than one
+    /// `Content-Length` value, may be framed differently by a proxy than by this server, which is
+    /// the basis of request smuggling. `serve` ignores request bodies, but when this is set, it
+    /// responds `400 Bad Request` to such requests rather than serving them. Defaults to false.
+    pub fn reject_ambiguous_framing(mut self, reject_ambiguous_framing: bool) -> Self {
+        self.reject_ambiguous_framing = reject_ambiguous_framing;
+        self
+    }
+
+    /// Sets the largest single byte range treated as a probe, such as the `Range: bytes=0-1` a
+    /// media player sends to learn the length and whether ranges are supported before seeking.
+    ///
+    /// A probe is answered like any other `206 Partial Content`, with its `Content-Range` giving
+    /// the full length, but is marked `ServeDisposition::Probe` so such requests can be counted
+    /// apart. If the entity's [`get_bytes`](trait.Entity.html#method.get_bytes) has the bytes at
+    /// hand, they're embedded in the body directly rather than read with `get_range`. Defaults
+    /// to 2; 0 disables.
+    ///
+    /// Only the range's size is considered, so any genuine read of at most this many bytes, such
+    /// as `Range: bytes=-1` for a file's last byte, is also marked `Probe`. Set this to 0 if
+    /// such reads must be told apart from probes.
+    pub fn probe_size(mut self, probe_size: u64) -> Self {
+        self.probe_size = Some(probe_size);
+        self
+    }
+
+    /// Sets the most bytes a `multipart/byteranges` response may send, including part headers and
+    /// boundaries.
+    ///
+    /// A multiple-range request whose response would exceed this is handled as configured by
+    /// [`multipart_fallback`](#method.multipart_fallback). This applies regardless of the
+    /// entity's length; independently, `serve` always sends the whole entity when a multipart
+    /// response would likely be longer. By default, there's no limit. Has no effect without the
+    /// `multipart` feature.
+    pub fn max_multipart_bytes(mut self, max: u64) -> Self {
+        self.max_multipart_bytes = Some(max);
+        self
+    }
+
+    /// Sets whether to answer multiple-range requests with `multipart/byteranges` even when that
+    /// would be longer than the whole entity.
+    ///
+    /// By default, `serve` sends the whole entity as `200 OK` when the multipart response would
+    /// likely be longer, which is allowed but can surprise clients which strictly expect
+    /// `206 Partial Content`. `max_multipart_bytes` still applies. Defaults to false. Has no
+    /// effect without the `multipart` feature.
+    pub fn always_multipart(mut self, always_multipart: bool) -> Self {
+        self.always_multipart = always_multipart;
+        self
+    }
+
+    /// Sets the most bytes of entity headers to repeat in each part of a `multipart/byteranges`
+    /// response, counting each as a `name: value` line.
+    ///
+    /// Each part carries the entity's headers, so an entity with a large header block (such as a
+    /// long `Link` or `Content-Disposition`) amplifies the response by that much per range. When
+    /// the headers exceed this, parts carry only `Content-Type` and `Content-Encoding`; when even
+    /// those do, the request is handled as configured by
+    /// [`multipart_fallback`](#method.multipart_fallback). By default, there's no limit. Has no
+    /// effect without the `multipart` feature.
+    pub fn max_part_headers_bytes(mut self, max: usize) -> Self {
+        self.max_part_headers_bytes = Some(max);
+        self
+    }
+
+    /// Sets the most bytes of headers the entity may add to a response, counting each as a
+    /// `name: value` line.
+    ///
+    /// Headers fed from user data (such as `Content-Disposition` filenames or CMS-supplied `Link`
+    /// headers) can grow past the limits of proxies, often 8 to 16 KiB, which then fail the
+    /// response with a hard-to-diagnose `502`. Over this limit, `serve` acts as configured by
+    /// [`header_overflow`](#method.header_overflow). This applies to the response's headers and
+    /// to those of each `multipart/byteranges` part. By default, there's no limit.
+    pub fn max_entity_header_bytes(mut self, max: usize) -> Self {
+        self.max_entity_header_bytes = Some(max);
+        self
+    }
+
+    /// Sets what to do when the entity's headers exceed
+    /// [`max_entity_header_bytes`](#method.max_entity_header_bytes). Defaults to
+    /// `HeaderOverflow::Truncate`.
+    pub fn header_overflow(mut self, header_overflow: HeaderOverflow) -> Self {
+        self.header_overflow = header_overflow;
+        self
+    }
+
+    /// Sets the response to a multiple-range request exceeding
+    /// [`max_multipart_bytes`](#method.max_multipart_bytes) or
+    /// [`max_part_headers_bytes`](#method.max_part_headers_bytes), or with no boundary passing
+    /// [`verify_boundary`](#method.verify_boundary). Defaults to
+    /// `MultipartFallback::Full`. Has no effect without the `multipart` feature.
+    pub fn multipart_fallback(mut self, fallback: MultipartFallback) -> Self {
+        self.multipart_fallback = fallback;
+        self
+    }
+
+    /// Sets a watch to track response bodies for graceful shutdown.
+    ///
+    /// Bodies of `200` and `206` responses are tracked until they finish or are dropped. Once the
+    /// watch starts draining, `serve` responds `503 Service Unavailable` to every request, and
+    /// bodies still unfinished at the end of its grace period are cut off. See the
+    /// [`drain`](drain/index.html) module.
+    pub fn drain(mut self, watch: Watch) -> Self {
+        self.drain = Some(watch);
+        self
+    }
+
+    /// Sets whether to attach a [`BytesSent`](struct.BytesSent.html) extension to responses with
+    /// a body, reporting how many body bytes were sent once the body ends.
+    ///
+    /// Like [`ServedRanges`](struct.ServedRanges.html), this is attached to `200` and `206`
+    /// responses to `GET` requests; comparing the two tells whether a client disconnected early.
+    /// Defaults to false.
+    pub fn report_bytes_sent(mut self, report_bytes_sent: bool) -> Self {
+        self.report_bytes_sent = report_bytes_sent;
+        self
+    }
+
+    /// Returns the `Allow` header value and `405 Method Not Allowed` body for these options.
+    fn allowed_methods(&self) -> (HeaderValue, &'static str) {
+        if self.handle_options {
+            (
+                HeaderValue::from_static("GET, HEAD, OPTIONS"),
+                "This resource only supports GET, HEAD, and OPTIONS.",
+            )
+        } else {
+            (
+                HeaderValue::from_static("GET, HEAD"),
+                "This resource only supports GET and HEAD.",
+            )
         }
-        range::ResolvedRanges::NotSatisfiable => {
-            res = res.header(
-                http::header::CONTENT_RANGE,
-                unsafe_fmt_ascii_val!(MAX_DECIMAL_U64_BYTES + "bytes */".len(), "bytes */{}", len),
-            );
-            res = res.status(StatusCode::RANGE_NOT_SATISFIABLE);
-            return ServeInner::Simple(res.body(empty_body::<D, E>().into()).unwrap());
+    }
+
+    /// Returns the current time, from the configured clock.
+    fn now(&self) -> SystemTime {
+        match self.clock {
+            Some(ref c) => c.now(),
+            None => DEFAULT_CLOCK.now(),
         }
-    };
-    res = res.header(
-        header::CONTENT_LENGTH,
-        unsafe_fmt_ascii_val!(MAX_DECIMAL_U64_BYTES, "{}", range.end - range.start),
-    );
-    let body = match *req.method() {
-        Method::HEAD => empty_body::<D, E>(),
-        _ => ent.get_range(range),
-    };
-    let mut res = res.body(body.into()).unwrap();
-    if include_entity_headers {
-        ent.add_headers(res.headers_mut());
     }
-    ServeInner::Simple(res)
-}
 
-/// A body for use in the "stream of streams" (see `prepare_multipart` and its call site).
-/// This avoids an extra allocation for the part headers and overall trailer.
-enum InnerBody<D, E> {
-    Once(Option<D>),
-    B(Pin<Box<dyn Stream<Item = Result<D, E>> + Sync + Send>>),
-}
+    fn max_probe_len(&self) -> u64 {
+        self.probe_size.unwrap_or(DEFAULT_PROBE_SIZE)
+    }
 
-impl<D, E> Stream for InnerBody<D, E> {
-    type Item = Result<D, E>;
-    fn poll_next(
-        self: Pin<&mut Self>,
-        ctx: &mut futures::task::Context,
-    ) -> std::task::Poll<Option<Result<D, E>>> {
-        // This is safe because the fields are not structurally pinned.
-        // https://doc.rust-lang.org/std/pin/#pinning-is-not-structural-for-field
-        // (In the B variant, the field _holds_ a pin, but it isn't itself pinned.)
-        match unsafe { self.get_unchecked_mut() } {
-            InnerBody::Once(ref mut o) => std::task::Poll::Ready(o.take().map(|d| Ok(d))),
-            InnerBody::B(b) => b.as_mut().poll_next(ctx),
+    /// Returns the `Vary` value for a response: the names set by `vary`, plus `accept-encoding`
+    /// if the response was negotiated on it.
+    fn vary_value(&self, negotiated: bool) -> Option<HeaderValue> {
+        if !negotiated && self.vary.is_empty() {
+            return None;
         }
+        let mut names: Vec<&str> = Vec::with_capacity(self.vary.len() + 1);
+        if negotiated {
+            names.push("accept-encoding");
+        }
+        for n in &self.vary {
+            if !names.contains(&n.as_str()) {
+                names.push(n.as_str());
+            }
+        }
+        Some(HeaderValue::from_str(&names.join(", ")).expect("header names are valid values"))
     }
-}
 
-/// Prepares to send a `multipart/mixed` response.
-/// Returns the response builder (with overall headers added) and each part's headers.
-fn prepare_multipart<D, E>(
-    ent: &dyn Entity<Data = D, Error = E>,
-    mut res: http::response::Builder,
-    ranges: &[Range<u64>],
-    len: u64,
-    include_entity_headers: bool,
-) -> (http::response::Builder, Vec<Vec<u8>>)
-where
-    D: 'static + Send + Sync + Buf + From<Vec<u8>> + From<&'static [u8]>,
-    E: 'static + Send + Sync,
-{
-    let mut each_part_headers = Vec::new();
-    if include_entity_headers {
-        let mut h = http::header::HeaderMap::new();
-        ent.add_headers(&mut h);
-        each_part_headers.reserve(
-            h.iter()
-                .map(|(k, v)| k.as_str().len() + v.as_bytes().len() + 4)
-                .sum::<usize>()
-                + 2,
-        );
-        for (k, v) in &h {
-            each_part_headers.extend_from_slice(k.as_str().as_bytes());
-            each_part_headers.extend_from_slice(b": ");
-            each_part_headers.extend_from_slice(v.as_bytes());
-            each_part_headers.extend_from_slice(b"\r\n");
-        }
-    }
-    each_part_headers.extend_from_slice(b"\r\n");
-
-    let mut body_len = 0;
-    let mut part_headers: Vec<Vec<u8>> = Vec::with_capacity(2 * ranges.len() + 1);
-    for r in ranges {
-        let mut buf = Vec::with_capacity(64 + each_part_headers.len());
-        write!(
-            &mut buf,
-            "\r\n--B\r\nContent-Range: bytes {}-{}/{}\r\n",
-            r.start,
-            r.end - 1,
-            len
-        )
-        .unwrap();
-        buf.extend_from_slice(&each_part_headers);
-        body_len += buf.len() as u64 + r.end - r.start;
-        part_headers.push(buf);
+    /// Returns a random number generator for one response, as configured by `rng_seed`.
+    #[cfg(feature = "multipart")]
+    pub(crate) fn rng(&self) -> SplitMix {
+        SplitMix::new(match self.rng_seed {
+            Some(seed) => seed,
+            None => default_rng().next_u64(),
+        })
     }
-    body_len += PART_TRAILER.len() as u64;
 
-    res = res.header(
-        header::CONTENT_LENGTH,
-        unsafe_fmt_ascii_val!(MAX_DECIMAL_U64_BYTES, "{}", body_len),
-    );
-    res = res.header(
-        header::CONTENT_TYPE,
-        HeaderValue::from_static("multipart/byteranges; boundary=B"),
-    );
-    res = res.status(StatusCode::PARTIAL_CONTENT);
+    /// Returns true if a body of `len` bytes exceeds the client's limit.
+    fn exceeds_max_content_length(&self, req_hdrs: &HeaderMap, len: u64) -> bool {
+        match self.max_content_length {
+            Some(ref f) => f(req_hdrs).map(|max| len > max).unwrap_or(false),
+            None => false,
+        }
+    }
+}
 
-    (res, part_headers)
+/// A `Cache-Control` policy, for
+/// [`ServeOptions::cache_policy`](struct.ServeOptions.html#method.cache_policy).
+///
+/// Responses which vary by the client's identity, such as by its `Authorization` or session
+/// cookie, should be `private`, so that shared caches don't hand one user's content to another.
+#[derive(Clone)]
+pub struct CachePolicy {
+    max_age: Duration,
+    private: CachePrivacy,
+    immutable: bool,
 }
 
-/// The trailer after all `multipart/byteranges` body parts.
-const PART_TRAILER: &[u8] = b"\r\n--B--\r\n";
+#[derive(Clone)]
+enum CachePrivacy {
+    Public,
+    Private,
+    PrivateIf(Arc<dyn Fn(&HeaderMap) -> bool + Send + Sync>),
+}
 
-/// Produces a single chunk of the body and the following state, for use in an `unfold` call.
-///
-/// Alternates between portions of `part_headers` and their corresponding bodies, then the overall
-/// trailer, then end the stream.
-fn next_multipart_body_chunk<D, E>(
-    state: usize,
-    ent: &dyn Entity<Data = D, Error = E>,
-    ranges: &[Range<u64>],
-    part_headers: &mut [Vec<u8>],
-) -> impl Future<Output = Option<(InnerBody<D, E>, usize)>>
-where
-    D: 'static + Send + Sync + Buf + From<Vec<u8>> + From<&'static [u8]>,
-    E: 'static + Send + Sync,
-{
-    let i = state >> 1;
-    let odd = (state & 1) == 1;
-    let body = if i == ranges.len() && odd {
-        return futures::future::ready(None);
-    } else if i == ranges.len() {
-        InnerBody::Once(Some(PART_TRAILER.into()))
-    } else if odd {
-        InnerBody::B(Pin::from(ent.get_range(ranges[i].clone())))
+impl CachePolicy {
+    /// Sends `Cache-Control: public, max-age=<max_age>`.
+    pub fn public(max_age: Duration) -> Self {
+        CachePolicy {
+            max_age,
+            private: CachePrivacy::Public,
+            immutable: false,
+        }
+    }
+
+    /// Sends `Cache-Control: public, max-age=31536000, immutable`, for responses which can
+    /// never change, such as those at content-addressed URLs. Browsers then skip revalidating
+    /// them even on reload. See [RFC 8246](https://tools.ietf.org/html/rfc8246).
+    pub fn immutable() -> Self {
+        CachePolicy {
+            max_age: Duration::from_secs(31_536_000),
+            private: CachePrivacy::Public,
+            immutable: true,
+        }
+    }
+
+    /// Sends `Cache-Control: private, max-age=<max_age>`.
+    pub fn private(max_age: Duration) -> Self {
+        CachePolicy {
+            max_age,
+            private: CachePrivacy::Private,
+            immutable: false,
+        }
+    }
+
+    /// Sends `private` if `f` returns true for the request headers and `public` otherwise, with
+    /// `max-age=<max_age>` either way.
+    pub fn private_if<F>(max_age: Duration, f: F) -> Self
+    where
+        F: Fn(&HeaderMap) -> bool + Send + Sync + 'static,
+    {
+        CachePolicy {
+            max_age,
+            private: CachePrivacy::PrivateIf(Arc::new(f)),
+            immutable: false,
+        }
+    }
+
+    /// Returns the `Cache-Control` value for a request with the given headers.
+    fn header_value(&self, req_hdrs: &HeaderMap) -> HeaderValue {
+        let private = match self.private {
+            CachePrivacy::Public => false,
+            CachePrivacy::Private => true,
+            CachePrivacy::PrivateIf(ref f) => f(req_hdrs),
+        };
+        let scope = if private { "private" } else { "public" };
+        let immutable = if self.immutable { ", immutable" } else { "" };
+        HeaderValue::from_str(&format!(
+            "{}, max-age={}{}",
+            scope,
+            self.max_age.as_secs(),
+            immutable
+        ))
+        .expect("cache-control is a valid header value")
+    }
+}
+
+impl std::fmt::Debug for CachePolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let private = match self.private {
+            CachePrivacy::Public => "public",
+            CachePrivacy::Private => "private",
+            CachePrivacy::PrivateIf(_) => "private_if",
+        };
+        f.debug_struct("CachePolicy")
+            .field("max_age", &self.max_age)
+            .field("private", &private)
+            .field("immutable", &self.immutable)
+            .finish()
+    }
+}
+
+/// The response to a multiple-range request whose `multipart/byteranges` response would exceed
+/// [`ServeOptions::max_multipart_bytes`](struct.ServeOptions.html#method.max_multipart_bytes) or
+/// [`ServeOptions::max_part_headers_bytes`](struct.ServeOptions.html#method.max_part_headers_bytes),
+/// or for which no boundary passes
+/// [`ServeOptions::verify_boundary`](struct.ServeOptions.html#method.verify_boundary).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum MultipartFallback {
+    /// The whole entity, as if the request had no `Range` header.
+    Full,
+
+    /// The whole entity if it's at most this many bytes, otherwise `416 Range Not Satisfiable`.
+    FullUpTo(u64),
+
+    /// `416 Range Not Satisfiable`.
+    RangeNotSatisfiable,
+}
+
+impl MultipartFallback {
+    /// Returns true if the whole entity of `len` bytes may be sent.
+    #[cfg(feature = "multipart")]
+    fn allows_full(self, len: u64) -> bool {
+        match self {
+            MultipartFallback::Full => true,
+            MultipartFallback::FullUpTo(max) => len <= max,
+            MultipartFallback::RangeNotSatisfiable => false,
+        }
+    }
+}
+
+impl Default for MultipartFallback {
+    fn default() -> Self {
+        MultipartFallback::Full
+    }
+}
+
+/// How to answer a request for multiple ranges, as chosen by
+/// [`ServeOptions::multirange_compat`](struct.ServeOptions.html#method.multirange_compat).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum MultirangeMode {
+    /// A `multipart/byteranges` response, subject to the other options (or without the
+    /// `multipart` feature, the whole entity).
+    Multipart,
+
+    /// The whole entity, as if the request had no `Range` header.
+    FullEntity,
+
+    /// Only the first satisfiable range, in the order requested, as a single-range `206 Partial
+    /// Content`. The rest are ignored.
+    FirstRangeOnly,
+}
+
+impl Default for MultirangeMode {
+    fn default() -> Self {
+        MultirangeMode::Multipart
+    }
+}
+
+/// What to do when an entity's headers exceed
+/// [`ServeOptions::max_entity_header_bytes`](struct.ServeOptions.html#method.max_entity_header_bytes).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum HeaderOverflow {
+    /// Send as many headers as fit, in the entity's order, and leave out the rest.
+    /// `Content-Type` and `Content-Encoding` are always sent. The response carries a
+    /// [`DroppedHeaders`](struct.DroppedHeaders.html) extension naming those left out, and with
+    /// the `tracing` feature, a warning is logged.
+    Truncate,
+
+    /// Respond with `500 Internal Server Error` in place of any response carrying the entity's
+    /// headers.
+    InternalServerError,
+}
+
+impl Default for HeaderOverflow {
+    fn default() -> Self {
+        HeaderOverflow::Truncate
+    }
+}
+
+/// The entity headers `serve` left out of a response under `HeaderOverflow::Truncate`, as a
+/// response extension.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DroppedHeaders {
+    names: Vec<HeaderName>,
+}
+
+impl DroppedHeaders {
+    /// Returns the names of headers with at least one value left out, in the entity's order.
+    pub fn names(&self) -> &[HeaderName] {
+        &self.names[..]
+    }
+}
+
+/// The byte ranges of the entity committed to by a response, as a response extension.
+///
+/// `serve` attaches this to `200 OK` and `206 Partial Content` responses to `GET` requests before
+/// any of the body has been streamed, so callers can log or account for the response even if the
+/// client disconnects midway. It's absent on other responses, such as `304 Not Modified` or
+/// `416 Range Not Satisfiable`, on responses to `HEAD` requests, and on custom-unit responses
+/// from `Entity::get_custom_range`, whose ranges aren't in bytes.
+///
+/// ```
+/// # fn f(resp: http::Response<hyper::Body>) {
+/// if let Some(served) = resp.extensions().get::<http_serve::ServedRanges>() {
+///     println!("serving {:?} ({} bytes)", served.ranges(), served.body_len());
+/// }
+/// # }
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ServedRanges {
+    ranges: SmallVec<[Range<u64>; 1]>,
+    body_len: u64,
+    boundary: Option<String>,
+}
+
+impl ServedRanges {
+    /// Returns the ranges of the entity to be sent, in order. A full `200 OK` response has the
+    /// single range `0..len`.
+    pub fn ranges(&self) -> &[Range<u64>] {
+        &self.ranges[..]
+    }
+
+    /// Returns the length of the response body, as sent in `Content-Length`. For a
+    /// `multipart/byteranges` response, this includes the part headers and boundaries.
+    pub fn body_len(&self) -> u64 {
+        self.body_len
+    }
+
+    /// Returns the boundary of a `multipart/byteranges` response, as in its `Content-Type`, for
+    /// splitting the body into parts. It's `None` for a single range.
+    pub fn boundary(&self) -> Option<&str> {
+        self.boundary.as_ref().map(|b| &b[..])
+    }
+}
+
+/// How `serve` decided to respond, as a response extension.
+///
+/// `serve` attaches this to every response it builds. Building a response has no side effects:
+/// the entity's metadata (length, validators, variants) is consulted, but `get_range` isn't called
+/// until the body is first polled. So a caller can inspect the disposition and substitute an
+/// entirely different response, such as a redirect in place of a `412 Precondition Failed`,
+/// simply by dropping the original; see [`replace_if`](fn.replace_if.html).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum ServeDisposition {
+    /// The whole entity, as `200 OK` or the configured success status.
+    Full,
+
+    /// A single range, as `206 Partial Content`.
+    Partial,
+
+    /// A single range no longer than `ServeOptions::probe_size`, as `206 Partial Content`.
+    Probe,
+
+    /// Several ranges, as a `206 Partial Content` with a `multipart/byteranges` body. Only
+    /// with the `multipart` feature.
+    Multipart,
+
+    /// `304 Not Modified`.
+    NotModified,
+
+    /// `412 Precondition Failed`.
+    PreconditionFailed,
+
+    /// `416 Range Not Satisfiable`.
+    RangeNotSatisfiable,
+
+    /// `405 Method Not Allowed`.
+    MethodNotAllowed,
+
+    /// `204 No Content` in response to `OPTIONS`, as enabled by `ServeOptions::handle_options`.
+    Options,
+
+    /// `400 Bad Request`, for malformed conditional request headers.
+    BadRequest,
+
+    /// `413 Payload Too Large`, as configured by `ServeOptions::max_content_length`.
+    PayloadTooLarge,
+
+    /// `503 Service Unavailable`, as the watch set by `ServeOptions::drain` is draining.
+    Unavailable,
+
+    /// `500 Internal Server Error`, as the entity's headers exceeded
+    /// `ServeOptions::max_entity_header_bytes` under `HeaderOverflow::InternalServerError`.
+    HeadersTooLarge,
+}
+
+impl ServeDisposition {
+    /// Returns the disposition of a response from `serve`, or `None` for any other response.
+    pub fn of<B>(res: &Response<B>) -> Option<Self> {
+        res.extensions().get::<ServeDisposition>().copied()
+    }
+}
+
+/// Replaces a response from `serve` with the one `f` returns, if any, given its disposition.
+///
+/// The original response is dropped without its body having been polled, so the entity is never
+/// read.
+///
+/// ```
+/// # use http::{header, Response, StatusCode};
+/// # use http_serve::ServeDisposition;
+/// # fn f(resp: Response<hyper::Body>) -> Response<hyper::Body> {
+/// http_serve::replace_if(resp, |d| match d {
+///     ServeDisposition::PreconditionFailed => Some(
+///         Response::builder()
+///             .status(StatusCode::SEE_OTHER)
+///             .header(header::LOCATION, "/reauth")
+///             .body(hyper::Body::empty())
+///             .unwrap(),
+///     ),
+///     _ => None,
+/// })
+/// # }
+/// ```
+pub fn replace_if<B, F>(res: Response<B>, f: F) -> Response<B>
+where
+    F: FnOnce(ServeDisposition) -> Option<Response<B>>,
+{
+    match ServeDisposition::of(&res).and_then(f) {
+        Some(replacement) => replacement,
+        None => res,
+    }
+}
+
+/// Serves GET and HEAD requests for a given byte-ranged entity.
+/// Handles conditional & subrange requests.
+/// The caller is expected to have already determined the correct entity and appended
+/// `Expires`, `Cache-Control`, and `Vary` headers if desired.
+///
+/// A request for several ranges gets a `multipart/byteranges` response, or the whole entity if
+/// that would likely be shorter. Without the `multipart` feature (on by default), which minimal
+/// builds may disable to drop that code, it always gets the whole entity.
+///
+/// If the entity offers content-coded [variants](trait.Entity.html#method.variants), the one
+/// best matching the request's `Accept-Encoding` is served.
+///
+/// `304 Not Modified` and `412 Precondition Failed` responses carry the entity's current `ETag`
+/// and `Last-Modified`, so a client whose validators went stale (for example, because a file was
+/// replaced between its `HEAD` and `GET`) can retry with them.
+pub fn serve<
+    Ent: Entity,
+    B: Body + From<Box<dyn Stream<Item = Result<Ent::Data, Ent::Error>> + Send>>,
+    BI,
+>(
+    entity: Ent,
+    req: &Request<BI>,
+) -> Response<B> {
+    serve_with_options(entity, req, &ServeOptions::default())
+}
+
+/// Serves GET and HEAD requests for a given byte-ranged entity, as
+/// [`serve`](fn.serve.html) does, with non-default options.
+pub fn serve_with_options<
+    Ent: Entity,
+    B: Body + From<Box<dyn Stream<Item = Result<Ent::Data, Ent::Error>> + Send>>,
+    BI,
+>(
+    entity: Ent,
+    req: &Request<BI>,
+    opts: &ServeOptions,
+) -> Response<B> {
+    // serve takes entity itself for ownership, as needed for the multipart case. But to avoid
+    // monomorphization code bloat when there are many implementations of Entity<Data, Error>,
+    // delegate as much as possible to functions which take a reference to a trait object.
+    let (coding, variant) = select_variant(&entity, req.headers());
+    let ent: &dyn Entity<Data = Ent::Data, Error = Ent::Error> = match variant {
+        Some(ref v) => &**v,
+        None => &entity,
+    };
+    let res = match serve_inner(ent, coding, req, opts) {
+        ServeInner::Simple(res) => res,
+        ServeInner::Single { res, range } => {
+            let probe = match ServeDisposition::of(&res) {
+                Some(ServeDisposition::Probe) => ent
+                    .get_bytes(range.clone())
+                    .filter(|d| d.remaining() as u64 == range.end - range.start),
+                _ => None,
+            };
+            if let Some(d) = probe {
+                // The probe's bytes are at hand, so there's no need to defer anything.
+                let body = futures::stream::once(futures::future::ok(d));
+                finish_body(res.into_parts().0, body, opts)
+            } else {
+                // Defer get_range until the body is polled, so that building the response has no
+                // side effects.
+                let body = futures::stream::once(futures::future::lazy(move |_| {
+                    let ent: &dyn Entity<Data = Ent::Data, Error = Ent::Error> = match variant {
+                        Some(ref v) => &**v,
+                        None => &entity,
+                    };
+                    let len = range.end - range.start;
+                    let cut = Arc::new(AtomicBool::new(false));
+                    let s = Trim::exact(Pin::from(ent.get_range(range)), len, cut);
+                    keep_alive(s, (entity, variant))
+                }))
+                .flatten();
+                finish_body(res.into_parts().0, body, opts)
+            }
+        }
+        #[cfg(feature = "multipart")]
+        ServeInner::Multipart {
+            res,
+            mut part_headers,
+            ranges,
+        } => {
+            let cut = Arc::new(AtomicBool::new(false));
+            let bodies = futures::stream::unfold(0, move |state| {
+                let ent: &dyn Entity<Data = Ent::Data, Error = Ent::Error> = match variant {
+                    Some(ref v) => &**v,
+                    None => &entity,
+                };
+                next_multipart_body_chunk(state, ent, &ranges[..], &mut part_headers[..], &cut)
+            });
+            let body = bodies.flatten();
+            finish_body(res.body(()).unwrap().into_parts().0, body, opts)
+        }
+        ServeInner::Custom { res, body } => {
+            let body = keep_alive(Pin::from(body), (entity, variant));
+            finish_body(res.into_parts().0, body, opts)
+        }
+    };
+    #[cfg(feature = "tracing")]
+    trace_decision(req, &res);
+    res
+}
+
+/// Returns `s`, holding `owner` until the stream is dropped.
+///
+/// `serve` keeps the entity alive this way for as long as the body streams, not just while
+/// `get_range` runs, so an entity holding a resource (such as a file handle or a place in a
+/// queue) releases it only once its bytes are sent. The multipart body keeps it in its `unfold`
+/// closure instead.
+fn keep_alive<S: Stream + Unpin, T>(s: S, owner: T) -> KeepAlive<S, T> {
+    KeepAlive { stream: s, owner }
+}
+
+/// A body stream which holds `owner` until it's dropped; see `keep_alive`.
+struct KeepAlive<S, T> {
+    stream: S,
+    #[allow(dead_code)] // only held, never read.
+    owner: T,
+}
+
+// `owner` is never pinned, so KeepAlive is Unpin whenever the stream is.
+impl<S: Unpin, T> Unpin for KeepAlive<S, T> {}
+
+impl<S: Stream + Unpin, T> Stream for KeepAlive<S, T> {
+    type Item = S::Item;
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context,
+    ) -> std::task::Poll<Option<S::Item>> {
+        Pin::new(&mut self.get_mut().stream).poll_next(cx)
+    }
+}
+
+/// Returns the value of the first `name` query parameter as a `Range` header value.
+///
+/// A bare byte-range set such as `0-99` gets a `bytes=` prefix.
+fn range_from_query(uri: &http::Uri, name: &str) -> Option<HeaderValue> {
+    let value = uri.query()?.split('&').find_map(|pair| {
+        let mut kv = pair.splitn(2, '=');
+        if kv.next() == Some(name) {
+            Some(kv.next().unwrap_or(""))
+        } else {
+            None
+        }
+    })?;
+    if value.contains('=') {
+        HeaderValue::from_str(value).ok()
     } else {
-        let v = std::mem::replace(&mut part_headers[i], Vec::new());
-        InnerBody::Once(Some(v.into()))
+        HeaderValue::from_str(&format!("bytes={}", value)).ok()
+    }
+}
+
+/// Emits a debug event recording the request's conditional and range headers (the first value of
+/// each, if valid UTF-8), how the range header parsed, and the response's status and disposition.
+#[cfg(feature = "tracing")]
+fn trace_decision<BI, B>(req: &Request<BI>, res: &Response<B>) {
+    let h = |name| req.headers().get(name).and_then(|v| v.to_str().ok());
+    let diag = res.extensions().get::<range::RangeDiagnostics>();
+    let malformed = diag.and_then(range::RangeDiagnostics::malformed);
+    tracing::debug!(
+        range = h(header::RANGE),
+        range_specs = diag.map(range::RangeDiagnostics::specs),
+        range_unsatisfiable = diag.map(range::RangeDiagnostics::unsatisfiable),
+        range_clamped = diag.map(range::RangeDiagnostics::clamped),
+        range_malformed_offset = malformed.map(range::MalformedRange::offset),
+        range_malformed_fragment = malformed.map(range::MalformedRange::fragment),
+        if_range = h(header::IF_RANGE),
+        if_none_match = h(header::IF_NONE_MATCH),
+        if_match = h(header::IF_MATCH),
+        if_modified_since = h(header::IF_MODIFIED_SINCE),
+        if_unmodified_since = h(header::IF_UNMODIFIED_SINCE),
+        status = res.status().as_u16(),
+        disposition = ?ServeDisposition::of(res),
+        "serve decision"
+    );
+}
+
+/// Completes a response with an entity body stream, applying the options which wrap it.
+fn finish_body<S, D, E, B>(res: http::response::Parts, body: S, opts: &ServeOptions) -> Response<B>
+where
+    S: Stream<Item = Result<D, E>> + Send + 'static,
+    D: 'static + Send + Buf,
+    E: 'static + Send,
+    B: From<Box<dyn Stream<Item = Result<D, E>> + Send>>,
+{
+    let body: Box<dyn Stream<Item = Result<D, E>> + Send> = match opts.yield_every {
+        Some(n) => Box::new(CooperativeYield::new(Box::pin(body), n)),
+        None => Box::new(body),
+    };
+    let body = match opts.drain {
+        Some(ref w) => w.register(body),
+        None => body,
+    };
+    if !opts.report_bytes_sent {
+        return Response::from_parts(res, body.into());
+    }
+    let (body, sent) = Tallied::new(Pin::from(body), Buf::remaining);
+    let body: Box<dyn Stream<Item = Result<D, E>> + Send> = Box::new(body);
+    let mut res = Response::from_parts(res, body.into());
+    res.extensions_mut().insert(sent);
+    res
+}
+
+/// Serves as [`serve_with_options`](fn.serve_with_options.html) does, with a body which can carry
+/// trailers, as used by `ServeOptions::error_trailer`.
+pub fn serve_with_trailers<Ent: Entity, BI>(
+    entity: Ent,
+    req: &Request<BI>,
+    opts: &ServeOptions,
+) -> Response<TrailerBody<Ent::Data, Ent::Error>> {
+    let mut res: Response<TrailerBody<_, _>> = serve_with_options(entity, req, opts);
+    if req.version() != http::Version::HTTP_2 || *req.method() != Method::GET {
+        return res;
+    }
+    #[cfg(feature = "content-md5")]
+    if opts.content_md5_trailer && res.extensions().get::<ServedRanges>().is_some() {
+        res.headers_mut()
+            .append(header::TRAILER, HeaderValue::from_static("content-md5"));
+        res = res.map(TrailerBody::with_content_md5);
+    }
+    #[cfg(feature = "repr-digest-trailer")]
+    if opts.repr_digest_trailer
+        && ServeDisposition::of(&res) == Some(ServeDisposition::Full)
+        && !res.headers().contains_key("repr-digest")
+    {
+        res.headers_mut()
+            .append(header::TRAILER, HeaderValue::from_static("repr-digest"));
+        res = res.map(TrailerBody::with_repr_digest);
+    }
+    if let Some(ref name) = opts.error_trailer {
+        // An HTTP/2 client rejects a body shorter than its Content-Length before reading the
+        // trailers.
+        res.headers_mut().remove(header::CONTENT_LENGTH);
+        res = res.map(|b| b.with_error_trailer(name.clone()));
+    }
+    res
+}
+
+/// Serves an entity at a content-addressed URL, whose content can never change, constructing it
+/// only when its body is needed.
+///
+/// `etag` is the entity's known tag, typically derived from the hash in the URL; it replaces the
+/// entity's own `ETag`. A `GET` or `HEAD` whose `If-None-Match` has it (and whose `If-Match`, if
+/// any, does too) gets `304 Not Modified` without calling `entity`, so revalidation skips an
+/// expensive entity build such as opening a file. Otherwise the entity is served as by
+/// [`serve_with_options`](fn.serve_with_options.html), with `Cache-Control` set by
+/// [`CachePolicy::immutable`](struct.CachePolicy.html#method.immutable) (in place of any
+/// `cache_policy` in `opts`) and no `Last-Modified`, which is redundant with the tag.
+/// Content-coded variants keep their own etags, so an `If-Match` lacking the known tag may still
+/// match one; such requests are evaluated by constructing the entity.
+///
+/// ```
+/// # use http::{HeaderValue, Request, Response};
+/// # type BoxedError = Box<dyn std::error::Error + Send + Sync>;
+/// # fn f(req: Request<hyper::Body>) -> Response<hyper::Body> {
+/// // For a URL such as /static/app.3f2a9c.js:
+/// let etag = HeaderValue::from_static("\"3f2a9c\"");
+/// http_serve::serve_immutable(
+///     &etag,
+///     || http_serve::CowEntity::<bytes::Bytes, BoxedError>::new(&b"..."[..]),
+///     &req,
+///     &http_serve::ServeOptions::default(),
+/// )
+/// # }
+/// ```
+pub fn serve_immutable<Ent, F, B, BI>(
+    etag: &HeaderValue,
+    entity: F,
+    req: &Request<BI>,
+    opts: &ServeOptions,
+) -> Response<B>
+where
+    Ent: Entity,
+    F: FnOnce() -> Ent,
+    B: Body + From<Box<dyn Stream<Item = Result<Ent::Data, Ent::Error>> + Send>>,
+{
+    let draining = opts.drain.as_ref().map_or(false, Watch::is_draining);
+    let ambiguous = opts.reject_ambiguous_framing && ambiguous_framing(req.headers()).is_some();
+    if (*req.method() == Method::GET || *req.method() == Method::HEAD) && !draining && !ambiguous {
+        let known = Some(etag.clone());
+
+        // Only a match short-circuits. Unparseable headers are left for serve_with_options to
+        // reject, and failed preconditions to evaluate against the variants' etags too.
+        if let Ok((false, true)) = parse_modified_hdrs(&known, req.headers(), None, opts) {
+            let mut res = base_response(None, true);
+            if !opts.suppress_etag {
+                res = res.header(header::ETAG, etag);
+            }
+            if let Some(v) = opts.vary_value(false) {
+                res = res.header(header::VARY, v);
+            }
+            let res = res
+                .status(StatusCode::NOT_MODIFIED)
+                .header(
+                    header::CACHE_CONTROL,
+                    CachePolicy::immutable().header_value(req.headers()),
+                )
+                .extension(ServeDisposition::NotModified);
+            if let ServeInner::Simple(res) =
+                finish_simple::<Ent::Data, Ent::Error, B>(res, "", opts)
+            {
+                #[cfg(feature = "tracing")]
+                trace_decision(req, &res);
+                return res;
+            }
+        }
+    }
+    let opts = opts
+        .clone()
+        .omit_last_modified(true)
+        .cache_policy(CachePolicy::immutable());
+    let entity = ImmutableEntity {
+        inner: entity(),
+        etag: etag.clone(),
+    };
+    serve_with_options(entity, req, &opts)
+}
+
+/// Serves a one-shot stream of unknown length as a `200 OK` with the given `Content-Type`.
+///
+/// This is for bodies which can't implement `Entity`, which needs a known length and a body that
+/// can be read again for each range, so it supports neither ranges nor conditional requests;
+/// hyper sends the body with chunked transfer encoding. For `HEAD` requests the stream is dropped
+/// without being polled. Other methods get the same `405 Method Not Allowed` as from `serve`.
+pub fn serve_stream<S, D, E, B, BI>(
+    req: &Request<BI>,
+    stream: S,
+    content_type: HeaderValue,
+) -> Response<B>
+where
+    S: Stream<Item = Result<D, E>> + Send + 'static,
+    D: 'static + Send + Sync + Buf + From<Vec<u8>> + From<&'static [u8]>,
+    E: 'static + Send + Sync,
+    B: Body + From<Box<dyn Stream<Item = Result<D, E>> + Send>>,
+{
+    let body: Box<dyn Stream<Item = Result<D, E>> + Send> = match *req.method() {
+        Method::GET => Box::new(stream),
+        Method::HEAD => empty_body::<D, E>(),
+        _ => return method_not_allowed(None, &ServeOptions::default()),
+    };
+    Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .body(body.into())
+        .unwrap()
+}
+
+/// Chooses among `ent` and its variants according to the `Accept-Encoding` in `req_hdrs`.
+///
+/// Returns the chosen coding, or `None` if the entity has no variants (and thus no negotiation
+/// took place). The returned variant is `None` if `ent` itself should be served.
+///
+/// An entity whose own headers already carry a `Content-Encoding` other than `identity` is
+/// precompressed; encoding it again would send the client bytes it can't decode, so it's served
+/// as-is without negotiation.
+fn select_variant<D, E>(
+    ent: &dyn Entity<Data = D, Error = E>,
+    req_hdrs: &HeaderMap,
+) -> (Option<ContentCoding>, Option<BoxedEntity<D, E>>)
+where
+    D: 'static + Send + Sync + Buf + From<Vec<u8>> + From<&'static [u8]>,
+    E: 'static + Send + Sync,
+{
+    let mut variants = ent.variants();
+    if variants.is_empty() {
+        return (None, None);
+    }
+    if let Some(_existing) = preset_content_encoding(ent) {
+        #[cfg(feature = "tracing")]
+        tracing::warn!(
+            content_encoding = ?_existing,
+            "entity already sets Content-Encoding; not negotiating its variants"
+        );
+        return (None, None);
+    }
+
+    // Brotli typically compresses static assets better than gzip, so it's preferred whenever it's
+    // accepted, regardless of the two codings' relative qvalues.
+    for &preferred in &[ContentCoding::Brotli, ContentCoding::Gzip] {
+        if !crate::should_encode(req_hdrs, preferred) {
+            continue;
+        }
+        if let Some(i) = variants.iter().position(|&(c, _)| c == preferred) {
+            return (Some(preferred), Some(variants.swap_remove(i).1));
+        }
+    }
+    (Some(ContentCoding::Identity), None)
+}
+
+/// Returns the entity's own `Content-Encoding`, if it sets one other than `identity`.
+fn preset_content_encoding<D, E>(ent: &dyn Entity<Data = D, Error = E>) -> Option<HeaderValue>
+where
+    D: 'static + Send + Sync + Buf + From<Vec<u8>> + From<&'static [u8]>,
+    E: 'static + Send + Sync,
+{
+    let mut scratch = HeaderMap::new();
+    let h = match ent.static_headers() {
+        Some(h) => h,
+        None => {
+            ent.add_headers(&mut scratch);
+            &scratch
+        }
+    };
+    h.get_all(header::CONTENT_ENCODING)
+        .iter()
+        .find(|v| !v.as_bytes().eq_ignore_ascii_case(b"identity"))
+        .cloned()
+}
+
+/// Headers which `serve` sets itself, so entities mustn't add.
+#[cfg(any(debug_assertions, test, feature = "test-support"))]
+pub(crate) static SERVE_SET_HEADERS: [HeaderName; 5] = [
+    header::CONTENT_LENGTH,
+    header::CONTENT_RANGE,
+    header::ETAG,
+    header::LAST_MODIFIED,
+    header::DATE,
+];
+
+/// Headers which `HeaderOverflow::Truncate` never leaves out, as they're needed to interpret the
+/// body.
+static ESSENTIAL_HEADERS: [HeaderName; 2] = [header::CONTENT_TYPE, header::CONTENT_ENCODING];
+
+/// Returns the length of `h` written as `name: value` CRLF lines.
+pub(crate) fn header_block_len(h: &HeaderMap) -> usize {
+    h.iter()
+        .map(|(k, v)| k.as_str().len() + v.as_bytes().len() + 4)
+        .sum()
+}
+
+/// Entity headers limited by `ServeOptions::max_entity_header_bytes`.
+struct LimitedHeaders {
+    headers: HeaderMap,
+    dropped: Vec<HeaderName>,
+}
+
+/// Applies `opts.max_entity_header_bytes` to the headers `add_entity_headers` would add.
+///
+/// Returns `Ok(None)` if there's no limit, and `Err(())` if the headers exceed it under
+/// `HeaderOverflow::InternalServerError`.
+fn limit_entity_headers<D, E>(
+    ent: &dyn Entity<Data = D, Error = E>,
+    coding: Option<ContentCoding>,
+    opts: &ServeOptions,
+) -> Result<Option<LimitedHeaders>, ()>
+where
+    D: 'static + Send + Sync + Buf + From<Vec<u8>> + From<&'static [u8]>,
+    E: 'static + Send + Sync,
+{
+    let max = match opts.max_entity_header_bytes {
+        Some(m) => m,
+        None => return Ok(None),
     };
-    futures::future::ready(Some((body, state + 1)))
+    let mut all = HeaderMap::new();
+    add_entity_headers(ent, coding, &mut all);
+    if header_block_len(&all) <= max {
+        return Ok(Some(LimitedHeaders {
+            headers: all,
+            dropped: Vec::new(),
+        }));
+    }
+    if opts.header_overflow == HeaderOverflow::InternalServerError {
+        return Err(());
+    }
+    let mut headers = HeaderMap::new();
+    let mut dropped = Vec::new();
+    let mut used = 0;
+    for name in &ESSENTIAL_HEADERS {
+        for v in all.get_all(name) {
+            used += name.as_str().len() + v.len() + 4;
+            headers.append(name.clone(), v.clone());
+        }
+    }
+    for (name, v) in &all {
+        if ESSENTIAL_HEADERS.contains(name) {
+            continue;
+        }
+        let n = name.as_str().len() + v.len() + 4;
+        if used + n > max {
+            if !dropped.contains(name) {
+                dropped.push(name.clone());
+            }
+            continue;
+        }
+        used += n;
+        headers.append(name.clone(), v.clone());
+    }
+    #[cfg(feature = "tracing")]
+    tracing::warn!(
+        dropped = ?dropped,
+        max,
+        "left out entity headers exceeding max_entity_header_bytes"
+    );
+    Ok(Some(LimitedHeaders { headers, dropped }))
+}
+
+/// Adds the entity's headers to `hdrs`: `limited`, if given, or else those from
+/// `add_entity_headers`.
+fn put_entity_headers<D, E>(
+    ent: &dyn Entity<Data = D, Error = E>,
+    coding: Option<ContentCoding>,
+    limited: Option<&HeaderMap>,
+    hdrs: &mut HeaderMap,
+) where
+    D: 'static + Send + Sync + Buf + From<Vec<u8>> + From<&'static [u8]>,
+    E: 'static + Send + Sync,
+{
+    match limited {
+        Some(h) => {
+            for (k, v) in h {
+                hdrs.append(k, v.clone());
+            }
+        }
+        None => add_entity_headers(ent, coding, hdrs),
+    }
+}
+
+/// Adds `ent`'s headers to `hdrs`, plus `Content-Encoding` if a non-identity `coding` was chosen.
+///
+/// With debug assertions, panics if the entity adds any of `SERVE_SET_HEADERS`.
+fn add_entity_headers<D, E>(
+    ent: &dyn Entity<Data = D, Error = E>,
+    coding: Option<ContentCoding>,
+    hdrs: &mut HeaderMap,
+) where
+    D: 'static + Send + Sync + Buf + From<Vec<u8>> + From<&'static [u8]>,
+    E: 'static + Send + Sync,
+{
+    #[cfg(debug_assertions)]
+    let before: Vec<usize> = SERVE_SET_HEADERS
+        .iter()
+        .map(|h| hdrs.get_all(h).iter().count())
+        .collect();
+    match ent.static_headers() {
+        Some(h) => {
+            for (k, v) in h {
+                hdrs.append(k, v.clone());
+            }
+        }
+        None => ent.add_headers(hdrs),
+    }
+    #[cfg(debug_assertions)]
+    for (h, before) in SERVE_SET_HEADERS.iter().zip(before) {
+        debug_assert_eq!(
+            hdrs.get_all(h).iter().count(),
+            before,
+            "Entity must not add {} header",
+            h
+        );
+    }
+    match coding {
+        None | Some(ContentCoding::Identity) => {}
+        Some(c) => {
+            hdrs.insert(
+                header::CONTENT_ENCODING,
+                HeaderValue::from_static(c.as_str()),
+            );
+        }
+    }
+}
+
+/// An instruction from `serve_inner` to `serve` on how to respond.
+enum ServeInner<D, E, B> {
+    Simple(Response<B>),
+
+    /// A single range of the entity, whose body `serve` creates.
+    Single {
+        res: Response<()>,
+        range: Range<u64>,
+    },
+
+    #[cfg(feature = "multipart")]
+    Multipart {
+        res: http::response::Builder,
+        part_headers: Vec<Vec<u8>>,
+        ranges: SmallVec<[Range<u64>; 1]>,
+    },
+
+    /// A range in a unit other than bytes, with the body returned by `get_custom_range`.
+    Custom {
+        res: Response<()>,
+        body: Box<dyn Stream<Item = Result<D, E>> + Send + Sync>,
+    },
+}
+
+/// Starts a response with the headers `serve` includes on every response: `Date`, if `now` is
+/// supplied, and, if `accept_ranges`, `Accept-Ranges: bytes`.
+fn base_response(now: Option<SystemTime>, accept_ranges: bool) -> http::response::Builder {
+    let mut res = Response::builder();
+    if let Some(now) = now {
+        res = res.header(header::DATE, &*fmt_http_date(now));
+    }
+    if accept_ranges {
+        return res.header(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    }
+    res
+}
+
+/// Returns `etag`, quoted if it lacks quotes and `ServeOptions::normalize_etags` is set.
+fn normalized_etag(etag: Option<HeaderValue>, opts: &ServeOptions) -> Option<HeaderValue> {
+    let e = etag?;
+    let b = e.as_bytes();
+    if b.starts_with(b"\"") || b.starts_with(b"W/\"") {
+        return Some(e);
+    }
+    if !opts.normalize_etags {
+        #[cfg(all(debug_assertions, feature = "tracing"))]
+        tracing::warn!(
+            etag = ?e,
+            "entity etag lacks quotes, so requests' etags won't match it; \
+             see ServeOptions::normalize_etags"
+        );
+        return Some(e);
+    }
+    let mut quoted = Vec::with_capacity(b.len() + 2);
+    quoted.push(b'"');
+    quoted.extend_from_slice(b);
+    quoted.push(b'"');
+    Some(HeaderValue::from_maybe_shared(quoted).expect("quoted etag is a valid header value"))
+}
+
+/// Returns why the request's framing is ambiguous, if it is; see
+/// `ServeOptions::reject_ambiguous_framing`.
+fn ambiguous_framing(hdrs: &HeaderMap) -> Option<&'static str> {
+    let mut lengths = hdrs
+        .get_all(header::CONTENT_LENGTH)
+        .iter()
+        .flat_map(|v| v.as_bytes().split(|&b| b == b','));
+    lengths.next()?;
+    if hdrs.contains_key(header::TRANSFER_ENCODING) {
+        return Some("Request has both Content-Length and Transfer-Encoding.");
+    }
+    if lengths.next().is_some() {
+        return Some("Request has multiple Content-Length values.");
+    }
+    None
+}
+
+/// Runs trait object-based inner logic for `serve`.
+fn serve_inner<
+    D: 'static + Send + Sync + Buf + From<Vec<u8>> + From<&'static [u8]>,
+    E: 'static + Send + Sync,
+    B: Body + From<Box<dyn Stream<Item = Result<D, E>> + Send>>,
+    BI,
+>(
+    ent: &dyn Entity<Error = E, Data = D>,
+    coding: Option<ContentCoding>,
+    req: &Request<BI>,
+    opts: &ServeOptions,
+) -> ServeInner<D, E, B> {
+    let last_modified = if opts.omit_last_modified {
+        None
+    } else {
+        ent.last_modified()
+    };
+
+    // The Date is needed only to bound the Last-Modified (see below). Without one, leave the Date
+    // to hyper, which caches its formatted value.
+    let now = last_modified.map(|_| opts.now());
+    if opts.drain.as_ref().map_or(false, Watch::is_draining) {
+        let mut res = base_response(now, opts.accept_ranges_always)
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .extension(ServeDisposition::Unavailable);
+        if req.version() < http::Version::HTTP_2 {
+            res = res.header(header::CONNECTION, HeaderValue::from_static("close"));
+        }
+        return finish_simple::<D, E, B>(res, "The server is shutting down.", opts);
+    }
+    if *req.method() == Method::OPTIONS && opts.handle_options {
+        let res = base_response(now, true)
+            .status(StatusCode::NO_CONTENT)
+            .header(header::ALLOW, opts.allowed_methods().0)
+            .extension(ServeDisposition::Options);
+        return finish_simple::<D, E, B>(res, "", opts);
+    }
+    if *req.method() != Method::GET && *req.method() != Method::HEAD {
+        return ServeInner::Simple(method_not_allowed(now, opts));
+    }
+    if opts.reject_ambiguous_framing {
+        if let Some(msg) = ambiguous_framing(req.headers()) {
+            let res = base_response(now, opts.accept_ranges_always)
+                .status(StatusCode::BAD_REQUEST)
+                .extension(ServeDisposition::BadRequest);
+            return finish_simple::<D, E, B>(res, msg, opts);
+        }
+    }
+
+    let payload_too_large = || {
+        let res = base_response(now, opts.accept_ranges_always)
+            .status(StatusCode::PAYLOAD_TOO_LARGE)
+            .extension(ServeDisposition::PayloadTooLarge);
+        finish_simple::<D, E, B>(res, "Response exceeds the client's limit.", opts)
+    };
+
+    let etag = normalized_etag(ent.etag(), opts);
+
+    let (precondition_failed, not_modified) =
+        match parse_modified_hdrs(&etag, req.headers(), last_modified, opts) {
+            Err(s) => {
+                let res = base_response(now, opts.accept_ranges_always)
+                    .status(StatusCode::BAD_REQUEST)
+                    .extension(ServeDisposition::BadRequest);
+                return finish_simple::<D, E, B>(res, s, opts);
+            }
+            Ok(p) => p,
+        };
+
+    // See RFC 7233 section 4.1 <https://tools.ietf.org/html/rfc7233#section-4.1>: a Partial
+    // Content response should include other representation header fields (aka entity-headers in
+    // RFC 2616) iff the client didn't specify If-Range.
+    let query_range = match opts.range_query_param {
+        Some(ref name) if !req.headers().contains_key(header::RANGE) => {
+            range_from_query(req.uri(), name)
+        }
+        _ => None,
+    };
+    let mut range_hdr = req.headers().get(header::RANGE).or(query_range.as_ref());
+    let include_entity_headers_on_range = match req.headers().get(header::IF_RANGE) {
+        Some(if_range) => {
+            let if_range = if_range.as_bytes();
+            if if_range.starts_with(b"W/\"") || if_range.starts_with(b"\"") {
+                // etag case.
+                if let Some(ref some_etag) = etag {
+                    if etag::strong_eq(if_range, some_etag.as_bytes(), opts.constant_time_etags) {
+                        false
+                    } else {
+                        range_hdr = None;
+                        true
+                    }
+                } else {
+                    range_hdr = None;
+                    true
+                }
+            } else if opts.permissive_if_range_dates
+                && if_range_date_matches(if_range, etag.as_ref(), last_modified, now)
+            {
+                false
+            } else {
+                // Date case.
+                // Use the strong validation rules for an origin server:
+                // <https://tools.ietf.org/html/rfc7232#section-2.2.2>.
+                // The resource could have changed twice in the supplied second, so never match.
+                range_hdr = None;
+                true
+            }
+        }
+        None => true,
+    };
+
+    let mut res = base_response(now, true);
+    if let Some(s) = opts.success_status {
+        // Statuses set below, such as 206 or 304, replace this one.
+        res = res.status(s);
+    }
+    if let Some(v) = opts.vary_value(coding.is_some()) {
+        res = res.header(header::VARY, v);
+    }
+    if let (Some(m), Some(now), false) = (last_modified, now, opts.suppress_last_modified) {
+        // See RFC 7232 section 2.2.1 <https://tools.ietf.org/html/rfc7232#section-2.2.1>: the
+        // Last-Modified must not exceed the Date. base_response set the Date to now rather than
+        // letting hyper set it, which guarantees this.
+        let clamped_m = std::cmp::min(m, now);
+        res = res.header(header::LAST_MODIFIED, &*fmt_http_date(clamped_m));
+    }
+    if let Some(e) = etag.as_ref().filter(|_| !opts.suppress_etag) {
+        let encoded = match coding {
+            None | Some(ContentCoding::Identity) => false,
+            Some(_) => true,
+        };
+        if !opts.strong_encoded_etags && encoded && !e.as_bytes().starts_with(b"W/") {
+            let mut weak = b"W/".to_vec();
+            weak.extend_from_slice(e.as_bytes());
+            res = res.header(
+                http::header::ETAG,
+                HeaderValue::from_maybe_shared(weak).expect("W/ + etag is a valid header value"),
+            );
+        } else {
+            res = res.header(http::header::ETAG, e);
+        }
+    }
+
+    if precondition_failed {
+        res = res
+            .status(StatusCode::PRECONDITION_FAILED)
+            .extension(ServeDisposition::PreconditionFailed);
+        return finish_simple::<D, E, B>(res, "Precondition failed", opts);
+    }
+
+    // Kept on 304, 206, and 200 responses; HEADER_RULES removes them from 416 responses.
+    if let Some(ref p) = opts.cache_policy {
+        res = res.header(header::CACHE_CONTROL, p.header_value(req.headers()));
+    }
+    if let Some(l) = ent.content_location() {
+        res = res.header(header::CONTENT_LOCATION, l);
+    }
+
+    if not_modified {
+        res = res
+            .status(StatusCode::NOT_MODIFIED)
+            .extension(ServeDisposition::NotModified);
+        return finish_simple::<D, E, B>(res, "", opts);
+    }
+
+    let limited = match limit_entity_headers(ent, coding, opts) {
+        Ok(l) => l,
+        Err(()) => {
+            let res = base_response(now, opts.accept_ranges_always)
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .extension(ServeDisposition::HeadersTooLarge);
+            return finish_simple::<D, E, B>(res, "The entity's headers are too large.", opts);
+        }
+    };
+    if let Some(LimitedHeaders { ref dropped, .. }) = limited {
+        if !dropped.is_empty() {
+            res = res.extension(DroppedHeaders {
+                names: dropped.clone(),
+            });
+        }
+    }
+    let limited = limited.as_ref().map(|l| &l.headers);
+
+    if let Some((unit, first, last)) = range::parse_custom(range_hdr) {
+        if let Some(body) = ent.get_custom_range(unit, first, last) {
+            res = res
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(
+                    header::CONTENT_RANGE,
+                    format!("{} {}-{}", unit, first, last),
+                )
+                .extension(ServeDisposition::Partial);
+            let is_head = *req.method() == Method::HEAD;
+            let mut res = res.body(()).unwrap();
+            if include_entity_headers_on_range {
+                put_entity_headers(ent, coding, limited, res.headers_mut());
+            }
+            if is_head {
+                let (parts, ()) = res.into_parts();
+                return ServeInner::Simple(Response::from_parts(
+                    parts,
+                    empty_body::<D, E>().into(),
+                ));
+            }
+            return ServeInner::Custom { res, body };
+        }
+    }
+
+    let len = ent.len();
+    let (resolved, diagnostics) = range::parse_with_diagnostics(range_hdr, len);
+    if range_hdr.is_some() {
+        res = res.extension(diagnostics);
+    }
+    let (range, include_entity_headers, partial) = match resolved {
+        range::ResolvedRanges::None => (0..len, true, false),
+        range::ResolvedRanges::Satisfiable(range::SatisfiableRanges { mut ranges }) => {
+            // Ranges which each start where the previous ended, such as `0-99,100-199`,
+            // concatenate to a single span. Serve that rather than a multipart/byteranges body.
+            if ranges.windows(2).all(|w| w[0].end == w[1].start) {
+                let end = ranges[ranges.len() - 1].end;
+                ranges.truncate(1);
+                ranges[0].end = end;
+            }
+            let mode = match opts.multirange_compat {
+                Some(ref f) if ranges.len() > 1 => f(req.headers()),
+                _ => MultirangeMode::Multipart,
+            };
+            if mode == MultirangeMode::FirstRangeOnly {
+                ranges.truncate(1);
+            }
+            if ranges.len() == 1 {
+                res = res.header(
+                    header::CONTENT_RANGE,
+                    unsafe_fmt_ascii_val!(
+                        MAX_DECIMAL_U64_BYTES * 3 + "bytes -/".len(),
+                        "bytes {}-{}/{}",
+                        ranges[0].start,
+                        ranges[0].end - 1,
+                        len
+                    ),
+                );
+                res = res.status(StatusCode::PARTIAL_CONTENT);
+                (ranges[0].clone(), include_entity_headers_on_range, true)
+            } else if mode == MultirangeMode::FullEntity {
+                (0..len, true, false)
+            } else {
+                // Before serving multiple ranges via multipart/byteranges, estimate the total
+                // length. ("80" is the RFC's estimate of the size of each part's header.) If it's
+                // more than simply serving the whole entity, do that instead, unless configured
+                // otherwise. Without the multipart feature, always do that.
+                #[cfg(feature = "multipart")]
+                let est_len: u64 = ranges.iter().map(|r| 80 + r.end - r.start).sum();
+                #[cfg(feature = "multipart")]
+                if est_len < len || opts.always_multipart {
+                    let boundary = choose_boundary(ent, &ranges[..], opts);
+                    let part_entity_headers = if include_entity_headers_on_range {
+                        let mut h = HeaderMap::new();
+                        put_entity_headers(ent, coding, limited, &mut h);
+                        Some(h)
+                    } else {
+                        // Each part should still have the Content-Type a 200 would have; see
+                        // RFC 7233 section 4.1.
+                        ent.content_type().map(|t| {
+                            let mut h = HeaderMap::new();
+                            h.insert(header::CONTENT_TYPE, t);
+                            h
+                        })
+                    };
+                    // A boundary which couldn't be verified is handled as an oversized response.
+                    let prepared = boundary.and_then(|b| {
+                        let (p, l) = prepare_multipart(
+                            part_entity_headers,
+                            &ranges[..],
+                            len,
+                            opts.max_part_headers_bytes,
+                            &b,
+                        )?;
+                        Some((p, l, b))
+                    });
+                    let (part_headers, body_len, boundary, oversized) = match prepared {
+                        Some((p, l, b)) => {
+                            (p, l, b, opts.max_multipart_bytes.map_or(false, |m| l > m))
+                        }
+                        None => (Vec::new(), 0, String::new(), true),
+                    };
+                    if oversized && !opts.multipart_fallback.allows_full(len) {
+                        return range_not_satisfiable::<D, E, B>(res, len, opts);
+                    }
+                    if !oversized {
+                        if opts.exceeds_max_content_length(req.headers(), body_len) {
+                            return payload_too_large();
+                        }
+                        let mut res = multipart_response(res, &boundary, body_len);
+                        res = res.extension(ServeDisposition::Multipart);
+                        if *req.method() == Method::HEAD {
+                            return ServeInner::Simple(
+                                res.body(empty_body::<D, E>().into()).unwrap(),
+                            );
+                        }
+                        res = res.extension(ServedRanges {
+                            ranges: ranges.clone(),
+                            body_len,
+                            boundary: Some(boundary),
+                        });
+                        return ServeInner::Multipart {
+                            res,
+                            part_headers,
+                            ranges,
+                        };
+                    }
+                }
+
+                (0..len, true, false)
+            }
+        }
+        range::ResolvedRanges::NotSatisfiable => {
+            return range_not_satisfiable::<D, E, B>(res, len, opts);
+        }
+    };
+    if opts.exceeds_max_content_length(req.headers(), range.end - range.start) {
+        return payload_too_large();
+    }
+    res = res.header(
+        header::CONTENT_LENGTH,
+        unsafe_fmt_ascii_val!(MAX_DECIMAL_U64_BYTES, "{}", range.end - range.start),
+    );
+    if !partial {
+        // The content is the whole representation, so the two digests are the same.
+        if let Some(d) = ent.digest() {
+            res = res.header(HeaderName::from_static("repr-digest"), d.clone());
+            res = res.header(HeaderName::from_static("content-digest"), d);
+        }
+    }
+    res = res.extension(
+        if partial && range.end - range.start <= opts.max_probe_len() {
+            ServeDisposition::Probe
+        } else if partial {
+            ServeDisposition::Partial
+        } else {
+            ServeDisposition::Full
+        },
+    );
+    if *req.method() == Method::HEAD {
+        let mut res = res.body(empty_body::<D, E>().into()).unwrap();
+        if include_entity_headers {
+            put_entity_headers(ent, coding, limited, res.headers_mut());
+        }
+        return ServeInner::Simple(res);
+    }
+    let mut ranges = SmallVec::new();
+    ranges.push(range.clone());
+    res = res.extension(ServedRanges {
+        body_len: range.end - range.start,
+        ranges,
+        boundary: None,
+    });
+    let mut res = res.body(()).unwrap();
+    if include_entity_headers {
+        put_entity_headers(ent, coding, limited, res.headers_mut());
+    }
+    ServeInner::Single { res, range }
+}
+
+/// Finishes a `416 Range Not Satisfiable` response for an entity of `len` bytes.
+fn range_not_satisfiable<D, E, B>(
+    res: http::response::Builder,
+    len: u64,
+    opts: &ServeOptions,
+) -> ServeInner<D, E, B>
+where
+    D: 'static + Send + Sync + Buf + From<Vec<u8>> + From<&'static [u8]>,
+    E: 'static + Send + Sync,
+    B: Body + From<Box<dyn Stream<Item = Result<D, E>> + Send>>,
+{
+    let res = res
+        .header(
+            http::header::CONTENT_RANGE,
+            unsafe_fmt_ascii_val!(MAX_DECIMAL_U64_BYTES + "bytes */".len(), "bytes */{}", len),
+        )
+        .status(StatusCode::RANGE_NOT_SATISFIABLE)
+        .extension(ServeDisposition::RangeNotSatisfiable);
+    finish_simple::<D, E, B>(res, "", opts)
+}
+
+/// The headers a response of a given status, without an entity body, must and mustn't carry.
+struct HeaderRule {
+    status: StatusCode,
+    required: &'static [HeaderName],
+    forbidden: &'static [HeaderName],
+
+    /// True if the status allows a body, and thus a `Content-Length`.
+    has_body: bool,
+}
+
+/// The rules `finish_simple` applies, covering every status `serve` responds with other than
+/// `200` and `206`.
+///
+/// `Content-Length` is always forbidden, so that no value from builder state (such as the
+/// entity's length) survives. Responses with a body get one back, describing the body, if
+/// `ServeOptions::error_content_length` is set. `Cache-Control` is forbidden on errors so that
+/// caches don't store them in place of the entity.
+static HEADER_RULES: [HeaderRule; 9] = [
+    HeaderRule {
+        status: StatusCode::NO_CONTENT,
+        required: &[header::ALLOW],
+        forbidden: &[header::CONTENT_LENGTH, header::CONTENT_RANGE],
+        has_body: false,
+    },
+    HeaderRule {
+        status: StatusCode::NOT_MODIFIED,
+        required: &[],
+        forbidden: &[
+            header::CONTENT_LENGTH,
+            header::CONTENT_RANGE,
+            header::CONTENT_TYPE,
+        ],
+        has_body: false,
+    },
+    HeaderRule {
+        status: StatusCode::BAD_REQUEST,
+        required: &[],
+        forbidden: &[
+            header::CONTENT_LENGTH,
+            header::CONTENT_RANGE,
+            header::CACHE_CONTROL,
+        ],
+        has_body: true,
+    },
+    HeaderRule {
+        status: StatusCode::METHOD_NOT_ALLOWED,
+        required: &[header::ALLOW],
+        forbidden: &[
+            header::CONTENT_LENGTH,
+            header::CONTENT_RANGE,
+            header::CACHE_CONTROL,
+        ],
+        has_body: true,
+    },
+    HeaderRule {
+        status: StatusCode::PRECONDITION_FAILED,
+        required: &[],
+        forbidden: &[
+            header::CONTENT_LENGTH,
+            header::CONTENT_RANGE,
+            header::CACHE_CONTROL,
+        ],
+        has_body: true,
+    },
+    HeaderRule {
+        status: StatusCode::PAYLOAD_TOO_LARGE,
+        required: &[],
+        forbidden: &[
+            header::CONTENT_LENGTH,
+            header::CONTENT_RANGE,
+            header::CACHE_CONTROL,
+        ],
+        has_body: true,
+    },
+    HeaderRule {
+        status: StatusCode::RANGE_NOT_SATISFIABLE,
+        required: &[header::CONTENT_RANGE],
+        forbidden: &[
+            header::CONTENT_LENGTH,
+            header::CACHE_CONTROL,
+            header::CONTENT_LOCATION,
+        ],
+        has_body: true,
+    },
+    HeaderRule {
+        status: StatusCode::INTERNAL_SERVER_ERROR,
+        required: &[],
+        forbidden: &[
+            header::CONTENT_LENGTH,
+            header::CONTENT_RANGE,
+            header::CACHE_CONTROL,
+        ],
+        has_body: true,
+    },
+    HeaderRule {
+        status: StatusCode::SERVICE_UNAVAILABLE,
+        required: &[],
+        forbidden: &[
+            header::CONTENT_LENGTH,
+            header::CONTENT_RANGE,
+            header::CACHE_CONTROL,
+        ],
+        has_body: true,
+    },
+];
+
+/// The `Content-Type` of `serve`'s built-in error texts.
+const ERROR_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+
+/// Returns a `405 Method Not Allowed` response, listing the methods allowed by `opts`.
+fn method_not_allowed<D, E, B>(now: Option<SystemTime>, opts: &ServeOptions) -> Response<B>
+where
+    D: 'static + Send + Sync + Buf + From<Vec<u8>> + From<&'static [u8]>,
+    E: 'static + Send + Sync,
+    B: Body + From<Box<dyn Stream<Item = Result<D, E>> + Send>>,
+{
+    let (allow, msg) = opts.allowed_methods();
+    let res = base_response(now, opts.accept_ranges_always)
+        .status(StatusCode::METHOD_NOT_ALLOWED)
+        .header(header::ALLOW, allow)
+        .extension(ServeDisposition::MethodNotAllowed);
+    match finish_simple::<D, E, B>(res, msg, opts) {
+        ServeInner::Simple(res) => res,
+        _ => unreachable!("finish_simple returns a simple response"),
+    }
+}
+
+/// Finishes a response without an entity body, with `body` as its (possibly empty) text, applying
+/// the matching rule from `HEADER_RULES`.
+///
+/// With debug assertions, panics if the response lacks a required header.
+fn finish_simple<D, E, B>(
+    res: http::response::Builder,
+    body: &'static str,
+    opts: &ServeOptions,
+) -> ServeInner<D, E, B>
+where
+    D: 'static + Send + Sync + Buf + From<Vec<u8>> + From<&'static [u8]>,
+    E: 'static + Send + Sync,
+    B: Body + From<Box<dyn Stream<Item = Result<D, E>> + Send>>,
+{
+    let mut res = res.body(()).unwrap();
+    if let Some(rule) = HEADER_RULES.iter().find(|r| r.status == res.status()) {
+        debug_assert!(rule.has_body || body.is_empty());
+        let hdrs = res.headers_mut();
+        for h in rule.forbidden {
+            hdrs.remove(h);
+        }
+        for h in rule.required {
+            debug_assert!(
+                hdrs.contains_key(h),
+                "{} response must have {} header",
+                rule.status,
+                h
+            );
+        }
+        if rule.has_body && opts.error_content_length {
+            hdrs.insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+        }
+    }
+    let body = if body.is_empty() {
+        empty_body::<D, E>()
+    } else {
+        res.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static(ERROR_CONTENT_TYPE),
+        );
+        static_body::<D, E>(body)
+    };
+    ServeInner::Simple(res.map(|()| body.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Entity, ServeOptions, ServedRanges};
+    use crate::test_support::{FakeEntity, FakeRequest};
+    use crate::ContentCoding;
+    use bytes::Bytes;
+    use futures::{stream, Stream};
+    use http::header::{self, HeaderMap, HeaderName, HeaderValue};
+    use http::{Method, Request, Response, StatusCode};
+    use std::ops::Range;
+    use std::time::SystemTime;
+
+    type BoxedError = Box<dyn std::error::Error + Send + Sync>;
+
+    static BODY: &[u8] =
+        b"01234567890123456789012345678901234567890123456789012345678901234567890123456789\
+          01234567890123456789012345678901234567890123456789012345678901234567890123456789\
+          01234567890123456789012345678901234567890123456789012345678901234567890123456789";
+
+    /// A shared `FakeEntity` with the etag and `Content-Type` most tests expect.
+    fn entity(body: &'static [u8]) -> FakeEntity {
+        FakeEntity::new(body)
+            .etag("\"foo\"")
+            .content_type("text/plain")
+    }
+
+    /// A variant for `entity`, with an etag naming its coding.
+    fn encoded(coding: ContentCoding, body: &'static [u8]) -> FakeEntity {
+        FakeEntity::new(body)
+            .etag(&format!("\"foo-{}\"", coding.as_str()))
+            .content_type("text/plain")
+    }
+
+    fn serve<E>(e: E, req: &Request<()>) -> Response<hyper::Body>
+    where
+        E: Entity<Data = Bytes, Error = BoxedError>,
+    {
+        super::serve(e, req)
+    }
+
+    fn serve_with<E>(e: E, req: &Request<()>, opts: &ServeOptions) -> Response<hyper::Body>
+    where
+        E: Entity<Data = Bytes, Error = BoxedError>,
+    {
+        super::serve_with_options(e, req, opts)
+    }
+
+    async fn body(resp: Response<hyper::Body>) -> Bytes {
+        hyper::body::to_bytes(resp.into_body()).await.unwrap()
+    }
+
+    /// Returns the boundary of a `multipart/byteranges` response, checking its `Content-Type`.
+    #[cfg(feature = "multipart")]
+    fn boundary(resp: &Response<hyper::Body>) -> String {
+        let served = resp.extensions().get::<ServedRanges>().unwrap();
+        let boundary = served.boundary().unwrap().to_owned();
+        assert_eq!(
+            resp.headers()[header::CONTENT_TYPE],
+            format!("multipart/byteranges; boundary={}", boundary)
+        );
+        boundary
+    }
+
+    #[tokio::test]
+    async fn gzip_variant() {
+        let e = entity(b"identity").variant(
+            ContentCoding::Gzip,
+            encoded(ContentCoding::Gzip, b"gzipped"),
+        );
+
+        // The gzip variant is picked when accepted.
+        let resp = serve(
+            e.clone(),
+            &FakeRequest::get()
+                .header(header::ACCEPT_ENCODING, "gzip")
+                .build(),
+        );
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_ENCODING).unwrap(),
+            "gzip"
+        );
+        assert_eq!(resp.headers().get(header::VARY).unwrap(), "accept-encoding");
+        assert_eq!(resp.headers().get(header::ETAG).unwrap(), "W/\"foo-gzip\"");
+        assert_eq!(&body(resp).await[..], b"gzipped");
+
+        // ...and ranges apply to the encoded representation.
+        let resp = serve(
+            e.clone(),
+            &FakeRequest::get()
+                .header(header::ACCEPT_ENCODING, "gzip")
+                .range("bytes=0-1")
+                .build(),
+        );
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_ENCODING).unwrap(),
+            "gzip"
+        );
+        assert_eq!(&body(resp).await[..], b"gz");
+
+        // The identity variant is picked otherwise.
+        let resp = serve(e.clone(), &FakeRequest::get().build());
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get(header::CONTENT_ENCODING), None);
+        assert_eq!(resp.headers().get(header::VARY).unwrap(), "accept-encoding");
+        assert_eq!(resp.headers().get(header::ETAG).unwrap(), "\"foo\"");
+        assert_eq!(&body(resp).await[..], b"identity");
+
+        // Entities without variants don't negotiate.
+        let resp = serve(
+            entity(b"identity"),
+            &FakeRequest::get()
+                .header(header::ACCEPT_ENCODING, "gzip")
+                .build(),
+        );
+        assert_eq!(resp.headers().get(header::CONTENT_ENCODING), None);
+        assert_eq!(resp.headers().get(header::VARY), None);
+        assert_eq!(&body(resp).await[..], b"identity");
+    }
+
+    #[tokio::test]
+    async fn br_variant() {
+        let e = entity(b"identity")
+            .variant(
+                ContentCoding::Gzip,
+                encoded(ContentCoding::Gzip, b"gzipped"),
+            )
+            .variant(
+                ContentCoding::Brotli,
+                encoded(ContentCoding::Brotli, b"brotli"),
+            );
+
+        // br is preferred when both are accepted, even at a lower qvalue.
+        for &ae in &["gzip, br", "br;q=0.5, gzip"] {
+            let resp = serve(
+                e.clone(),
+                &FakeRequest::get()
+                    .header(header::ACCEPT_ENCODING, ae)
+                    .build(),
+            );
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(resp.headers().get(header::CONTENT_ENCODING).unwrap(), "br");
+            assert_eq!(resp.headers().get(header::VARY).unwrap(), "accept-encoding");
+            assert_eq!(resp.headers().get(header::ETAG).unwrap(), "W/\"foo-br\"");
+            assert_eq!(&body(resp).await[..], b"brotli");
+        }
+
+        // Ranges apply to the br representation.
+        let resp = serve(
+            e.clone(),
+            &FakeRequest::get()
+                .header(header::ACCEPT_ENCODING, "gzip, br")
+                .range("bytes=0-1")
+                .build(),
+        );
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_RANGE).unwrap(),
+            "bytes 0-1/6"
+        );
+        assert_eq!(&body(resp).await[..], b"br");
+
+        // gzip is served when br isn't accepted.
+        let resp = serve(
+            e.clone(),
+            &FakeRequest::get()
+                .header(header::ACCEPT_ENCODING, "gzip, br;q=0")
+                .build(),
+        );
+        assert_eq!(
+            resp.headers().get(header::CONTENT_ENCODING).unwrap(),
+            "gzip"
+        );
+        assert_eq!(&body(resp).await[..], b"gzipped");
+    }
+
+    #[tokio::test]
+    async fn constant_time_etags() {
+        let opts = ServeOptions::new().constant_time_etags(true);
+        let e = entity(b"body");
+        let cases: &[(&str, &str, StatusCode)] = &[
+            ("If-Match", "\"foo\"", StatusCode::OK),
+            ("If-Match", "\"fob\"", StatusCode::PRECONDITION_FAILED),
+            ("If-Match", "\"fo\"", StatusCode::PRECONDITION_FAILED),
+            ("If-Match", "W/\"foo\"", StatusCode::PRECONDITION_FAILED),
+            ("If-None-Match", "\"foo\"", StatusCode::NOT_MODIFIED),
+            ("If-None-Match", "W/\"foo\"", StatusCode::NOT_MODIFIED),
+            (
+                "If-None-Match",
+                "\"bar\", \"foo\"",
+                StatusCode::NOT_MODIFIED,
+            ),
+            ("If-None-Match", "\"fooo\"", StatusCode::OK),
+        ];
+        for &(name, value, status) in cases {
+            let r = match name {
+                "If-Match" => FakeRequest::get().if_match(value),
+                _ => FakeRequest::get().if_none_match(value),
+            }
+            .build();
+            assert_eq!(serve(e.clone(), &r).status(), status, "{}: {}", name, value);
+            assert_eq!(
+                serve_with(e.clone(), &r, &opts).status(),
+                status,
+                "{}: {}",
+                name,
+                value
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn served_ranges() {
+        let e = entity(BODY);
+        let len = e.len();
+
+        // Full.
+        let resp = serve(e.clone(), &FakeRequest::get().build());
+        let served = resp.extensions().get::<ServedRanges>().unwrap().clone();
+        assert_eq!(served.ranges(), std::slice::from_ref(&(0..len)));
+        assert_eq!(served.body_len(), len);
+        assert_eq!(body(resp).await.len() as u64, len);
+
+        // Single range.
+        let resp = serve(e.clone(), &FakeRequest::get().range("bytes=1-3").build());
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        let served = resp.extensions().get::<ServedRanges>().unwrap().clone();
+        assert_eq!(served.ranges(), std::slice::from_ref(&(1..4)));
+        assert_eq!(served.body_len(), 3);
+
+        // Multipart, or the whole entity without the multipart feature.
+        let resp = serve(
+            e.clone(),
+            &FakeRequest::get().range("bytes=0-1, -2").build(),
+        );
+        let served = resp.extensions().get::<ServedRanges>().unwrap().clone();
+        if cfg!(feature = "multipart") {
+            assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+            assert_eq!(served.ranges(), &[0..2, len - 2..len]);
+        } else {
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(served.ranges(), std::slice::from_ref(&(0..len)));
+        }
+        let content_len: u64 = resp.headers()[header::CONTENT_LENGTH]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(served.body_len(), content_len);
+        assert_eq!(body(resp).await.len() as u64, content_len);
+
+        // Not modified.
+        let resp = serve(
+            e.clone(),
+            &FakeRequest::get().if_none_match("\"foo\"").build(),
+        );
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert!(resp.extensions().get::<ServedRanges>().is_none());
+
+        // Not satisfiable.
+        let resp = serve(e.clone(), &FakeRequest::get().range("bytes=500-").build());
+        assert_eq!(resp.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert!(resp.extensions().get::<ServedRanges>().is_none());
+
+        // HEAD.
+        let resp = serve(e.clone(), &FakeRequest::head().build());
+        assert!(resp.extensions().get::<ServedRanges>().is_none());
+    }
+
+    #[tokio::test]
+    async fn if_none_match_any() {
+        let no_etag = FakeEntity::new(&b"body"[..]).content_type("text/plain");
+        for e in &[entity(b"body"), no_etag] {
+            for method in &[Method::GET, Method::HEAD] {
+                let resp = serve(
+                    e.clone(),
+                    &FakeRequest::new(method.clone()).if_none_match("*").build(),
+                );
+                assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+                assert_eq!(resp.headers().get(header::ETAG), Entity::etag(e).as_ref());
+                assert_eq!(resp.headers().get(header::CONTENT_LENGTH), None);
+                assert_eq!(resp.headers().get(header::CONTENT_TYPE), None);
+                assert_eq!(&body(resp).await[..], b"");
+            }
+        }
+    }
+
+    #[test]
+    fn accept_ranges() {
+        let e = FakeEntity::new(BODY).etag("\"foo\"");
+        let cases = vec![
+            (FakeRequest::get(), StatusCode::OK),
+            (
+                FakeRequest::get().range("bytes=0-0"),
+                StatusCode::PARTIAL_CONTENT,
+            ),
+            (
+                FakeRequest::get().if_none_match("\"foo\""),
+                StatusCode::NOT_MODIFIED,
+            ),
+            (
+                FakeRequest::get().if_match("\"bar\""),
+                StatusCode::PRECONDITION_FAILED,
+            ),
+            (
+                FakeRequest::get().range("bytes=1000-"),
+                StatusCode::RANGE_NOT_SATISFIABLE,
+            ),
+        ];
+        for (r, status) in cases {
+            let resp = serve(e.clone(), &r.build());
+            assert_eq!(resp.status(), status);
+            assert_eq!(resp.headers().get(header::ACCEPT_RANGES).unwrap(), "bytes");
+        }
+        let r = FakeRequest::new(Method::POST).build();
+        let resp = serve(e, &r);
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(resp.headers().get(header::ACCEPT_RANGES), None);
+    }
+
+    #[test]
+    fn max_content_length() {
+        let opts = ServeOptions::new().max_content_length(|hdrs| {
+            hdrs.get("x-max-bytes")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse().ok())
+        });
+        let cases: &[(&str, StatusCode)] = &[
+            ("", StatusCode::PAYLOAD_TOO_LARGE),
+            ("bytes=0-99", StatusCode::PAYLOAD_TOO_LARGE),
+            ("bytes=0-0,10-10", StatusCode::PAYLOAD_TOO_LARGE),
+            ("bytes=0-9", StatusCode::PARTIAL_CONTENT),
+        ];
+        for &(range, status) in cases {
+            let mut r = FakeRequest::get().header(HeaderName::from_static("x-max-bytes"), "10");
+            if !range.is_empty() {
+                r = r.range(range);
+            }
+            let resp = serve_with(entity(BODY), &r.build(), &opts);
+            assert_eq!(resp.status(), status, "range {:?}", range);
+        }
+
+        // Without the header, there's no limit.
+        let resp = serve_with(entity(BODY), &FakeRequest::get().build(), &opts);
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    /// `would_not_modify` and `would_precondition_fail` must agree with `serve`, and their
+    /// `_with_options` variants with `serve_with_options`.
+    #[test]
+    fn predicates_agree_with_serve() {
+        let t = httpdate::parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT").unwrap();
+        let earlier = t - std::time::Duration::from_secs(3600);
+        let later = t + std::time::Duration::from_secs(3600);
+        let etags = [None, Some("\"foo\""), Some("W/\"foo\"")];
+        let mtimes = [None, Some(t)];
+        let requests: Vec<fn(SystemTime, SystemTime) -> FakeRequest> = vec![
+            |_, _| FakeRequest::get(),
+            |_, _| FakeRequest::get().if_match("\"foo\""),
+            |_, _| FakeRequest::get().if_match("\"bar\""),
+            |_, _| FakeRequest::get().if_match("W/\"foo\""),
+            |_, _| FakeRequest::get().if_match("*"),
+            |_, _| FakeRequest::get().if_none_match("\"foo\""),
+            |_, _| FakeRequest::get().if_none_match("W/\"foo\""),
+            |_, _| FakeRequest::get().if_none_match("W/\"foo\", \"bar\""),
+            |_, _| FakeRequest::get().if_none_match("\"bar\""),
+            |_, _| FakeRequest::get().if_none_match("*"),
+            |e, _| FakeRequest::get().if_modified_since(e),
+            |_, l| FakeRequest::get().if_modified_since(l),
+            |e, _| FakeRequest::get().if_unmodified_since(e),
+            |_, l| FakeRequest::get().if_unmodified_since(l),
+            |e, _| FakeRequest::get().if_match("\"bar\"").if_modified_since(e),
+            |_, l| {
+                FakeRequest::head()
+                    .if_none_match("\"foo\"")
+                    .if_unmodified_since(l)
+            },
+            |_, l| {
+                FakeRequest::get()
+                    .if_none_match("\"bar\"")
+                    .if_modified_since(l)
+            },
+            |_, _| FakeRequest::get().header(header::IF_MODIFIED_SINCE, "bogus"),
+            |_, _| FakeRequest::get().header(header::IF_UNMODIFIED_SINCE, "bogus"),
+        ];
+        let options = [
+            None,
+            Some(ServeOptions::new().if_none_match_comparison(crate::EtagComparison::StrongOnly)),
+            Some(ServeOptions::new().omit_last_modified(true)),
+            Some(ServeOptions::new().permissive_if_range_dates(true)),
+        ];
+        for opts in &options {
+            for etag in &etags {
+                for mtime in &mtimes {
+                    let mut e = FakeEntity::new(BODY);
+                    if let Some(etag) = etag {
+                        e = e.etag(etag);
+                    }
+                    if let Some(m) = mtime {
+                        e = e.last_modified(*m);
+                    }
+                    let etag = etag.map(HeaderValue::from_static);
+                    for (i, r) in requests.iter().enumerate() {
+                        let r = r(earlier, later).build();
+                        let (resp, not_modified, precondition_failed) = match opts {
+                            None => (
+                                serve(e.clone(), &r),
+                                super::would_not_modify(r.headers(), etag.as_ref(), *mtime),
+                                super::would_precondition_fail(r.headers(), etag.as_ref(), *mtime),
+                            ),
+                            Some(o) => (
+                                serve_with(e.clone(), &r, o),
+                                super::would_not_modify_with_options(
+                                    r.headers(),
+                                    etag.as_ref(),
+                                    *mtime,
+                                    o,
+                                ),
+                                super::would_precondition_fail_with_options(
+                                    r.headers(),
+                                    etag.as_ref(),
+                                    *mtime,
+                                    o,
+                                ),
+                            ),
+                        };
+                        let ctx = (i, &etag, mtime, opts);
+                        assert_eq!(
+                            not_modified,
+                            resp.status() == StatusCode::NOT_MODIFIED,
+                            "{:?}",
+                            ctx
+                        );
+                        assert_eq!(
+                            precondition_failed,
+                            resp.status() == StatusCode::PRECONDITION_FAILED,
+                            "{:?}",
+                            ctx
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn permissive_if_range_dates() {
+        use std::time::Duration;
+        let date = "Sun, 06 Nov 1994 08:49:37 GMT";
+        let t = httpdate::parse_http_date(date).unwrap();
+        let permissive = ServeOptions::new().permissive_if_range_dates(true);
+        let status = |e: &FakeEntity, opts: &ServeOptions, if_range: &str| {
+            let r = FakeRequest::get()
+                .range("bytes=0-0")
+                .if_range(if_range)
+                .build();
+            let resp = serve_with(e.clone(), &r, opts);
+            resp.status()
+        };
+        let e = FakeEntity::new(BODY).etag("\"foo\"").last_modified(t);
+
+        // Equal second.
+        assert_eq!(status(&e, &permissive, date), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(status(&e, &ServeOptions::new(), date), StatusCode::OK);
+
+        // Differing second.
+        let other = "Sun, 06 Nov 1994 08:49:38 GMT";
+        assert_eq!(status(&e, &permissive, other), StatusCode::OK);
+
+        // Sub-second precision in the last modified time.
+        let subsec = e.clone().last_modified(t + Duration::from_millis(1));
+        assert_eq!(status(&subsec, &permissive, date), StatusCode::OK);
+
+        // Weak or no etag.
+        let weak = e.clone().etag("W/\"foo\"");
+        assert_eq!(status(&weak, &permissive, date), StatusCode::OK);
+        let none = FakeEntity::new(BODY).last_modified(t);
+        assert_eq!(status(&none, &permissive, date), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn ranged_head() {
+        let e = FakeEntity::new(BODY).content_type("text/plain");
+
+        let r = FakeRequest::head().range("bytes=1-10").build();
+        let resp = serve(e.clone(), &r);
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_RANGE).unwrap(),
+            "bytes 1-10/240"
+        );
+        assert_eq!(resp.headers().get(header::CONTENT_LENGTH).unwrap(), "10");
+        assert_eq!(&body(resp).await[..], b"");
+
+        // The multipart (or, without the multipart feature, full) Content-Length must match that of
+        // the equivalent GET.
+        let get = FakeRequest::get().range("bytes=0-1, -2").build();
+        let get_resp = serve(e.clone(), &get);
+        let get_len = get_resp
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .unwrap()
+            .clone();
+        assert_eq!(
+            get_len.to_str().unwrap(),
+            body(get_resp).await.len().to_string()
+        );
+
+        let r = FakeRequest::head().range("bytes=0-1, -2").build();
+        let resp = serve(e, &r);
+        let content_type = resp.headers()[header::CONTENT_TYPE].to_str().unwrap();
+        if cfg!(feature = "multipart") {
+            assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+            assert!(content_type.starts_with("multipart/byteranges; boundary="));
+        } else {
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(content_type, "text/plain");
+        }
+        assert_eq!(
+            resp.headers().get(header::CONTENT_LENGTH).unwrap(),
+            &get_len
+        );
+        assert_eq!(&body(resp).await[..], b"");
+    }
+
+    /// Two downloads of an always-ready entity on a single-threaded runtime should interleave
+    /// when yielding is enabled, rather than one finishing before the other starts.
+    #[tokio::test]
+    async fn yield_every() {
+        use futures::StreamExt;
+        use std::sync::{Arc, Mutex};
+
+        async fn downloads(opts: ServeOptions) -> Vec<usize> {
+            let log = Arc::new(Mutex::new(Vec::new()));
+            let handles: Vec<_> = (0..2)
+                .map(|i| {
+                    let log = Arc::clone(&log);
+                    let resp = serve_with(
+                        FakeEntity::new(BODY).chunk_size(10),
+                        &FakeRequest::get().build(),
+                        &opts,
+                    );
+                    tokio::spawn(async move {
+                        let mut body = resp.into_body();
+                        while let Some(c) = body.next().await {
+                            c.unwrap();
+                            log.lock().unwrap().push(i);
+                        }
+                    })
+                })
+                .collect();
+            for h in handles {
+                h.await.unwrap();
+            }
+            let log = log.lock().unwrap();
+            log.clone()
+        }
+
+        // Count the number of times the log switches between downloads.
+        let switches = |log: &[usize]| log.windows(2).filter(|w| w[0] != w[1]).count();
+        assert_eq!(switches(&downloads(ServeOptions::new()).await), 1);
+        assert!(switches(&downloads(ServeOptions::new().yield_every(50)).await) > 4);
+    }
+
+    /// `yield_every(0)` never yields, rather than yielding forever without progress.
+    #[tokio::test]
+    async fn yield_every_zero() {
+        let opts = ServeOptions::new().yield_every(0);
+        let resp = serve_with(entity(BODY), &FakeRequest::get().build(), &opts);
+        assert_eq!(&body(resp).await[..], BODY);
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn serve_stream() {
+        use futures::StreamExt;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+
+        let polled = Arc::new(AtomicBool::new(false));
+        let svc_polled = Arc::clone(&polled);
+        let make_svc =
+            hyper::service::make_service_fn(move |_conn| {
+                let polled = Arc::clone(&svc_polled);
+                futures::future::ok::<_, hyper::Error>(hyper::service::service_fn(
+                    move |req: Request<hyper::Body>| {
+                        let polled = Arc::clone(&polled);
+                        let chunks = stream::iter(vec!["one ", "two ", "three"]).map(move |c| {
+                            polled.store(true, Ordering::SeqCst);
+                            Ok::<_, BoxedError>(Bytes::from_static(c.as_bytes()))
+                        });
+                        let content_type = HeaderValue::from_static("text/plain");
+                        futures::future::ok::<Response<hyper::Body>, BoxedError>(
+                            super::serve_stream(&req, chunks, content_type),
+                        )
+                    },
+                ))
+            });
+        let srv = hyper::server::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let url = format!("http://{}/", srv.local_addr());
+        tokio::spawn(srv);
+        let client = reqwest::Client::new();
+
+        let resp = client.head(&url).send().await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        assert!(!polled.load(Ordering::SeqCst));
+
+        let resp = client.get(&url).send().await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(header::TRANSFER_ENCODING).unwrap(),
+            "chunked"
+        );
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/plain"
+        );
+        assert_eq!(&resp.bytes().await.unwrap()[..], b"one two three");
+        assert!(polled.load(Ordering::SeqCst));
+    }
+
+    /// By default, a content-coded response's etag is weak, and still validates with
+    /// `If-None-Match`. `weak_encoded_etags(false)` sends it as is.
+    #[test]
+    fn encoded_etag_is_weak() {
+        let e = entity(b"identity").variant(
+            ContentCoding::Gzip,
+            encoded(ContentCoding::Gzip, b"gzipped"),
+        );
+        let gzip = FakeRequest::get()
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .build();
+        let resp = serve(e.clone(), &gzip);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_ENCODING).unwrap(),
+            "gzip"
+        );
+        assert_eq!(resp.headers().get(header::ETAG).unwrap(), "W/\"foo-gzip\"");
+
+        let resp = serve(
+            e.clone(),
+            &FakeRequest::get()
+                .header(header::ACCEPT_ENCODING, "gzip")
+                .if_none_match("W/\"foo-gzip\"")
+                .build(),
+        );
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(resp.headers().get(header::ETAG).unwrap(), "W/\"foo-gzip\"");
+
+        let opts = ServeOptions::new().weak_encoded_etags(false);
+        let resp = serve_with(e, &gzip, &opts);
+        assert_eq!(resp.headers().get(header::ETAG).unwrap(), "\"foo-gzip\"");
+    }
+
+    /// The identity representation's etag stays strong by default, so `If-Range` still resumes it.
+    #[tokio::test]
+    async fn identity_etag_is_strong() {
+        let e = entity(BODY).variant(
+            ContentCoding::Gzip,
+            encoded(ContentCoding::Gzip, b"gzipped"),
+        );
+        let resp = serve(e.clone(), &FakeRequest::get().build());
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get(header::CONTENT_ENCODING), None);
+        assert_eq!(resp.headers().get(header::ETAG).unwrap(), "\"foo\"");
+
+        let resp = serve(
+            e,
+            &FakeRequest::get()
+                .range("bytes=10-19")
+                .if_range("\"foo\"")
+                .build(),
+        );
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(resp.headers().get(header::ETAG).unwrap(), "\"foo\"");
+        assert_eq!(&body(resp).await[..], &BODY[10..20]);
+    }
+
+    #[test]
+    fn common_headers() {
+        let e = FakeEntity::new(BODY)
+            .etag("\"foo\"")
+            .last_modified(SystemTime::now());
+        let cases = vec![
+            (FakeRequest::get(), StatusCode::OK, true),
+            (
+                FakeRequest::get().range("bytes=0-0"),
+                StatusCode::PARTIAL_CONTENT,
+                true,
+            ),
+            (
+                FakeRequest::get().if_none_match("\"foo\""),
+                StatusCode::NOT_MODIFIED,
+                true,
+            ),
+            (
+                FakeRequest::get().if_match("\"bar\""),
+                StatusCode::PRECONDITION_FAILED,
+                true,
+            ),
+            (
+                FakeRequest::get().range("bytes=1000-"),
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                true,
+            ),
+            (
+                FakeRequest::get().header(header::IF_MODIFIED_SINCE, "bogus"),
+                StatusCode::BAD_REQUEST,
+                false,
+            ),
+            (
+                FakeRequest::new(Method::POST),
+                StatusCode::METHOD_NOT_ALLOWED,
+                false,
+            ),
+            (
+                FakeRequest::get().header(header::HeaderName::from_static("x-max"), "1"),
+                StatusCode::PAYLOAD_TOO_LARGE,
+                false,
+            ),
+        ];
+        let limit = |hdrs: &HeaderMap| hdrs.get("x-max").map(|_| 1);
+        for (r, status, accept_ranges_by_default) in cases {
+            let r = r.build();
+            for &always in &[false, true] {
+                let opts = ServeOptions::new()
+                    .max_content_length(limit)
+                    .accept_ranges_always(always);
+                let resp = serve_with(e.clone(), &r, &opts);
+                assert_eq!(resp.status(), status);
+                assert!(resp.headers().contains_key(header::DATE), "{}", status);
+                assert_eq!(
+                    resp.headers().contains_key(header::ACCEPT_RANGES),
+                    always || accept_ranges_by_default,
+                    "{} always={}",
+                    status,
+                    always
+                );
+            }
+        }
+    }
+
+    #[cfg(feature = "multipart")]
+    #[tokio::test]
+    async fn verify_boundary() {
+        // The body contains the delimiters for the first two random candidates from seed 42, so
+        // the third should be chosen.
+        let rng = crate::rng::SplitMix::new(42);
+        let first = crate::multipart::next_boundary_candidate(&rng);
+        let second = crate::multipart::next_boundary_candidate(&rng);
+        let third = crate::multipart::next_boundary_candidate(&rng);
+        let mut b = BODY.to_vec();
+        b[0..18].copy_from_slice(format!("--{}", first).as_bytes());
+        b[100..118].copy_from_slice(format!("--{}", second).as_bytes());
+        let e = FakeEntity::new(b.clone());
+        let r = FakeRequest::get().range("bytes=0-19, 90-129").build();
+
+        // Without verification, the first candidate is used regardless.
+        let opts = ServeOptions::new().rng_seed(42);
+        let resp = serve_with(e.clone(), &r, &opts);
+        assert_eq!(boundary(&resp), first);
+
+        let opts = opts.verify_boundary(true);
+        let resp = serve_with(e, &r, &opts);
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            resp.headers()
+                .get(header::CONTENT_TYPE)
+                .unwrap()
+                .to_str()
+                .unwrap(),
+            format!("multipart/byteranges; boundary={}", third)
+        );
+        let len: usize = resp.headers()[header::CONTENT_LENGTH]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let body = body(resp).await;
+        assert_eq!(body.len(), len);
+
+        // Splitting on the delimiter yields exactly the preamble, two parts, and the epilogue.
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        let parts: Vec<&str> = body.split(&format!("\r\n--{}", third)).collect();
+        assert_eq!(parts.len(), 4, "{:?}", parts);
+        assert_eq!(parts[0], "");
+        assert_eq!(parts[3], "--\r\n");
+        for (part, range) in parts[1..3].iter().zip(&[0..20, 90..130]) {
+            let content = &part[part.find("\r\n\r\n").unwrap() + 4..];
+            assert_eq!(content.as_bytes(), &b[range.clone()]);
+        }
+    }
+
+    /// When every candidate boundary collides, the multipart fallback applies rather than an
+    /// unverified boundary being sent.
+    #[cfg(feature = "multipart")]
+    #[tokio::test]
+    async fn verify_boundary_exhausted() {
+        use super::MultipartFallback;
+
+        /// Claims to contain every needle.
+        struct AlwaysContains(FakeEntity);
+
+        impl Entity for AlwaysContains {
+            type Data = Bytes;
+            type Error = BoxedError;
+
+            fn len(&self) -> u64 {
+                self.0.len()
+            }
+            fn get_range(
+                &self,
+                range: Range<u64>,
+            ) -> Box<dyn Stream<Item = Result<Self::Data, Self::Error>> + Send + Sync> {
+                self.0.get_range(range)
+            }
+            fn add_headers(&self, h: &mut HeaderMap) {
+                self.0.add_headers(h)
+            }
+            fn etag(&self) -> Option<HeaderValue> {
+                None
+            }
+            fn last_modified(&self) -> Option<SystemTime> {
+                None
+            }
+            fn contains(&self, _needle: &[u8], _within: Range<u64>) -> Option<bool> {
+                Some(true)
+            }
+        }
+
+        let e = || AlwaysContains(FakeEntity::new(BODY));
+        let r = FakeRequest::get().range("bytes=0-19, 90-129").build();
+
+        let resp = serve(e(), &r);
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        boundary(&resp);
+
+        let opts = ServeOptions::new().verify_boundary(true);
+        let resp = serve_with(e(), &r, &opts);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp
+            .extensions()
+            .get::<ServedRanges>()
+            .unwrap()
+            .boundary()
+            .is_none());
+        assert_eq!(&body(resp).await[..], BODY);
+
+        let opts = opts.multipart_fallback(MultipartFallback::RangeNotSatisfiable);
+        let resp = serve_with(e(), &r, &opts);
+        assert_eq!(resp.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+    }
+
+    #[cfg(feature = "multipart")]
+    #[tokio::test]
+    async fn multipart_golden() {
+        let e = FakeEntity::new(BODY).content_type("text/plain");
+        let r = FakeRequest::get().range("bytes=0-9,200-209").build();
+        let resp = serve_with(e, &r, &ServeOptions::new().rng_seed(1));
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            "multipart/byteranges; boundary=910a2dec89025cc1"
+        );
+        let mut expected = Vec::new();
+        expected.extend_from_slice(
+            b"\r\n--910a2dec89025cc1\r\n\
+              Content-Range: bytes 0-9/240\r\n\
+              content-type: text/plain\r\n\
+              \r\n",
+        );
+        expected.extend_from_slice(&BODY[0..10]);
+        expected.extend_from_slice(
+            b"\r\n--910a2dec89025cc1\r\n\
+              Content-Range: bytes 200-209/240\r\n\
+              content-type: text/plain\r\n\
+              \r\n",
+        );
+        expected.extend_from_slice(&BODY[200..210]);
+        expected.extend_from_slice(b"\r\n--910a2dec89025cc1--\r\n");
+        assert_eq!(
+            resp.headers().get(header::CONTENT_LENGTH).unwrap(),
+            &expected.len().to_string()[..]
+        );
+        let body = body(resp).await;
+        assert_eq!(
+            String::from_utf8_lossy(&body),
+            String::from_utf8_lossy(&expected)
+        );
+    }
+
+    /// The first-and-last-byte probe media players send: `bytes=0-0,-1`.
+    #[cfg(feature = "multipart")]
+    #[tokio::test]
+    async fn multipart_first_and_last_byte() {
+        let e = FakeEntity::new(BODY).content_type("text/plain");
+        let r = FakeRequest::get().range("bytes=0-0,-1").build();
+        let resp = serve(e, &r);
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        let b = boundary(&resp);
+        let mut expected = Vec::new();
+        expected.extend_from_slice(
+            format!(
+                "\r\n--{}\r\n\
+                 Content-Range: bytes 0-0/240\r\n\
+                 content-type: text/plain\r\n\
+                 \r\n",
+                b
+            )
+            .as_bytes(),
+        );
+        expected.push(BODY[0]);
+        expected.extend_from_slice(
+            format!(
+                "\r\n--{}\r\n\
+                 Content-Range: bytes 239-239/240\r\n\
+                 content-type: text/plain\r\n\
+                 \r\n",
+                b
+            )
+            .as_bytes(),
+        );
+        expected.push(BODY[239]);
+        expected.extend_from_slice(format!("\r\n--{}--\r\n", b).as_bytes());
+        assert_eq!(
+            resp.headers().get(header::CONTENT_LENGTH).unwrap(),
+            &expected.len().to_string()[..]
+        );
+        let b = body(resp).await;
+        assert_eq!(
+            String::from_utf8_lossy(&b),
+            String::from_utf8_lossy(&expected)
+        );
+
+        // With a one-byte entity, both ranges name the same byte, so they're coalesced into a
+        // single-part response.
+        let e = FakeEntity::new(&b"x"[..]).content_type("text/plain");
+        let resp = serve(e, &r);
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(resp.headers()[header::CONTENT_RANGE], "bytes 0-0/1");
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "text/plain");
+        assert_eq!(resp.headers()[header::CONTENT_LENGTH], "1");
+        assert_eq!(&body(resp).await[..], b"x");
+    }
+
+    /// Simulates a download manager resuming with `Range: bytes=N-` and `If-Range: <date>`.
+    #[tokio::test]
+    async fn if_range_date_resume() {
+        use std::time::Duration;
+        let t = httpdate::parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT").unwrap();
+        let permissive = ServeOptions::new().permissive_if_range_dates(true);
+        let unchanged = FakeEntity::new(BODY).etag("\"v1\"").last_modified(t);
+        let changed = FakeEntity::new(&b"new contents"[..])
+            .etag("\"v2\"")
+            .last_modified(t + Duration::from_secs(60));
+
+        // The initial download, interrupted after 100 bytes.
+        let initial = FakeRequest::get().build();
+        let resp = serve_with(unchanged.clone(), &initial, &permissive);
+        assert_eq!(resp.status(), StatusCode::OK);
+        let last_modified = resp.headers()[header::LAST_MODIFIED]
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let resume = FakeRequest::get()
+            .range("bytes=100-")
+            .if_range(&last_modified)
+            .build();
+
+        // Unchanged: the rest of the body.
+        let resp = serve_with(unchanged.clone(), &resume, &permissive);
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_RANGE).unwrap(),
+            "bytes 100-239/240"
+        );
+        assert_eq!(&body(resp).await[..], &BODY[100..]);
+
+        // Changed: the whole new body.
+        let resp = serve_with(changed, &resume, &permissive);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get(header::CONTENT_RANGE), None);
+        assert_eq!(&body(resp).await[..], b"new contents");
+
+        // Without the permissive option, a date never matches, so the resume restarts.
+        let resp = serve(unchanged, &resume);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(&body(resp).await[..], BODY);
+    }
+
+    #[tokio::test]
+    async fn success_status() {
+        let opts = ServeOptions::new().success_status(StatusCode::NON_AUTHORITATIVE_INFORMATION);
+        let resp = serve_with(entity(BODY), &FakeRequest::get().build(), &opts);
+        assert_eq!(resp.status(), StatusCode::NON_AUTHORITATIVE_INFORMATION);
+        assert_eq!(&body(resp).await[..], BODY);
+
+        let resp = serve_with(entity(BODY), &FakeRequest::head().build(), &opts);
+        assert_eq!(resp.status(), StatusCode::NON_AUTHORITATIVE_INFORMATION);
+
+        let resp = serve_with(
+            entity(BODY),
+            &FakeRequest::get().range("bytes=0-9").build(),
+            &opts,
+        );
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(&body(resp).await[..], &BODY[0..10]);
+
+        let resp = serve_with(
+            entity(BODY),
+            &FakeRequest::get().if_none_match("\"foo\"").build(),
+            &opts,
+        );
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[tokio::test]
+    async fn digest() {
+        const DIGEST: &str = "sha-256=:X48E9qOokqqrvdts8nOJRJN3OWDUoyWxBf7kbu9DBPE=:";
+        let e = FakeEntity::new(&b"Hello, world!"[..])
+            .etag("\"v1\"")
+            .digest(DIGEST);
+        let cases = vec![
+            (FakeRequest::get(), StatusCode::OK, true),
+            (FakeRequest::head(), StatusCode::OK, true),
+            // If-Range mismatch: the full representation is sent.
+            (
+                FakeRequest::get().range("bytes=0-4").if_range("\"v0\""),
+                StatusCode::OK,
+                true,
+            ),
+            (
+                FakeRequest::get().range("bytes=0-4"),
+                StatusCode::PARTIAL_CONTENT,
+                false,
+            ),
+            (
+                FakeRequest::get().range("bytes=0-"),
+                StatusCode::PARTIAL_CONTENT,
+                false,
+            ),
+            // Multiple ranges of a short body: the full representation is cheaper.
+            (
+                FakeRequest::get().range("bytes=0-1,5-6"),
+                StatusCode::OK,
+                true,
+            ),
+            (
+                FakeRequest::get().if_none_match("\"v1\""),
+                StatusCode::NOT_MODIFIED,
+                false,
+            ),
+        ];
+        for (req, status, expected) in cases {
+            let req = req.build();
+            let resp = serve(e.clone(), &req);
+            assert_eq!(resp.status(), status, "{:?}", req);
+            for name in &["repr-digest", "content-digest"] {
+                let expected = if expected { Some(DIGEST) } else { None };
+                assert_eq!(
+                    resp.headers().get(*name).map(|v| v.to_str().unwrap()),
+                    expected,
+                    "{} {:?}",
+                    name,
+                    req
+                );
+            }
+        }
+
+        // Without a digest, neither header is sent.
+        let e = FakeEntity::new(&b"Hello, world!"[..]);
+        let resp = serve(e, &FakeRequest::get().build());
+        assert!(!resp.headers().contains_key("repr-digest"));
+        assert!(!resp.headers().contains_key("content-digest"));
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn error_trailer() {
+        use hyper::body::HttpBody;
+        async fn fetch(
+            opts: ServeOptions,
+            h2: bool,
+        ) -> Result<(Response<hyper::Body>, Vec<u8>), hyper::Error> {
+            let make_svc = hyper::service::make_service_fn(move |_conn| {
+                let opts = opts.clone();
+                futures::future::ok::<_, hyper::Error>(hyper::service::service_fn(
+                    move |req: Request<hyper::Body>| {
+                        futures::future::ok::<_, BoxedError>(super::serve_with_trailers(
+                            FakeEntity::new(BODY).fail_after(40),
+                            &req,
+                            &opts,
+                        ))
+                    },
+                ))
+            });
+            let srv = hyper::Server::bind(&([127, 0, 0, 1], 0).into())
+                .http2_only(h2)
+                .serve(make_svc);
+            let addr = srv.local_addr();
+            tokio::spawn(srv);
+            let client = hyper::Client::builder().http2_only(h2).build_http();
+            let req = Request::get(format!("http://{}/", addr))
+                .header(header::RANGE, "bytes=10-89")
+                .body(hyper::Body::empty())
+                .unwrap();
+            let mut resp = client.request(req).await?;
+            let mut data = Vec::new();
+            while let Some(chunk) = resp.data().await {
+                data.extend_from_slice(&chunk?);
+            }
+            Ok((resp, data))
+        }
+
+        let name = header::HeaderName::from_static("x-stream-error");
+        let opts = ServeOptions::new().error_trailer(name.clone());
+
+        // HTTP/2: the body ends early, with the trailer.
+        let (mut resp, data) = fetch(opts.clone(), true).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(resp.headers().get(header::CONTENT_LENGTH), None);
+        assert_eq!(&data[..], &BODY[10..50]);
+        let trailers = resp.trailers().await.unwrap().unwrap();
+        assert_eq!(trailers.get(&name).unwrap(), "io");
+
+        // HTTP/2 without the option: the stream is reset, possibly before the client has seen
+        // the response headers.
+        fetch(ServeOptions::new(), true).await.unwrap_err();
+
+        // HTTP/1.1: the connection is dropped, even with the option.
+        fetch(opts, false).await.unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn handle_options() {
+        let e = FakeEntity::new(BODY);
+        let check = |opts: &ServeOptions, method: Method, status, allow: Option<&str>| {
+            let r = FakeRequest::new(method.clone()).build();
+            let resp = serve_with(e.clone(), &r, opts);
+            assert_eq!(resp.status(), status, "{}", method);
+            assert_eq!(
+                resp.headers()
+                    .get(header::ALLOW)
+                    .map(|v| v.to_str().unwrap()),
+                allow,
+                "{}",
+                method
+            );
+            resp
+        };
+
+        let off = ServeOptions::new();
+        let allow = Some("GET, HEAD");
+        check(&off, Method::PUT, StatusCode::METHOD_NOT_ALLOWED, allow);
+        check(&off, Method::OPTIONS, StatusCode::METHOD_NOT_ALLOWED, allow);
+        check(&off, Method::GET, StatusCode::OK, None);
+
+        let on = ServeOptions::new().handle_options(true);
+        let allow = Some("GET, HEAD, OPTIONS");
+        check(&on, Method::PUT, StatusCode::METHOD_NOT_ALLOWED, allow);
+        let resp = check(&on, Method::OPTIONS, StatusCode::NO_CONTENT, allow);
+        assert!(!resp.headers().contains_key(header::CONTENT_LENGTH));
+        assert!(body(resp).await.is_empty());
+        check(&on, Method::GET, StatusCode::OK, None);
+    }
+
+    /// `serve` should produce the same responses whether an entity's headers come from
+    /// `static_headers` or `add_headers`.
+    #[tokio::test]
+    async fn static_headers() {
+        /// Hides the wrapped entity's `static_headers`.
+        struct Dynamic(FakeEntity);
+
+        impl Entity for Dynamic {
+            type Data = Bytes;
+            type Error = BoxedError;
+
+            fn len(&self) -> u64 {
+                self.0.len()
+            }
+            fn get_range(
+                &self,
+                range: Range<u64>,
+            ) -> Box<dyn Stream<Item = Result<Self::Data, Self::Error>> + Send + Sync> {
+                self.0.get_range(range)
+            }
+            fn add_headers(&self, h: &mut HeaderMap) {
+                self.0.add_headers(h)
+            }
+            fn etag(&self) -> Option<HeaderValue> {
+                Entity::etag(&self.0)
+            }
+            fn last_modified(&self) -> Option<SystemTime> {
+                Entity::last_modified(&self.0)
+            }
+        }
+
+        let e = FakeEntity::new(BODY)
+            .etag("\"foo\"")
+            .content_type("text/plain")
+            .header(header::CONTENT_LANGUAGE, "en")
+            .header(header::LINK, "</a>; rel=preload")
+            .header(header::LINK, "</b>; rel=preload");
+        assert!(e.static_headers().is_some());
+
+        // Pin the multipart boundary so both responses have the same one.
+        let opts = ServeOptions::new();
+        #[cfg(feature = "multipart")]
+        let opts = opts.rng_seed(1);
+        for r in vec![
+            FakeRequest::get(),
+            FakeRequest::head(),
+            FakeRequest::get().range("bytes=0-9"),
+            FakeRequest::get().range("bytes=0-9,200-209"),
+            FakeRequest::get().if_none_match("\"foo\""),
+        ] {
+            let r = r.build();
+            let a = serve_with(e.clone(), &r, &opts);
+            let b = serve_with(Dynamic(e.clone()), &r, &opts);
+            let (mut a, a_body) = a.into_parts();
+            let (mut b, b_body) = b.into_parts();
+            a.headers.remove(header::DATE);
+            b.headers.remove(header::DATE);
+            assert_eq!(a.status, b.status, "{:?}", r);
+            assert_eq!(a.headers, b.headers, "{:?}", r);
+            let a_body = hyper::body::to_bytes(a_body).await.unwrap();
+            let b_body = hyper::body::to_bytes(b_body).await.unwrap();
+            assert_eq!(a_body, b_body, "{:?}", r);
+        }
+    }
+
+    /// Building a response shouldn't read the entity, whatever the disposition, so dropping it
+    /// unpolled is free.
+    #[tokio::test]
+    async fn disposition_is_side_effect_free() {
+        use super::ServeDisposition;
+
+        let e = || FakeEntity::new(BODY).etag("\"foo\"");
+        let cases = vec![
+            (FakeRequest::get(), ServeDisposition::Full),
+            (FakeRequest::head(), ServeDisposition::Full),
+            (
+                FakeRequest::get().range("bytes=0-9"),
+                ServeDisposition::Partial,
+            ),
+            (
+                FakeRequest::get().range("bytes=0-9,200-209"),
+                if cfg!(feature = "multipart") {
+                    ServeDisposition::Multipart
+                } else {
+                    ServeDisposition::Full
+                },
+            ),
+            (
+                FakeRequest::get().if_none_match("\"foo\""),
+                ServeDisposition::NotModified,
+            ),
+            (
+                FakeRequest::get().if_match("\"bar\""),
+                ServeDisposition::PreconditionFailed,
+            ),
+            (
+                FakeRequest::get().range("bytes=500-"),
+                ServeDisposition::RangeNotSatisfiable,
+            ),
+            (
+                FakeRequest::new(Method::POST),
+                ServeDisposition::MethodNotAllowed,
+            ),
+            (FakeRequest::new(Method::OPTIONS), ServeDisposition::Options),
+        ];
+        for (r, disposition) in cases {
+            let r = r.build();
+            let opts = ServeOptions::new()
+                .handle_options(true)
+                .yield_every(16)
+                .max_content_length(|_| Some(1000));
+            let e = e();
+            let resp = serve_with(e.clone(), &r, &opts);
+            assert_eq!(ServeDisposition::of(&resp), Some(disposition), "{:?}", r);
+            drop(resp);
+            assert_eq!(e.get_range_calls(), 0, "{:?}", r);
+        }
+
+        // The entity is read once the body is polled.
+        let e = e();
+        let r = FakeRequest::get().range("bytes=0-9").build();
+        let resp = serve(e.clone(), &r);
+        assert_eq!(e.get_range_calls(), 0);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"0123456789");
+        assert_eq!(e.get_range_calls(), 1);
+    }
+
+    #[test]
+    fn replace_if() {
+        use super::ServeDisposition;
+
+        let redirect = || {
+            Response::builder()
+                .status(StatusCode::SEE_OTHER)
+                .header(header::LOCATION, "/reauth")
+                .body(hyper::Body::empty())
+                .unwrap()
+        };
+        let veto = |d| match d {
+            ServeDisposition::PreconditionFailed => Some(redirect()),
+            _ => None,
+        };
+        let resp = super::replace_if(serve(entity(BODY), &FakeRequest::get().build()), veto);
+        assert_eq!(resp.status(), StatusCode::OK);
+        let r = FakeRequest::get().if_match("\"bar\"").build();
+        let resp = super::replace_if(serve(entity(BODY), &r), veto);
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+
+        // Responses not from serve are left alone.
+        let resp = super::replace_if(redirect(), |_| -> Option<Response<hyper::Body>> {
+            panic!("no disposition")
+        });
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+    }
+
+    /// An empty entity is served with an explicit `Content-Length: 0` and its entity headers,
+    /// rather than a chunked or length-less body.
+    #[tokio::test]
+    async fn empty_entity() {
+        let e = FakeEntity::new(&b""[..])
+            .etag("\"empty\"")
+            .content_type("text/plain");
+        for r in vec![FakeRequest::get(), FakeRequest::head()] {
+            let r = r.build();
+            let resp = serve(e.clone(), &r);
+            assert_eq!(resp.status(), StatusCode::OK, "{:?}", r);
+            assert_eq!(
+                resp.headers().get(header::CONTENT_LENGTH).unwrap(),
+                "0",
+                "{:?}",
+                r
+            );
+            assert_eq!(
+                resp.headers().get(header::CONTENT_TYPE).unwrap(),
+                "text/plain",
+                "{:?}",
+                r
+            );
+            assert_eq!(resp.headers().get(header::ETAG).unwrap(), "\"empty\"");
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            assert!(body.is_empty());
+        }
+    }
+
+    /// An etag precondition takes precedence over its date counterpart, which is then ignored.
+    #[test]
+    fn etag_preconditions_take_precedence() {
+        use std::time::Duration;
+
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        let later = modified + Duration::from_secs(3600);
+        let earlier = modified - Duration::from_secs(3600);
+        let e = FakeEntity::new(BODY)
+            .etag("\"foo\"")
+            .last_modified(modified);
+        let cases = vec![
+            // If-Modified-Since alone would give a 304.
+            (
+                FakeRequest::get().if_modified_since(later),
+                StatusCode::NOT_MODIFIED,
+            ),
+            (
+                FakeRequest::get()
+                    .if_none_match("\"bar\"")
+                    .if_modified_since(later),
+                StatusCode::OK,
+            ),
+            (
+                FakeRequest::get()
+                    .if_none_match("\"foo\"")
+                    .if_modified_since(earlier),
+                StatusCode::NOT_MODIFIED,
+            ),
+            // If-Unmodified-Since alone would give a 412.
+            (
+                FakeRequest::get().if_unmodified_since(earlier),
+                StatusCode::PRECONDITION_FAILED,
+            ),
+            (
+                FakeRequest::get()
+                    .if_match("\"foo\"")
+                    .if_unmodified_since(earlier),
+                StatusCode::OK,
+            ),
+            (
+                FakeRequest::get()
+                    .if_match("\"bar\"")
+                    .if_unmodified_since(later),
+                StatusCode::PRECONDITION_FAILED,
+            ),
+        ];
+        for (r, status) in cases {
+            let r = r.build();
+            let resp = serve(e.clone(), &r);
+            assert_eq!(resp.status(), status, "{:?}", r);
+            assert_eq!(
+                super::would_not_modify(r.headers(), Entity::etag(&e).as_ref(), Some(modified)),
+                status == StatusCode::NOT_MODIFIED,
+                "{:?}",
+                r
+            );
+        }
+    }
+
+    #[cfg(feature = "multipart")]
+    #[tokio::test]
+    async fn max_multipart_bytes() {
+        use super::MultipartFallback;
+
+        let e = FakeEntity::new(BODY).etag("\"foo\"");
+        let r = FakeRequest::get().range("bytes=0-9,200-209").build();
+        let serve_with = |opts: ServeOptions| -> Response<hyper::Body> {
+            super::serve_with_options(e.clone(), &r, &opts)
+        };
+        let unlimited = serve_with(ServeOptions::new());
+        let body_len = unlimited
+            .extensions()
+            .get::<ServedRanges>()
+            .unwrap()
+            .body_len();
+        let len = BODY.len() as u64;
+        let cases = [
+            (
+                body_len,
+                MultipartFallback::Full,
+                StatusCode::PARTIAL_CONTENT,
+            ),
+            (body_len - 1, MultipartFallback::Full, StatusCode::OK),
+            (
+                body_len - 1,
+                MultipartFallback::FullUpTo(len),
+                StatusCode::OK,
+            ),
+            (
+                body_len - 1,
+                MultipartFallback::FullUpTo(len - 1),
+                StatusCode::RANGE_NOT_SATISFIABLE,
+            ),
+            (
+                body_len - 1,
+                MultipartFallback::RangeNotSatisfiable,
+                StatusCode::RANGE_NOT_SATISFIABLE,
+            ),
+        ];
+        for &(max, fallback, status) in &cases {
+            let resp = serve_with(
+                ServeOptions::new()
+                    .max_multipart_bytes(max)
+                    .multipart_fallback(fallback),
+            );
+            assert_eq!(resp.status(), status, "{} {:?}", max, fallback);
+            match status {
+                StatusCode::PARTIAL_CONTENT => {
+                    let content_len = body_len.to_string();
+                    assert_eq!(
+                        resp.headers().get(header::CONTENT_LENGTH).unwrap(),
+                        &content_len[..]
+                    );
+                }
+                StatusCode::OK => assert_eq!(&body(resp).await[..], BODY),
+                _ => assert_eq!(
+                    resp.headers().get(header::CONTENT_RANGE).unwrap(),
+                    "bytes */240"
+                ),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn report_bytes_sent() {
+        use crate::BytesSent;
+        use http_body::Body as _;
+
+        let e = FakeEntity::new(BODY).etag("\"foo\"");
+        let opts = ServeOptions::new().report_bytes_sent(true);
+
+        // A full transfer, single-range or multipart, counts the whole body.
+        for r in vec![
+            FakeRequest::get(),
+            FakeRequest::get().range("bytes=0-9"),
+            FakeRequest::get().range("bytes=0-9,200-209"),
+        ] {
+            let r = r.build();
+            let mut resp = serve_with(e.clone(), &r, &opts);
+            let sent = resp.extensions_mut().remove::<BytesSent>().unwrap();
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            assert_eq!(sent.await, body.len() as u64, "{:?}", r);
+        }
+
+        // A truncated one counts what was taken before the body was dropped.
+        let r = FakeRequest::get().range("bytes=0-9,200-209").build();
+        let mut resp = serve_with(e.clone(), &r, &opts);
+        let sent = resp.extensions_mut().remove::<BytesSent>().unwrap();
+        let mut body = resp.into_body();
+        let first = body.data().await.unwrap().unwrap();
+        drop(body);
+        assert_eq!(sent.await, first.len() as u64);
+
+        // Responses without a body don't have one.
+        for r in vec![
+            FakeRequest::head(),
+            FakeRequest::get().if_none_match("\"foo\""),
+        ] {
+            let r = r.build();
+            let resp = serve_with(e.clone(), &r, &opts);
+            assert!(resp.extensions().get::<BytesSent>().is_none(), "{:?}", r);
+        }
+    }
+
+    /// Over HTTP/2, the `Content-MD5` trailer matches the body received.
+    #[cfg(feature = "content-md5")]
+    #[tokio::test]
+    async fn content_md5_trailer() {
+        use hyper::body::HttpBody;
+
+        let e = FakeEntity::new(BODY).etag("\"foo\"");
+        let opts = ServeOptions::new().content_md5_trailer(true);
+        let (svc_e, svc_opts) = (e.clone(), opts.clone());
+        let make_svc = hyper::service::make_service_fn(move |_conn| {
+            let (e, opts) = (svc_e.clone(), svc_opts.clone());
+            futures::future::ok::<_, hyper::Error>(hyper::service::service_fn(
+                move |req: Request<hyper::Body>| {
+                    futures::future::ok::<_, BoxedError>(super::serve_with_trailers(
+                        e.clone(),
+                        &req,
+                        &opts,
+                    ))
+                },
+            ))
+        });
+        let srv = hyper::Server::bind(&([127, 0, 0, 1], 0).into())
+            .http2_only(true)
+            .serve(make_svc);
+        let url = format!("http://{}/", srv.local_addr());
+        tokio::spawn(srv);
+        let client = hyper::Client::builder().http2_only(true).build_http();
+
+        // hyper's HTTP/2 support strips the Trailer header, so check it before sending.
+        let mut r = FakeRequest::get().build();
+        *r.version_mut() = http::Version::HTTP_2;
+        let resp = super::serve_with_trailers(e.clone(), &r, &opts);
+        assert_eq!(resp.headers().get(header::TRAILER).unwrap(), "content-md5");
+        let resp = super::serve_with_trailers(e.clone(), &FakeRequest::get().build(), &opts);
+        assert_eq!(resp.headers().get(header::TRAILER), None);
+
+        for range in &[None, Some("bytes=0-9,200-209")] {
+            let mut req = FakeRequest::get();
+            if let Some(r) = range {
+                req = req.range(r);
+            }
+            let (mut parts, ()) = req.build().into_parts();
+            parts.uri = url.parse().unwrap();
+            let mut resp = client
+                .request(Request::from_parts(parts, hyper::Body::empty()))
+                .await
+                .unwrap();
+            let mut data = Vec::new();
+            while let Some(chunk) = resp.data().await {
+                data.extend_from_slice(&chunk.unwrap());
+            }
+            let expected = crate::trailers::base64(&<md5::Md5 as md5::Digest>::digest(&data));
+            let trailers = resp.trailers().await.unwrap().unwrap();
+            assert_eq!(
+                trailers.get("content-md5").unwrap(),
+                &expected[..],
+                "{:?}",
+                range
+            );
+        }
+    }
+
+    #[test]
+    fn omit_last_modified() {
+        use std::time::Duration;
+
+        let t = SystemTime::now() - Duration::from_secs(3600);
+        let e = FakeEntity::new(BODY).etag("\"foo\"").last_modified(t);
+        let opts = ServeOptions::new().omit_last_modified(true);
+        let resp = serve_with(e.clone(), &FakeRequest::get().build(), &opts);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get(header::ETAG).unwrap(), "\"foo\"");
+        assert_eq!(resp.headers().get(header::LAST_MODIFIED), None);
+
+        // Date preconditions don't reveal the time...
+        let req = FakeRequest::get().if_modified_since(t).build();
+        let resp = serve_with(e.clone(), &req, &opts);
+        assert_eq!(resp.status(), StatusCode::OK);
+        let req = FakeRequest::get()
+            .if_unmodified_since(t - Duration::from_secs(60))
+            .build();
+        let resp = serve_with(e.clone(), &req, &opts);
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // ...but etags still validate.
+        let req = FakeRequest::get().if_none_match("\"foo\"").build();
+        let resp = serve_with(e, &req, &opts);
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(resp.headers().get(header::LAST_MODIFIED), None);
+    }
+
+    /// Unlike `omit_last_modified`, `emit_etag` and `emit_last_modified` leave preconditions
+    /// evaluated against the entity's validators.
+    #[test]
+    fn emit_validators() {
+        use std::time::Duration;
+
+        let t = SystemTime::now() - Duration::from_secs(3600);
+        let e = FakeEntity::new(BODY).etag("\"foo\"").last_modified(t);
+        let opts = ServeOptions::new()
+            .emit_etag(false)
+            .emit_last_modified(false);
+        let serve = |req: &Request<()>, opts: &ServeOptions| -> Response<hyper::Body> {
+            super::serve_with_options(e.clone(), req, opts)
+        };
+
+        let resp = serve(&FakeRequest::get().build(), &ServeOptions::new());
+        assert_eq!(resp.headers()[header::ETAG], "\"foo\"");
+        assert!(resp.headers().contains_key(header::LAST_MODIFIED));
+        let resp = serve(&FakeRequest::get().build(), &opts);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get(header::ETAG), None);
+        assert_eq!(resp.headers().get(header::LAST_MODIFIED), None);
+
+        let cases = [
+            (
+                FakeRequest::get().if_none_match("\"foo\"").build(),
+                StatusCode::NOT_MODIFIED,
+            ),
+            (
+                FakeRequest::get().if_none_match("\"bar\"").build(),
+                StatusCode::OK,
+            ),
+            (
+                FakeRequest::get().if_match("\"bar\"").build(),
+                StatusCode::PRECONDITION_FAILED,
+            ),
+            (
+                FakeRequest::get()
+                    .if_modified_since(t + Duration::from_secs(1))
+                    .build(),
+                StatusCode::NOT_MODIFIED,
+            ),
+            (
+                FakeRequest::get()
+                    .if_unmodified_since(t - Duration::from_secs(60))
+                    .build(),
+                StatusCode::PRECONDITION_FAILED,
+            ),
+        ];
+        for (i, &(ref req, status)) in cases.iter().enumerate() {
+            let resp = serve(req, &opts);
+            assert_eq!(resp.status(), status, "case {}", i);
+            assert_eq!(resp.headers().get(header::ETAG), None, "case {}", i);
+            assert_eq!(
+                resp.headers().get(header::LAST_MODIFIED),
+                None,
+                "case {}",
+                i
+            );
+        }
+
+        // serve_immutable's short-circuit also honors emit_etag.
+        let etag = HeaderValue::from_static("\"foo\"");
+        let req = FakeRequest::get().if_none_match("\"foo\"").build();
+        let resp: Response<hyper::Body> = super::serve_immutable(&etag, || e.clone(), &req, &opts);
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(resp.headers().get(header::ETAG), None);
+    }
+
+    /// Media with one byte per 10 milliseconds, supporting `ms` ranges.
+    struct TimedEntity;
+
+    impl Entity for TimedEntity {
+        type Data = Bytes;
+        type Error = BoxedError;
+
+        fn len(&self) -> u64 {
+            BODY.len() as u64
+        }
+        fn get_range(
+            &self,
+            range: Range<u64>,
+        ) -> Box<dyn Stream<Item = Result<Self::Data, Self::Error>> + Send + Sync> {
+            Box::new(stream::once(futures::future::ok(Bytes::from_static(
+                &BODY[range.start as usize..range.end as usize],
+            ))))
+        }
+        fn add_headers(&self, h: &mut HeaderMap) {
+            h.insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("video/x-fake"),
+            );
+        }
+        fn etag(&self) -> Option<HeaderValue> {
+            Some(HeaderValue::from_static("\"t\""))
+        }
+        fn last_modified(&self) -> Option<SystemTime> {
+            None
+        }
+        #[allow(clippy::type_complexity)]
+        fn get_custom_range(
+            &self,
+            unit: &str,
+            first: u64,
+            last: u64,
+        ) -> Option<Box<dyn Stream<Item = Result<Self::Data, Self::Error>> + Send + Sync>> {
+            if unit != "ms" {
+                return None;
+            }
+            let end = std::cmp::min(last / 10 + 1, BODY.len() as u64);
+            Some(self.get_range(first / 10..end))
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_range_unit() {
+        let req = FakeRequest::get().range("ms=1000-1999").build();
+        let resp = serve(TimedEntity, &req);
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_RANGE).unwrap(),
+            "ms 1000-1999"
+        );
+        assert_eq!(resp.headers().get(header::CONTENT_LENGTH), None);
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            "video/x-fake"
+        );
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(&body[..], &BODY[100..200]);
+
+        // Its length in bytes isn't known, so neither ServedRanges nor max_content_length apply.
+        let opts = ServeOptions::new().max_content_length(|_| Some(1));
+        let resp = serve_with(TimedEntity, &req, &opts);
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert!(resp.extensions().get::<ServedRanges>().is_none());
+
+        let req = FakeRequest::head().range("ms=1000-1999").build();
+        let resp = serve(TimedEntity, &req);
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert!(body.is_empty());
+
+        // Unsupported units, and ranges not matching If-Range, are ignored.
+        for req in vec![
+            FakeRequest::get().range("frames=1-2"),
+            FakeRequest::get().range("ms=1000-1999").if_range("\"u\""),
+        ] {
+            let resp = serve(TimedEntity, &req.build());
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(resp.headers().get(header::CONTENT_RANGE), None);
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            assert_eq!(&body[..], BODY);
+        }
+
+        // So are all units for an entity using the default implementation.
+        let req = FakeRequest::get().range("ms=1000-1999").build();
+        let resp = serve(entity(BODY), &req);
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(&body[..], BODY);
+    }
+
+    #[test]
+    fn clock_steps_backwards() {
+        use crate::{Clock, MonotonicClock};
+        use httpdate::{fmt_http_date, parse_http_date};
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use std::sync::Arc;
+        use std::time::{Duration, UNIX_EPOCH};
+
+        /// A clock set by hand, in seconds since the epoch.
+        struct SteppedClock(Arc<AtomicU64>);
+
+        impl Clock for SteppedClock {
+            fn now(&self) -> SystemTime {
+                UNIX_EPOCH + Duration::from_secs(self.0.load(Ordering::Relaxed))
+            }
+        }
+
+        let t = 1_500_000_000;
+        let secs = Arc::new(AtomicU64::new(t));
+        let opts = ServeOptions::new().clock(MonotonicClock::new(SteppedClock(secs.clone())));
+        let modified = UNIX_EPOCH + Duration::from_secs(t - 10);
+        let e = FakeEntity::new(BODY).last_modified(modified);
+
+        let mut last_date = UNIX_EPOCH;
+        for &now in &[t, t - 100, t - 5, t + 1] {
+            secs.store(now, Ordering::Relaxed);
+            let resp = serve_with(e.clone(), &FakeRequest::get().build(), &opts);
+            let date = parse_http_date(resp.headers()[header::DATE].to_str().unwrap()).unwrap();
+            assert!(date >= last_date, "{} went backwards", now);
+            last_date = date;
+            assert_eq!(
+                resp.headers()[header::LAST_MODIFIED],
+                &*fmt_http_date(modified),
+                "{}",
+                now
+            );
+
+            let req = FakeRequest::get().if_modified_since(modified).build();
+            let resp = serve_with(e.clone(), &req, &opts);
+            assert_eq!(resp.status(), StatusCode::NOT_MODIFIED, "{}", now);
+        }
+        assert_eq!(last_date, UNIX_EPOCH + Duration::from_secs(t + 1));
+    }
+
+    /// `Date` is formatted only to bound a `Last-Modified`; otherwise hyper adds it.
+    #[test]
+    fn date_only_with_last_modified() {
+        let e = FakeEntity::new(BODY).etag("\"foo\"");
+        for r in vec![
+            FakeRequest::get(),
+            FakeRequest::get().range("bytes=0-9"),
+            FakeRequest::get().if_none_match("\"foo\""),
+            FakeRequest::new(Method::POST),
+        ] {
+            let r = r.build();
+            let resp = serve(e.clone(), &r);
+            assert_eq!(resp.headers().get(header::DATE), None, "{:?}", r);
+            assert_eq!(resp.headers().get(header::LAST_MODIFIED), None, "{:?}", r);
+
+            let resp = serve(e.clone().last_modified(SystemTime::now()), &r);
+            assert!(resp.headers().contains_key(header::DATE), "{:?}", r);
+        }
+    }
+
+    #[tokio::test]
+    async fn cache_policy() {
+        use super::CachePolicy;
+        use std::time::Duration;
+        let opts = ServeOptions::new()
+            .cache_policy(CachePolicy::private_if(Duration::from_secs(60), |h| {
+                h.contains_key(header::AUTHORIZATION)
+            }));
+        let cc = |resp: &Response<hyper::Body>| resp.headers().get(header::CACHE_CONTROL).cloned();
+        let e = || entity(BODY);
+
+        let resp = serve_with(e(), &FakeRequest::get().build(), &opts);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(cc(&resp).unwrap(), "public, max-age=60");
+
+        let auth = || FakeRequest::get().header(header::AUTHORIZATION, "Bearer x");
+        let resp = serve_with(e(), &auth().build(), &opts);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(cc(&resp).unwrap(), "private, max-age=60");
+
+        let resp = serve_with(e(), &auth().range("bytes=0-1").build(), &opts);
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(cc(&resp).unwrap(), "private, max-age=60");
+
+        let resp = serve_with(e(), &auth().if_none_match("\"foo\"").build(), &opts);
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(cc(&resp).unwrap(), "private, max-age=60");
+
+        // Error responses aren't given a lifetime.
+        let resp = serve_with(e(), &FakeRequest::get().range("bytes=1000-").build(), &opts);
+        assert_eq!(resp.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(cc(&resp), None);
+        let resp = serve_with(e(), &FakeRequest::get().if_match("\"bar\"").build(), &opts);
+        assert_eq!(resp.status(), StatusCode::PRECONDITION_FAILED);
+        assert_eq!(cc(&resp), None);
+
+        // Without a policy, there's no Cache-Control.
+        let resp = serve(e(), &FakeRequest::get().build());
+        assert_eq!(cc(&resp), None);
+    }
+
+    #[tokio::test]
+    async fn header_hygiene() {
+        let names = |resp: &Response<hyper::Body>| {
+            let mut n: Vec<&str> = resp.headers().keys().map(|k| k.as_str()).collect();
+            n.sort();
+            n.join(",")
+        };
+        let e = || entity(BODY);
+        let not_modified = FakeRequest::get().if_none_match("\"foo\"").build();
+        let failed = FakeRequest::get().if_match("\"bar\"").build();
+        let unsatisfiable = FakeRequest::get().range("bytes=1000-").build();
+        let bad = FakeRequest::get().if_match("bogus").build();
+        let post = FakeRequest::new(Method::POST).build();
+
+        let resp = serve(e(), &not_modified);
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(names(&resp), "accept-ranges,etag");
+        let resp = serve(e(), &failed);
+        assert_eq!(resp.status(), StatusCode::PRECONDITION_FAILED);
+        assert_eq!(names(&resp), "accept-ranges,content-type,etag");
+        let resp = serve(e(), &unsatisfiable);
+        assert_eq!(resp.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(names(&resp), "accept-ranges,content-range,etag");
+        assert_eq!(resp.headers()[header::CONTENT_RANGE], "bytes */240");
+        let resp = serve(e(), &post);
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(names(&resp), "allow,content-type");
+        let resp = serve(e(), &bad);
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(names(&resp), "content-type");
+
+        // With error_content_length, only responses which may have a body get a Content-Length,
+        // and it describes the error text rather than the entity.
+        let opts = ServeOptions::new().error_content_length(true);
+        let resp = serve_with(e(), &not_modified, &opts);
+        assert_eq!(names(&resp), "accept-ranges,etag");
+        for r in &[failed, unsatisfiable, post, bad] {
+            let resp = serve_with(e(), r, &opts);
+            let len = resp.headers()[header::CONTENT_LENGTH].clone();
+            assert_eq!(len.to_str().unwrap(), body(resp).await.len().to_string());
+        }
+    }
+
+    /// A `Range:` header present with an empty value is ignored rather than rejected.
+    #[tokio::test]
+    async fn empty_range() {
+        for &v in &["", " "] {
+            let resp = serve(entity(BODY), &FakeRequest::get().range(v).build());
+            assert_eq!(resp.status(), StatusCode::OK, "{:?}", v);
+            assert_eq!(resp.headers().get(header::CONTENT_RANGE), None);
+            assert_eq!(&body(resp).await[..], BODY);
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    type Fields = std::sync::Arc<std::sync::Mutex<Vec<(String, String)>>>;
+
+    /// A `tracing` subscriber which records the fields of every event.
+    #[cfg(feature = "tracing")]
+    struct Recorder(Fields);
+
+    #[cfg(feature = "tracing")]
+    impl tracing::field::Visit for Recorder {
+        fn record_debug(&mut self, f: &tracing::field::Field, v: &dyn std::fmt::Debug) {
+            let v = format!("{:?}", v);
+            self.0.lock().unwrap().push((f.name().to_owned(), v));
+        }
+
+        fn record_str(&mut self, f: &tracing::field::Field, v: &str) {
+            let v = v.to_owned();
+            self.0.lock().unwrap().push((f.name().to_owned(), v));
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    impl tracing::Subscriber for Recorder {
+        fn enabled(&self, _: &tracing::Metadata) -> bool {
+            true
+        }
+        fn new_span(&self, _: &tracing::span::Attributes) -> tracing::span::Id {
+            tracing::span::Id::from_u64(1)
+        }
+        fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record) {}
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+        fn event(&self, e: &tracing::Event) {
+            e.record(&mut Recorder(self.0.clone()));
+        }
+        fn enter(&self, _: &tracing::span::Id) {}
+        fn exit(&self, _: &tracing::span::Id) {}
+    }
+
+    /// With the `tracing` feature, each response emits an event recording the request's
+    /// conditional and range headers and the decision made.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn trace_decision() {
+        let fields = Fields::default();
+        let r = FakeRequest::get()
+            .range("bytes=0-9")
+            .if_range("\"foo\"")
+            .if_none_match("\"bar\"")
+            .build();
+        let resp =
+            tracing::subscriber::with_default(Recorder(fields.clone()), || serve(entity(BODY), &r));
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+
+        // Absent headers aren't recorded.
+        let fields = fields.lock().unwrap();
+        let got: Vec<(&str, &str)> = fields
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        assert_eq!(
+            got,
+            [
+                ("message", "serve decision"),
+                ("range", "bytes=0-9"),
+                ("range_specs", "1"),
+                ("range_unsatisfiable", "0"),
+                ("range_clamped", "false"),
+                ("if_range", "\"foo\""),
+                ("if_none_match", "\"bar\""),
+                ("status", "206"),
+                ("disposition", "Some(Partial)"),
+            ]
+        );
+    }
+
+    /// The event also records which range specs were dropped or clamped, and where a malformed
+    /// header failed to parse.
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn trace_range_diagnostics() {
+        let record = |range| {
+            let fields = Fields::default();
+            let r = FakeRequest::get().range(range).build();
+            tracing::subscriber::with_default(Recorder(fields.clone()), || serve(entity(BODY), &r));
+            let fields = fields.lock().unwrap();
+            fields
+                .iter()
+                .filter(|(k, _)| k.starts_with("range_"))
+                .map(|(k, v)| format!("{}={}", k, v))
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        assert_eq!(
+            record("bytes=0-999,9999-"),
+            "range_specs=2 range_unsatisfiable=1 range_clamped=true"
+        );
+        assert_eq!(
+            record("bytes=0-1,x-2"),
+            "range_specs=2 range_unsatisfiable=0 range_clamped=false \
+             range_malformed_offset=10 range_malformed_fragment=x-2"
+        );
+    }
+
+    /// A 304 for a negotiated variant names that variant's `Content-Location`, as do 200 and 206
+    /// responses.
+    #[tokio::test]
+    async fn not_modified_content_location() {
+        let e = entity(b"identity")
+            .variant(
+                ContentCoding::Gzip,
+                encoded(ContentCoding::Gzip, b"gzipped").content_location("/foo.gz"),
+            )
+            .variant(
+                ContentCoding::Brotli,
+                encoded(ContentCoding::Brotli, b"brotli").content_location("/foo.br"),
+            );
+        for &(ae, etag, location) in &[
+            ("gzip", "\"foo-gzip\"", "/foo.gz"),
+            ("br", "\"foo-br\"", "/foo.br"),
+        ] {
+            let resp = serve(
+                e.clone(),
+                &FakeRequest::get()
+                    .header(header::ACCEPT_ENCODING, ae)
+                    .if_none_match(etag)
+                    .build(),
+            );
+            assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+            assert_eq!(
+                resp.headers().get(header::ETAG).unwrap(),
+                &format!("W/{}", etag)[..]
+            );
+            assert_eq!(resp.headers().get(header::VARY).unwrap(), "accept-encoding");
+            assert_eq!(
+                resp.headers().get(header::CONTENT_LOCATION).unwrap(),
+                location
+            );
+            assert!(resp.headers().get(header::CONTENT_ENCODING).is_none());
+            assert_eq!(&body(resp).await[..], b"");
+        }
+
+        // 200 and 206 responses for the variant carry it too; a 416 doesn't.
+        for &(range, status) in &[
+            (None, StatusCode::OK),
+            (Some("bytes=0-1"), StatusCode::PARTIAL_CONTENT),
+            (Some("bytes=100-"), StatusCode::RANGE_NOT_SATISFIABLE),
+        ] {
+            let mut r = FakeRequest::get().header(header::ACCEPT_ENCODING, "gzip");
+            if let Some(range) = range {
+                r = r.range(range);
+            }
+            let resp = serve(e.clone(), &r.build());
+            assert_eq!(resp.status(), status);
+            assert_eq!(
+                resp.headers().get(header::CONTENT_LOCATION).is_some(),
+                status != StatusCode::RANGE_NOT_SATISFIABLE,
+                "{}",
+                status
+            );
+        }
+
+        // The identity representation has none, so its 304 has none either.
+        let resp = serve(
+            e,
+            &FakeRequest::get()
+                .header(header::ACCEPT_ENCODING, "identity")
+                .if_none_match("\"foo\"")
+                .build(),
+        );
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert!(resp.headers().get(header::CONTENT_LOCATION).is_none());
+    }
+
+    /// Seeding the generator pins the randomized boundary, and with it the whole body.
+    #[cfg(feature = "multipart")]
+    #[tokio::test]
+    async fn seeded_boundary() {
+        let e = FakeEntity::new(BODY);
+        let r = FakeRequest::get().range("bytes=0-19, 90-129").build();
+        let serve = |opts: &ServeOptions| {
+            let resp = serve_with(e.clone(), &r, opts);
+            (boundary(&resp), resp)
+        };
+
+        // Each response starts afresh from the seed, so reusing options repeats the boundary.
+        let opts = ServeOptions::new().rng_seed(1);
+        let (b1, r1) = serve(&opts);
+        let (b2, r2) = serve(&opts);
+        let (b3, _) = serve(&opts.clone());
+        let (b4, _) = serve(&ServeOptions::new().rng_seed(2));
+        assert_eq!(b1, b2);
+        assert_eq!(b1, b3);
+        assert_ne!(b1, b4);
+        assert_eq!(body(r1).await, body(r2).await);
+    }
+
+    /// Without a seed, boundaries don't repeat.
+    #[cfg(feature = "multipart")]
+    #[test]
+    fn unseeded_boundaries_unique() {
+        let e = FakeEntity::new(BODY);
+        let r = FakeRequest::get().range("bytes=0-19, 90-129").build();
+        let mut seen = std::collections::HashSet::new();
+        for _ in 0..10_000 {
+            let b = boundary(&serve(e.clone(), &r));
+            assert!(seen.insert(b.clone()), "repeated {}", b);
+        }
+    }
+
+    /// Contiguous ranges are served as one span, not as `multipart/byteranges`.
+    #[tokio::test]
+    async fn contiguous_ranges() {
+        let e = FakeEntity::new(BODY).content_type("text/plain");
+        for &r in &[
+            "bytes=0-99,100-199",
+            "bytes=10-19, 20-20, 21-29",
+            "bytes=200-219,220-",
+        ] {
+            let req = FakeRequest::get().range(r).build();
+            let resp = serve(e.clone(), &req);
+            assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT, "{}", r);
+            assert_eq!(resp.headers()[header::CONTENT_TYPE], "text/plain", "{}", r);
+            let served = resp.extensions().get::<ServedRanges>().unwrap().clone();
+            assert_eq!(served.ranges().len(), 1, "{}", r);
+            let range = served.ranges()[0].clone();
+            assert_eq!(
+                resp.headers()[header::CONTENT_RANGE],
+                format!("bytes {}-{}/240", range.start, range.end - 1)
+            );
+            let body = body(resp).await;
+            assert_eq!(&body[..], &BODY[range.start as usize..range.end as usize]);
+        }
+
+        // Out of order or overlapping ranges aren't one span.
+        #[cfg(feature = "multipart")]
+        for &r in &["bytes=10-19,0-9", "bytes=0-9,9-19"] {
+            let req = FakeRequest::get().range(r).build();
+            let resp = serve(e.clone(), &req);
+            assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+            boundary(&resp);
+        }
+    }
+
+    /// An entity's large header block isn't repeated in every part.
+    #[cfg(feature = "multipart")]
+    #[tokio::test]
+    async fn max_part_headers_bytes() {
+        use super::MultipartFallback;
+
+        let link = format!("<{}>; rel=preload", "x".repeat(16 * 1024));
+        let e = FakeEntity::new(BODY)
+            .content_type("text/plain")
+            .header(header::LINK, &link);
+        let r = FakeRequest::get().range("bytes=0-9,200-209").build();
+        let parts = |opts: &ServeOptions| {
+            let resp = serve_with(e.clone(), &r, opts);
+            assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+            resp
+        };
+
+        // By default, each part repeats the whole block.
+        let full = body(parts(&ServeOptions::new())).await;
+        let full = String::from_utf8(full.to_vec()).unwrap();
+        assert_eq!(full.matches("link: <").count(), 2);
+        assert!(full.len() > 32 * 1024);
+
+        // Over the limit, parts carry only the content type.
+        let resp = parts(&ServeOptions::new().max_part_headers_bytes(1024));
+        let b = boundary(&resp);
+        let len: usize = resp.headers()[header::CONTENT_LENGTH]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let trimmed = body(resp).await;
+        assert_eq!(trimmed.len(), len);
+        assert_eq!(
+            String::from_utf8(trimmed.to_vec()).unwrap(),
+            format!(
+                "\r\n--{b}\r\n\
+                 Content-Range: bytes 0-9/240\r\n\
+                 content-type: text/plain\r\n\
+                 \r\n\
+                 0123456789\
+                 \r\n--{b}\r\n\
+                 Content-Range: bytes 200-209/240\r\n\
+                 content-type: text/plain\r\n\
+                 \r\n\
+                 0123456789\
+                 \r\n--{b}--\r\n",
+                b = b
+            )
+        );
+
+        // When even that's too long, the multipart fallback applies.
+        let opts = ServeOptions::new().max_part_headers_bytes(10);
+        let resp = serve_with(e.clone(), &r, &opts);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(&body(resp).await[..], BODY);
+        let opts = opts.multipart_fallback(MultipartFallback::RangeNotSatisfiable);
+        let resp = serve_with(e, &r, &opts);
+        assert_eq!(resp.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+    }
+
+    /// Entity headers over the limit are truncated or fail the response, as configured.
+    #[tokio::test]
+    async fn max_entity_header_bytes() {
+        use super::{DroppedHeaders, HeaderOverflow, ServeDisposition};
+
+        let huge = format!("attachment; filename=\"{}\"", "x".repeat(64 * 1024));
+        let e = FakeEntity::new(BODY)
+            .content_type("text/plain")
+            .header(header::CONTENT_DISPOSITION, &huge)
+            .header(header::CONTENT_LANGUAGE, "en");
+        let reqs = || {
+            let mut reqs = vec![
+                (FakeRequest::get().build(), StatusCode::OK),
+                (FakeRequest::head().build(), StatusCode::OK),
+                (
+                    FakeRequest::get().range("bytes=0-9").build(),
+                    StatusCode::PARTIAL_CONTENT,
+                ),
+            ];
+            if cfg!(feature = "multipart") {
+                reqs.push((
+                    FakeRequest::get().range("bytes=0-9,200-209").build(),
+                    StatusCode::PARTIAL_CONTENT,
+                ));
+            }
+            reqs
+        };
+
+        // Without a limit, the header is sent as is.
+        let resp = serve(e.clone(), &FakeRequest::get().build());
+        assert_eq!(resp.headers()[header::CONTENT_DISPOSITION], &huge[..]);
+
+        let opts = ServeOptions::new().max_entity_header_bytes(8 * 1024);
+        for (r, status) in reqs() {
+            let resp = serve_with(e.clone(), &r, &opts);
+            assert_eq!(resp.status(), status, "{:?}", r);
+            assert_eq!(
+                resp.extensions().get::<DroppedHeaders>().unwrap().names(),
+                &[header::CONTENT_DISPOSITION]
+            );
+            assert!(resp.headers().get(header::CONTENT_DISPOSITION).is_none());
+            let multipart = resp.headers()[header::CONTENT_TYPE] != "text/plain";
+            if !multipart {
+                assert_eq!(resp.headers()[header::CONTENT_LANGUAGE], "en");
+            }
+            let body = body(resp).await;
+            assert!(body.len() < 1024, "{:?}", r);
+            if multipart {
+                let body = String::from_utf8(body.to_vec()).unwrap();
+                assert_eq!(body.matches("content-language: en\r\n").count(), 2);
+                assert!(!body.contains("filename"));
+            }
+        }
+
+        let opts = opts.header_overflow(HeaderOverflow::InternalServerError);
+        for (r, _) in reqs() {
+            let resp = serve_with(e.clone(), &r, &opts);
+            assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR, "{:?}", r);
+            assert_eq!(
+                ServeDisposition::of(&resp),
+                Some(ServeDisposition::HeadersTooLarge)
+            );
+            assert!(resp.headers().get(header::CONTENT_DISPOSITION).is_none());
+            assert!(resp.extensions().get::<DroppedHeaders>().is_none());
+        }
+
+        // Headers within the limit are unaffected by either policy.
+        let opts = ServeOptions::new().max_entity_header_bytes(128 * 1024);
+        let resp = serve_with(e, &FakeRequest::get().build(), &opts);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::CONTENT_DISPOSITION], &huge[..]);
+        assert!(resp.extensions().get::<DroppedHeaders>().is_none());
+    }
+
+    /// `HEAD` never reads the entity, whatever the response; `GET` reads each range once.
+    #[tokio::test]
+    async fn head_never_reads() {
+        use super::ServeDisposition;
+
+        let e = || FakeEntity::new(BODY).etag("\"foo\"");
+        let multipart_reads = if cfg!(feature = "multipart") { 2 } else { 1 };
+        type Build = fn(FakeRequest) -> FakeRequest;
+        let cases: Vec<(Build, ServeDisposition, usize)> = vec![
+            (|r| r, ServeDisposition::Full, 1),
+            (|r| r.range("bytes=0-9"), ServeDisposition::Partial, 1),
+            (
+                |r| r.range("bytes=0-9,200-209"),
+                if cfg!(feature = "multipart") {
+                    ServeDisposition::Multipart
+                } else {
+                    ServeDisposition::Full
+                },
+                multipart_reads,
+            ),
+            (
+                |r| r.range("bytes=0-9").if_range("\"bar\""),
+                ServeDisposition::Full,
+                1,
+            ),
+            (
+                |r| r.if_none_match("\"foo\""),
+                ServeDisposition::NotModified,
+                0,
+            ),
+            (
+                |r| r.if_match("\"bar\""),
+                ServeDisposition::PreconditionFailed,
+                0,
+            ),
+            (
+                |r| r.range("bytes=500-"),
+                ServeDisposition::RangeNotSatisfiable,
+                0,
+            ),
+        ];
+        for (build, disposition, get_reads) in cases {
+            for &(ref method, reads) in &[(Method::HEAD, 0), (Method::GET, get_reads)] {
+                let r = build(FakeRequest::new(method.clone())).build();
+                let e = e();
+                let resp = serve(e.clone(), &r);
+                assert_eq!(ServeDisposition::of(&resp), Some(disposition), "{:?}", r);
+                body(resp).await;
+                assert_eq!(e.get_range_calls(), reads, "{:?}", r);
+            }
+        }
+    }
+
+    /// `Content-Type` parameters survive into full responses and multipart parts.
+    #[tokio::test]
+    async fn content_type_parameters() {
+        for &ct in &[
+            "text/html; charset=utf-8",
+            "text/plain; charset=\"UTF-8\"; format=flowed",
+        ] {
+            let e = FakeEntity::new(BODY).content_type(ct);
+            let resp = serve(e.clone(), &FakeRequest::get().build());
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(resp.headers()[header::CONTENT_TYPE], ct);
+
+            let r = FakeRequest::get().range("bytes=0-9").build();
+            let resp = serve(e.clone(), &r);
+            assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+            assert_eq!(resp.headers()[header::CONTENT_TYPE], ct);
+
+            if cfg!(feature = "multipart") {
+                let r = FakeRequest::get().range("bytes=0-9,200-209").build();
+                let part = format!("\r\ncontent-type: {}\r\n\r\n", ct);
+                for opts in &[
+                    ServeOptions::new(),
+                    ServeOptions::new().max_part_headers_bytes(ct.len() + 16),
+                ] {
+                    let resp = serve_with(e.clone(), &r, opts);
+                    assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+                    let body = String::from_utf8(body(resp).await.to_vec()).unwrap();
+                    assert_eq!(body.matches(&part[..]).count(), 2, "{}", body);
+                }
+            }
+        }
+    }
+
+    /// `always_multipart` answers many small ranges, whose estimated multipart length exceeds the
+    /// entity's, with multipart rather than the whole entity.
+    #[cfg(feature = "multipart")]
+    #[tokio::test]
+    async fn always_multipart() {
+        let e = FakeEntity::new(BODY);
+        let r = FakeRequest::get()
+            .range("bytes=0-0,10-10,20-20,30-30,40-40")
+            .build();
+
+        let resp = serve(e.clone(), &r);
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let opts = ServeOptions::new().always_multipart(true);
+        let resp = serve_with(e.clone(), &r, &opts);
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        let b = boundary(&resp);
+        let len: u64 = resp.headers()[header::CONTENT_LENGTH]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let body = String::from_utf8(body(resp).await.to_vec()).unwrap();
+        assert_eq!(body.len() as u64, len);
+        assert_eq!(body.matches(&format!("\r\n--{}\r\n", b)).count(), 5);
+
+        // max_multipart_bytes still applies.
+        let opts = opts.max_multipart_bytes(100);
+        let resp = serve_with(e, &r, &opts);
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    /// `multirange_compat` picks the response to multiple-range requests per client.
+    #[tokio::test]
+    async fn multirange_compat() {
+        use super::MultirangeMode;
+        let e = FakeEntity::new(BODY).content_type("text/plain");
+        let opts = ServeOptions::new().multirange_compat(|h| {
+            match h.get(header::USER_AGENT).map(|v| v.as_bytes()) {
+                Some(b"legacy-full") => MultirangeMode::FullEntity,
+                Some(b"legacy-first") => MultirangeMode::FirstRangeOnly,
+                _ => MultirangeMode::Multipart,
+            }
+        });
+        let serve = |ua: &str, range: &str| -> Response<hyper::Body> {
+            let r = FakeRequest::get()
+                .header(header::USER_AGENT, ua)
+                .range(range)
+                .build();
+            super::serve_with_options(e.clone(), &r, &opts)
+        };
+
+        let resp = serve("modern", "bytes=200-209,0-9");
+        if cfg!(feature = "multipart") {
+            assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+            assert!(resp.headers()[header::CONTENT_TYPE]
+                .to_str()
+                .unwrap()
+                .starts_with("multipart/byteranges; boundary="));
+        } else {
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+
+        let resp = serve("legacy-full", "bytes=200-209,0-9");
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.headers().get(header::CONTENT_RANGE).is_none());
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "text/plain");
+        assert_eq!(&body(resp).await[..], BODY);
+
+        // Only the first satisfiable range is sent, in the order requested.
+        for &(range, ref first) in &[
+            ("bytes=200-209,0-9", 200..210),
+            ("bytes=500-,10-19,0-9", 10..20),
+        ] {
+            let resp = serve("legacy-first", range);
+            assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+            assert_eq!(
+                resp.headers()[header::CONTENT_RANGE],
+                format!("bytes {}-{}/240", first.start, first.end - 1)
+            );
+            assert_eq!(resp.headers()[header::CONTENT_TYPE], "text/plain");
+            assert_eq!(&body(resp).await[..], &BODY[first.clone()]);
+        }
+
+        // A single range is served as usual, whatever the mode.
+        for &ua in &["legacy-full", "legacy-first"] {
+            let resp = serve(ua, "bytes=0-9");
+            assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+            assert_eq!(resp.headers()[header::CONTENT_RANGE], "bytes 0-9/240");
+        }
+    }
+
+    #[cfg(feature = "multipart")]
+    #[tokio::test]
+    async fn if_range_part_content_type() {
+        let e = entity(BODY).header(header::CONTENT_LANGUAGE, "en");
+        let r = FakeRequest::get()
+            .range("bytes=0-9,200-209")
+            .if_range("\"foo\"")
+            .build();
+        let resp = serve(e, &r);
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        let b = body(resp).await;
+        let b = std::str::from_utf8(&b).unwrap();
+        assert_eq!(
+            b.matches("\r\ncontent-type: text/plain\r\n").count(),
+            2,
+            "{}",
+            b
+        );
+        assert!(!b.contains("content-language"), "{}", b);
+    }
+
+    #[tokio::test]
+    async fn range_query_param() {
+        let e = entity(BODY);
+        let opts = ServeOptions::new().range_query_param("range");
+        let get = |uri: &str| {
+            let mut r = FakeRequest::get().build();
+            *r.uri_mut() = uri.parse().unwrap();
+            r
+        };
+
+        let resp = serve_with(e.clone(), &get("/f?x=1&range=0-99"), &opts);
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(resp.headers()[header::CONTENT_RANGE], "bytes 0-99/240");
+        assert_eq!(&body(resp).await[..], &BODY[..100]);
+
+        let resp = serve_with(e.clone(), &get("/f?range=bytes=-10"), &opts);
+        assert_eq!(resp.headers()[header::CONTENT_RANGE], "bytes 230-239/240");
+
+        // The header takes precedence, and If-Range applies as usual.
+        let mut r = FakeRequest::get().range("bytes=0-9").build();
+        *r.uri_mut() = "/f?range=0-99".parse().unwrap();
+        let resp = serve_with(e.clone(), &r, &opts);
+        assert_eq!(resp.headers()[header::CONTENT_RANGE], "bytes 0-9/240");
+        let mut r = FakeRequest::get().if_range("\"bar\"").build();
+        *r.uri_mut() = "/f?range=0-99".parse().unwrap();
+        assert_eq!(serve_with(e.clone(), &r, &opts).status(), StatusCode::OK);
+
+        // It's opt-in, and only the named parameter counts.
+        let resp = serve_with(e.clone(), &get("/f?range=0-99"), &ServeOptions::new());
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = serve_with(e, &get("/f?myrange=0-99"), &opts);
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    /// An entity breaking the `get_range` contract: each range yields `delta` more or fewer bytes
+    /// than requested, without an error.
+    struct Miscounted(i64);
+
+    impl Entity for Miscounted {
+        type Data = Bytes;
+        type Error = BoxedError;
+
+        fn len(&self) -> u64 {
+            BODY.len() as u64
+        }
+        fn get_range(
+            &self,
+            range: Range<u64>,
+        ) -> Box<dyn Stream<Item = Result<Self::Data, Self::Error>> + Send + Sync> {
+            let end = std::cmp::max(range.start as i64, range.end as i64 + self.0) as usize;
+            let end = std::cmp::min(end, BODY.len());
+            let b = Bytes::from_static(&BODY[range.start as usize..end]);
+            Box::new(stream::once(futures::future::ok(b)))
+        }
+        fn add_headers(&self, _: &mut HeaderMap) {}
+        fn etag(&self) -> Option<HeaderValue> {
+            None
+        }
+        fn last_modified(&self) -> Option<SystemTime> {
+            None
+        }
+    }
+
+    /// `serve` never sends more than a range's length, and stops the whole body (falling short of
+    /// its `Content-Length`, which aborts the response) once a range comes up short.
+    #[tokio::test]
+    async fn miscounted_ranges() {
+        let content_length = |r: &Response<hyper::Body>| -> usize {
+            r.headers()[header::CONTENT_LENGTH]
+                .to_str()
+                .unwrap()
+                .parse()
+                .unwrap()
+        };
+        for &delta in &[-5, 5] {
+            let r = FakeRequest::get().range("bytes=0-9").build();
+            let resp = serve(Miscounted(delta), &r);
+            assert_eq!(content_length(&resp), 10);
+            let b = body(resp).await;
+            assert_eq!(&b[..], &BODY[..std::cmp::min(10, (10 + delta) as usize)]);
+        }
+
+        if cfg!(feature = "multipart") {
+            let r = FakeRequest::get().range("bytes=0-9,200-209").build();
+            let resp = serve(Miscounted(5), &r);
+            let len = content_length(&resp);
+            assert_eq!(body(resp).await.len(), len);
+
+            let resp = serve(Miscounted(-5), &r);
+            let len = content_length(&resp);
+            let b = body(resp).await;
+            assert!(b.len() < len);
+            assert!(b.ends_with(&BODY[..5]), "{:?}", b);
+        }
+    }
+
+    /// `HEAD` negotiates the content coding as `GET` does, so clients can probe for a variant.
+    #[tokio::test]
+    async fn head_negotiates_variant() {
+        let e = entity(b"identity").variant(
+            ContentCoding::Gzip,
+            encoded(ContentCoding::Gzip, b"gzipped"),
+        );
+        for &(ae, coding, len) in &[("gzip", Some("gzip"), "7"), ("identity", None, "8")] {
+            let get = serve(
+                e.clone(),
+                &FakeRequest::get()
+                    .header(header::ACCEPT_ENCODING, ae)
+                    .build(),
+            );
+            let head = serve(
+                e.clone(),
+                &FakeRequest::head()
+                    .header(header::ACCEPT_ENCODING, ae)
+                    .build(),
+            );
+            assert_eq!(head.status(), StatusCode::OK);
+            assert_eq!(head.headers(), get.headers(), "{}", ae);
+            assert_eq!(head.headers()[header::VARY], "accept-encoding");
+            assert_eq!(head.headers()[header::CONTENT_LENGTH], len);
+            assert_eq!(
+                head.headers()
+                    .get(header::CONTENT_ENCODING)
+                    .map(|v| v.to_str().unwrap()),
+                coding
+            );
+            assert_eq!(&body(head).await[..], b"");
+        }
+    }
+
+    #[tokio::test]
+    async fn if_none_match_comparison() {
+        use crate::EtagComparison;
+        let weak = entity(BODY).etag("W/\"foo\"");
+        let strong = entity(BODY);
+        let strong_only = ServeOptions::new().if_none_match_comparison(EtagComparison::StrongOnly);
+        let cases = [
+            (&weak, "W/\"foo\"", StatusCode::NOT_MODIFIED, StatusCode::OK),
+            (
+                &strong,
+                "W/\"foo\"",
+                StatusCode::NOT_MODIFIED,
+                StatusCode::OK,
+            ),
+            (&weak, "\"foo\"", StatusCode::NOT_MODIFIED, StatusCode::OK),
+            (
+                &strong,
+                "\"foo\"",
+                StatusCode::NOT_MODIFIED,
+                StatusCode::NOT_MODIFIED,
+            ),
+            (
+                &strong,
+                "*",
+                StatusCode::NOT_MODIFIED,
+                StatusCode::NOT_MODIFIED,
+            ),
+        ];
+        for &(e, inm, default, strict) in &cases {
+            let r = FakeRequest::get().if_none_match(inm).build();
+            assert_eq!(serve(e.clone(), &r).status(), default, "{}", inm);
+            assert_eq!(
+                serve_with(e.clone(), &r, &strong_only).status(),
+                strict,
+                "{}",
+                inm
+            );
+        }
+    }
+
+    /// A failed `If-Match` yields a plain 412, even with a satisfiable `Range` and an
+    /// acceptable variant: preconditions are evaluated before ranges, and the 412 carries the
+    /// negotiated variant's validator but no sign of the range or the variant's coding.
+    #[tokio::test]
+    async fn if_match_fails_before_range_and_coding() {
+        let e = entity(BODY).variant(
+            ContentCoding::Gzip,
+            encoded(
+                ContentCoding::Gzip,
+                b"gzipped but long enough for the range",
+            ),
+        );
+        let resp = serve(
+            e,
+            &FakeRequest::get()
+                .if_match("\"wrong\"")
+                .range("bytes=0-10")
+                .header(header::ACCEPT_ENCODING, "gzip")
+                .build(),
+        );
+        assert_eq!(resp.status(), StatusCode::PRECONDITION_FAILED);
+        let h = resp.headers();
+        assert_eq!(h.get(header::ETAG).unwrap(), "W/\"foo-gzip\"");
+        assert!(!h.contains_key(header::CONTENT_RANGE));
+        assert!(!h.contains_key(header::CONTENT_ENCODING));
+        assert!(!h.contains_key(header::CONTENT_LOCATION));
+        assert_eq!(&body(resp).await[..], b"Precondition failed");
+    }
+
+    /// Revalidating a content-addressed entity never constructs it, and a full fetch carries the
+    /// immutable caching headers with the known tag in place of the entity's own validators.
+    #[tokio::test]
+    async fn serve_immutable() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let built = AtomicUsize::new(0);
+        let make = || {
+            built.fetch_add(1, Ordering::SeqCst);
+            FakeEntity::new(BODY)
+                .etag("\"inode-based\"")
+                .last_modified(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000))
+        };
+        let etag = HeaderValue::from_static("\"3f2a9c\"");
+        let opts = ServeOptions::default();
+        let immutable = "public, max-age=31536000, immutable";
+
+        let resp: Response<hyper::Body> =
+            super::serve_immutable(&etag, make, &FakeRequest::get().build(), &opts);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::ETAG], "\"3f2a9c\"");
+        assert_eq!(resp.headers()[header::CACHE_CONTROL], immutable);
+        assert!(!resp.headers().contains_key(header::LAST_MODIFIED));
+        assert_eq!(&body(resp).await[..], BODY);
+        assert_eq!(built.load(Ordering::SeqCst), 1);
+
+        for method in &[Method::GET, Method::HEAD] {
+            let r = FakeRequest::new(method.clone())
+                .if_none_match("\"other\", W/\"3f2a9c\"")
+                .build();
+            let resp: Response<hyper::Body> = super::serve_immutable(&etag, make, &r, &opts);
+            assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+            assert_eq!(resp.headers()[header::ETAG], "\"3f2a9c\"");
+            assert_eq!(resp.headers()[header::CACHE_CONTROL], immutable);
+            assert_eq!(
+                super::ServeDisposition::of(&resp),
+                Some(super::ServeDisposition::NotModified)
+            );
+        }
+        assert_eq!(built.load(Ordering::SeqCst), 1);
+
+        // A failed If-Match might yet match a variant's etag, so it's evaluated by the entity.
+        for method in &[Method::GET, Method::HEAD] {
+            let r = FakeRequest::new(method.clone())
+                .if_match("\"inode-based\"")
+                .build();
+            let resp: Response<hyper::Body> = super::serve_immutable(&etag, make, &r, &opts);
+            assert_eq!(resp.status(), StatusCode::PRECONDITION_FAILED);
+            assert_eq!(resp.headers()[header::ETAG], "\"3f2a9c\"");
+        }
+        assert_eq!(built.load(Ordering::SeqCst), 3);
+
+        // A matching If-Match, or a stale If-None-Match, serves the entity.
+        let r = FakeRequest::get()
+            .if_match("\"3f2a9c\"")
+            .range("bytes=0-9")
+            .build();
+        let resp: Response<hyper::Body> = super::serve_immutable(&etag, make, &r, &opts);
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(resp.headers()[header::CACHE_CONTROL], immutable);
+        assert_eq!(&body(resp).await[..], &BODY[..10]);
+        let r = FakeRequest::get().if_none_match("\"2e1b8d\"").build();
+        let resp: Response<hyper::Body> = super::serve_immutable(&etag, make, &r, &opts);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(built.load(Ordering::SeqCst), 5);
+    }
+
+    /// An `If-Match` naming a content-coded variant's etag rather than the known tag serves that
+    /// variant.
+    #[tokio::test]
+    async fn serve_immutable_variant_if_match() {
+        let etag = HeaderValue::from_static("\"3f2a9c\"");
+        let make = || {
+            entity(BODY).variant(
+                ContentCoding::Gzip,
+                encoded(ContentCoding::Gzip, b"gzipped"),
+            )
+        };
+        let opts = ServeOptions::default().weak_encoded_etags(false);
+        let r = FakeRequest::get()
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .if_match("\"foo-gzip\"")
+            .build();
+        let resp: Response<hyper::Body> = super::serve_immutable(&etag, make, &r, &opts);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::ETAG], "\"foo-gzip\"");
+        assert_eq!(resp.headers()[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(&body(resp).await[..], b"gzipped");
+    }
+
+    /// An unquoted etag, such as a raw digest, revalidates once normalized. Without
+    /// normalization it's sent as is and never matches, as before.
+    #[tokio::test]
+    async fn normalize_etags() {
+        let e = || entity(BODY).etag("3f2a9c");
+        let opts = ServeOptions::default().normalize_etags(true);
+        let resp = serve_with(e(), &FakeRequest::get().build(), &opts);
+        assert_eq!(resp.headers()[header::ETAG], "\"3f2a9c\"");
+        for &inm in &["\"3f2a9c\"", "W/\"3f2a9c\""] {
+            let resp = serve_with(e(), &FakeRequest::get().if_none_match(inm).build(), &opts);
+            assert_eq!(resp.status(), StatusCode::NOT_MODIFIED, "{}", inm);
+            assert_eq!(resp.headers()[header::ETAG], "\"3f2a9c\"");
+        }
+        let resp = serve_with(
+            e(),
+            &FakeRequest::get().if_match("\"3f2a9c\"").build(),
+            &opts,
+        );
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // Quoted etags are left alone.
+        let resp = serve_with(entity(BODY), &FakeRequest::get().build(), &opts);
+        assert_eq!(resp.headers()[header::ETAG], "\"foo\"");
+
+        let resp = serve(e(), &FakeRequest::get().build());
+        assert_eq!(resp.headers()[header::ETAG], "3f2a9c");
+        let resp = serve(e(), &FakeRequest::get().if_none_match("\"3f2a9c\"").build());
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    /// Without normalization, debug builds warn of an unquoted etag.
+    #[cfg(all(debug_assertions, feature = "tracing"))]
+    #[test]
+    fn unquoted_etag_warning() {
+        let e = |etag| entity(BODY).etag(etag);
+        let warned = |e: FakeEntity, opts: &ServeOptions| {
+            let fields = Fields::default();
+            tracing::subscriber::with_default(Recorder(fields.clone()), || {
+                serve_with(e, &FakeRequest::get().build(), opts)
+            });
+            let fields = fields.lock().unwrap();
+            fields.iter().any(|(k, v)| k == "etag" && v == "\"3f2a9c\"")
+        };
+        let normalize = ServeOptions::default().normalize_etags(true);
+        assert!(warned(e("3f2a9c"), &ServeOptions::default()));
+        assert!(!warned(e("3f2a9c"), &normalize));
+        assert!(!warned(e("\"3f2a9c\""), &ServeOptions::default()));
+    }
+
+    /// Built-in error texts are labeled as UTF-8 plain text. `replace_if` can substitute a
+    /// response with a different body and type.
+    #[tokio::test]
+    async fn error_content_type() {
+        let post = FakeRequest::new(Method::POST).build();
+        let resp = serve(entity(BODY), &post);
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(
+            resp.headers()[header::CONTENT_TYPE],
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(
+            &body(resp).await[..],
+            b"This resource only supports GET and HEAD."
+        );
+
+        let stream = stream::once(futures::future::ok::<_, BoxedError>(Bytes::new()));
+        let resp: Response<hyper::Body> =
+            super::serve_stream(&post, stream, HeaderValue::from_static("text/csv"));
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(
+            resp.headers()[header::CONTENT_TYPE],
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(resp.headers()[header::ALLOW], "GET, HEAD");
+        assert!(matches!(
+            resp.extensions().get::<super::ServeDisposition>(),
+            Some(super::ServeDisposition::MethodNotAllowed)
+        ));
+        assert_eq!(
+            &body(resp).await[..],
+            b"This resource only supports GET and HEAD."
+        );
+
+        let resp = super::replace_if(serve(entity(BODY), &post), |d| match d {
+            super::ServeDisposition::MethodNotAllowed => Some(
+                Response::builder()
+                    .status(StatusCode::METHOD_NOT_ALLOWED)
+                    .header(header::CONTENT_TYPE, "application/problem+json")
+                    .body(hyper::Body::from("{}"))
+                    .unwrap(),
+            ),
+            _ => None,
+        });
+        assert_eq!(
+            resp.headers()[header::CONTENT_TYPE],
+            "application/problem+json"
+        );
+    }
+
+    /// Configured `Vary` names are sent whether or not the response was negotiated, merged with
+    /// `Accept-Encoding` when it was.
+    #[tokio::test]
+    async fn vary() {
+        let opts = ServeOptions::default().vary(&[header::ACCEPT_LANGUAGE]);
+        let resp = serve_with(entity(BODY), &FakeRequest::get().build(), &opts);
+        assert_eq!(resp.headers()[header::VARY], "accept-language");
+        let not_modified = FakeRequest::get().if_none_match("\"foo\"").build();
+        let resp = serve_with(entity(BODY), &not_modified, &opts);
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(resp.headers()[header::VARY], "accept-language");
+
+        let e = || {
+            entity(BODY).variant(
+                ContentCoding::Gzip,
+                encoded(ContentCoding::Gzip, b"gzipped"),
+            )
+        };
+        let resp = serve_with(
+            e(),
+            &FakeRequest::get()
+                .header(header::ACCEPT_ENCODING, "gzip")
+                .build(),
+            &opts,
+        );
+        assert_eq!(
+            resp.headers()[header::VARY],
+            "accept-encoding, accept-language"
+        );
+        let opts = ServeOptions::default().vary(&[header::ACCEPT_ENCODING, header::COOKIE]);
+        let resp = serve_with(e(), &FakeRequest::get().build(), &opts);
+        assert_eq!(resp.headers()[header::VARY], "accept-encoding, cookie");
+
+        // Requests rejected outright don't get one.
+        let resp = serve_with(e(), &FakeRequest::new(Method::POST).build(), &opts);
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(resp.headers().get(header::VARY), None);
+    }
+
+    /// A precompressed entity isn't encoded again, even if it (mistakenly) offers variants, and
+    /// its ranges are of the compressed bytes.
+    #[tokio::test]
+    async fn precompressed() {
+        let e = || {
+            entity(BODY)
+                .header(header::CONTENT_ENCODING, "gzip")
+                .variant(ContentCoding::Gzip, encoded(ContentCoding::Gzip, b"double"))
+                .variant(
+                    ContentCoding::Brotli,
+                    encoded(ContentCoding::Brotli, b"double"),
+                )
+        };
+        let resp = serve(
+            e(),
+            &FakeRequest::get()
+                .header(header::ACCEPT_ENCODING, "gzip, br")
+                .build(),
+        );
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(resp.headers()[header::ETAG], "\"foo\"");
+        assert!(!resp.headers().contains_key(header::VARY));
+        assert_eq!(body(resp).await, BODY);
+
+        let r = FakeRequest::get()
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .range("bytes=1-3")
+            .build();
+        let resp = serve(e(), &r);
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(resp.headers()[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(resp.headers()[header::CONTENT_RANGE], "bytes 1-3/240");
+        assert_eq!(resp.headers()[header::CONTENT_LENGTH], "3");
+        assert_eq!(body(resp).await, &BODY[1..4]);
+    }
+
+    /// Records when the wrapped entity is dropped.
+    struct DropRecorder {
+        inner: FakeEntity,
+        dropped: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    }
+
+    impl Drop for DropRecorder {
+        fn drop(&mut self) {
+            self.dropped
+                .store(true, std::sync::atomic::Ordering::SeqCst);
+        }
+    }
+
+    impl Entity for DropRecorder {
+        type Data = Bytes;
+        type Error = BoxedError;
+
+        fn len(&self) -> u64 {
+            self.inner.len()
+        }
+        fn get_range(
+            &self,
+            range: Range<u64>,
+        ) -> Box<dyn Stream<Item = Result<Self::Data, Self::Error>> + Send + Sync> {
+            self.inner.get_range(range)
+        }
+        fn add_headers(&self, headers: &mut HeaderMap) {
+            self.inner.add_headers(headers)
+        }
+        fn etag(&self) -> Option<HeaderValue> {
+            Entity::etag(&self.inner)
+        }
+        fn last_modified(&self) -> Option<SystemTime> {
+            None
+        }
+    }
+
+    /// `serve` owns the entity until its body has been fully streamed, rather than dropping it
+    /// once `get_range` returns.
+    #[tokio::test]
+    async fn entity_outlives_body() {
+        use http_body::Body as _;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+        let mut ranges = vec!["bytes=0-9", "bytes=-10"];
+        if cfg!(feature = "multipart") {
+            ranges.push("bytes=0-4,10-14");
+        }
+        for &range in &ranges {
+            let dropped = Arc::new(AtomicBool::new(false));
+            let e = DropRecorder {
+                inner: entity(BODY).chunk_size(1),
+                dropped: dropped.clone(),
+            };
+            let resp = serve(e, &FakeRequest::get().range(range).build());
+            assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT, "{}", range);
+            let mut b = resp.into_body();
+            let mut chunks = 0;
+            while !b.is_end_stream() {
+                match b.data().await {
+                    None => break,
+                    Some(c) => {
+                        c.unwrap();
+                        chunks += 1;
+                        assert!(
+                            !dropped.load(Ordering::SeqCst),
+                            "{} after {}",
+                            range,
+                            chunks
+                        );
+                    }
+                }
+            }
+            assert!(chunks >= 10, "{}", range);
+            drop(b);
+            assert!(dropped.load(Ordering::SeqCst), "{}", range);
+        }
+    }
+
+    /// Entity metadata can't inject response headers. Values with CR or LF can't be built as
+    /// `HeaderValue`s at all, and the nearest that can be (with a tab) stays within its own
+    /// header line, including when `serve` quotes an etag or copies entity headers into each part
+    /// of a multipart body.
+    #[tokio::test]
+    async fn header_injection() {
+        for attack in &[
+            "x\r\nSet-Cookie: a=b",
+            "x\nSet-Cookie: a=b",
+            "x\rSet-Cookie: a=b",
+        ] {
+            assert!(HeaderValue::from_str(attack).is_err(), "{:?}", attack);
+            assert!(crate::make_strong_etag(attack).is_err(), "{:?}", attack);
+        }
+
+        let e = FakeEntity::new(BODY)
+            .etag("v1\tSet-Cookie: a=b")
+            .content_type("text/plain\tSet-Cookie: a=b");
+        let opts = ServeOptions::new().normalize_etags(true);
+        let resp = serve_with(e.clone(), &FakeRequest::get().build(), &opts);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(!resp.headers().contains_key(header::SET_COOKIE));
+        assert_eq!(resp.headers()[header::ETAG], "\"v1\tSet-Cookie: a=b\"");
+        assert_eq!(
+            resp.headers()[header::CONTENT_TYPE],
+            "text/plain\tSet-Cookie: a=b"
+        );
+
+        #[cfg(feature = "multipart")]
+        {
+            let r = FakeRequest::get().range("bytes=0-0,10-10").build();
+            let resp = serve_with(e, &r, &opts);
+            assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+            let b = body(resp).await;
+            let b = String::from_utf8_lossy(&b);
+            assert_eq!(
+                b.matches("\r\ncontent-type: text/plain\tSet-Cookie: a=b\r\n\r\n")
+                    .count(),
+                2,
+                "{:?}",
+                b
+            );
+            assert!(!b.contains("\nSet-Cookie"), "{:?}", b);
+        }
+    }
+
+    /// Ranges within `probe_size` are marked as probes, report the full length, and are served
+    /// from `get_bytes` when the entity has them in memory.
+    #[tokio::test]
+    async fn probe() {
+        use super::ServeDisposition;
+        let cases = [
+            ("bytes=0-1", None, ServeDisposition::Probe, 0..2),
+            ("bytes=-1", None, ServeDisposition::Probe, 239..240),
+            ("bytes=0-2", None, ServeDisposition::Partial, 0..3),
+            ("bytes=0-2", Some(3), ServeDisposition::Probe, 0..3),
+            ("bytes=0-0", Some(0), ServeDisposition::Partial, 0..1),
+        ];
+        for &(range, probe_size, disposition, ref expected) in &cases {
+            let e = entity(BODY);
+            let mut opts = ServeOptions::default();
+            if let Some(p) = probe_size {
+                opts = opts.probe_size(p);
+            }
+            let resp: Response<hyper::Body> =
+                serve_with(e.clone(), &FakeRequest::get().range(range).build(), &opts);
+            assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT, "{}", range);
+            assert_eq!(ServeDisposition::of(&resp), Some(disposition), "{}", range);
+            assert_eq!(
+                resp.headers()[header::CONTENT_RANGE],
+                &format!("bytes {}-{}/240", expected.start, expected.end - 1)[..],
+                "{}",
+                range
+            );
+            assert_eq!(
+                resp.headers()[header::CONTENT_LENGTH],
+                &(expected.end - expected.start).to_string()[..]
+            );
+            assert_eq!(&body(resp).await[..], &BODY[expected.clone()], "{}", range);
+            let fast = disposition == ServeDisposition::Probe;
+            assert_eq!(e.get_range_calls(), if fast { 0 } else { 1 }, "{}", range);
+        }
+
+        // Without get_bytes (which DropRecorder doesn't forward), a probe is read as usual.
+        let e = DropRecorder {
+            inner: entity(BODY),
+            dropped: Default::default(),
+        };
+        let resp = serve(e, &FakeRequest::get().range("bytes=0-1").build());
+        assert_eq!(ServeDisposition::of(&resp), Some(ServeDisposition::Probe));
+        assert_eq!(resp.headers()[header::CONTENT_RANGE], "bytes 0-1/240");
+        assert_eq!(&body(resp).await[..], &BODY[0..2]);
+
+        // CowEntity has its body in memory.
+        let e = crate::CowEntity::<Bytes, BoxedError>::new(BODY);
+        let resp = serve(e, &FakeRequest::get().range("bytes=0-1").build());
+        assert_eq!(resp.headers()[header::CONTENT_RANGE], "bytes 0-1/240");
+        assert_eq!(&body(resp).await[..], &BODY[0..2]);
+    }
+
+    /// A `Repr-Digest` trailer, computed as a many-chunk body streams, matches the body.
+    #[cfg(feature = "repr-digest-trailer")]
+    #[tokio::test]
+    async fn repr_digest_trailer() {
+        use hyper::body::HttpBody;
+        let e = || DropRecorder {
+            inner: entity(BODY).chunk_size(1),
+            dropped: Default::default(),
+        };
+        let h2 = |r: FakeRequest| {
+            let mut r = r.build();
+            *r.version_mut() = http::Version::HTTP_2;
+            r
+        };
+        let expected = format!(
+            "sha-256=:{}:",
+            crate::trailers::base64(&<sha2::Sha256 as sha2::Digest>::digest(BODY))
+        );
+
+        let opts = ServeOptions::new().repr_digest_trailer(true);
+        let resp = super::serve_with_trailers(e(), &h2(FakeRequest::get()), &opts);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::TRAILER], "repr-digest");
+        let mut b = resp.into_body();
+        let (mut data, mut chunks) = (Vec::new(), 0);
+        while let Some(chunk) = b.data().await {
+            data.extend_from_slice(&chunk.unwrap());
+            chunks += 1;
+        }
+        assert_eq!(&data[..], BODY);
+        assert_eq!(chunks, BODY.len());
+        let trailers = b.trailers().await.unwrap().unwrap();
+        assert_eq!(trailers["repr-digest"], &expected[..]);
+
+        // Alongside Content-MD5, both are announced and sent.
+        #[cfg(feature = "content-md5")]
+        {
+            let both = opts.clone().content_md5_trailer(true);
+            let resp = super::serve_with_trailers(e(), &h2(FakeRequest::get()), &both);
+            let announced: Vec<_> = resp.headers().get_all(header::TRAILER).iter().collect();
+            assert_eq!(announced, ["content-md5", "repr-digest"]);
+            let mut b = resp.into_body();
+            while let Some(chunk) = b.data().await {
+                chunk.unwrap();
+            }
+            let trailers = b.trailers().await.unwrap().unwrap();
+            assert_eq!(trailers["repr-digest"], &expected[..]);
+            assert!(trailers.contains_key("content-md5"));
+        }
+
+        // Partial content, HTTP/1.1, and an entity with its own digest get no trailer.
+        let own = FakeEntity::new(BODY).digest("sha-256=:AAAA:");
+        let cases = vec![
+            (e(), h2(FakeRequest::get().range("bytes=0-9"))),
+            (e(), FakeRequest::get().build()),
+        ];
+        for (e, r) in cases {
+            let resp = super::serve_with_trailers(e, &r, &opts);
+            assert!(!resp.headers().contains_key(header::TRAILER));
+            let mut b = resp.into_body();
+            while let Some(chunk) = b.data().await {
+                chunk.unwrap();
+            }
+            assert_eq!(b.trailers().await.unwrap(), None);
+        }
+        let resp = super::serve_with_trailers(own, &h2(FakeRequest::get()), &opts);
+        assert_eq!(resp.headers()["repr-digest"], "sha-256=:AAAA:");
+        assert!(!resp.headers().contains_key(header::TRAILER));
+    }
+
+    /// With `reject_ambiguous_framing`, requests a proxy might frame differently get a 400, even
+    /// where `serve_immutable` would otherwise short-circuit.
+    #[tokio::test]
+    async fn ambiguous_framing() {
+        type Case = (&'static [(&'static str, &'static str)], StatusCode);
+        let cases: &[Case] = &[
+            (&[], StatusCode::OK),
+            (&[("content-length", "0")], StatusCode::OK),
+            (&[("transfer-encoding", "chunked")], StatusCode::OK),
+            (
+                &[("content-length", "0"), ("transfer-encoding", "chunked")],
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                &[("content-length", "0"), ("content-length", "5")],
+                StatusCode::BAD_REQUEST,
+            ),
+            (&[("content-length", "0, 0")], StatusCode::BAD_REQUEST),
+        ];
+        let opts = ServeOptions::new().reject_ambiguous_framing(true);
+        for &(hdrs, status) in cases {
+            for method in &[Method::GET, Method::HEAD] {
+                let mut r = FakeRequest::new(method.clone());
+                for &(k, v) in hdrs {
+                    r = r.header(HeaderName::from_static(k), v);
+                }
+                let r = r.build();
+                assert_eq!(serve(entity(BODY), &r).status(), StatusCode::OK);
+                let resp = serve_with(entity(BODY), &r, &opts);
+                assert_eq!(resp.status(), status, "{} {:?}", method, hdrs);
+                if status == StatusCode::BAD_REQUEST {
+                    assert_eq!(
+                        resp.extensions().get::<super::ServeDisposition>(),
+                        Some(&super::ServeDisposition::BadRequest)
+                    );
+                }
+            }
+        }
+
+        let etag = HeaderValue::from_static("\"v1\"");
+        let r = FakeRequest::get()
+            .if_none_match("\"v1\"")
+            .header(header::CONTENT_LENGTH, "0")
+            .header(header::TRANSFER_ENCODING, "chunked")
+            .build();
+        let resp: Response<hyper::Body> =
+            super::serve_immutable(&etag, || entity(BODY), &r, &ServeOptions::new());
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        let resp: Response<hyper::Body> = super::serve_immutable(&etag, || entity(BODY), &r, &opts);
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
 }
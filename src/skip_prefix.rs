@@ -0,0 +1,205 @@
+// Copyright (c) 2016-2020 The http-serve developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::trim::Trim;
+use crate::Entity;
+use bytes::Buf;
+use futures::{stream, Stream};
+use http::header::{self, HeaderMap, HeaderValue};
+use std::error::Error as StdError;
+use std::ops::Range;
+use std::pin::Pin;
+use std::time::SystemTime;
+
+type BoxedStream<D, E> = Pin<Box<dyn Stream<Item = Result<D, E>> + Send + Sync>>;
+
+/// An entity of known length whose body is produced by regenerating it from the start, such as a
+/// CSV export assembled from database rows. See [`csv_entity`](fn.csv_entity.html).
+///
+/// A range is served by regenerating the body, discarding the bytes before the range's start, and
+/// stopping at its end. This supports resuming an interrupted download (`Range: bytes=N-`)
+/// without storing the body, at the cost of producing (but not sending) the first `N` bytes
+/// again. Other ranges are served the same way, so each range of a `multipart/byteranges`
+/// response regenerates the body separately.
+///
+/// Every regeneration must produce identical bytes, for example by querying a snapshot in a stable
+/// order. Set an etag identifying the snapshot so that `If-Range` keeps clients from splicing
+/// together two different exports. A body shorter than `len` ends the response with an error;
+/// bytes beyond `len` are ignored.
+pub struct SkipPrefixEntity<D, E> {
+    len: u64,
+    regenerate: Box<dyn Fn() -> BoxedStream<D, E> + Send + Sync>,
+    headers: HeaderMap,
+    etag: Option<HeaderValue>,
+    last_modified: Option<SystemTime>,
+}
+
+impl<D, E> SkipPrefixEntity<D, E>
+where
+    D: 'static + Send + Sync + Buf + From<Vec<u8>> + From<&'static [u8]>,
+    E: 'static + Send + Sync + From<Box<dyn StdError + Send + Sync>>,
+{
+    /// Creates an entity of `len` bytes with the given headers, whose body is the stream
+    /// returned by `regenerate`.
+    pub fn new<F, S>(len: u64, headers: HeaderMap, regenerate: F) -> Self
+    where
+        F: Fn() -> S + Send + Sync + 'static,
+        S: Stream<Item = Result<D, E>> + Send + Sync + 'static,
+    {
+        SkipPrefixEntity {
+            len,
+            regenerate: Box::new(move || Box::pin(regenerate())),
+            headers,
+            etag: None,
+            last_modified: None,
+        }
+    }
+
+    /// Sets the etag, which should identify the data the body is regenerated from.
+    pub fn etag(mut self, etag: HeaderValue) -> Self {
+        self.etag = Some(etag);
+        self
+    }
+
+    /// Sets the last modified time of the data the body is regenerated from.
+    pub fn last_modified(mut self, last_modified: SystemTime) -> Self {
+        self.last_modified = Some(last_modified);
+        self
+    }
+}
+
+impl<D, E> Entity for SkipPrefixEntity<D, E>
+where
+    D: 'static + Send + Sync + Buf + From<Vec<u8>> + From<&'static [u8]>,
+    E: 'static + Send + Sync + From<Box<dyn StdError + Send + Sync>>,
+{
+    type Data = D;
+    type Error = E;
+
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn get_range(
+        &self,
+        range: Range<u64>,
+    ) -> Box<dyn Stream<Item = Result<Self::Data, Self::Error>> + Send + Sync> {
+        if range.start == range.end {
+            return Box::new(stream::empty());
+        }
+        let end = range.end;
+        let on_short = move |missing| {
+            let msg = format!(
+                "regenerated body ended {} bytes before byte {}",
+                missing, end
+            );
+            Some(E::from(msg.into()))
+        };
+        Box::new(Trim::new((self.regenerate)(), range.start, end - range.start).on_short(on_short))
+    }
+
+    fn add_headers(&self, h: &mut HeaderMap) {
+        h.extend(self.headers.iter().map(|(k, v)| (k.clone(), v.clone())));
+    }
+
+    fn content_type(&self) -> Option<HeaderValue> {
+        self.headers.get(header::CONTENT_TYPE).cloned()
+    }
+
+    fn etag(&self) -> Option<HeaderValue> {
+        self.etag.clone()
+    }
+
+    fn last_modified(&self) -> Option<SystemTime> {
+        self.last_modified
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SkipPrefixEntity;
+    use crate::test_support::FakeRequest;
+    use crate::Entity;
+    use bytes::Bytes;
+    use futures::{stream, TryStreamExt};
+    use http::header::{self, HeaderMap, HeaderValue};
+    use http::{Response, StatusCode};
+    use std::pin::Pin;
+
+    type BoxedError = Box<dyn std::error::Error + Send + Sync>;
+
+    static BODY: &[u8] = b"id,name\r\n1,alpha\r\n2,beta\r\n3,gamma\r\n";
+
+    /// An entity regenerating `BODY` in chunks of uneven sizes, truncated to `produced` bytes.
+    fn entity(produced: usize) -> SkipPrefixEntity<Bytes, BoxedError> {
+        SkipPrefixEntity::new(BODY.len() as u64, HeaderMap::new(), move || {
+            let bounds = [0, 1, 5, 6, 14, 22, BODY.len()];
+            let chunks: Vec<Result<Bytes, BoxedError>> = bounds
+                .windows(2)
+                .filter(|w| w[0] < produced)
+                .map(|w| Ok(Bytes::from_static(&BODY[w[0]..w[1].min(produced)])))
+                .collect();
+            stream::iter(chunks)
+        })
+        .etag(HeaderValue::from_static("\"snapshot-1\""))
+    }
+
+    #[tokio::test]
+    async fn all_ranges() {
+        let e = entity(BODY.len());
+        for start in 0..=BODY.len() {
+            for end in start..=BODY.len() {
+                let s = Pin::from(e.get_range(start as u64..end as u64));
+                let chunks: Vec<Bytes> = s.try_collect().await.unwrap();
+                assert_eq!(chunks.concat(), &BODY[start..end], "{}..{}", start, end);
+            }
+        }
+    }
+
+    /// Resuming an interrupted export yields the same bytes as a full one.
+    #[tokio::test]
+    async fn resume() {
+        let full: Response<hyper::Body> =
+            crate::serve(entity(BODY.len()), &FakeRequest::get().build());
+        assert_eq!(full.status(), StatusCode::OK);
+        let full = hyper::body::to_bytes(full.into_body()).await.unwrap();
+        assert_eq!(&full[..], BODY);
+
+        for received in 1..BODY.len() {
+            let req = FakeRequest::get()
+                .range(&format!("bytes={}-", received))
+                .header(header::IF_RANGE, "\"snapshot-1\"")
+                .build();
+            let resp: Response<hyper::Body> = crate::serve(entity(BODY.len()), &req);
+            assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+            let rest = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            let mut resumed = full[..received].to_vec();
+            resumed.extend_from_slice(&rest);
+            assert_eq!(resumed, &full[..], "resumed at {}", received);
+        }
+
+        // A changed snapshot restarts the download.
+        let req = FakeRequest::get()
+            .range("bytes=10-")
+            .header(header::IF_RANGE, "\"snapshot-0\"")
+            .build();
+        let resp: Response<hyper::Body> = crate::serve(entity(BODY.len()), &req);
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn short_body() {
+        let e = entity(20);
+        let s = Pin::from(e.get_range(10..BODY.len() as u64));
+        let r: Result<Vec<Bytes>, BoxedError> = s.try_collect().await;
+        assert_eq!(
+            r.unwrap_err().to_string(),
+            "regenerated body ended 15 bytes before byte 35"
+        );
+    }
+}
@@ -0,0 +1,478 @@
+// Copyright (c) 2016-2020 The http-serve developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Serving from synchronous code, such as a `tiny_http` preview server or a test fixture.
+//!
+//! A [`SyncEntity`] reads its ranges with `std::io::Read` rather than a stream, and
+//! [`serve`](fn.serve.html) returns a response whose [`Body`] is likewise a `Read`. Underneath,
+//! this is the same code as the async [`serve`](../fn.serve.html), so conditional requests,
+//! ranges, `multipart/byteranges` responses, and [`ServeOptions`](../struct.ServeOptions.html)
+//! behave identically. A body which ends short of its `Content-Length`, such as one cut off by a
+//! draining [`Watch`](../drain/struct.Watch.html), fails with `UnexpectedEof`, as hyper fails the
+//! transfer.
+//!
+//! Serving and reading bodies needs no async runtime. Awaiting
+//! [`Watch::drain`](../drain/struct.Watch.html#method.drain) does, for its grace period timer,
+//! but draining starts on that call alone, so a synchronous server can start it without awaiting
+//! and let the bodies already underway run out their grace period.
+//!
+//! ```
+//! use http::header::{HeaderMap, HeaderValue};
+//! use http::{Method, StatusCode};
+//! use std::io::{Cursor, Read};
+//! use std::ops::Range;
+//! use std::time::SystemTime;
+//!
+//! struct Hello;
+//!
+//! impl http_serve::sync::SyncEntity for Hello {
+//!     fn len(&self) -> u64 {
+//!         12
+//!     }
+//!     fn get_range(&self, range: Range<u64>) -> std::io::Result<Box<dyn Read + Send>> {
+//!         let data = &b"hello world\n"[range.start as usize..range.end as usize];
+//!         Ok(Box::new(Cursor::new(data)))
+//!     }
+//!     fn add_headers(&self, h: &mut HeaderMap) {
+//!         h.insert(http::header::CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+//!     }
+//!     fn etag(&self) -> Option<HeaderValue> {
+//!         Some(HeaderValue::from_static("\"v1\""))
+//!     }
+//!     fn last_modified(&self) -> Option<SystemTime> {
+//!         None
+//!     }
+//! }
+//!
+//! let mut hdrs = HeaderMap::new();
+//! hdrs.insert(http::header::RANGE, HeaderValue::from_static("bytes=0-4"));
+//! let resp = http_serve::sync::serve(Hello, &Method::GET, &hdrs);
+//! assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+//! let mut body = String::new();
+//! resp.into_body().read_to_string(&mut body).unwrap();
+//! assert_eq!(body, "hello");
+//! ```
+
+use crate::{Entity, ServeOptions};
+use bytes::{Buf, Bytes};
+use futures::{Stream, StreamExt};
+use http::header::{self, HeaderMap, HeaderValue};
+use http::{Method, Request, Response};
+use std::io::{self, Read};
+use std::ops::Range;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+
+/// The most bytes read from a `SyncEntity`'s reader at a time.
+const CHUNK_SIZE: usize = 65_536;
+
+type BoxedStream = Pin<Box<dyn Stream<Item = Result<Bytes, io::Error>> + Send>>;
+
+/// A reusable, read-only, byte-rangeable HTTP entity read synchronously, the counterpart of
+/// [`Entity`](../trait.Entity.html).
+///
+/// The same contract applies: every call must describe the same data.
+pub trait SyncEntity: 'static + Send + Sync {
+    /// Returns the length of the entity's body in bytes.
+    fn len(&self) -> u64;
+
+    /// Returns true iff the entity's body has length 0.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns a reader of the given range, which must yield exactly `range.end - range.start`
+    /// bytes. Small entities can return an `io::Cursor` over a `Vec<u8>`.
+    ///
+    /// This is called only as the response body is read, once per range.
+    fn get_range(&self, range: Range<u64>) -> io::Result<Box<dyn Read + Send>>;
+
+    /// Adds entity headers such as `Content-Type` to the supplied `Headers`, as for
+    /// `Entity::add_headers`.
+    fn add_headers(&self, _: &mut HeaderMap);
+
+    /// Returns an etag for this entity, if available, including its quotes.
+    fn etag(&self) -> Option<HeaderValue>;
+
+    /// Returns the last modified time of this entity, if available.
+    fn last_modified(&self) -> Option<SystemTime>;
+}
+
+/// A response body read synchronously, as returned by [`serve`](fn.serve.html).
+///
+/// Reading blocks the calling thread until the entity's reader yields data.
+pub struct Body {
+    stream: BoxedStream,
+    chunk: Bytes,
+
+    /// The bytes of the `Content-Length` not yet read, if any.
+    remaining: Option<u64>,
+}
+
+impl Read for Body {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while !self.chunk.has_remaining() {
+            match futures::executor::block_on(self.stream.next()) {
+                None => match self.remaining {
+                    Some(r) if r > 0 => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            format!("body ended {} bytes short of its Content-Length", r),
+                        ))
+                    }
+                    _ => return Ok(0),
+                },
+                Some(Err(e)) => return Err(e),
+                Some(Ok(c)) => self.chunk = c,
+            }
+        }
+        let n = std::cmp::min(buf.len(), self.chunk.len());
+        if let Some(ref mut r) = self.remaining {
+            *r = r.saturating_sub(n as u64);
+        }
+        buf[..n].copy_from_slice(&self.chunk[..n]);
+        self.chunk.advance(n);
+        Ok(n)
+    }
+}
+
+impl std::fmt::Debug for Body {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Body")
+            .field("buffered", &self.chunk.len())
+            .finish()
+    }
+}
+
+/// Serves a `GET` or `HEAD` request with the given method and headers, as
+/// [`crate::serve`](../fn.serve.html) does.
+pub fn serve<E: SyncEntity>(entity: E, method: &Method, req_hdrs: &HeaderMap) -> Response<Body> {
+    serve_with_options(entity, method, req_hdrs, &ServeOptions::default())
+}
+
+/// Serves a request as [`serve`](fn.serve.html) does, with non-default options.
+pub fn serve_with_options<E: SyncEntity>(
+    entity: E,
+    method: &Method,
+    req_hdrs: &HeaderMap,
+    opts: &ServeOptions,
+) -> Response<Body> {
+    let mut req = Request::new(());
+    *req.method_mut() = method.clone();
+    *req.headers_mut() = req_hdrs.clone();
+    let res: Response<StreamBody> = crate::serve_with_options(Adapter(entity), &req, opts);
+    let remaining = match *method {
+        Method::HEAD => None,
+        _ => res
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok()),
+    };
+    res.map(|b| Body {
+        stream: b.0,
+        chunk: Bytes::new(),
+        remaining,
+    })
+}
+
+/// A `SyncEntity` as an `Entity`, whose streams read synchronously when polled.
+struct Adapter<E>(E);
+
+impl<E: SyncEntity> Entity for Adapter<E> {
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn len(&self) -> u64 {
+        self.0.len()
+    }
+
+    fn get_range(
+        &self,
+        range: Range<u64>,
+    ) -> Box<dyn Stream<Item = Result<Self::Data, Self::Error>> + Send + Sync> {
+        let len = range.end - range.start;
+        let reader = match self.0.get_range(range) {
+            Ok(r) => r,
+            Err(e) => return Box::new(futures::stream::once(futures::future::err(e))),
+        };
+
+        // The reader needn't be Sync; it's only ever used by the one stream polling it.
+        let state = (Mutex::new(reader), len);
+        Box::new(futures::stream::unfold(
+            state,
+            |(reader, left)| async move {
+                if left == 0 {
+                    return None;
+                }
+                let mut buf = vec![0; std::cmp::min(left, CHUNK_SIZE as u64) as usize];
+                let r = reader.lock().unwrap().read(&mut buf);
+                match r {
+                    Ok(0) => Some((
+                        Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            format!("entity's reader ended {} bytes short", left),
+                        )),
+                        (reader, 0),
+                    )),
+                    Ok(n) => {
+                        buf.truncate(n);
+                        Some((Ok(Bytes::from(buf)), (reader, left - n as u64)))
+                    }
+                    Err(e) => Some((Err(e), (reader, 0))),
+                }
+            },
+        ))
+    }
+
+    fn add_headers(&self, h: &mut HeaderMap) {
+        self.0.add_headers(h)
+    }
+
+    fn etag(&self) -> Option<HeaderValue> {
+        self.0.etag()
+    }
+
+    fn last_modified(&self) -> Option<SystemTime> {
+        self.0.last_modified()
+    }
+}
+
+/// The body type handed to `crate::serve_with_options`, holding its stream for `Body`.
+struct StreamBody(BoxedStream);
+
+impl From<Box<dyn Stream<Item = Result<Bytes, io::Error>> + Send>> for StreamBody {
+    fn from(stream: Box<dyn Stream<Item = Result<Bytes, io::Error>> + Send>) -> Self {
+        StreamBody(stream.into())
+    }
+}
+
+impl http_body::Body for StreamBody {
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        self.get_mut().0.as_mut().poll_next(cx)
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        _cx: &mut Context,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Poll::Ready(Ok(None))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{serve, serve_with_options, SyncEntity};
+    use crate::drain::Watch;
+    use crate::ServeOptions;
+    use http::header::{self, HeaderMap, HeaderValue};
+    use http::{Method, Response, StatusCode};
+    use std::io::{self, Cursor, Read};
+    use std::ops::Range;
+    use std::time::{Duration, SystemTime};
+
+    /// An entity of `len` bytes (`i % 251` at offset `i`), whose readers yield at most `step`
+    /// bytes per call and `short` fewer bytes than requested in all.
+    #[derive(Clone)]
+    struct Pattern {
+        len: u64,
+        step: usize,
+        short: u64,
+    }
+
+    impl Pattern {
+        fn new(len: u64) -> Self {
+            Pattern {
+                len,
+                step: 1000,
+                short: 0,
+            }
+        }
+
+        fn data(&self, range: Range<u64>) -> Vec<u8> {
+            range.map(|i| (i % 251) as u8).collect()
+        }
+    }
+
+    /// Reads at most `step` bytes per call.
+    struct Trickle(Cursor<Vec<u8>>, usize);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = std::cmp::min(buf.len(), self.1);
+            self.0.read(&mut buf[..n])
+        }
+    }
+
+    impl SyncEntity for Pattern {
+        fn len(&self) -> u64 {
+            self.len
+        }
+        fn get_range(&self, range: Range<u64>) -> io::Result<Box<dyn Read + Send>> {
+            let data = self.data(range.start..range.end - self.short);
+            Ok(Box::new(Trickle(Cursor::new(data), self.step)))
+        }
+        fn add_headers(&self, h: &mut HeaderMap) {
+            h.insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/octet-stream"),
+            );
+        }
+        fn etag(&self) -> Option<HeaderValue> {
+            Some(HeaderValue::from_static("\"pattern\""))
+        }
+        fn last_modified(&self) -> Option<SystemTime> {
+            None
+        }
+    }
+
+    fn hdrs(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut h = HeaderMap::new();
+        for &(k, v) in pairs {
+            h.append(k, HeaderValue::from_static(v));
+        }
+        h
+    }
+
+    fn read(resp: Response<super::Body>) -> io::Result<Vec<u8>> {
+        let mut body = Vec::new();
+        resp.into_body().read_to_end(&mut body)?;
+        Ok(body)
+    }
+
+    /// The conditional and range cases, answered as the async path would.
+    #[test]
+    fn matrix() {
+        let e = Pattern::new(200_000);
+        let full = e.data(0..200_000);
+        type Case = (
+            &'static [(&'static str, &'static str)],
+            StatusCode,
+            Option<Range<usize>>,
+        );
+        let cases: &[Case] = &[
+            (&[], StatusCode::OK, Some(0..200_000)),
+            (
+                &[("Range", "bytes=10-99999")],
+                StatusCode::PARTIAL_CONTENT,
+                Some(10..100_000),
+            ),
+            (
+                &[("Range", "bytes=-5")],
+                StatusCode::PARTIAL_CONTENT,
+                Some(199_995..200_000),
+            ),
+            (
+                &[("Range", "bytes=300000-")],
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                None,
+            ),
+            (
+                &[("If-None-Match", "\"pattern\"")],
+                StatusCode::NOT_MODIFIED,
+                None,
+            ),
+            (
+                &[("If-Match", "\"other\"")],
+                StatusCode::PRECONDITION_FAILED,
+                None,
+            ),
+            (
+                &[("Range", "bytes=0-9"), ("If-Range", "\"other\"")],
+                StatusCode::OK,
+                Some(0..200_000),
+            ),
+            (
+                &[("Range", "bytes=0-9"), ("If-Range", "\"pattern\"")],
+                StatusCode::PARTIAL_CONTENT,
+                Some(0..10),
+            ),
+        ];
+        for &(pairs, status, ref expected) in cases {
+            let resp = serve(e.clone(), &Method::GET, &hdrs(pairs));
+            assert_eq!(resp.status(), status, "{:?}", pairs);
+            let body = read(resp).unwrap();
+            if let Some(ref r) = *expected {
+                assert!(body == full[r.clone()], "{:?}", pairs);
+            }
+
+            let resp = serve(e.clone(), &Method::HEAD, &hdrs(pairs));
+            assert_eq!(resp.status(), status, "HEAD {:?}", pairs);
+            if status.is_success() {
+                assert_eq!(read(resp).unwrap(), b"", "HEAD {:?}", pairs);
+            }
+        }
+    }
+
+    #[cfg(feature = "multipart")]
+    #[test]
+    fn multipart() {
+        let e = Pattern::new(1000);
+        let resp = serve(
+            e.clone(),
+            &Method::GET,
+            &hdrs(&[("Range", "bytes=0-9,500-509")]),
+        );
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        let len: usize = resp.headers()[header::CONTENT_LENGTH]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let body = read(resp).unwrap();
+        assert_eq!(body.len(), len);
+        let find = |needle: &[u8]| body.windows(needle.len()).any(|w| w == needle);
+        assert!(find(b"Content-Range: bytes 0-9/1000"));
+        assert!(find(&e.data(0..10)));
+        assert!(find(b"Content-Range: bytes 500-509/1000"));
+        assert!(find(&e.data(500..510)));
+    }
+
+    /// A reader which ends early makes the body fail rather than end short.
+    #[test]
+    fn short_reader() {
+        let e = Pattern {
+            short: 10,
+            ..Pattern::new(1000)
+        };
+        let resp = serve(e, &Method::GET, &hdrs(&[]));
+        assert_eq!(resp.status(), StatusCode::OK);
+        let err = read(resp).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    /// Draining works without a runtime: a body underway when it starts is cut off once the
+    /// grace period ends, failing rather than ending short, and later requests are refused.
+    #[test]
+    fn drain() {
+        let watch = Watch::new(Duration::from_millis(0));
+        let opts = ServeOptions::new().drain(watch.clone());
+        let e = Pattern::new(200_000);
+        let resp = serve_with_options(e.clone(), &Method::GET, &hdrs(&[]), &opts);
+        assert_eq!(resp.status(), StatusCode::OK);
+        let mut body = resp.into_body();
+        let mut buf = [0; 100];
+        body.read_exact(&mut buf).unwrap();
+
+        drop(watch.drain());
+        let mut rest = Vec::new();
+        let err = body.read_to_end(&mut rest).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        let resp = serve_with_options(e, &Method::GET, &hdrs(&[]), &opts);
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
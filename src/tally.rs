@@ -0,0 +1,96 @@
+// Copyright (c) 2016-2020 The http-serve developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use futures::channel::oneshot;
+use futures::Stream;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// A future which resolves to the number of body bytes taken by the server for sending, as a
+/// response extension.
+///
+/// Attached by `serve` when `ServeOptions::report_bytes_sent` is set, and by `streaming_body`
+/// when `StreamingBodyBuilder::with_report_bytes_sent` is. It resolves once the body has ended or
+/// been dropped, so a client which disconnects early is counted only for what the server sent
+/// before then; a response dropped without being sent counts zero bytes. Bytes buffered by hyper
+/// or the OS but never delivered are still counted.
+///
+/// ```
+/// # async fn f(mut resp: http::Response<hyper::Body>) {
+/// if let Some(sent) = resp.extensions_mut().remove::<http_serve::BytesSent>() {
+///     tokio::spawn(async move { println!("sent {} bytes", sent.await) });
+/// }
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct BytesSent(oneshot::Receiver<u64>);
+
+impl Future for BytesSent {
+    type Output = u64;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<u64> {
+        // The sender always sends before being dropped.
+        Pin::new(&mut self.get_mut().0)
+            .poll(cx)
+            .map(|r| r.expect("tally sends before drop"))
+    }
+}
+
+/// A body stream which counts the bytes it yields, reporting the total to a `BytesSent` when it
+/// ends or is dropped.
+pub(crate) struct Tallied<S, T> {
+    stream: S,
+    len: fn(&T) -> usize,
+    count: u64,
+    tx: Option<oneshot::Sender<u64>>,
+}
+
+impl<S, T> Tallied<S, T> {
+    /// Wraps `stream`, measuring each chunk with `len`.
+    pub(crate) fn new(stream: S, len: fn(&T) -> usize) -> (Self, BytesSent) {
+        let (tx, rx) = oneshot::channel();
+        let tallied = Tallied {
+            stream,
+            len,
+            count: 0,
+            tx: Some(tx),
+        };
+        (tallied, BytesSent(rx))
+    }
+
+    fn report(&mut self) {
+        if let Some(tx) = self.tx.take() {
+            let _ = tx.send(self.count);
+        }
+    }
+}
+
+impl<S, T, E> Stream for Tallied<S, T>
+where
+    S: Stream<Item = Result<T, E>> + Unpin,
+{
+    type Item = Result<T, E>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Result<T, E>>> {
+        let this = self.get_mut();
+        let p = Pin::new(&mut this.stream).poll_next(cx);
+        match p {
+            Poll::Ready(Some(Ok(ref d))) => this.count += (this.len)(d) as u64,
+            Poll::Ready(None) => this.report(),
+            _ => {}
+        }
+        p
+    }
+}
+
+impl<S, T> Drop for Tallied<S, T> {
+    fn drop(&mut self) {
+        self.report();
+    }
+}
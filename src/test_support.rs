@@ -0,0 +1,640 @@
+// Copyright (c) 2016-2020 The http-serve developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Helpers for testing handlers built on `http-serve`. Available with the `test-support` feature.
+//!
+//! ```
+//! use http_serve::test_support::{FakeEntity, FakeRequest};
+//!
+//! let e = FakeEntity::new(&b"hello world"[..]).etag("\"v1\"").content_type("text/plain");
+//! let req = FakeRequest::get().range("bytes=0-4").build();
+//! let resp: http::Response<hyper::Body> = http_serve::serve(e, &req);
+//! assert_eq!(resp.status(), http::StatusCode::PARTIAL_CONTENT);
+//! ```
+//!
+//! [`validate_entity`](fn.validate_entity.html) checks an `Entity` implementation for mistakes
+//! which would otherwise only show up as corrupt downloads.
+
+use crate::{BoxedEntity, ContentCoding, Entity};
+use bytes::{Buf, Bytes};
+use futures::{Stream, StreamExt};
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use http::{Method, Request};
+use httpdate::fmt_http_date;
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// An in-memory `Entity` with a configurable body, etag, last modified time, content type,
+/// digest, and content-coded variants.
+///
+/// By default it has no etag, no last modified time, no `Content-Type`, no digest, and no
+/// variants, and `get_range` yields each range as a single chunk. Clones share a count of
+/// `get_range` calls; see [`get_range_calls`](#method.get_range_calls).
+#[derive(Clone, Debug)]
+pub struct FakeEntity {
+    body: Bytes,
+    etag: Option<HeaderValue>,
+    last_modified: Option<SystemTime>,
+
+    /// The headers returned by `static_headers`, built up by the setters.
+    headers: HeaderMap,
+    digest: Option<HeaderValue>,
+    content_location: Option<HeaderValue>,
+    variants: Vec<(ContentCoding, FakeEntity)>,
+    chunk_size: Option<usize>,
+    fail_after: Option<u64>,
+    get_range_calls: Arc<AtomicUsize>,
+}
+
+impl FakeEntity {
+    /// Creates an entity with the given body.
+    pub fn new<B: Into<Bytes>>(body: B) -> Self {
+        FakeEntity {
+            body: body.into(),
+            etag: None,
+            last_modified: None,
+            headers: HeaderMap::new(),
+            digest: None,
+            content_location: None,
+            variants: Vec::new(),
+            chunk_size: None,
+            fail_after: None,
+            get_range_calls: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Sets the etag, which must include its quotes (and `W/` prefix, if weak).
+    ///
+    /// Panics if `etag` isn't a valid header value.
+    pub fn etag(mut self, etag: &str) -> Self {
+        self.etag = Some(HeaderValue::from_str(etag).expect("valid etag"));
+        self
+    }
+
+    /// Sets the last modified time.
+    pub fn last_modified(mut self, last_modified: SystemTime) -> Self {
+        self.last_modified = Some(last_modified);
+        self
+    }
+
+    /// Sets the `Content-Type`.
+    ///
+    /// Panics if `content_type` isn't a valid header value.
+    pub fn content_type(mut self, content_type: &str) -> Self {
+        self.headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_str(content_type).expect("valid content type"),
+        );
+        self
+    }
+
+    /// Sets the `Content-Location`, as returned by `Entity::content_location`, such as a variant
+    /// stored under its own URL might have.
+    ///
+    /// Panics if `content_location` isn't a valid header value.
+    pub fn content_location(mut self, content_location: &str) -> Self {
+        self.content_location =
+            Some(HeaderValue::from_str(content_location).expect("valid content location"));
+        self
+    }
+
+    /// Appends an entity header, such as `Content-Language`.
+    ///
+    /// Panics if `value` isn't a valid header value.
+    pub fn header(mut self, name: HeaderName, value: &str) -> Self {
+        self.headers.append(
+            name,
+            HeaderValue::from_str(value).expect("valid header value"),
+        );
+        self
+    }
+
+    /// Sets the digest, as returned by `Entity::digest`. It isn't checked against the body.
+    ///
+    /// Panics if `digest` isn't a valid header value.
+    pub fn digest(mut self, digest: &str) -> Self {
+        self.digest = Some(HeaderValue::from_str(digest).expect("valid digest"));
+        self
+    }
+
+    /// Adds a content-coded variant, as returned by `Entity::variants`.
+    ///
+    /// The variant is served with its own body, etag, and headers when `serve` negotiates
+    /// `coding`; `serve` adds the `Content-Encoding` itself.
+    pub fn variant(mut self, coding: ContentCoding, variant: FakeEntity) -> Self {
+        self.variants.push((coding, variant));
+        self
+    }
+
+    /// Makes `get_range` yield chunks of at most `chunk_size` bytes, as a file read in pieces
+    /// would.
+    ///
+    /// Panics if `chunk_size` is 0.
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        assert!(chunk_size > 0, "chunk_size must be positive");
+        self.chunk_size = Some(chunk_size);
+        self
+    }
+
+    /// Makes `get_range` fail with an `std::io::Error` after yielding `n` bytes of any longer
+    /// range, as a file truncated or removed mid-read would. `get_bytes` then returns `None`.
+    pub fn fail_after(mut self, n: u64) -> Self {
+        self.fail_after = Some(n);
+        self
+    }
+
+    /// Returns how many times `get_range` has been called on this entity or any of its clones.
+    pub fn get_range_calls(&self) -> usize {
+        self.get_range_calls.load(Ordering::SeqCst)
+    }
+}
+
+impl Entity for FakeEntity {
+    type Data = Bytes;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+
+    fn len(&self) -> u64 {
+        self.body.len() as u64
+    }
+
+    fn get_range(
+        &self,
+        range: Range<u64>,
+    ) -> Box<dyn Stream<Item = Result<Self::Data, Self::Error>> + Send + Sync> {
+        self.get_range_calls.fetch_add(1, Ordering::SeqCst);
+        let mut body = self.body.slice(range.start as usize..range.end as usize);
+        let fail = match self.fail_after {
+            Some(n) if n < range.end - range.start => {
+                body.truncate(n as usize);
+                true
+            }
+            _ => false,
+        };
+        let mut chunks: Vec<Result<Self::Data, Self::Error>> = Vec::new();
+        match self.chunk_size {
+            None => chunks.push(Ok(body)),
+            Some(n) => {
+                while !body.is_empty() {
+                    let len = std::cmp::min(n, body.len());
+                    chunks.push(Ok(body.split_to(len)));
+                }
+            }
+        }
+        if fail {
+            chunks.push(Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "FakeEntity::fail_after",
+            ))));
+        }
+        Box::new(futures::stream::iter(chunks))
+    }
+
+    fn get_bytes(&self, range: Range<u64>) -> Option<Self::Data> {
+        if self.fail_after.is_some() {
+            return None;
+        }
+        Some(self.body.slice(range.start as usize..range.end as usize))
+    }
+
+    fn add_headers(&self, h: &mut HeaderMap) {
+        h.extend(self.headers.iter().map(|(k, v)| (k.clone(), v.clone())));
+    }
+
+    fn static_headers(&self) -> Option<&HeaderMap> {
+        Some(&self.headers)
+    }
+
+    fn content_type(&self) -> Option<HeaderValue> {
+        self.headers.get(header::CONTENT_TYPE).cloned()
+    }
+
+    fn etag(&self) -> Option<HeaderValue> {
+        self.etag.clone()
+    }
+
+    fn last_modified(&self) -> Option<SystemTime> {
+        self.last_modified
+    }
+
+    fn digest(&self) -> Option<HeaderValue> {
+        self.digest.clone()
+    }
+
+    fn content_location(&self) -> Option<HeaderValue> {
+        self.content_location.clone()
+    }
+
+    fn contains(&self, needle: &[u8], within: Range<u64>) -> Option<bool> {
+        if needle.is_empty() {
+            return Some(true);
+        }
+        let haystack = &self.body[within.start as usize..within.end as usize];
+        Some(haystack.windows(needle.len()).any(|w| w == needle))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn variants(&self) -> Vec<(ContentCoding, BoxedEntity<Self::Data, Self::Error>)> {
+        self.variants
+            .iter()
+            .map(|&(coding, ref v)| {
+                let v: BoxedEntity<Self::Data, Self::Error> = Box::new(v.clone());
+                (coding, v)
+            })
+            .collect()
+    }
+}
+
+/// A builder for requests exercising range and conditional serving.
+#[derive(Debug)]
+pub struct FakeRequest {
+    method: Method,
+    headers: HeaderMap,
+}
+
+impl FakeRequest {
+    /// Starts a request with the given method.
+    pub fn new(method: Method) -> Self {
+        FakeRequest {
+            method,
+            headers: HeaderMap::new(),
+        }
+    }
+
+    /// Starts a `GET` request.
+    pub fn get() -> Self {
+        Self::new(Method::GET)
+    }
+
+    /// Starts a `HEAD` request.
+    pub fn head() -> Self {
+        Self::new(Method::HEAD)
+    }
+
+    /// Appends an arbitrary header.
+    ///
+    /// Panics if `value` isn't a valid header value.
+    pub fn header(mut self, name: HeaderName, value: &str) -> Self {
+        self.headers.append(
+            name,
+            HeaderValue::from_str(value).expect("valid header value"),
+        );
+        self
+    }
+
+    /// Sets `Range`, such as `bytes=0-99`.
+    pub fn range(self, range: &str) -> Self {
+        self.header(header::RANGE, range)
+    }
+
+    /// Sets `If-Range` to an etag.
+    pub fn if_range(self, etag: &str) -> Self {
+        self.header(header::IF_RANGE, etag)
+    }
+
+    /// Sets `If-Match`.
+    pub fn if_match(self, etags: &str) -> Self {
+        self.header(header::IF_MATCH, etags)
+    }
+
+    /// Sets `If-None-Match`.
+    pub fn if_none_match(self, etags: &str) -> Self {
+        self.header(header::IF_NONE_MATCH, etags)
+    }
+
+    /// Sets `If-Modified-Since`.
+    pub fn if_modified_since(self, t: SystemTime) -> Self {
+        self.header(header::IF_MODIFIED_SINCE, &fmt_http_date(t))
+    }
+
+    /// Sets `If-Unmodified-Since`.
+    pub fn if_unmodified_since(self, t: SystemTime) -> Self {
+        self.header(header::IF_UNMODIFIED_SINCE, &fmt_http_date(t))
+    }
+
+    /// Builds the request, with an empty body.
+    pub fn build(self) -> Request<()> {
+        let mut r = Request::new(());
+        *r.method_mut() = self.method;
+        *r.headers_mut() = self.headers;
+        r
+    }
+}
+
+/// A mistake in an `Entity` implementation, as found by [`validate_entity`](fn.validate_entity.html).
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Violation {
+    /// `get_range(range)` yielded `yielded` bytes, not `range.end - range.start`.
+    WrongLength { range: Range<u64>, yielded: u64 },
+
+    /// `get_range(range)` yielded different bytes than the same span of `get_range(0..len)`.
+    InconsistentData { range: Range<u64> },
+
+    /// `get_range(range)` yielded an error.
+    RangeFailed { range: Range<u64> },
+
+    /// `etag` returned something other than a valid `entity-tag`, such as a value missing its
+    /// quotes.
+    InvalidEtag(HeaderValue),
+
+    /// `add_headers` or `static_headers` included a header which `serve` sets itself:
+    /// `Content-Length`, `Content-Range`, `ETag`, `Last-Modified`, or `Date`.
+    ForbiddenHeader(HeaderName),
+
+    /// `content_type` returned `reported`, but `add_headers` added `added`.
+    InconsistentContentType {
+        reported: Option<HeaderValue>,
+        added: Option<HeaderValue>,
+    },
+}
+
+/// Checks `e` for common implementation mistakes, returning all those found.
+///
+/// This drains `get_range` for the whole entity and a few subranges, checking that each yields
+/// the expected number of bytes and that subranges agree with the whole. It also checks that
+/// `etag` is well-formed, that neither `add_headers` nor `static_headers` sets headers `serve`
+/// sets itself, and that `content_type` agrees with `add_headers`. (Debug builds of `serve`
+/// also panic on forbidden headers.)
+///
+/// The streams are driven by blocking the current thread, so this must not be called from async
+/// code.
+///
+/// ```
+/// use http_serve::test_support::{validate_entity, FakeEntity, Violation};
+///
+/// let e = FakeEntity::new(&b"hello world"[..]).etag("\"v1\"");
+/// assert_eq!(validate_entity(&e), vec![]);
+///
+/// let e = FakeEntity::new(&b"hello world"[..]).etag("v1");
+/// assert_eq!(
+///     validate_entity(&e),
+///     vec![Violation::InvalidEtag(http::HeaderValue::from_static("v1"))],
+/// );
+/// ```
+pub fn validate_entity<E: Entity>(e: &E) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    let len = e.len();
+    let full = read_range(e, 0..len, &mut violations);
+    let mut subranges = vec![0..len / 2, len / 2..len, len / 3..len - len / 3];
+    subranges.retain(|r| r.start < r.end && *r != (0..len));
+    subranges.dedup();
+    for range in subranges {
+        let data = match read_range(e, range.clone(), &mut violations) {
+            None => continue,
+            Some(d) => d,
+        };
+        if let Some(ref full) = full {
+            if data[..] != full[range.start as usize..range.end as usize] {
+                violations.push(Violation::InconsistentData { range });
+            }
+        }
+    }
+
+    if let Some(etag) = e.etag() {
+        if !crate::etag::is_valid(etag.as_bytes()) {
+            violations.push(Violation::InvalidEtag(etag));
+        }
+    }
+
+    let mut added = HeaderMap::new();
+    e.add_headers(&mut added);
+    let maps = std::iter::once(&added).chain(e.static_headers());
+    for h in crate::serving::SERVE_SET_HEADERS.iter() {
+        if maps.clone().any(|m| m.contains_key(h)) {
+            violations.push(Violation::ForbiddenHeader(h.clone()));
+        }
+    }
+    let reported = e.content_type();
+    let added = added.remove(header::CONTENT_TYPE);
+    if reported != added {
+        violations.push(Violation::InconsistentContentType { reported, added });
+    }
+    violations
+}
+
+/// Reads `range` of `e`, returning the data if it was of the correct length.
+fn read_range<E: Entity>(
+    e: &E,
+    range: Range<u64>,
+    violations: &mut Vec<Violation>,
+) -> Option<Vec<u8>> {
+    let mut stream = std::pin::Pin::from(e.get_range(range.clone()));
+    let mut data = Vec::new();
+    let ok = futures::executor::block_on(async {
+        while let Some(chunk) = stream.next().await {
+            let mut chunk = match chunk {
+                Err(_) => return false,
+                Ok(c) => c,
+            };
+            while chunk.has_remaining() {
+                let b = chunk.bytes();
+                let n = b.len();
+                data.extend_from_slice(b);
+                chunk.advance(n);
+            }
+        }
+        true
+    });
+    if !ok {
+        violations.push(Violation::RangeFailed { range });
+        return None;
+    }
+    if data.len() as u64 != range.end - range.start {
+        violations.push(Violation::WrongLength {
+            range,
+            yielded: data.len() as u64,
+        });
+        return None;
+    }
+    Some(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{validate_entity, FakeEntity, Violation};
+    use crate::{ChunkedReadFile, Entity, PrefixedEntity};
+    use bytes::Bytes;
+    use futures::Stream;
+    use http::header::{self, HeaderMap, HeaderValue};
+    use std::io::Write;
+    use std::ops::Range;
+    use std::time::SystemTime;
+
+    type BoxedError = Box<dyn std::error::Error + Send + Sync>;
+
+    static BODY: &[u8] = b"0123456789abcdefghij";
+
+    #[test]
+    fn crate_entities_pass() {
+        let e = FakeEntity::new(BODY)
+            .etag("W/\"foo\"")
+            .content_type("text/plain")
+            .last_modified(SystemTime::now());
+        assert_eq!(validate_entity(&e), vec![]);
+        assert_eq!(validate_entity(&FakeEntity::new(&b""[..])), vec![]);
+        assert_eq!(validate_entity(&FakeEntity::new(&b"x"[..])), vec![]);
+        assert_eq!(
+            validate_entity(&PrefixedEntity::new(e, crate::UTF8_BOM)),
+            vec![]
+        );
+
+        let tmp = tempfile::tempdir().unwrap();
+        let p = tmp.path().join("f");
+        std::fs::File::create(&p).unwrap().write_all(BODY).unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+        let crf =
+            ChunkedReadFile::<Bytes, BoxedError>::new(std::fs::File::open(&p).unwrap(), headers)
+                .unwrap();
+        assert_eq!(validate_entity(&crf), vec![]);
+    }
+
+    /// An entity whose body is produced by a function of the requested range.
+    struct Broken(fn(Range<u64>) -> Result<Bytes, BoxedError>);
+
+    impl Entity for Broken {
+        type Data = Bytes;
+        type Error = BoxedError;
+
+        fn len(&self) -> u64 {
+            BODY.len() as u64
+        }
+        fn get_range(
+            &self,
+            range: Range<u64>,
+        ) -> Box<dyn Stream<Item = Result<Self::Data, Self::Error>> + Send + Sync> {
+            Box::new(futures::stream::once(futures::future::ready((self.0)(
+                range,
+            ))))
+        }
+        fn add_headers(&self, h: &mut HeaderMap) {
+            h.insert(header::DATE, HeaderValue::from_static("yesterday"));
+        }
+        fn etag(&self) -> Option<HeaderValue> {
+            None
+        }
+        fn last_modified(&self) -> Option<SystemTime> {
+            None
+        }
+    }
+
+    #[test]
+    fn violations() {
+        // Each range one byte short.
+        let e = Broken(|r| {
+            Ok(Bytes::from_static(
+                &BODY[r.start as usize..r.end as usize - 1],
+            ))
+        });
+        let v = validate_entity(&e);
+        assert_eq!(
+            v[0],
+            Violation::WrongLength {
+                range: 0..20,
+                yielded: 19
+            }
+        );
+        assert_eq!(v.len(), 5, "{:?}", v); // the full range, three subranges, and Date.
+        assert_eq!(v[4], Violation::ForbiddenHeader(header::DATE));
+
+        // Each range starts from the beginning.
+        let e = Broken(|r| Ok(Bytes::from_static(&BODY[..(r.end - r.start) as usize])));
+        assert_eq!(
+            validate_entity(&e),
+            vec![
+                Violation::InconsistentData { range: 10..20 },
+                Violation::InconsistentData { range: 6..14 },
+                Violation::ForbiddenHeader(header::DATE),
+            ]
+        );
+
+        let e = Broken(|_| Err("oops".into()));
+        assert_eq!(
+            validate_entity(&e)[..2],
+            [
+                Violation::RangeFailed { range: 0..20 },
+                Violation::RangeFailed { range: 0..10 },
+            ]
+        );
+
+        let e = FakeEntity::new(BODY)
+            .etag("\"a b\"")
+            .header(header::CONTENT_LENGTH, "20")
+            .header(header::LAST_MODIFIED, "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(
+            validate_entity(&e),
+            vec![
+                Violation::InvalidEtag(HeaderValue::from_static("\"a b\"")),
+                Violation::ForbiddenHeader(header::CONTENT_LENGTH),
+                Violation::ForbiddenHeader(header::LAST_MODIFIED),
+            ]
+        );
+    }
+
+    /// An entity whose `content_type` disagrees with its `add_headers`.
+    struct Mistyped(FakeEntity);
+
+    impl Entity for Mistyped {
+        type Data = Bytes;
+        type Error = BoxedError;
+
+        fn len(&self) -> u64 {
+            self.0.len()
+        }
+        fn get_range(
+            &self,
+            range: Range<u64>,
+        ) -> Box<dyn Stream<Item = Result<Self::Data, Self::Error>> + Send + Sync> {
+            self.0.get_range(range)
+        }
+        fn add_headers(&self, h: &mut HeaderMap) {
+            self.0.add_headers(h)
+        }
+        fn content_type(&self) -> Option<HeaderValue> {
+            Some(HeaderValue::from_static("text/html"))
+        }
+        fn etag(&self) -> Option<HeaderValue> {
+            None
+        }
+        fn last_modified(&self) -> Option<SystemTime> {
+            None
+        }
+    }
+
+    #[test]
+    fn contains() {
+        let e = FakeEntity::new(BODY);
+        assert_eq!(e.contains(b"", 0..0), Some(true));
+        assert_eq!(e.contains(b"", 0..20), Some(true));
+        assert_eq!(e.contains(b"abc", 0..20), Some(true));
+        assert_eq!(e.contains(b"abc", 0..12), Some(false));
+    }
+
+    #[test]
+    fn inconsistent_content_type() {
+        let e = Mistyped(FakeEntity::new(BODY).content_type("text/plain"));
+        assert_eq!(
+            validate_entity(&e),
+            vec![Violation::InconsistentContentType {
+                reported: Some(HeaderValue::from_static("text/html")),
+                added: Some(HeaderValue::from_static("text/plain")),
+            }]
+        );
+    }
+
+    /// Debug builds of `serve` catch forbidden headers too.
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "Entity must not add content-range header")]
+    fn serve_checks_headers() {
+        let e = FakeEntity::new(BODY).header(header::CONTENT_RANGE, "bytes 0-19/20");
+        let _: http::Response<hyper::Body> = crate::serve(e, &super::FakeRequest::get().build());
+    }
+}
@@ -0,0 +1,377 @@
+// Copyright (c) 2016-2020 The http-serve developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A directory server mapping requests to files as `tower-http`'s `ServeDir` does, to ease
+//! migration. Available with the `tower-http-compat` feature.
+//!
+//! [`ServeDir`](struct.ServeDir.html) resolves paths like `tower_http::services::ServeDir` 0.2:
+//! the path is percent-decoded; `..`, absolute, and prefixed components get `404 Not Found`; a
+//! directory without a trailing slash gets a `307 Temporary Redirect` to the path with one; and
+//! a directory with one gets its `index.html`. `Content-Type` is guessed from the extension.
+//! Files are then served with [`serve_with_options`](../fn.serve_with_options.html), so responses
+//! differ where `tower-http`'s handling is more limited:
+//!
+//! *   Responses carry an `ETag`, and `If-None-Match`, `If-Match`, and `If-Range` are honored.
+//! *   Requests for several ranges get a `multipart/byteranges` response rather than
+//!     `416 Range Not Satisfiable`.
+//! *   Methods other than `GET` and `HEAD` get `405 Method Not Allowed` rather than the file.
+//! *   With `precompressed_gzip` or `precompressed_br`, responses for files with a `.gz` or `.br`
+//!     sibling carry `Vary: accept-encoding`, and a compressed file without an uncompressed
+//!     original isn't served. `br` is preferred to `gzip` whenever both are accepted.
+//! *   `416 Range Not Satisfiable` responses omit the file's `Content-Type`.
+//! *   I/O errors other than "not found" and "permission denied" get
+//!     `500 Internal Server Error` rather than failing the service.
+//! *   Redirect `Location`s percent-encode characters which `http::Uri` accepts but RFC 3986
+//!     doesn't, such as `"` and `{`.
+//!
+//! By default, symlinks are followed only while they resolve within the directory; one leading
+//! out of it gets `404 Not Found`, as if it didn't exist, where `tower-http` follows it. See
+//! [`SymlinkPolicy`](../enum.SymlinkPolicy.html) for the alternatives. On Unix the path is resolved
+//! a component at a time with `openat` and `O_NOFOLLOW`, so a symlink swapped in while a request
+//! is served can't lead out of the directory either. Anything other than a regular file or
+//! directory, such as a FIFO, gets `404 Not Found` rather than blocking the thread serving it.
+//!
+//! No header value is built from a file name; only the redirect `Location`, built from the request
+//! path, and the guessed `Content-Type` vary with the request. Names which can't be expressed in
+//! a percent-decoded UTF-8 path, such as non-UTF-8 ones on Unix, get `404 Not Found`.
+//!
+//! `tests/tower-http-compat.rs` compares the two on a matrix of requests.
+
+use crate::{
+    open_beneath, serve_with_options, BoxedEntity, ChunkedReadFile, ContentCoding, Entity,
+    ServeOptions, SymlinkPolicy,
+};
+use bytes::{Buf, Bytes};
+use futures::Stream;
+use http::header::{self, HeaderMap, HeaderValue};
+use http::{Request, Response, StatusCode, Uri};
+use http_body::Body;
+use percent_encoding::{AsciiSet, CONTROLS};
+use std::error::Error as StdError;
+use std::ffi::OsString;
+use std::io;
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+/// Serves files from a directory, resolving request paths as `tower-http`'s `ServeDir` does.
+///
+/// See the [module documentation](index.html) for how it differs.
+#[derive(Clone, Debug)]
+pub struct ServeDir {
+    base: PathBuf,
+    append_index_html_on_directories: bool,
+    precompressed_gzip: bool,
+    precompressed_br: bool,
+    symlink_policy: SymlinkPolicy,
+    options: ServeOptions,
+}
+
+impl ServeDir {
+    /// Serves files within `path`.
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        ServeDir {
+            base: path.as_ref().to_path_buf(),
+            append_index_html_on_directories: true,
+            precompressed_gzip: false,
+            precompressed_br: false,
+            symlink_policy: SymlinkPolicy::default(),
+            options: ServeOptions::default(),
+        }
+    }
+
+    /// Sets whether requests for a directory with a trailing slash serve its `index.html`, rather
+    /// than `404 Not Found`. Defaults to true.
+    pub fn append_index_html_on_directories(mut self, append: bool) -> Self {
+        self.append_index_html_on_directories = append;
+        self
+    }
+
+    /// Offers each file's `.gz` sibling (`foo.txt.gz` for `foo.txt`), if any, as its `gzip`
+    /// variant.
+    pub fn precompressed_gzip(mut self) -> Self {
+        self.precompressed_gzip = true;
+        self
+    }
+
+    /// Offers each file's `.br` sibling (`foo.txt.br` for `foo.txt`), if any, as its `br`
+    /// variant. `serve` prefers it to the `gzip` variant when the client accepts both.
+    pub fn precompressed_br(mut self) -> Self {
+        self.precompressed_br = true;
+        self
+    }
+
+    /// Sets which symlinks are followed. Defaults to `SymlinkPolicy::FollowIfSameRoot`.
+    pub fn symlink_policy(mut self, policy: SymlinkPolicy) -> Self {
+        self.symlink_policy = policy;
+        self
+    }
+
+    /// Sets the options passed to `serve_with_options`.
+    pub fn options(mut self, options: ServeOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Serves `req`.
+    ///
+    /// File system calls are wrapped in `tokio::task::block_in_place`, so this expects to be used
+    /// from a tokio threadpool, as `ChunkedReadFile` does.
+    pub fn serve<D, E, B, BI>(&self, req: &Request<BI>) -> Response<B>
+    where
+        D: 'static + Send + Sync + Buf + From<Vec<u8>> + From<&'static [u8]> + From<Bytes>,
+        E: 'static
+            + Send
+            + Sync
+            + Into<Box<dyn StdError + Send + Sync>>
+            + From<Box<dyn StdError + Send + Sync>>,
+        B: Body + From<Box<dyn Stream<Item = Result<D, E>> + Send>>,
+    {
+        let path = match build_and_validate_path(req.uri().path()) {
+            Some(p) => p,
+            None => return status::<D, E, B>(StatusCode::NOT_FOUND),
+        };
+        match tokio::task::block_in_place(|| self.open::<D, E>(path, req.uri())) {
+            Ok(Opened::Entity(e)) => serve_with_options(e, req, &self.options),
+            Ok(Opened::Redirect(location)) => {
+                let mut res = status::<D, E, B>(StatusCode::TEMPORARY_REDIRECT);
+                res.headers_mut().insert(header::LOCATION, location);
+                res
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => status(StatusCode::NOT_FOUND),
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => status(StatusCode::NOT_FOUND),
+            Err(_) => status(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+
+    /// Opens the file at `path`, relative to the base, or decides to redirect to the directory
+    /// `uri` with a slash.
+    fn open<D, E>(&self, mut path: PathBuf, uri: &Uri) -> Result<Opened<D, E>, io::Error>
+    where
+        D: 'static + Send + Sync + Buf + From<Vec<u8>> + From<&'static [u8]> + From<Bytes>,
+        E: 'static
+            + Send
+            + Sync
+            + Into<Box<dyn StdError + Send + Sync>>
+            + From<Box<dyn StdError + Send + Sync>>,
+    {
+        let mut file = open_beneath(&self.base, &path, self.symlink_policy)?;
+        let is_dir = file.metadata()?.is_dir();
+        if !uri.path().ends_with('/') {
+            if is_dir {
+                return append_slash(uri)
+                    .map(Opened::Redirect)
+                    .ok_or_else(|| io::ErrorKind::NotFound.into());
+            }
+        } else if is_dir {
+            if !self.append_index_html_on_directories {
+                return Err(io::ErrorKind::NotFound.into());
+            }
+            path.push("index.html");
+            file = open_beneath(&self.base, &path, self.symlink_policy)?;
+        }
+        let mime = mime_guess::from_path(&path)
+            .first_raw()
+            .unwrap_or("application/octet-stream");
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(mime));
+        let mut variants = Vec::new();
+        let precompressed = [
+            (self.precompressed_gzip, ContentCoding::Gzip, ".gz"),
+            (self.precompressed_br, ContentCoding::Brotli, ".br"),
+        ];
+        for &(enabled, coding, ext) in &precompressed {
+            if !enabled {
+                continue;
+            }
+            let mut sibling = OsString::from(path.clone());
+            sibling.push(ext);
+            match open_beneath(&self.base, Path::new(&sibling), self.symlink_policy) {
+                Ok(f) => variants.push((coding, ChunkedReadFile::new(f, headers.clone())?)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let file = ChunkedReadFile::new(file, headers)?;
+        Ok(Opened::Entity(FileWithVariants { file, variants }))
+    }
+}
+
+enum Opened<D, E>
+where
+    D: 'static + Send + Sync + Buf + From<Vec<u8>> + From<&'static [u8]> + From<Bytes>,
+    E: 'static
+        + Send
+        + Sync
+        + Into<Box<dyn StdError + Send + Sync>>
+        + From<Box<dyn StdError + Send + Sync>>,
+{
+    Entity(FileWithVariants<D, E>),
+    Redirect(HeaderValue),
+}
+
+/// A file plus its precompressed siblings.
+struct FileWithVariants<D, E>
+where
+    D: 'static + Send + Sync + Buf + From<Vec<u8>> + From<&'static [u8]> + From<Bytes>,
+    E: 'static
+        + Send
+        + Sync
+        + Into<Box<dyn StdError + Send + Sync>>
+        + From<Box<dyn StdError + Send + Sync>>,
+{
+    file: ChunkedReadFile<D, E>,
+    variants: Vec<(ContentCoding, ChunkedReadFile<D, E>)>,
+}
+
+impl<D, E> Entity for FileWithVariants<D, E>
+where
+    D: 'static + Send + Sync + Buf + From<Vec<u8>> + From<&'static [u8]> + From<Bytes>,
+    E: 'static
+        + Send
+        + Sync
+        + Into<Box<dyn StdError + Send + Sync>>
+        + From<Box<dyn StdError + Send + Sync>>,
+{
+    type Data = D;
+    type Error = E;
+
+    fn len(&self) -> u64 {
+        self.file.len()
+    }
+
+    fn get_range(
+        &self,
+        range: Range<u64>,
+    ) -> Box<dyn Stream<Item = Result<Self::Data, Self::Error>> + Send + Sync> {
+        self.file.get_range(range)
+    }
+
+    fn add_headers(&self, h: &mut HeaderMap) {
+        self.file.add_headers(h)
+    }
+
+    fn content_type(&self) -> Option<HeaderValue> {
+        self.file.content_type()
+    }
+
+    fn etag(&self) -> Option<HeaderValue> {
+        self.file.etag()
+    }
+
+    fn last_modified(&self) -> Option<SystemTime> {
+        self.file.last_modified()
+    }
+
+    fn variants(&self) -> Vec<(ContentCoding, BoxedEntity<D, E>)> {
+        self.variants
+            .iter()
+            .map(|(c, f)| (*c, Box::new(f.clone()) as BoxedEntity<D, E>))
+            .collect()
+    }
+}
+
+/// Returns a response with the given status and an empty body.
+fn status<D, E, B>(status: StatusCode) -> Response<B>
+where
+    D: 'static + Send,
+    E: 'static + Send,
+    B: From<Box<dyn Stream<Item = Result<D, E>> + Send>>,
+{
+    let body: Box<dyn Stream<Item = Result<D, E>> + Send> = Box::new(futures::stream::empty());
+    let mut res = Response::new(body.into());
+    *res.status_mut() = status;
+    res
+}
+
+/// Returns the percent-decoded `requested` path relative to the base, or `None` if it has any
+/// component which could escape the base.
+fn build_and_validate_path(requested: &str) -> Option<PathBuf> {
+    let requested = requested.trim_start_matches('/');
+    let decoded = percent_encoding::percent_decode(requested.as_bytes())
+        .decode_utf8()
+        .ok()?;
+    let mut path = PathBuf::new();
+    for c in Path::new(&*decoded).components() {
+        match c {
+            // A decoded component can't contain a separator, but may on Windows hold a prefix.
+            Component::Normal(n)
+                if Path::new(n).components().all(|c| match c {
+                    Component::Normal(_) => true,
+                    _ => false,
+                }) =>
+            {
+                path.push(n)
+            }
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    Some(path)
+}
+
+/// Characters percent-encoded in a redirect `Location`: those which aren't valid in a URI's path or
+/// query, but aren't rejected by `http::Uri`. `%` is left alone so existing escapes are kept.
+const LOCATION_ENCODE: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'<')
+    .add(b'>')
+    .add(b'\\')
+    .add(b'^')
+    .add(b'`')
+    .add(b'{')
+    .add(b'|')
+    .add(b'}');
+
+/// Returns `uri`'s path and query with a slash appended to the path.
+///
+/// Leading slashes are collapsed to one, as `//host/...` would name another host. The
+/// percent-encoded result is visible ASCII, so this shouldn't return `None`, but a failure is
+/// served as `404 Not Found` rather than a panic.
+fn append_slash(uri: &Uri) -> Option<HeaderValue> {
+    let path = uri.path().trim_start_matches('/');
+    let mut location = String::with_capacity(path.len() + 2);
+    location.push('/');
+    location.extend(percent_encoding::utf8_percent_encode(path, LOCATION_ENCODE));
+    location.push('/');
+    if let Some(q) = uri.query() {
+        location.push('?');
+        location.extend(percent_encoding::utf8_percent_encode(q, LOCATION_ENCODE));
+    }
+    HeaderValue::from_maybe_shared(Bytes::from(location)).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    #[test]
+    fn build_and_validate_path() {
+        let p = super::build_and_validate_path;
+        assert_eq!(p("/a/b.txt").unwrap(), Path::new("a/b.txt"));
+        assert_eq!(p("/a%20b/./c").unwrap(), Path::new("a b/c"));
+        assert_eq!(p("/").unwrap(), Path::new(""));
+        assert_eq!(p("/a/../b"), None);
+        assert_eq!(p("/a/%2e%2e/b"), None);
+        assert_eq!(p("//etc/passwd").unwrap(), Path::new("etc/passwd"));
+        assert_eq!(p("/%ff"), None);
+    }
+
+    #[test]
+    fn append_slash() {
+        let a = |s: &'static str| super::append_slash(&http::Uri::from_static(s)).unwrap();
+        assert_eq!(a("/sub"), "/sub/");
+        assert_eq!(a("//sub"), "/sub/");
+        assert_eq!(a("///evil.example/x"), "/evil.example/x/");
+        assert_eq!(a("/a%0Ab?x=1"), "/a%0Ab/?x=1");
+        assert_eq!(a("/a%0D%0ASet-Cookie:%20x=1"), "/a%0D%0ASet-Cookie:%20x=1/");
+        assert_eq!(a("/a\"b{c}|d"), "/a%22b%7Bc%7D%7Cd/");
+        assert_eq!(a("/a?q=x|y"), "/a/?q=x%7Cy");
+    }
+}
@@ -0,0 +1,345 @@
+// Copyright (c) 2016-2020 The http-serve developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use bytes::{Buf, Bytes};
+use futures::{Stream, StreamExt};
+use http::header::{HeaderMap, HeaderName, HeaderValue};
+#[cfg(feature = "content-md5")]
+use md5::Md5;
+#[cfg(feature = "repr-digest-trailer")]
+use sha2::Sha256;
+use std::any::Any;
+use std::error::Error as StdError;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+type BoxedError = Box<dyn StdError + Send + Sync>;
+
+/// A response body which can end with HTTP trailers, as returned by
+/// [`serve_with_trailers`](fn.serve_with_trailers.html).
+///
+/// Converted from a stream, it behaves like `hyper::Body::from`: stream errors are passed
+/// through, and hyper drops the connection or resets the stream. With an error trailer set, an
+/// error instead ends the body with a trailer naming the error's class.
+///
+/// To hand the body to a framework with its own body type, use
+/// [`into_stream`](#method.into_stream), or with the `hyper` feature, `hyper::Body::from`.
+pub struct TrailerBody<D, E> {
+    stream: Pin<Box<dyn Stream<Item = Result<D, E>> + Send>>,
+    error_trailer: Option<HeaderName>,
+
+    /// The digest of the data so far, if a `Content-MD5` trailer was requested and the data has
+    /// all been hashable.
+    #[cfg(feature = "content-md5")]
+    md5: Option<Md5>,
+
+    /// Likewise, the SHA-256 digest of the data so far, for a `Repr-Digest` trailer.
+    #[cfg(feature = "repr-digest-trailer")]
+    sha256: Option<Sha256>,
+
+    /// Set when the body has ended with trailers; taken by `poll_trailers`.
+    trailers: Option<HeaderMap>,
+}
+
+impl<D, E> TrailerBody<D, E> {
+    /// Sets the trailer to send in place of a stream error.
+    pub(crate) fn with_error_trailer(self, name: HeaderName) -> Self {
+        TrailerBody {
+            error_trailer: Some(name),
+            ..self
+        }
+    }
+
+    /// Ends the body with a `Content-MD5` trailer holding the base64 MD5 digest of its data, as
+    /// expected by some legacy clients to check a transfer's integrity.
+    ///
+    /// The digest is computed incrementally as the body streams, so the length needn't be known
+    /// up front. This is opt-in and meant only for such clients: MD5 is unsuitable for anything
+    /// security-sensitive, and `Content-MD5` is obsolete. It's omitted if the body ends with an
+    /// error, or if a chunk doesn't expose all of its bytes via `Buf::bytes_vectored`. The
+    /// caller should announce the trailer with a `Trailer: content-md5` response header. hyper
+    /// sends trailers only on HTTP/2.
+    ///
+    /// Available with the `content-md5` feature.
+    #[cfg(feature = "content-md5")]
+    pub fn with_content_md5(self) -> Self {
+        TrailerBody {
+            md5: Some(Md5::default()),
+            ..self
+        }
+    }
+
+    /// Ends the body with a `Repr-Digest` trailer holding the SHA-256 digest of its data, as in
+    /// [RFC 9530](https://www.rfc-editor.org/rfc/rfc9530), such as `sha-256=:<base64>:`.
+    ///
+    /// As with `with_content_md5`, the digest is computed incrementally as the body streams, and
+    /// is omitted if the body ends with an error or a chunk doesn't expose all of its bytes. A
+    /// `Repr-Digest` covers the whole selected representation, so this suits only bodies which
+    /// are exactly that, not partial content. The caller should announce the trailer with a
+    /// `Trailer: repr-digest` response header.
+    ///
+    /// Available with the `repr-digest-trailer` feature.
+    #[cfg(feature = "repr-digest-trailer")]
+    pub fn with_repr_digest(self) -> Self {
+        TrailerBody {
+            sha256: Some(Sha256::default()),
+            ..self
+        }
+    }
+
+    /// Returns the body's data as a stream of `Bytes`, the form most body types can be built
+    /// from, such as with `hyper::Body::wrap_stream` or `axum::body::Body::from_stream`.
+    ///
+    /// This reuses the stream the body already holds, so it doesn't allocate by itself. Each
+    /// chunk goes through `Into<Bytes>`, which is free for `Bytes` but may copy or reallocate for
+    /// other types, and each error is boxed. Trailers are lost: errors are passed through even
+    /// with an error trailer set, and no `Content-MD5` or `Repr-Digest` trailer is computed.
+    pub fn into_stream(self) -> impl Stream<Item = Result<Bytes, BoxedError>> + Send
+    where
+        D: Into<Bytes> + 'static,
+        E: Into<BoxedError> + 'static,
+    {
+        self.stream.map(|r| r.map(Into::into).map_err(Into::into))
+    }
+}
+
+/// Wraps the body with `hyper::Body::wrap_stream`, which allocates once to box the stream.
+///
+/// As with [`TrailerBody::into_stream`](struct.TrailerBody.html#method.into_stream), trailers are
+/// lost. Serving a `TrailerBody` with hyper directly keeps them.
+#[cfg(feature = "hyper")]
+impl<D, E> From<TrailerBody<D, E>> for hyper::Body
+where
+    D: Into<Bytes> + 'static,
+    E: Into<BoxedError> + 'static,
+{
+    fn from(body: TrailerBody<D, E>) -> Self {
+        hyper::Body::wrap_stream(body.into_stream())
+    }
+}
+
+impl<D, E> From<Box<dyn Stream<Item = Result<D, E>> + Send>> for TrailerBody<D, E> {
+    fn from(stream: Box<dyn Stream<Item = Result<D, E>> + Send>) -> Self {
+        TrailerBody {
+            stream: stream.into(),
+            error_trailer: None,
+            #[cfg(feature = "content-md5")]
+            md5: None,
+            #[cfg(feature = "repr-digest-trailer")]
+            sha256: None,
+            trailers: None,
+        }
+    }
+}
+
+impl<D, E> http_body::Body for TrailerBody<D, E>
+where
+    D: Buf,
+    E: 'static,
+{
+    type Data = D;
+    type Error = E;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let this = self.get_mut();
+        if this.trailers.is_some() {
+            return Poll::Ready(None);
+        }
+        match this.stream.as_mut().poll_next(cx) {
+            #[cfg(any(feature = "content-md5", feature = "repr-digest-trailer"))]
+            Poll::Ready(Some(Ok(d))) => {
+                this.update_digests(&d);
+                Poll::Ready(Some(Ok(d)))
+            }
+            #[cfg(any(feature = "content-md5", feature = "repr-digest-trailer"))]
+            Poll::Ready(None) => {
+                let trailers = this.digest_trailers();
+                if !trailers.is_empty() {
+                    this.trailers = Some(trailers);
+                }
+                Poll::Ready(None)
+            }
+            Poll::Ready(Some(Err(e))) => match this.error_trailer.take() {
+                Some(name) => {
+                    let mut trailers = HeaderMap::new();
+                    trailers.insert(name, HeaderValue::from_static(error_class(&e)));
+                    this.trailers = Some(trailers);
+                    Poll::Ready(None)
+                }
+                None => Poll::Ready(Some(Err(e))),
+            },
+            p => p,
+        }
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        _cx: &mut Context,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Poll::Ready(Ok(self.get_mut().trailers.take()))
+    }
+}
+
+#[cfg(any(feature = "content-md5", feature = "repr-digest-trailer"))]
+impl<D: Buf, E> TrailerBody<D, E> {
+    /// Adds the bytes of `d` to the digests in progress, abandoning them if the bytes aren't all
+    /// available.
+    fn update_digests(&mut self, d: &D) {
+        let mut slices = [io::IoSlice::new(&[]); 16];
+        let n = d.bytes_vectored(&mut slices);
+        let complete = slices[..n].iter().map(|s| s.len()).sum::<usize>() == d.remaining();
+        #[cfg(feature = "content-md5")]
+        {
+            if !complete {
+                self.md5 = None;
+            }
+            if let Some(ref mut md5) = self.md5 {
+                slices[..n]
+                    .iter()
+                    .for_each(|s| md5::Digest::update(md5, &**s));
+            }
+        }
+        #[cfg(feature = "repr-digest-trailer")]
+        {
+            if !complete {
+                self.sha256 = None;
+            }
+            if let Some(ref mut sha256) = self.sha256 {
+                slices[..n]
+                    .iter()
+                    .for_each(|s| sha2::Digest::update(sha256, &**s));
+            }
+        }
+    }
+
+    /// Takes the finished digests, as trailers.
+    fn digest_trailers(&mut self) -> HeaderMap {
+        let mut trailers = HeaderMap::new();
+        #[cfg(feature = "content-md5")]
+        {
+            if let Some(md5) = self.md5.take() {
+                let digest = base64(&md5::Digest::finalize(md5));
+                trailers.insert(
+                    HeaderName::from_static("content-md5"),
+                    HeaderValue::from_str(&digest).expect("base64 is a valid header value"),
+                );
+            }
+        }
+        #[cfg(feature = "repr-digest-trailer")]
+        {
+            if let Some(sha256) = self.sha256.take() {
+                let digest = format!("sha-256=:{}:", base64(&sha2::Digest::finalize(sha256)));
+                trailers.insert(
+                    HeaderName::from_static("repr-digest"),
+                    HeaderValue::from_str(&digest).expect("base64 is a valid header value"),
+                );
+            }
+        }
+        trailers
+    }
+}
+
+/// Encodes `data` as padded standard base64, as `Content-MD5` requires.
+#[cfg(any(feature = "content-md5", feature = "repr-digest-trailer"))]
+pub(crate) fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Returns a short class for a body stream error: `io` for `std::io::Error`s (bare or boxed),
+/// `internal` for anything else.
+fn error_class<E: 'static>(e: &E) -> &'static str {
+    let e: &dyn Any = e;
+    let is_io = if e.is::<io::Error>() {
+        true
+    } else if let Some(b) = e.downcast_ref::<Box<dyn StdError + Send + Sync>>() {
+        b.is::<io::Error>()
+    } else {
+        false
+    };
+    if is_io {
+        "io"
+    } else {
+        "internal"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    /// The test vectors from RFC 4648 section 10.
+    #[cfg(any(feature = "content-md5", feature = "repr-digest-trailer"))]
+    #[test]
+    fn base64() {
+        let cases = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ];
+        for &(input, output) in &cases {
+            assert_eq!(super::base64(input.as_bytes()), output);
+        }
+    }
+
+    fn partial() -> http::Response<super::TrailerBody<bytes::Bytes, super::BoxedError>> {
+        let e = crate::test_support::FakeEntity::new(&b"0123456789abcdefghij"[..]);
+        let req = crate::test_support::FakeRequest::get()
+            .range("bytes=5-14")
+            .build();
+        let resp = crate::serve_with_trailers(e, &req, &crate::ServeOptions::default());
+        assert_eq!(resp.status(), http::StatusCode::PARTIAL_CONTENT);
+        resp
+    }
+
+    #[tokio::test]
+    async fn into_stream() {
+        use futures::TryStreamExt;
+        let chunks: Vec<bytes::Bytes> = partial()
+            .into_body()
+            .into_stream()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(chunks.concat(), b"56789abcde");
+
+        let body = hyper::Body::wrap_stream(partial().into_body().into_stream());
+        let body = hyper::body::to_bytes(body).await.unwrap();
+        assert_eq!(&body[..], b"56789abcde");
+    }
+
+    #[cfg(feature = "hyper")]
+    #[tokio::test]
+    async fn into_hyper_body() {
+        let resp = partial().map(hyper::Body::from);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"56789abcde");
+    }
+}
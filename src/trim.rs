@@ -0,0 +1,111 @@
+// Copyright (c) 2016-2020 The http-serve developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use bytes::Buf;
+use futures::Stream;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+type BoxedStream<D, E> = Pin<Box<dyn Stream<Item = Result<D, E>> + Send + Sync>>;
+
+/// Called with the number of bytes missing when a `Trim`'s inner stream ends early. Returns the
+/// error to end with, or `None` to simply end.
+type OnShort<E> = Box<dyn FnOnce(u64) -> Option<E> + Send + Sync>;
+
+/// A stream adapter which yields bytes `skip..skip + len` of its inner stream.
+///
+/// Leading bytes are dropped from the first chunks, and bytes beyond the end are dropped,
+/// truncating the last chunk. If the inner stream ends early without an error, the adapter ends
+/// too, first consulting `on_short` if set.
+pub(crate) struct Trim<D, E> {
+    inner: BoxedStream<D, E>,
+    skip: u64,
+    remaining: u64,
+    on_short: Option<OnShort<E>>,
+}
+
+impl<D, E> Trim<D, E> {
+    pub(crate) fn new(inner: BoxedStream<D, E>, skip: u64, len: u64) -> Self {
+        Trim {
+            inner,
+            skip,
+            remaining: len,
+            on_short: None,
+        }
+    }
+
+    /// Sets what to do if the inner stream ends early; see `OnShort`.
+    pub(crate) fn on_short<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(u64) -> Option<E> + Send + Sync + 'static,
+    {
+        self.on_short = Some(Box::new(f));
+        self
+    }
+
+    /// Holds a `get_range` stream to the length `serve` advertised for it.
+    ///
+    /// A stream which ends early without an error breaks the `Entity` contract, but there's no
+    /// `Entity::Error` to report that with, so this ends too and sets `cut`. `serve` then sends
+    /// nothing more, not even the rest of a `multipart/byteranges` body, so the response falls
+    /// short of its `Content-Length`, which hyper treats as an aborted response rather than a
+    /// complete one.
+    pub(crate) fn exact(inner: BoxedStream<D, E>, len: u64, cut: Arc<AtomicBool>) -> Self {
+        Trim::new(inner, 0, len).on_short(move |_missing| {
+            #[cfg(feature = "tracing")]
+            tracing::error!(
+                missing = _missing,
+                "entity's get_range stream ended early without an error"
+            );
+            cut.store(true, Ordering::Relaxed);
+            None
+        })
+    }
+}
+
+impl<D: Buf + From<Vec<u8>>, E> Stream for Trim<D, E> {
+    type Item = Result<D, E>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        // Trim is Unpin; the inner stream is pinned on the heap.
+        let this = self.get_mut();
+        while this.remaining > 0 {
+            let mut d = match this.inner.as_mut().poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Some(Ok(d))) => d,
+                Poll::Ready(Some(Err(e))) => {
+                    this.remaining = 0;
+                    return Poll::Ready(Some(Err(e)));
+                }
+                Poll::Ready(None) => {
+                    let missing = std::mem::replace(&mut this.remaining, 0);
+                    return Poll::Ready(this.on_short.take().and_then(|f| f(missing)).map(Err));
+                }
+            };
+            let n = d.remaining() as u64;
+            if n <= this.skip {
+                this.skip -= n;
+                continue;
+            }
+            d.advance(this.skip as usize);
+            let n = n - this.skip;
+            this.skip = 0;
+            if n <= this.remaining {
+                this.remaining -= n;
+                return Poll::Ready(Some(Ok(d)));
+            }
+            let mut head = vec![0; this.remaining as usize];
+            d.copy_to_slice(&mut head);
+            this.remaining = 0;
+            return Poll::Ready(Some(Ok(D::from(head))));
+        }
+        Poll::Ready(None)
+    }
+}
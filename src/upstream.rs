@@ -0,0 +1,290 @@
+// Copyright (c) 2016-2020 The http-serve developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::Entity;
+use bytes::{Buf, Bytes};
+use futures::{Stream, TryStreamExt};
+use http::header::{self, HeaderMap, HeaderValue};
+use http::StatusCode;
+use std::error::Error as StdError;
+use std::ops::Range;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+use sync_wrapper::SyncWrapper;
+
+type BoxedError = Box<dyn StdError + Send + Sync>;
+
+/// Representation headers which are copied from the upstream response.
+const COPIED_HEADERS: [header::HeaderName; 4] = [
+    header::CONTENT_TYPE,
+    header::CONTENT_ENCODING,
+    header::CONTENT_LANGUAGE,
+    header::CONTENT_DISPOSITION,
+];
+
+/// A HTTP entity backed by a resource on an upstream HTTP server, for range-aware proxying.
+///
+/// The length, etag, last modified time, and representation headers such as `Content-Type` are
+/// taken from a `HEAD` request to the upstream at construction. Each `get_range` call sends a
+/// ranged `GET` request upstream and streams its body. If the upstream supplied a strong etag,
+/// these requests carry it in `If-Match`, so that a changed upstream resource causes a stream
+/// error rather than a response which splices together two versions.
+///
+/// Available with the `upstream` feature.
+pub struct HttpUpstreamEntity<D, E>
+where
+    D: 'static + Send + Sync + Buf + From<Vec<u8>> + From<&'static [u8]> + From<Bytes>,
+    E: 'static + Send + Sync + From<BoxedError>,
+{
+    inner: Arc<HttpUpstreamEntityInner>,
+    phantom: std::marker::PhantomData<(D, E)>,
+}
+
+struct HttpUpstreamEntityInner {
+    client: reqwest::Client,
+    url: reqwest::Url,
+    len: u64,
+    etag: Option<HeaderValue>,
+    last_modified: Option<SystemTime>,
+    headers: HeaderMap,
+}
+
+// Implemented by hand so that `D` and `E` needn't be `Clone`.
+impl<D, E> Clone for HttpUpstreamEntity<D, E>
+where
+    D: 'static + Send + Sync + Buf + From<Vec<u8>> + From<&'static [u8]> + From<Bytes>,
+    E: 'static + Send + Sync + From<BoxedError>,
+{
+    fn clone(&self) -> Self {
+        HttpUpstreamEntity {
+            inner: Arc::clone(&self.inner),
+            phantom: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<D, E> HttpUpstreamEntity<D, E>
+where
+    D: 'static + Send + Sync + Buf + From<Vec<u8>> + From<&'static [u8]> + From<Bytes>,
+    E: 'static + Send + Sync + From<BoxedError>,
+{
+    /// Creates a new entity by sending a `HEAD` request for `url`.
+    ///
+    /// Fails if the request fails, the upstream doesn't respond with `200 OK`, or the response
+    /// lacks a `Content-Length`.
+    pub async fn new(client: reqwest::Client, url: reqwest::Url) -> Result<Self, BoxedError> {
+        let resp = client.head(url.clone()).send().await?;
+        if resp.status() != StatusCode::OK {
+            return Err(format!("upstream HEAD returned {}", resp.status()).into());
+        }
+        let hdrs = resp.headers();
+        let len = hdrs
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .ok_or("upstream HEAD has no valid Content-Length")?;
+        let etag = hdrs.get(header::ETAG).cloned();
+        let last_modified = hdrs
+            .get(header::LAST_MODIFIED)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| httpdate::parse_http_date(v).ok());
+        let mut headers = HeaderMap::new();
+        for name in &COPIED_HEADERS {
+            for v in hdrs.get_all(name) {
+                headers.append(name, v.clone());
+            }
+        }
+        Ok(HttpUpstreamEntity {
+            inner: Arc::new(HttpUpstreamEntityInner {
+                client,
+                url,
+                len,
+                etag,
+                last_modified,
+                headers,
+            }),
+            phantom: std::marker::PhantomData,
+        })
+    }
+}
+
+impl<D, E> Entity for HttpUpstreamEntity<D, E>
+where
+    D: 'static + Send + Sync + Buf + From<Vec<u8>> + From<&'static [u8]> + From<Bytes>,
+    E: 'static + Send + Sync + From<BoxedError>,
+{
+    type Data = D;
+    type Error = E;
+
+    fn len(&self) -> u64 {
+        self.inner.len
+    }
+
+    fn get_range(
+        &self,
+        range: Range<u64>,
+    ) -> Box<dyn Stream<Item = Result<Self::Data, Self::Error>> + Send + Sync> {
+        if range.start == range.end {
+            return Box::new(futures::stream::empty());
+        }
+        let mut req = self.inner.client.get(self.inner.url.clone()).header(
+            header::RANGE,
+            format!("bytes={}-{}", range.start, range.end - 1),
+        );
+        match self.inner.etag {
+            Some(ref e) if !e.as_bytes().starts_with(b"W/") => {
+                req = req.header(header::IF_MATCH, e.clone());
+            }
+            _ => {}
+        }
+        let full = range.start == 0 && range.end == self.inner.len;
+        let body = futures::stream::once(async move {
+            let resp = req.send().await?;
+            match resp.status() {
+                StatusCode::PARTIAL_CONTENT => {
+                    let expected = format!("bytes {}-{}/", range.start, range.end - 1);
+                    let matches = resp
+                        .headers()
+                        .get(header::CONTENT_RANGE)
+                        .map(|v| v.as_bytes().starts_with(expected.as_bytes()))
+                        .unwrap_or(false);
+                    if !matches {
+                        return Err(BoxedError::from(
+                            "upstream sent an unexpected Content-Range",
+                        ));
+                    }
+                }
+                StatusCode::OK if full => {}
+                StatusCode::PRECONDITION_FAILED => {
+                    return Err(BoxedError::from("upstream entity has changed"));
+                }
+                s => return Err(format!("upstream GET returned {}", s).into()),
+            }
+            Ok(resp.bytes_stream().err_into::<BoxedError>())
+        })
+        .try_flatten()
+        .map_ok(D::from)
+        .map_err(E::from);
+        Box::new(SyncStream(SyncWrapper::new(Box::pin(body))))
+    }
+
+    fn add_headers(&self, h: &mut HeaderMap) {
+        h.extend(
+            self.inner
+                .headers
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone())),
+        );
+    }
+
+    fn static_headers(&self) -> Option<&HeaderMap> {
+        Some(&self.inner.headers)
+    }
+
+    fn content_type(&self) -> Option<HeaderValue> {
+        self.inner.headers.get(header::CONTENT_TYPE).cloned()
+    }
+
+    fn etag(&self) -> Option<HeaderValue> {
+        self.inner.etag.clone()
+    }
+
+    fn last_modified(&self) -> Option<SystemTime> {
+        self.inner.last_modified
+    }
+}
+
+/// Wraps a `Send` stream to make it `Sync`, as `Entity::get_range` requires.
+///
+/// reqwest's body stream isn't `Sync`; `SyncWrapper` only hands out the stream through exclusive
+/// references, which makes it safe to share.
+struct SyncStream<S>(SyncWrapper<Pin<Box<S>>>);
+
+impl<S: Stream> Stream for SyncStream<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<S::Item>> {
+        self.0.get_mut().as_mut().poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HttpUpstreamEntity;
+    use crate::test_support::FakeEntity;
+    use crate::Entity;
+    use bytes::Bytes;
+    use http::header::{self, HeaderMap};
+    use hyper::Body;
+    use once_cell::sync::Lazy;
+    use std::sync::Mutex;
+
+    type BoxedError = Box<dyn std::error::Error + Send + Sync>;
+
+    static BODY: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+    /// The `Range` headers of requests received by the upstream server.
+    static RANGES: Lazy<Mutex<Vec<Option<String>>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+    async fn origin(req: http::Request<Body>) -> Result<http::Response<Body>, BoxedError> {
+        RANGES.lock().unwrap().push(
+            req.headers()
+                .get(header::RANGE)
+                .map(|v| v.to_str().unwrap().to_owned()),
+        );
+        let origin = FakeEntity::new(BODY)
+            .etag("\"origin\"")
+            .content_type("text/plain");
+        Ok(crate::serve(origin, &req))
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn range_translation() {
+        let make_svc = hyper::service::make_service_fn(|_conn| {
+            futures::future::ok::<_, hyper::Error>(hyper::service::service_fn(origin))
+        });
+        let srv = hyper::server::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let url = format!("http://{}/", srv.local_addr()).parse().unwrap();
+        tokio::spawn(srv);
+
+        let e = HttpUpstreamEntity::<Bytes, BoxedError>::new(reqwest::Client::new(), url)
+            .await
+            .unwrap();
+        assert_eq!(e.len(), BODY.len() as u64);
+        assert_eq!(e.etag().unwrap(), "\"origin\"");
+        let mut h = HeaderMap::new();
+        e.add_headers(&mut h);
+        assert_eq!(h.get(header::CONTENT_TYPE).unwrap(), "text/plain");
+
+        let req = http::Request::get("/")
+            .header(header::RANGE, "bytes=3-5")
+            .body(())
+            .unwrap();
+        let resp: http::Response<Body> = crate::serve(e.clone(), &req);
+        assert_eq!(resp.status(), http::StatusCode::PARTIAL_CONTENT);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"345");
+
+        let req = http::Request::get("/").body(()).unwrap();
+        let resp: http::Response<Body> = crate::serve(e, &req);
+        assert_eq!(resp.status(), http::StatusCode::OK);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(&body[..], BODY);
+
+        assert_eq!(
+            &RANGES.lock().unwrap()[..],
+            &[
+                None,
+                Some("bytes=3-5".to_owned()),
+                Some("bytes=0-35".to_owned())
+            ]
+        );
+    }
+}
@@ -0,0 +1,490 @@
+// Copyright (c) 2016-2020 The http-serve developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Streaming `zip` archives of entities, as described in [PKWARE's
+//! APPNOTE.TXT](https://pkware.cachefly.net/webdocs/casestudies/APPNOTE.TXT).
+//!
+//! Entries are stored uncompressed. Each entry's CRC-32 is only known once its data has been
+//! streamed, so it follows the data in a data descriptor (general purpose flag bit 3). Zip64
+//! records are used for entries, offsets, and counts which don't fit the original format.
+
+use crate::{serve_stream, BoxedEntity};
+use bytes::Buf;
+use futures::Stream;
+use http::header::{self, HeaderValue};
+use http::{Request, Response};
+use http_body::Body;
+use std::error::Error as StdError;
+use std::io::IoSlice;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const LOCAL_HEADER_SIG: u32 = 0x0403_4b50;
+const DATA_DESCRIPTOR_SIG: u32 = 0x0807_4b50;
+const CENTRAL_HEADER_SIG: u32 = 0x0201_4b50;
+const ZIP64_END_SIG: u32 = 0x0606_4b50;
+const ZIP64_LOCATOR_SIG: u32 = 0x0706_4b50;
+const END_SIG: u32 = 0x0605_4b50;
+
+/// General purpose flags: sizes and CRC in a data descriptor (bit 3); UTF-8 names (bit 11).
+const FLAGS: u16 = 0x0808;
+
+const VERSION: u16 = 20;
+const VERSION_ZIP64: u16 = 45;
+
+/// Serves a `zip` archive of `entries`, each a file name and its contents.
+///
+/// The archive is generated as it's sent, reading each entity's full range in turn, so it's never
+/// held in memory. The response is `application/zip` with no `Content-Length` and
+/// `Accept-Ranges: none`; conditional and range requests aren't supported. `HEAD` and
+/// non-`GET` requests are handled as by [`serve_stream`](fn.serve_stream.html).
+///
+/// An entity yielding a different number of bytes than its `len` ends the body with an error, as
+/// the archive would otherwise be corrupt. So does a name longer than 65,535 bytes, the most a zip
+/// header can record.
+pub fn serve_zip<D, E, B, BI>(
+    req: &Request<BI>,
+    entries: Vec<(String, BoxedEntity<D, E>)>,
+) -> Response<B>
+where
+    D: 'static + Send + Sync + Buf + From<Vec<u8>> + From<&'static [u8]>,
+    E: 'static + Send + Sync + From<Box<dyn StdError + Send + Sync>>,
+    B: Body + From<Box<dyn Stream<Item = Result<D, E>> + Send>>,
+{
+    let stream = ZipStream {
+        entries: entries.into_iter(),
+        current: None,
+        offset: 0,
+        central: Vec::new(),
+        count: 0,
+        done: false,
+    };
+    let mut res = serve_stream(req, stream, HeaderValue::from_static("application/zip"));
+    if res.status().is_success() {
+        res.headers_mut()
+            .insert(header::ACCEPT_RANGES, HeaderValue::from_static("none"));
+    }
+    res
+}
+
+struct ZipStream<D, E> {
+    entries: std::vec::IntoIter<(String, BoxedEntity<D, E>)>,
+
+    /// The entry whose data is being streamed, if any.
+    current: Option<Entry<D, E>>,
+
+    /// The number of bytes yielded so far.
+    offset: u64,
+
+    /// The central directory headers of the completed entries.
+    central: Vec<u8>,
+    count: u64,
+    done: bool,
+}
+
+struct Entry<D, E> {
+    name: String,
+    len: u64,
+    header_offset: u64,
+    dos_time: u16,
+    dos_date: u16,
+    zip64: bool,
+    data: Pin<Box<dyn Stream<Item = Result<D, E>> + Send + Sync>>,
+    crc: flate2::Crc,
+    read: u64,
+}
+
+impl<D, E> Stream for ZipStream<D, E>
+where
+    D: 'static + Send + Sync + Buf + From<Vec<u8>> + From<&'static [u8]>,
+    E: 'static + Send + Sync + From<Box<dyn StdError + Send + Sync>>,
+{
+    type Item = Result<D, E>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if let Some(ref mut cur) = this.current {
+            match cur.data.as_mut().poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Some(Err(e))) => {
+                    this.current = None;
+                    this.done = true;
+                    return Poll::Ready(Some(Err(e)));
+                }
+                Poll::Ready(Some(Ok(d))) => {
+                    let d = update_crc(&mut cur.crc, d);
+                    let n = d.remaining() as u64;
+                    cur.read += n;
+                    this.offset += n;
+                    return Poll::Ready(Some(Ok(d)));
+                }
+                Poll::Ready(None) => {}
+            }
+            let cur = this.current.take().expect("current is some");
+            if cur.read != cur.len {
+                this.done = true;
+                let msg = format!(
+                    "zip entry {:?} yielded {} bytes; expected {}",
+                    cur.name, cur.read, cur.len
+                );
+                return Poll::Ready(Some(Err(E::from(msg.into()))));
+            }
+            let descriptor = cur.descriptor();
+            cur.central_header(&mut this.central);
+            this.count += 1;
+            this.offset += descriptor.len() as u64;
+            return Poll::Ready(Some(Ok(descriptor.into())));
+        }
+        if this.done {
+            return Poll::Ready(None);
+        }
+        match this.entries.next() {
+            Some((name, ent)) => {
+                if name.len() > usize::from(std::u16::MAX) {
+                    this.done = true;
+                    let msg = format!(
+                        "zip entry name is {} bytes; at most {} are allowed",
+                        name.len(),
+                        std::u16::MAX
+                    );
+                    return Poll::Ready(Some(Err(E::from(msg.into()))));
+                }
+                let len = ent.len();
+                let (dos_time, dos_date) = dos_time(ent.last_modified());
+                let cur = Entry {
+                    name,
+                    len,
+                    header_offset: this.offset,
+                    dos_time,
+                    dos_date,
+                    zip64: len >= 0xFFFF_FFFF || this.offset >= 0xFFFF_FFFF,
+                    data: Pin::from(ent.get_range(0..len)),
+                    crc: flate2::Crc::new(),
+                    read: 0,
+                };
+                let header = cur.local_header();
+                this.offset += header.len() as u64;
+                this.current = Some(cur);
+                Poll::Ready(Some(Ok(header.into())))
+            }
+            None => {
+                this.done = true;
+                let central = std::mem::take(&mut this.central);
+                Poll::Ready(Some(Ok(end(central, this.offset, this.count).into())))
+            }
+        }
+    }
+}
+
+impl<D, E> Entry<D, E> {
+    fn local_header(&self) -> Vec<u8> {
+        let mut h = Vec::with_capacity(30 + self.name.len() + 20);
+        put_u32(&mut h, LOCAL_HEADER_SIG);
+        put_u16(&mut h, if self.zip64 { VERSION_ZIP64 } else { VERSION });
+        put_u16(&mut h, FLAGS);
+        put_u16(&mut h, 0); // method: stored.
+        put_u16(&mut h, self.dos_time);
+        put_u16(&mut h, self.dos_date);
+        put_u32(&mut h, 0); // crc: in the data descriptor.
+        let size = if self.zip64 { 0xFFFF_FFFF } else { 0 };
+        put_u32(&mut h, size); // compressed size.
+        put_u32(&mut h, size); // uncompressed size.
+        put_u16(&mut h, self.name.len() as u16);
+        put_u16(&mut h, if self.zip64 { 20 } else { 0 });
+        h.extend_from_slice(self.name.as_bytes());
+        if self.zip64 {
+            put_u16(&mut h, 0x0001);
+            put_u16(&mut h, 16);
+            put_u64(&mut h, 0); // uncompressed size: in the data descriptor.
+            put_u64(&mut h, 0); // compressed size.
+        }
+        h
+    }
+
+    fn descriptor(&self) -> Vec<u8> {
+        let mut d = Vec::with_capacity(24);
+        put_u32(&mut d, DATA_DESCRIPTOR_SIG);
+        put_u32(&mut d, self.crc.sum());
+        if self.zip64 {
+            put_u64(&mut d, self.len);
+            put_u64(&mut d, self.len);
+        } else {
+            put_u32(&mut d, self.len as u32);
+            put_u32(&mut d, self.len as u32);
+        }
+        d
+    }
+
+    fn central_header(&self, c: &mut Vec<u8>) {
+        put_u32(c, CENTRAL_HEADER_SIG);
+        let version = if self.zip64 { VERSION_ZIP64 } else { VERSION };
+        put_u16(c, version); // version made by.
+        put_u16(c, version); // version needed to extract.
+        put_u16(c, FLAGS);
+        put_u16(c, 0); // method: stored.
+        put_u16(c, self.dos_time);
+        put_u16(c, self.dos_date);
+        put_u32(c, self.crc.sum());
+        if self.zip64 {
+            put_u32(c, 0xFFFF_FFFF);
+            put_u32(c, 0xFFFF_FFFF);
+        } else {
+            put_u32(c, self.len as u32);
+            put_u32(c, self.len as u32);
+        }
+        put_u16(c, self.name.len() as u16);
+        put_u16(c, if self.zip64 { 28 } else { 0 });
+        put_u16(c, 0); // comment length.
+        put_u16(c, 0); // disk number start.
+        put_u16(c, 0); // internal attributes.
+        put_u32(c, 0); // external attributes.
+        if self.zip64 {
+            put_u32(c, 0xFFFF_FFFF);
+        } else {
+            put_u32(c, self.header_offset as u32);
+        }
+        c.extend_from_slice(self.name.as_bytes());
+        if self.zip64 {
+            put_u16(c, 0x0001);
+            put_u16(c, 24);
+            put_u64(c, self.len); // uncompressed size.
+            put_u64(c, self.len); // compressed size.
+            put_u64(c, self.header_offset);
+        }
+    }
+}
+
+/// Returns the central directory followed by the end of central directory record (and, as needed,
+/// zip64 equivalents), given that the directory starts at `offset`.
+fn end(mut central: Vec<u8>, offset: u64, count: u64) -> Vec<u8> {
+    let size = central.len() as u64;
+    let zip64 = count >= 0xFFFF || size >= 0xFFFF_FFFF || offset >= 0xFFFF_FFFF;
+    let e = &mut central;
+    if zip64 {
+        let zip64_end_offset = offset + size;
+        put_u32(e, ZIP64_END_SIG);
+        put_u64(e, 44); // size of the remaining record.
+        put_u16(e, VERSION_ZIP64); // version made by.
+        put_u16(e, VERSION_ZIP64); // version needed to extract.
+        put_u32(e, 0); // this disk.
+        put_u32(e, 0); // disk with the central directory.
+        put_u64(e, count); // entries on this disk.
+        put_u64(e, count); // total entries.
+        put_u64(e, size);
+        put_u64(e, offset);
+
+        put_u32(e, ZIP64_LOCATOR_SIG);
+        put_u32(e, 0); // disk with the zip64 end of central directory record.
+        put_u64(e, zip64_end_offset);
+        put_u32(e, 1); // total disks.
+    }
+    put_u32(e, END_SIG);
+    put_u16(e, 0); // this disk.
+    put_u16(e, 0); // disk with the central directory.
+    let count16 = if zip64 { 0xFFFF } else { count as u16 };
+    put_u16(e, count16); // entries on this disk.
+    put_u16(e, count16); // total entries.
+    put_u32(e, if zip64 { 0xFFFF_FFFF } else { size as u32 });
+    put_u32(e, if zip64 { 0xFFFF_FFFF } else { offset as u32 });
+    put_u16(e, 0); // comment length.
+    central
+}
+
+/// Updates `crc` with `d`, copying it into a fresh chunk in the unlikely case it has more slices
+/// than `bytes_vectored` returns at once.
+fn update_crc<D: Buf + From<Vec<u8>>>(crc: &mut flate2::Crc, mut d: D) -> D {
+    let mut slices = [IoSlice::new(&[]); 16];
+    let n = d.bytes_vectored(&mut slices);
+    let covered: usize = slices[..n].iter().map(|s| s.len()).sum();
+    if covered == d.remaining() {
+        for s in &slices[..n] {
+            crc.update(s);
+        }
+        return d;
+    }
+    let v = d.to_bytes().to_vec();
+    crc.update(&v);
+    v.into()
+}
+
+/// Returns the MS-DOS time and date of `t` in UTC, as zip headers use, or 1980-01-01 00:00:00
+/// (the earliest representable) if unknown or out of range.
+fn dos_time(t: Option<SystemTime>) -> (u16, u16) {
+    const EARLIEST: (u16, u16) = (0, (1 << 5) | 1);
+    let secs = match t.and_then(|t| t.duration_since(UNIX_EPOCH).ok()) {
+        Some(d) => d.as_secs(),
+        None => return EARLIEST,
+    };
+    let (days, secs) = (secs / 86_400, secs % 86_400);
+
+    // Civil from days, per Howard Hinnant's algorithm.
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    if !(1980..=2107).contains(&year) {
+        return EARLIEST;
+    }
+    let time = ((secs / 3600) << 11) | ((secs / 60 % 60) << 5) | ((secs % 60) / 2);
+    let date = ((year - 1980) << 9) | (month << 5) | day;
+    (time as u16, date as u16)
+}
+
+fn put_u16(v: &mut Vec<u8>, x: u16) {
+    v.extend_from_slice(&x.to_le_bytes());
+}
+
+fn put_u32(v: &mut Vec<u8>, x: u32) {
+    v.extend_from_slice(&x.to_le_bytes());
+}
+
+fn put_u64(v: &mut Vec<u8>, x: u64) {
+    v.extend_from_slice(&x.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::{FakeEntity, FakeRequest};
+    use crate::BoxedEntity;
+    use bytes::Bytes;
+    use http::{header, Response, StatusCode};
+    use std::convert::TryInto;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    type BoxedError = Box<dyn std::error::Error + Send + Sync>;
+
+    fn u16_at(b: &[u8], i: usize) -> u16 {
+        u16::from_le_bytes(b[i..i + 2].try_into().unwrap())
+    }
+
+    fn u32_at(b: &[u8], i: usize) -> u32 {
+        u32::from_le_bytes(b[i..i + 4].try_into().unwrap())
+    }
+
+    /// Extracts a (non-zip64) archive via its central directory, checking each entry's CRC.
+    fn extract(zip: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let end = zip.len() - 22;
+        assert_eq!(u32_at(zip, end), super::END_SIG);
+        let count = u16_at(zip, end + 10) as usize;
+        let mut c = u32_at(zip, end + 16) as usize;
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            assert_eq!(u32_at(zip, c), super::CENTRAL_HEADER_SIG);
+            assert_eq!(u16_at(zip, c + 10), 0, "stored");
+            let crc = u32_at(zip, c + 16);
+            let size = u32_at(zip, c + 20) as usize;
+            let name_len = u16_at(zip, c + 28) as usize;
+            let extra_len = u16_at(zip, c + 30) as usize;
+            let local = u32_at(zip, c + 42) as usize;
+            let name = String::from_utf8(zip[c + 46..c + 46 + name_len].to_vec()).unwrap();
+
+            assert_eq!(u32_at(zip, local), super::LOCAL_HEADER_SIG);
+            assert_eq!(u16_at(zip, local + 6), super::FLAGS);
+            let data =
+                local + 30 + u16_at(zip, local + 26) as usize + u16_at(zip, local + 28) as usize;
+            let contents = zip[data..data + size].to_vec();
+            assert_eq!(u32_at(zip, data + size), super::DATA_DESCRIPTOR_SIG);
+            assert_eq!(u32_at(zip, data + size + 4), crc);
+            let mut actual = flate2::Crc::new();
+            actual.update(&contents);
+            assert_eq!(actual.sum(), crc, "{}", name);
+
+            entries.push((name, contents));
+            c += 46 + name_len + extra_len;
+        }
+        assert_eq!(c, end);
+        entries
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let t = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let entries: Vec<(String, BoxedEntity<Bytes, BoxedError>)> = vec![
+            (
+                "a.txt".to_owned(),
+                Box::new(FakeEntity::new(&b"alpha"[..]).last_modified(t)),
+            ),
+            (
+                "dir/b.bin".to_owned(),
+                Box::new(FakeEntity::new(vec![7u8; 100_000])),
+            ),
+            ("empty".to_owned(), Box::new(FakeEntity::new(&b""[..]))),
+            (
+                "héllo.txt".to_owned(),
+                Box::new(FakeEntity::new(&b"unicode"[..])),
+            ),
+        ];
+        let resp: Response<hyper::Body> = super::serve_zip(&FakeRequest::get().build(), entries);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/zip");
+        assert_eq!(resp.headers()[header::ACCEPT_RANGES], "none");
+        assert!(resp.headers().get(header::CONTENT_LENGTH).is_none());
+        let zip = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let entries = extract(&zip);
+        assert_eq!(
+            entries,
+            vec![
+                ("a.txt".to_owned(), b"alpha".to_vec()),
+                ("dir/b.bin".to_owned(), vec![7u8; 100_000]),
+                ("empty".to_owned(), Vec::new()),
+                ("héllo.txt".to_owned(), b"unicode".to_vec()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn long_name() {
+        let entries: Vec<(String, BoxedEntity<Bytes, BoxedError>)> = vec![
+            ("a.txt".to_owned(), Box::new(FakeEntity::new(&b"alpha"[..]))),
+            (
+                "x".repeat(usize::from(std::u16::MAX) + 1),
+                Box::new(FakeEntity::new(&b""[..])),
+            ),
+        ];
+        let resp: Response<hyper::Body> = super::serve_zip(&FakeRequest::get().build(), entries);
+        let e = hyper::body::to_bytes(resp.into_body()).await.unwrap_err();
+        assert!(e.to_string().contains("65536 bytes"), "{}", e);
+    }
+
+    #[test]
+    fn dos_time() {
+        // 2020-09-13 12:26:40 UTC.
+        let t = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let (time, date) = super::dos_time(Some(t));
+        assert_eq!(
+            (time >> 11, (time >> 5) & 0x3F, (time & 0x1F) * 2),
+            (12, 26, 40)
+        );
+        assert_eq!(
+            (1980 + (date >> 9), (date >> 5) & 0xF, date & 0x1F),
+            (2020, 9, 13)
+        );
+        assert_eq!(super::dos_time(None), (0, 0x21));
+        assert_eq!(super::dos_time(Some(UNIX_EPOCH)), (0, 0x21));
+    }
+
+    #[test]
+    fn zip64_end() {
+        // Past 4 GiB, the end records point to a zip64 end of central directory record.
+        let offset = 5 << 30;
+        let e = super::end(Vec::new(), offset, 1);
+        assert_eq!(e.len(), 56 + 20 + 22);
+        assert_eq!(u32_at(&e, 0), super::ZIP64_END_SIG);
+        assert_eq!(u64::from_le_bytes(e[48..56].try_into().unwrap()), offset);
+        assert_eq!(u32_at(&e, 56), super::ZIP64_LOCATOR_SIG);
+        assert_eq!(u64::from_le_bytes(e[64..72].try_into().unwrap()), offset);
+        assert_eq!(u32_at(&e, 76), super::END_SIG);
+        assert_eq!(u32_at(&e, 76 + 16), 0xFFFF_FFFF);
+    }
+}
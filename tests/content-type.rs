@@ -0,0 +1,98 @@
+// Copyright (c) 2016-2020 The http-serve developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Tests `Entity::content_type`, counting allocations. This is its own test binary so that the
+//! counting allocator sees nothing but this test.
+
+use bytes::Bytes;
+use futures::Stream;
+use http::header::{self, HeaderMap, HeaderValue};
+use http_serve::{CowEntity, Entity};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+type BoxedError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Wraps the system allocator to count allocations.
+struct CountingAlloc;
+
+static ALLOCS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+/// Returns `f()` and the number of allocations it made.
+fn count_allocs<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let before = ALLOCS.load(Ordering::Relaxed);
+    let t = f();
+    (t, ALLOCS.load(Ordering::Relaxed) - before)
+}
+
+/// An entity which implements only the required methods, so it gets the default
+/// `content_type`.
+struct AddsHeaders;
+
+impl Entity for AddsHeaders {
+    type Data = Bytes;
+    type Error = BoxedError;
+
+    fn len(&self) -> u64 {
+        0
+    }
+    fn get_range(
+        &self,
+        _range: Range<u64>,
+    ) -> Box<dyn Stream<Item = Result<Self::Data, Self::Error>> + Send + Sync> {
+        Box::new(futures::stream::empty())
+    }
+    fn add_headers(&self, h: &mut HeaderMap) {
+        h.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        h.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+    }
+    fn etag(&self) -> Option<HeaderValue> {
+        None
+    }
+    fn last_modified(&self) -> Option<SystemTime> {
+        None
+    }
+}
+
+#[test]
+fn content_type() {
+    let text_plain = Some(HeaderValue::from_static("text/plain"));
+
+    // The default implementation finds the value `add_headers` sets, via a `HeaderMap`.
+    let (t, allocs) = count_allocs(|| AddsHeaders.content_type());
+    assert_eq!(t, text_plain);
+    assert!(allocs > 0);
+
+    // An override doesn't allocate. (`CowEntity` has a `content_type` builder method too.)
+    let e = CowEntity::<Bytes, BoxedError>::new(&b"hello"[..])
+        .content_type(HeaderValue::from_static("text/plain"));
+    let (t, allocs) = count_allocs(|| Entity::content_type(&e));
+    assert_eq!(t, text_plain);
+    assert_eq!(allocs, 0);
+}
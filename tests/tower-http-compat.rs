@@ -0,0 +1,638 @@
+// Copyright (c) 2016-2020 The http-serve developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Compares `http_serve::tower_http_compat::ServeDir` with `tower_http::services::ServeDir` on
+//! the same directory tree. Tests named `same_*` expect matching responses; tests named
+//! `differs_*` document intentional differences.
+
+use bytes::Bytes;
+use flate2::write::GzEncoder;
+use http::{header, Method, Request, StatusCode};
+use http_serve::SymlinkPolicy;
+use once_cell::sync::Lazy;
+use std::io::Write;
+use tempfile::TempDir;
+use tower::ServiceExt;
+use tower_http_body::Body as _;
+
+type BoxedError = Box<dyn std::error::Error + Send + Sync>;
+
+static BODY: &[u8] = b"01234567890123456789012345678901234567890123456789";
+
+/// Headers compared between the two implementations.
+static COMPARED: [header::HeaderName; 8] = [
+    header::ACCEPT_RANGES,
+    header::CONTENT_ENCODING,
+    header::CONTENT_LENGTH,
+    header::CONTENT_RANGE,
+    header::CONTENT_TYPE,
+    header::LAST_MODIFIED,
+    header::LOCATION,
+    header::VARY,
+];
+
+/// A directory tree with:
+///
+/// * `index.html`
+/// * `a.txt` and its precompressed `a.txt.gz` and `a.txt.br`
+/// * `big.bin`, large enough that a multi-range request gets a multipart response
+/// * `only.txt.gz`, without an uncompressed original
+/// * `with space.txt`
+/// * `noindex/`, with no `index.html`
+/// * `sub/index.html`
+static TREE: Lazy<TempDir> = Lazy::new(|| {
+    let tmp = tempfile::tempdir().unwrap();
+    let p = tmp.path();
+    let gzip = |data: &[u8]| {
+        let mut e = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        e.write_all(data).unwrap();
+        e.finish().unwrap()
+    };
+    std::fs::write(p.join("index.html"), b"<b>index</b>").unwrap();
+    std::fs::write(p.join("a.txt"), BODY).unwrap();
+    std::fs::write(p.join("a.txt.gz"), gzip(BODY)).unwrap();
+    std::fs::write(p.join("a.txt.br"), b"not really brotli").unwrap();
+    std::fs::write(p.join("big.bin"), vec![b'x'; 65536]).unwrap();
+    std::fs::write(p.join("only.txt.gz"), gzip(BODY)).unwrap();
+    std::fs::write(p.join("with space.txt"), BODY).unwrap();
+    std::fs::create_dir(p.join("noindex")).unwrap();
+    std::fs::create_dir(p.join("sub")).unwrap();
+    std::fs::write(p.join("sub/index.html"), b"<b>sub</b>").unwrap();
+    tmp
+});
+
+/// A directory tree of names with bytes which are invalid in header values or URIs:
+///
+/// * `new\nline.txt` and `del\x7f.txt`
+/// * `quote"brace{}.txt`
+/// * `bad\xff.txt`, which isn't UTF-8
+/// * `dir\nname/` and `dir"q/`, each with an `index.html`
+#[cfg(unix)]
+static ADVERSARIAL: Lazy<TempDir> = Lazy::new(|| {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    let tmp = tempfile::tempdir().unwrap();
+    let p = tmp.path();
+    std::fs::write(p.join("new\nline.txt"), BODY).unwrap();
+    std::fs::write(p.join("del\x7f.txt"), BODY).unwrap();
+    std::fs::write(p.join("quote\"brace{}.txt"), BODY).unwrap();
+    std::fs::write(p.join(OsStr::from_bytes(b"bad\xff.txt")), BODY).unwrap();
+    for d in &["dir\nname", "dir\"q"] {
+        std::fs::create_dir(p.join(d)).unwrap();
+        std::fs::write(p.join(d).join("index.html"), b"<b>index</b>").unwrap();
+    }
+    tmp
+});
+
+/// A directory with a `root/` tree to serve and an `outside/secret.txt` beside it. `root/` has
+/// `real.txt` and `dir/f.txt`, and symlinks:
+///
+/// * `in.txt` to `real.txt`, `up.txt` to `dir/../real.txt`, and `abs.txt` to `real.txt`'s
+///   absolute path, which stay within it
+/// * `linkdir` to `dir`, a directory within it
+/// * `out.txt` to `../outside/secret.txt` and `absout.txt` to its absolute path, which escape it
+/// * `outdir` to `../outside`, a directory outside it
+#[cfg(unix)]
+static SYMLINKS: Lazy<TempDir> = Lazy::new(|| {
+    use std::os::unix::fs::symlink;
+    let tmp = tempfile::tempdir().unwrap();
+    let p = tmp.path().canonicalize().unwrap();
+    let root = p.join("root");
+    std::fs::create_dir(&root).unwrap();
+    std::fs::create_dir(root.join("dir")).unwrap();
+    std::fs::create_dir(p.join("outside")).unwrap();
+    std::fs::write(root.join("real.txt"), BODY).unwrap();
+    std::fs::write(root.join("dir/f.txt"), BODY).unwrap();
+    std::fs::write(p.join("outside/secret.txt"), b"secret").unwrap();
+    symlink("real.txt", root.join("in.txt")).unwrap();
+    symlink("dir/../real.txt", root.join("up.txt")).unwrap();
+    symlink(root.join("real.txt"), root.join("abs.txt")).unwrap();
+    symlink("dir", root.join("linkdir")).unwrap();
+    symlink("../outside/secret.txt", root.join("out.txt")).unwrap();
+    symlink(p.join("outside/secret.txt"), root.join("absout.txt")).unwrap();
+    symlink("../outside", root.join("outdir")).unwrap();
+    tmp
+});
+
+#[derive(Debug, PartialEq)]
+struct Summary {
+    status: StatusCode,
+    headers: Vec<(header::HeaderName, Option<header::HeaderValue>)>,
+
+    /// The body, if the status is successful. Error bodies are free-form.
+    body: Option<Bytes>,
+}
+
+impl Summary {
+    fn new(res: http::response::Parts, body: Bytes) -> Self {
+        Summary {
+            status: res.status,
+            headers: COMPARED
+                .iter()
+                .map(|h| (h.clone(), res.headers.get(h).cloned()))
+                .collect(),
+            body: if res.status.is_success() {
+                Some(body)
+            } else {
+                None
+            },
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Config {
+    append_index_html_on_directories: bool,
+    precompressed_gzip: bool,
+    precompressed_br: bool,
+
+    /// Applies only to http-serve; tower-http 0.2 always follows symlinks.
+    symlink_policy: SymlinkPolicy,
+}
+
+const DEFAULT: Config = Config {
+    append_index_html_on_directories: true,
+    precompressed_gzip: false,
+    precompressed_br: false,
+    symlink_policy: SymlinkPolicy::FollowIfSameRoot,
+};
+
+const GZIP: Config = Config {
+    precompressed_gzip: true,
+    ..DEFAULT
+};
+
+const GZIP_AND_BR: Config = Config {
+    precompressed_gzip: true,
+    precompressed_br: true,
+    ..DEFAULT
+};
+
+fn req(method: Method, uri: &str, hdrs: &[(&'static str, &'static str)]) -> Request<()> {
+    let mut r = Request::builder().method(method).uri(uri);
+    for &(k, v) in hdrs {
+        r = r.header(k, v);
+    }
+    r.body(()).unwrap()
+}
+
+/// Returns the responses of http-serve's and tower-http's `ServeDir`, in that order.
+fn both(cfg: Config, req: Request<()>) -> (Summary, Summary) {
+    both_in(TREE.path(), cfg, req)
+}
+
+/// As `both`, serving `dir` rather than `TREE`.
+fn both_in(dir: &std::path::Path, cfg: Config, req: Request<()>) -> (Summary, Summary) {
+    let mut theirs = tower_http::services::ServeDir::new(dir)
+        .append_index_html_on_directories(cfg.append_index_html_on_directories);
+    if cfg.precompressed_gzip {
+        theirs = theirs.precompressed_gzip();
+    }
+    if cfg.precompressed_br {
+        theirs = theirs.precompressed_br();
+    }
+    let ours = ours_in(dir, cfg, copy(&req));
+
+    let rt = tokio1::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let theirs = rt.block_on(async move {
+        let res = theirs.oneshot(req).await.unwrap();
+        let (parts, mut body) = res.into_parts();
+        let mut buf = Vec::new();
+        while let Some(chunk) = body.data().await {
+            buf.extend_from_slice(&chunk.unwrap());
+        }
+        Summary::new(parts, buf.into())
+    });
+    (ours, theirs)
+}
+
+/// Returns the response of http-serve's `ServeDir` alone, serving `dir`.
+fn ours_in(dir: &std::path::Path, cfg: Config, req: Request<()>) -> Summary {
+    let mut ours = http_serve::tower_http_compat::ServeDir::new(dir)
+        .append_index_html_on_directories(cfg.append_index_html_on_directories)
+        .symlink_policy(cfg.symlink_policy);
+    if cfg.precompressed_gzip {
+        ours = ours.precompressed_gzip();
+    }
+    if cfg.precompressed_br {
+        ours = ours.precompressed_br();
+    }
+
+    let mut rt = tokio::runtime::Builder::new()
+        .threaded_scheduler()
+        .enable_all()
+        .build()
+        .unwrap();
+    rt.block_on(async move {
+        tokio::spawn(async move {
+            let res: http::Response<hyper::Body> = ours.serve::<Bytes, BoxedError, _, _>(&req);
+            let (parts, body) = res.into_parts();
+            Summary::new(parts, hyper::body::to_bytes(body).await.unwrap())
+        })
+        .await
+        .unwrap()
+    })
+}
+
+fn copy(req: &Request<()>) -> Request<()> {
+    let mut r = Request::new(());
+    *r.method_mut() = req.method().clone();
+    *r.uri_mut() = req.uri().clone();
+    *r.headers_mut() = req.headers().clone();
+    r
+}
+
+fn assert_same(cfg: Config, req: Request<()>) -> Summary {
+    let (ours, theirs) = both(cfg, req);
+    assert_eq!(ours, theirs);
+    ours
+}
+
+#[test]
+fn same_file() {
+    let s = assert_same(DEFAULT, req(Method::GET, "/a.txt", &[]));
+    assert_eq!(s.status, StatusCode::OK);
+    assert_eq!(s.body.as_deref(), Some(BODY));
+}
+
+#[test]
+fn same_head() {
+    assert_same(DEFAULT, req(Method::HEAD, "/a.txt", &[]));
+}
+
+#[test]
+fn same_root_index() {
+    let s = assert_same(DEFAULT, req(Method::GET, "/", &[]));
+    assert_eq!(s.body.as_deref(), Some(&b"<b>index</b>"[..]));
+}
+
+#[test]
+fn same_subdir_index() {
+    assert_same(DEFAULT, req(Method::GET, "/sub/", &[]));
+}
+
+#[test]
+fn same_subdir_redirect() {
+    let s = assert_same(DEFAULT, req(Method::GET, "/sub", &[]));
+    assert_eq!(s.status, StatusCode::TEMPORARY_REDIRECT);
+}
+
+#[test]
+fn same_subdir_redirect_with_query() {
+    assert_same(DEFAULT, req(Method::GET, "/sub?x=1", &[]));
+}
+
+#[test]
+fn same_dir_without_index() {
+    assert_same(DEFAULT, req(Method::GET, "/noindex/", &[]));
+}
+
+#[test]
+fn same_dir_without_appending_index() {
+    let cfg = Config {
+        append_index_html_on_directories: false,
+        ..DEFAULT
+    };
+    let s = assert_same(cfg, req(Method::GET, "/sub/", &[]));
+    assert_eq!(s.status, StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn same_percent_decoded() {
+    let s = assert_same(DEFAULT, req(Method::GET, "/with%20space.txt", &[]));
+    assert_eq!(s.status, StatusCode::OK);
+}
+
+#[test]
+fn same_missing() {
+    let s = assert_same(DEFAULT, req(Method::GET, "/missing.txt", &[]));
+    assert_eq!(s.status, StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn same_traversal_rejected() {
+    let s = assert_same(DEFAULT, req(Method::GET, "/sub/%2e%2e/a.txt", &[]));
+    assert_eq!(s.status, StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn same_single_range() {
+    let s = assert_same(
+        DEFAULT,
+        req(Method::GET, "/a.txt", &[("Range", "bytes=10-19")]),
+    );
+    assert_eq!(s.status, StatusCode::PARTIAL_CONTENT);
+}
+
+/// Responses otherwise match, but http-serve adds `Vary: accept-encoding` to responses for a
+/// file with a precompressed variant, whichever coding it sends, so caches don't serve one coding
+/// to a client asking for another.
+#[test]
+fn differs_precompressed_adds_vary() {
+    let cases = [
+        (GZIP, Some("gzip"), Some("gzip")),
+        (GZIP, None, None),
+        (GZIP_AND_BR, Some("br, gzip"), Some("br")),
+        (GZIP_AND_BR, Some("br;q=0, gzip"), Some("gzip")),
+    ];
+    for &(cfg, accept, coding) in &cases {
+        let r = match accept {
+            Some(a) => req(Method::GET, "/a.txt", &[("Accept-Encoding", a)]),
+            None => req(Method::GET, "/a.txt", &[]),
+        };
+        let (mut ours, theirs) = both(cfg, r);
+        assert_eq!(
+            ours.headers[1].1,
+            coding.map(header::HeaderValue::from_static)
+        );
+        assert_eq!(
+            ours.headers[7].1.take(),
+            Some(header::HeaderValue::from_static("accept-encoding"))
+        );
+        assert_eq!(ours, theirs);
+    }
+}
+
+/// Both refuse, but tower-http 0.2 also sends the `Content-Type`, which describes the file rather
+/// than the `416 Range Not Satisfiable` response.
+#[test]
+fn differs_unsatisfiable_range_omits_content_type() {
+    let (ours, mut theirs) = both(
+        DEFAULT,
+        req(Method::GET, "/a.txt", &[("Range", "bytes=100-")]),
+    );
+    assert_eq!(ours.status, StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(ours.headers[4].1, None);
+    assert_eq!(
+        theirs.headers[4].1.take(),
+        Some(header::HeaderValue::from_static("text/plain"))
+    );
+    assert_eq!(ours, theirs);
+}
+
+/// http-serve prefers `br` whenever it's accepted; tower-http 0.2 takes the first of the
+/// highest-qvalue codings in `Accept-Encoding`.
+#[test]
+fn differs_br_preferred_over_gzip() {
+    let (ours, theirs) = both(
+        GZIP_AND_BR,
+        req(Method::GET, "/a.txt", &[("Accept-Encoding", "gzip, br")]),
+    );
+    let br = Some(header::HeaderValue::from_static("br"));
+    let gzip = Some(header::HeaderValue::from_static("gzip"));
+    assert_eq!(ours.headers[1].1, br);
+    assert_eq!(ours.body.as_deref(), Some(&b"not really brotli"[..]));
+    assert_eq!(theirs.headers[1].1, gzip);
+}
+
+/// http-serve sends an `ETag` and honors `If-None-Match`; tower-http 0.2 sends the file again.
+#[test]
+fn differs_if_none_match() {
+    let (ours, _) = both(DEFAULT, req(Method::GET, "/a.txt", &[]));
+    assert_eq!(ours.status, StatusCode::OK);
+    let (ours, theirs) = both(
+        DEFAULT,
+        req(Method::GET, "/a.txt", &[("If-None-Match", "*")]),
+    );
+    assert_eq!(ours.status, StatusCode::NOT_MODIFIED);
+    assert_eq!(theirs.status, StatusCode::OK);
+}
+
+/// http-serve sends a `multipart/byteranges` body; tower-http 0.2 refuses.
+#[cfg(feature = "multipart")]
+#[cfg(feature = "multipart")]
+#[test]
+fn differs_multiple_ranges() {
+    let (ours, theirs) = both(
+        DEFAULT,
+        req(Method::GET, "/big.bin", &[("Range", "bytes=0-1,1000-1001")]),
+    );
+    assert_eq!(ours.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(theirs.status, StatusCode::RANGE_NOT_SATISFIABLE);
+}
+
+/// http-serve only serves `GET` and `HEAD`; tower-http 0.2 serves the file for any method.
+#[test]
+fn differs_post() {
+    let (ours, theirs) = both(DEFAULT, req(Method::POST, "/a.txt", &[]));
+    assert_eq!(ours.status, StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(theirs.status, StatusCode::OK);
+}
+
+/// http-serve only offers a `.gz` file as a variant of its uncompressed original; tower-http 0.2
+/// serves it to clients accepting gzip even without one.
+#[test]
+fn differs_gzip_without_original() {
+    let (ours, theirs) = both(
+        GZIP,
+        req(Method::GET, "/only.txt", &[("Accept-Encoding", "gzip")]),
+    );
+    assert_eq!(ours.status, StatusCode::NOT_FOUND);
+    assert_eq!(theirs.status, StatusCode::OK);
+}
+
+/// Files and directories with adversarial names are served, redirected to, or refused without
+/// a panic or a malformed header.
+#[cfg(unix)]
+#[test]
+fn same_adversarial_names() {
+    let cases = [
+        ("/new%0Aline.txt", StatusCode::OK, None),
+        ("/del%7F.txt", StatusCode::OK, None),
+        ("/quote%22brace%7B%7D.txt", StatusCode::OK, None),
+        ("/bad%FF.txt", StatusCode::NOT_FOUND, None),
+        (
+            "/dir%0Aname",
+            StatusCode::TEMPORARY_REDIRECT,
+            Some("/dir%0Aname/"),
+        ),
+        ("/dir%0Aname/", StatusCode::OK, None),
+        (
+            "/dir%22q",
+            StatusCode::TEMPORARY_REDIRECT,
+            Some("/dir%22q/"),
+        ),
+        ("/dir%22q/", StatusCode::OK, None),
+    ];
+    for &(uri, status, location) in &cases {
+        let (ours, theirs) = both_in(ADVERSARIAL.path(), DEFAULT, req(Method::GET, uri, &[]));
+        assert_eq!(ours.status, status, "{}", uri);
+        assert_eq!(
+            ours.headers[6].1,
+            location.map(header::HeaderValue::from_static),
+            "{}",
+            uri
+        );
+        assert_eq!(ours, theirs, "{}", uri);
+    }
+}
+
+/// `http::Uri` accepts some characters RFC 3986 doesn't, such as `"`. http-serve percent-encodes
+/// them in a redirect's `Location`; tower-http 0.2 copies them as they are.
+#[cfg(unix)]
+#[test]
+fn differs_redirect_location_encoded() {
+    let (ours, theirs) = both_in(
+        ADVERSARIAL.path(),
+        DEFAULT,
+        req(Method::GET, "/dir\"q?a=|", &[]),
+    );
+    assert_eq!(ours.status, StatusCode::TEMPORARY_REDIRECT);
+    assert_eq!(theirs.status, StatusCode::TEMPORARY_REDIRECT);
+    let loc = |s: &Summary| s.headers[6].1.clone().unwrap();
+    assert_eq!(loc(&ours), "/dir%22q/?a=%7C");
+    assert_eq!(loc(&theirs), "/dir\"q/?a=|");
+    let (_, theirs) = both_in(
+        ADVERSARIAL.path(),
+        DEFAULT,
+        req(Method::GET, loc(&ours).to_str().unwrap(), &[]),
+    );
+    assert_eq!(theirs.status, StatusCode::OK);
+}
+
+/// Symlinks which resolve within the served directory, whether to a file or an intermediate
+/// directory, are followed under `Follow` and the default `FollowIfSameRoot`.
+#[cfg(unix)]
+#[test]
+fn same_symlink_within_root() {
+    let root = SYMLINKS.path().join("root");
+    for &symlink_policy in &[SymlinkPolicy::Follow, SymlinkPolicy::FollowIfSameRoot] {
+        let cfg = Config {
+            symlink_policy,
+            ..DEFAULT
+        };
+        for uri in &["/in.txt", "/up.txt", "/abs.txt", "/linkdir/f.txt"] {
+            let (ours, theirs) = both_in(&root, cfg, req(Method::GET, uri, &[]));
+            assert_eq!(ours.status, StatusCode::OK, "{:?} {}", symlink_policy, uri);
+            assert_eq!(
+                ours.body.as_deref(),
+                Some(BODY),
+                "{:?} {}",
+                symlink_policy,
+                uri
+            );
+            assert_eq!(ours, theirs, "{:?} {}", symlink_policy, uri);
+        }
+        let (ours, theirs) = both_in(&root, cfg, req(Method::GET, "/linkdir", &[]));
+        assert_eq!(
+            ours.status,
+            StatusCode::TEMPORARY_REDIRECT,
+            "{:?}",
+            symlink_policy
+        );
+        assert_eq!(ours, theirs, "{:?}", symlink_policy);
+    }
+}
+
+/// Under `Follow`, symlinks leading out of the served directory are followed as by tower-http.
+#[cfg(unix)]
+#[test]
+fn same_symlink_escaping_root_followed() {
+    let root = SYMLINKS.path().join("root");
+    let cfg = Config {
+        symlink_policy: SymlinkPolicy::Follow,
+        ..DEFAULT
+    };
+    for uri in &["/out.txt", "/absout.txt", "/outdir/secret.txt"] {
+        let (ours, theirs) = both_in(&root, cfg, req(Method::GET, uri, &[]));
+        assert_eq!(ours.status, StatusCode::OK, "{}", uri);
+        assert_eq!(ours.body.as_deref(), Some(&b"secret"[..]), "{}", uri);
+        assert_eq!(ours, theirs, "{}", uri);
+    }
+}
+
+/// Under `FollowIfSameRoot` and `Deny`, http-serve refuses symlinks which lead out of the served
+/// directory; tower-http 0.2 follows them.
+#[cfg(unix)]
+#[test]
+fn differs_symlink_escaping_root() {
+    let root = SYMLINKS.path().join("root");
+    for &symlink_policy in &[SymlinkPolicy::FollowIfSameRoot, SymlinkPolicy::Deny] {
+        let cfg = Config {
+            symlink_policy,
+            ..DEFAULT
+        };
+        for uri in &["/out.txt", "/absout.txt", "/outdir/secret.txt"] {
+            let (ours, theirs) = both_in(&root, cfg, req(Method::GET, uri, &[]));
+            assert_eq!(
+                ours.status,
+                StatusCode::NOT_FOUND,
+                "{:?} {}",
+                symlink_policy,
+                uri
+            );
+            assert_eq!(
+                theirs.status,
+                StatusCode::OK,
+                "{:?} {}",
+                symlink_policy,
+                uri
+            );
+            assert_eq!(theirs.body.as_deref(), Some(&b"secret"[..]));
+        }
+    }
+}
+
+/// Under `Deny`, http-serve refuses even symlinks within the served directory, whether to a file
+/// or an intermediate directory; tower-http 0.2 follows them. Other files are still served.
+#[cfg(unix)]
+#[test]
+fn differs_symlink_denied() {
+    let root = SYMLINKS.path().join("root");
+    let cfg = Config {
+        symlink_policy: SymlinkPolicy::Deny,
+        ..DEFAULT
+    };
+    for uri in &[
+        "/in.txt",
+        "/up.txt",
+        "/abs.txt",
+        "/linkdir/f.txt",
+        "/linkdir",
+    ] {
+        let (ours, theirs) = both_in(&root, cfg, req(Method::GET, uri, &[]));
+        assert_eq!(ours.status, StatusCode::NOT_FOUND, "{}", uri);
+        assert!(
+            theirs.status.is_success() || theirs.status.is_redirection(),
+            "{}",
+            uri
+        );
+    }
+    for uri in &["/real.txt", "/dir/f.txt"] {
+        let (ours, theirs) = both_in(&root, cfg, req(Method::GET, uri, &[]));
+        assert_eq!(ours.status, StatusCode::OK, "{}", uri);
+        assert_eq!(ours, theirs, "{}", uri);
+    }
+}
+
+/// A FIFO gets `404 Not Found` rather than blocking a worker thread on its open or read, under
+/// each policy. tower-http 0.2 would block, so only http-serve is run.
+#[cfg(unix)]
+#[test]
+fn fifo_not_found() {
+    let tmp = tempfile::tempdir().unwrap();
+    let status = std::process::Command::new("mkfifo")
+        .arg(tmp.path().join("fifo"))
+        .status()
+        .unwrap();
+    assert!(status.success());
+    for &symlink_policy in &[
+        SymlinkPolicy::Follow,
+        SymlinkPolicy::FollowIfSameRoot,
+        SymlinkPolicy::Deny,
+    ] {
+        let cfg = Config {
+            symlink_policy,
+            ..DEFAULT
+        };
+        let ours = ours_in(tmp.path(), cfg, req(Method::GET, "/fifo", &[]));
+        assert_eq!(ours.status, StatusCode::NOT_FOUND, "{:?}", symlink_policy);
+    }
+}
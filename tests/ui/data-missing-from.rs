@@ -0,0 +1,52 @@
+// A chunk type without `From<&'static [u8]>` is rejected at the `Entity` impl.
+
+use futures::Stream;
+use http::header::{HeaderMap, HeaderValue};
+use std::ops::Range;
+use std::time::SystemTime;
+
+struct Chunk(bytes::Bytes);
+
+impl bytes::Buf for Chunk {
+    fn remaining(&self) -> usize {
+        self.0.remaining()
+    }
+    fn bytes(&self) -> &[u8] {
+        self.0.bytes()
+    }
+    fn advance(&mut self, cnt: usize) {
+        self.0.advance(cnt)
+    }
+}
+
+impl From<Vec<u8>> for Chunk {
+    fn from(v: Vec<u8>) -> Self {
+        Chunk(v.into())
+    }
+}
+
+struct E;
+
+impl http_serve::Entity for E {
+    type Data = Chunk;
+    type Error = std::io::Error;
+
+    fn len(&self) -> u64 {
+        0
+    }
+    fn get_range(
+        &self,
+        _range: Range<u64>,
+    ) -> Box<dyn Stream<Item = Result<Self::Data, Self::Error>> + Send + Sync> {
+        Box::new(futures::stream::empty())
+    }
+    fn add_headers(&self, _: &mut HeaderMap) {}
+    fn etag(&self) -> Option<HeaderValue> {
+        None
+    }
+    fn last_modified(&self) -> Option<SystemTime> {
+        None
+    }
+}
+
+fn main() {}